@@ -0,0 +1,135 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+use zip::ZipArchive;
+
+use crate::scan::{is_audio_file, COVER_EXTENSIONS};
+
+/// The supported files of a zip archive, extracted to a temporary work directory.
+///
+/// The work directory and everything in it is removed when this value is dropped.
+pub struct ExtractedArchive {
+    dir: TempDir,
+    /// The extracted audio files in the order they appear in the archive.
+    pub audio_files: Vec<PathBuf>,
+    /// The extracted `cover.*` image closest to the archive root, if any.
+    pub cover: Option<PathBuf>,
+}
+
+impl ExtractedArchive {
+    /// The work directory holding the extracted files, with the archive's folders preserved.
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+/// Returns `true` when the path names a zip archive rather than a directory of audio files.
+pub fn is_zip_archive(path: &Path) -> bool {
+    path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Extracts the supported audio files and a cover image from a zip archive.
+///
+/// Nested folders are recreated inside the work directory. Entries whose path would escape it
+/// (absolute paths or `..` components) and all other files are skipped.
+///
+/// # Arguments
+///
+/// * `zip_path` - The archive to extract.
+/// * `work_root` - The directory in which the work directory is created.
+///
+/// # Returns
+///
+/// The `ExtractedArchive`, or an error message if the archive could not be read or extracted.
+pub fn extract_archive(zip_path: &Path, work_root: &Path) -> Result<ExtractedArchive, String> {
+    let file = File::open(zip_path).map_err(|err| format!("Could not open '{}': {}", zip_path.display(), err))?;
+    let mut archive = ZipArchive::new(file).map_err(|err| format!("Could not read zip archive '{}': {}", zip_path.display(), err))?;
+    let dir = tempfile::Builder::new()
+        .prefix("m4btool-zip-")
+        .tempdir_in(work_root)
+        .map_err(|err| format!("Could not create work directory for '{}': {}", zip_path.display(), err))?;
+
+    let mut audio_files = Vec::new();
+    let mut covers = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(|err| format!("Could not read zip archive '{}': {}", zip_path.display(), err))?;
+        let Some(relative_path) = entry.enclosed_name().filter(|_| entry.is_file()) else { continue };
+        let is_cover = is_cover_image(&relative_path);
+        if !is_audio_file(&relative_path) && !is_cover {
+            continue;
+        }
+
+        let target = dir.path().join(&relative_path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|err| format!("Could not create '{}': {}", parent.display(), err))?;
+        }
+        let mut output = File::create(&target).map_err(|err| format!("Could not create '{}': {}", target.display(), err))?;
+        io::copy(&mut entry, &mut output).map_err(|err| format!("Could not extract '{}': {}", relative_path.display(), err))?;
+
+        if is_cover {
+            covers.push((relative_path.components().count(), target));
+        } else {
+            audio_files.push(target);
+        }
+    }
+    // Prefer the cover nearest the archive root; ties keep archive order.
+    covers.sort_by_key(|(depth, _)| *depth);
+    let cover = covers.into_iter().next().map(|(_, path)| path);
+    Ok(ExtractedArchive { dir, audio_files, cover })
+}
+
+/// Returns `true` for an image named `cover` with a supported extension, in any letter case.
+fn is_cover_image(path: &Path) -> bool {
+    let stem_is_cover = path.file_stem().is_some_and(|stem| stem.eq_ignore_ascii_case("cover"));
+    let ext_lc = path.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+    stem_is_cover && COVER_EXTENSIONS.contains(&ext_lc.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        for (name, contents) in entries {
+            writer.start_file(*name, SimpleFileOptions::default()).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    /// Tests extraction of nested audio files and the cover, skipping other files, in archive order.
+    #[test]
+    fn test_extract_archive() {
+        let fixture_dir = tempdir().unwrap();
+        let zip_path = fixture_dir.path().join("book.zip");
+        write_zip(&zip_path, &[
+            ("Book/Disc 2/01.mp3", b"disc two"),
+            ("Book/Disc 1/01.mp3", b"disc one"),
+            ("Book/Disc 1/scans/Cover.png", b"nested cover"),
+            ("Book/cover.jpg", b"cover"),
+            ("Book/notes.txt", b"ignored"),
+            ("../escape.mp3", b"unsafe"),
+        ]);
+        assert!(is_zip_archive(&zip_path));
+
+        let extracted = extract_archive(&zip_path, fixture_dir.path()).unwrap();
+        let relative: Vec<PathBuf> = extracted.audio_files.iter()
+            .map(|path| path.strip_prefix(extracted.path()).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(relative, vec![PathBuf::from("Book/Disc 2/01.mp3"), PathBuf::from("Book/Disc 1/01.mp3")]);
+        assert_eq!(fs::read(&extracted.audio_files[1]).unwrap(), b"disc one");
+        assert_eq!(extracted.cover, Some(extracted.path().join("Book/cover.jpg")));
+        assert!(!extracted.path().join("Book/notes.txt").exists());
+
+        let work_dir = extracted.path().to_path_buf();
+        drop(extracted);
+        assert!(!work_dir.exists());
+    }
+}
@@ -0,0 +1,150 @@
+//! Book-level tags.
+//!
+//! `BookTags` holds the tags of a finished book and renders them as ffmpeg `-metadata`
+//! arguments, under the keys ffmpeg's MP4 muxer maps to iTunes atoms.
+
+use std::collections::HashMap;
+
+/// Book-level tags written to the output container.
+///
+/// This is the single tag model shared by the merge path, the `retag` subcommand, and the
+/// `BookPlan`, so all of them write the same keys in the same way. Build it with struct update
+/// syntax from `BookTags::default()`, which new fields do not break.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct BookTags {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub year: Option<String>,
+    /// Full publish date in `YYYY-MM-DD` form; takes precedence over `year` when both are set.
+    pub date: Option<String>,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    pub description: Option<String>,
+    /// One or more genres, joined with `; ` when several are known.
+    pub genre: Option<String>,
+    /// Track number and count, e.g. "1/1" for a single-file book.
+    pub track: Option<String>,
+    /// The length of the book in milliseconds.
+    pub total_duration_ms: Option<u64>,
+    pub chapter_count: Option<usize>,
+    /// ISO 639-2 language code, written for the container and the audio stream.
+    pub language: Option<String>,
+    /// Which inputs the book was built from (see `output::provenance`).
+    pub provenance: Option<String>,
+}
+
+/// ffmpeg metadata key used for the book title.
+pub const TITLE_KEY: &str = "title";
+/// ffmpeg metadata key used for the author (maps to the MP4 `©ART` atom).
+pub const AUTHOR_KEY: &str = "artist";
+/// ffmpeg metadata key used for the release year or date (maps to the MP4 `©day` atom).
+pub const YEAR_KEY: &str = "date";
+/// ffmpeg metadata key used for the narrator (maps to the MP4 `©wrt` atom, which players show as composer).
+pub const NARRATOR_KEY: &str = "composer";
+/// ffmpeg metadata key used for the series (maps to the MP4 `©grp` atom).
+pub const SERIES_KEY: &str = "grouping";
+/// ffmpeg metadata key used for the description (maps to the MP4 `desc` atom).
+pub const DESCRIPTION_KEY: &str = "description";
+/// ffmpeg metadata key used for the genre (maps to the MP4 `©gen` atom).
+pub const GENRE_KEY: &str = "genre";
+/// ffmpeg metadata key used for the album of an exported track (maps to the MP4 `©alb` atom).
+pub const ALBUM_KEY: &str = "album";
+/// ffmpeg metadata key used for the album artist of an exported track (maps to the MP4 `aART` atom).
+pub const ALBUM_ARTIST_KEY: &str = "album_artist";
+/// ffmpeg metadata key used for the track number and count (maps to the MP4 `trkn` atom).
+pub const TRACK_KEY: &str = "track";
+/// Custom metadata key for the book length in milliseconds. ffmpeg's MP4 muxer only keeps it
+/// with `METADATA_TAGS_MOVFLAG`; Matroska and other containers with free tags always do.
+pub const TOTAL_DURATION_KEY: &str = "TOTALDURATION";
+/// ffmpeg metadata key used for the language. MP4 keeps it only on the audio stream.
+pub const LANGUAGE_KEY: &str = "language";
+/// Custom metadata key for the number of chapters, kept like `TOTAL_DURATION_KEY`.
+pub const CHAPTER_COUNT_KEY: &str = "CHAPTERCOUNT";
+/// Custom metadata key for the build's provenance. The `©too` atom is no place for it, as
+/// ffmpeg fills it with its own name on every mux.
+pub const PROVENANCE_KEY: &str = "m4btool_provenance";
+/// The `-movflags` flag that makes ffmpeg's MP4 muxer write every tag under its own key,
+/// which keeps custom keys such as `PROVENANCE_KEY` that have no iTunes atom.
+pub const METADATA_TAGS_MOVFLAG: &str = "+use_metadata_tags";
+
+impl BookTags {
+    /// Returns `true` when no tag has been set.
+    pub fn is_empty(&self) -> bool {
+        self.metadata_pairs().is_empty() && self.language.is_none()
+    }
+
+    /// Lists the tags that are set as `(ffmpeg key, value)` pairs.
+    pub fn metadata_pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = Vec::new();
+        if let Some(title) = &self.title {
+            pairs.push((TITLE_KEY, title.clone()));
+        }
+        if let Some(author) = &self.author {
+            pairs.push((AUTHOR_KEY, author.clone()));
+        }
+        if let Some(day) = self.date.as_ref().or(self.year.as_ref()) {
+            pairs.push((YEAR_KEY, day.clone()));
+        }
+        let optional = [
+            (NARRATOR_KEY, &self.narrator),
+            (SERIES_KEY, &self.series),
+            (DESCRIPTION_KEY, &self.description),
+            (GENRE_KEY, &self.genre),
+            (TRACK_KEY, &self.track),
+            (PROVENANCE_KEY, &self.provenance),
+        ];
+        pairs.extend(optional.into_iter().filter_map(|(key, value)| value.clone().map(|value| (key, value))));
+        if let Some(total_duration_ms) = self.total_duration_ms {
+            pairs.push((TOTAL_DURATION_KEY, total_duration_ms.to_string()));
+        }
+        if let Some(chapter_count) = self.chapter_count {
+            pairs.push((CHAPTER_COUNT_KEY, chapter_count.to_string()));
+        }
+        pairs
+    }
+
+    /// Lists the keys of the tags that are set but missing from `written`, the tags ffprobe
+    /// reports for the written file, keyed by their lowercase name.
+    pub fn missing_from(&self, written: &HashMap<String, String>) -> Vec<&'static str> {
+        self.metadata_pairs().into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| !written.contains_key(&key.to_lowercase()))
+            .collect()
+    }
+
+    /// Builds the `-metadata key=value` arguments for an ffmpeg invocation, followed by the
+    /// language for the container and the first audio stream.
+    pub fn ffmpeg_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = self.metadata_pairs()
+            .into_iter()
+            .flat_map(|(key, value)| ["-metadata".to_string(), format!("{}={}", key, value)])
+            .collect();
+        if let Some(language) = &self.language {
+            let pair = format!("{}={}", LANGUAGE_KEY, language);
+            args.extend(["-metadata".to_string(), pair.clone(), "-metadata:s:a:0".to_string(), pair]);
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that only the tags that are set become `-metadata` arguments.
+    #[test]
+    fn test_ffmpeg_args() {
+        let tags = BookTags { title: Some("Dune".to_string()), year: Some("1965".to_string()), ..Default::default() };
+        assert_eq!(tags.ffmpeg_args(), vec!["-metadata", "title=Dune", "-metadata", "date=1965"]);
+        assert!(BookTags::default().is_empty());
+    }
+
+    /// Tests that a full date replaces the bare year in the date tag.
+    #[test]
+    fn test_date_takes_precedence_over_year() {
+        let tags = BookTags { year: Some("1965".to_string()), date: Some("1965-08-01".to_string()), ..Default::default() };
+        assert_eq!(tags.metadata_pairs(), vec![(YEAR_KEY, "1965-08-01".to_string())]);
+    }
+}
@@ -0,0 +1,347 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use tempfile::NamedTempFile;
+
+/// The header of an MP4 box, with its position in the bytes it was read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BoxHeader {
+    kind: [u8; 4],
+    start: usize,
+    /// 8, or 16 for a box with a 64-bit size.
+    header_len: usize,
+    end: usize,
+}
+
+impl BoxHeader {
+    fn payload(&self) -> std::ops::Range<usize> {
+        self.start + self.header_len..self.end
+    }
+}
+
+/// Reads the boxes that follow each other in `data`, such as the children of a container.
+fn boxes(data: &[u8]) -> Result<Vec<BoxHeader>, String> {
+    let mut headers = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let field = |at: usize, len: usize| data.get(start + at..start + at + len).ok_or("a box header is cut off");
+        let size = u32::from_be_bytes(field(0, 4)?.try_into().unwrap_or_default()) as u64;
+        let kind: [u8; 4] = field(4, 4)?.try_into().unwrap_or_default();
+        let (size, header_len) = match size {
+            0 => ((data.len() - start) as u64, 8),
+            1 => (u64::from_be_bytes(field(8, 8)?.try_into().unwrap_or_default()), 16),
+            size => (size, 8),
+        };
+        let end = start as u64 + size;
+        if size < header_len as u64 || end > data.len() as u64 {
+            return Err(format!("the '{}' box does not fit its parent", String::from_utf8_lossy(&kind)));
+        }
+        headers.push(BoxHeader { kind, start, header_len, end: end as usize });
+        start = end as usize;
+    }
+    Ok(headers)
+}
+
+/// Finds the first child of a kind among the children of the box at `parent`.
+fn child(data: &[u8], parent: BoxHeader, kind: &[u8; 4]) -> Result<Option<BoxHeader>, String> {
+    let payload = parent.payload();
+    Ok(boxes(&data[payload.clone()])?
+        .into_iter()
+        .find(|header| &header.kind == kind)
+        .map(|header| BoxHeader { start: header.start + payload.start, end: header.end + payload.start, ..header }))
+}
+
+/// Follows a path of box kinds down from the box at `parent`.
+fn descend(data: &[u8], parent: BoxHeader, path: &[&[u8; 4]]) -> Result<Option<BoxHeader>, String> {
+    let mut current = parent;
+    for kind in path {
+        match child(data, current, kind)? {
+            Some(found) => current = found,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(current))
+}
+
+/// A track of the `moov` box: its `trak` box, its ID, and its handler, e.g. `soun` or `vide`.
+struct Track {
+    trak: BoxHeader,
+    tkhd: BoxHeader,
+    id: u32,
+    handler: [u8; 4],
+}
+
+/// Reads the tracks of a `moov` box.
+fn tracks(moov: &[u8], root: BoxHeader) -> Result<Vec<Track>, String> {
+    let payload = root.payload();
+    let mut tracks = Vec::new();
+    for trak in boxes(&moov[payload.clone()])?.into_iter().filter(|header| &header.kind == b"trak") {
+        let trak = BoxHeader { start: trak.start + payload.start, end: trak.end + payload.start, ..trak };
+        let tkhd = child(moov, trak, b"tkhd")?.ok_or("a track has no header")?;
+        let hdlr = descend(moov, trak, &[b"mdia", b"hdlr"])?.ok_or("a track has no handler")?;
+        // The track ID follows the version, flags, and creation and modification times.
+        let id_at = tkhd.payload().start + if moov[tkhd.payload().start] == 1 { 20 } else { 12 };
+        let handler_at = hdlr.payload().start + 8;
+        let id = moov.get(id_at..id_at + 4).ok_or("a track header is cut off")?;
+        let handler = moov.get(handler_at..handler_at + 4).ok_or("a track handler is cut off")?;
+        tracks.push(Track { trak, tkhd, id: u32::from_be_bytes(id.try_into().unwrap_or_default()), handler: handler.try_into().unwrap_or_default() });
+    }
+    Ok(tracks)
+}
+
+/// Writes a box around a payload, keeping a 64-bit size if the box had one.
+fn write_box(kind: &[u8; 4], header_len: usize, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(payload.len() + header_len);
+    if header_len == 16 {
+        bytes.extend(1u32.to_be_bytes());
+        bytes.extend(kind);
+        bytes.extend((payload.len() as u64 + 16).to_be_bytes());
+    } else {
+        bytes.extend((payload.len() as u32 + 8).to_be_bytes());
+        bytes.extend(kind);
+    }
+    bytes.extend(payload);
+    bytes
+}
+
+/// Moves every chunk offset of the tracks at or after `from` by `delta` bytes, as the bytes
+/// after a grown `moov` box move.
+fn shift_chunk_offsets(moov: &mut [u8], root: BoxHeader, from: u64, delta: u64) -> Result<(), String> {
+    for track in tracks(moov, root)? {
+        let stbl = descend(moov, track.trak, &[b"mdia", b"minf", b"stbl"])?.ok_or("a track has no sample table")?;
+        for (kind, width) in [(b"stco", 4), (b"co64", 8)] {
+            let Some(table) = child(moov, stbl, kind)? else { continue };
+            let entries_at = table.payload().start + 8;
+            let count = u32::from_be_bytes(moov.get(entries_at - 4..entries_at).ok_or("a chunk table is cut off")?.try_into().unwrap_or_default()) as usize;
+            if entries_at + count * width > table.end {
+                return Err("a chunk table is cut off".to_string());
+            }
+            for entry in moov[entries_at..entries_at + count * width].chunks_exact_mut(width) {
+                if width == 4 {
+                    let offset = u32::from_be_bytes(entry.try_into().unwrap_or_default()) as u64;
+                    if offset >= from {
+                        let shifted = u32::try_from(offset + delta).map_err(|_| "a chunk offset no longer fits 32 bits")?;
+                        entry.copy_from_slice(&shifted.to_be_bytes());
+                    }
+                } else {
+                    let offset = u64::from_be_bytes(entry.try_into().unwrap_or_default());
+                    if offset >= from {
+                        entry.copy_from_slice(&(offset + delta).to_be_bytes());
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Points the audio track of a `moov` box at the timed image track with a `chap` track
+/// reference, which Apple players read the chapter images from, and disables the image track so
+/// it is not played as video.
+///
+/// # Arguments
+///
+/// * `moov` - The whole `moov` box.
+/// * `moov_end` - Where the box ends in the file: chunks stored after it move by the bytes added.
+///
+/// # Returns
+///
+/// The new `moov` box, or an error message when it has no audio or image track.
+fn add_chapter_reference(moov: &[u8], moov_end: u64) -> Result<Vec<u8>, String> {
+    let root = *boxes(moov)?.first().filter(|header| &header.kind == b"moov").ok_or("not a moov box")?;
+    let all = tracks(moov, root)?;
+    let audio = all.iter().find(|track| &track.handler == b"soun").ok_or("the book has no audio track")?;
+    let images = all.iter().rev().find(|track| &track.handler == b"vide").ok_or("the book has no image track")?;
+    let tref = child(moov, audio.trak, b"tref")?;
+    let chap = match tref {
+        Some(tref) => child(moov, tref, b"chap")?,
+        None => None,
+    };
+    if let Some(chap) = chap {
+        let ids = &moov[chap.payload()];
+        if ids.chunks_exact(4).any(|id| id == images.id.to_be_bytes()) {
+            return Ok(moov.to_vec());
+        }
+    }
+    let id = images.id.to_be_bytes();
+    let delta = match (tref, chap) {
+        (_, Some(_)) => 4,
+        (Some(_), None) => 12,
+        (None, None) => 20,
+    };
+
+    let mut moov = moov.to_vec();
+    shift_chunk_offsets(&mut moov, root, moov_end, delta)?;
+    // Bit 0 of the track header's flags enables the track.
+    moov[images.tkhd.payload().start + 3] &= !1;
+
+    // Rebuild the audio track with the reference, then the moov box around it.
+    let trak_payload = audio.trak.payload();
+    let new_trak_payload = match (tref, chap) {
+        (Some(tref), Some(chap)) => {
+            let new_chap = write_box(b"chap", chap.header_len, &[&moov[chap.payload()], &id[..]].concat());
+            let new_tref = write_box(b"tref", tref.header_len, &[&moov[tref.payload().start..chap.start], &new_chap, &moov[chap.end..tref.end]].concat());
+            [&moov[trak_payload.start..tref.start], &new_tref, &moov[tref.end..trak_payload.end]].concat()
+        }
+        (Some(tref), None) => {
+            let new_tref = write_box(b"tref", tref.header_len, &[&moov[tref.payload()], &write_box(b"chap", 8, &id)].concat());
+            [&moov[trak_payload.start..tref.start], &new_tref, &moov[tref.end..trak_payload.end]].concat()
+        }
+        // The reference goes right after the track header, where the format places it.
+        (None, _) => {
+            let new_tref = write_box(b"tref", 8, &write_box(b"chap", 8, &id));
+            [&moov[trak_payload.start..audio.tkhd.end], &new_tref, &moov[audio.tkhd.end..trak_payload.end]].concat()
+        }
+    };
+    let new_trak = write_box(b"trak", audio.trak.header_len, &new_trak_payload);
+    let moov_payload = root.payload();
+    let new_payload = [&moov[moov_payload.start..audio.trak.start], &new_trak, &moov[audio.trak.end..moov_payload.end]].concat();
+    Ok(write_box(b"moov", root.header_len, &new_payload))
+}
+
+/// Links the timed image track of `--chapter-thumbnails` to the chapters of a written book with
+/// the `chap` track reference that ffmpeg does not write.
+///
+/// A `moov` box at the end of the file is rewritten in place; one at the front, as with
+/// `--faststart`, moves the audio behind it, so the book is copied.
+///
+/// # Returns
+///
+/// An error message if the book could not be read or rewritten. The book is left as it was.
+pub fn link_chapter_images(path: &Path) -> Result<(), String> {
+    let fail = |err: io::Error| format!("Could not update '{}': {}", path.display(), err);
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(path).map_err(fail)?;
+    let file_len = file.metadata().map_err(fail)?.len();
+
+    // Find the moov box among the top-level boxes without reading the audio.
+    let mut start = 0;
+    let (moov_start, moov_end) = loop {
+        if start + 8 > file_len {
+            return Err(format!("'{}' has no moov box", path.display()));
+        }
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(start)).map_err(fail)?;
+        let read = file.read(&mut header).map_err(fail)?;
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap_or_default()) as u64 {
+            0 => file_len - start,
+            1 if read == 16 => u64::from_be_bytes(header[8..].try_into().unwrap_or_default()),
+            size => size,
+        };
+        if size < 8 || start + size > file_len {
+            return Err(format!("'{}' has a box that does not fit the file", path.display()));
+        }
+        if &header[4..8] == b"moov" {
+            break (start, start + size);
+        }
+        start += size;
+    };
+    let mut moov = vec![0; (moov_end - moov_start) as usize];
+    file.seek(SeekFrom::Start(moov_start)).map_err(fail)?;
+    file.read_exact(&mut moov).map_err(fail)?;
+    let new_moov = add_chapter_reference(&moov, moov_end).map_err(|err| format!("Could not link the chapter images in '{}': {}", path.display(), err))?;
+    if new_moov == moov {
+        return Ok(());
+    }
+
+    if moov_end == file_len {
+        file.seek(SeekFrom::Start(moov_start)).map_err(fail)?;
+        file.write_all(&new_moov).map_err(fail)?;
+        return Ok(());
+    }
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut copy = NamedTempFile::new_in(dir).map_err(fail)?;
+    file.seek(SeekFrom::Start(0)).map_err(fail)?;
+    io::copy(&mut (&mut file).take(moov_start), copy.as_file_mut()).map_err(fail)?;
+    copy.write_all(&new_moov).map_err(fail)?;
+    file.seek(SeekFrom::Start(moov_end)).map_err(fail)?;
+    io::copy(&mut file, copy.as_file_mut()).map_err(fail)?;
+    copy.persist(path).map_err(|err| fail(err.error))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a track with a header, a handler, and chunk offsets.
+    fn trak(id: u32, handler: &[u8; 4], offsets: &[u32], extra: &[u8]) -> Vec<u8> {
+        let tkhd = write_box(b"tkhd", 8, &[&[0, 0, 0, 3][..], &[0; 8], &id.to_be_bytes(), &[0; 68]].concat());
+        let hdlr = write_box(b"hdlr", 8, &[&[0; 8][..], handler, &[0; 13]].concat());
+        let stco_payload: Vec<u8> = [&[0; 4][..], &(offsets.len() as u32).to_be_bytes()].concat().into_iter()
+            .chain(offsets.iter().flat_map(|offset| offset.to_be_bytes()))
+            .collect();
+        let stbl = write_box(b"stbl", 8, &write_box(b"stco", 8, &stco_payload));
+        let mdia = write_box(b"mdia", 8, &[hdlr, write_box(b"minf", 8, &stbl)].concat());
+        write_box(b"trak", 8, &[tkhd, extra.to_vec(), mdia].concat())
+    }
+
+    /// The chunk offsets of every track, the enabled flag of each, and the `chap` IDs of the
+    /// audio track.
+    fn summary(moov: &[u8]) -> (Vec<Vec<u32>>, Vec<bool>, Vec<u32>) {
+        let root = boxes(moov).unwrap()[0];
+        let all = tracks(moov, root).unwrap();
+        let offsets = all.iter()
+            .map(|track| {
+                let stco = descend(moov, track.trak, &[b"mdia", b"minf", b"stbl", b"stco"]).unwrap().unwrap();
+                moov[stco.payload().start + 8..stco.end].chunks_exact(4).map(|entry| u32::from_be_bytes(entry.try_into().unwrap())).collect()
+            })
+            .collect();
+        let enabled = all.iter().map(|track| moov[track.tkhd.payload().start + 3] & 1 == 1).collect();
+        let chap = descend(moov, all[0].trak, &[b"tref", b"chap"]).unwrap()
+            .map(|chap| moov[chap.payload()].chunks_exact(4).map(|id| u32::from_be_bytes(id.try_into().unwrap())).collect())
+            .unwrap_or_default();
+        (offsets, enabled, chap)
+    }
+
+    /// Tests that the audio track gets a `chap` reference to the image track, that the image
+    /// track is disabled, and that only the chunks after the box move.
+    #[test]
+    fn test_add_chapter_reference() {
+        let moov = write_box(b"moov", 8, &[trak(1, b"soun", &[100, 5_000], &[]), trak(2, b"vide", &[4_000], &[])].concat());
+        let linked = add_chapter_reference(&moov, 1_000).unwrap();
+        assert_eq!(linked.len(), moov.len() + 20);
+        assert_eq!(summary(&linked), (vec![vec![100, 5_020], vec![4_020]], vec![true, false], vec![2]));
+        assert_eq!(add_chapter_reference(&linked, 1_000).unwrap(), linked);
+
+        // An existing chap reference, e.g. to a text track, keeps its IDs.
+        let chap = write_box(b"tref", 8, &write_box(b"chap", 8, &3u32.to_be_bytes()));
+        let moov = write_box(b"moov", 8, &[trak(1, b"soun", &[5_000], &chap), trak(2, b"vide", &[6_000], &[])].concat());
+        let linked = add_chapter_reference(&moov, 1_000).unwrap();
+        assert_eq!(summary(&linked), (vec![vec![5_004], vec![6_004]], vec![true, false], vec![3, 2]));
+
+        let audio_only = write_box(b"moov", 8, &trak(1, b"soun", &[5_000], &[]));
+        assert_eq!(add_chapter_reference(&audio_only, 1_000).unwrap_err(), "the book has no image track");
+    }
+
+    /// Tests rewriting a book with the moov box in front of the audio and at the end of it.
+    #[test]
+    fn test_link_chapter_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let ftyp = write_box(b"ftyp", 8, b"M4B \0\0\0\0");
+        let mdat = write_box(b"mdat", 8, b"audio and images");
+        let moov = |offset: usize| write_box(b"moov", 8, &[trak(1, b"soun", &[offset as u32], &[]), trak(2, b"vide", &[offset as u32 + 6], &[])].concat());
+        for faststart in [true, false] {
+            let path = dir.path().join("book.m4b");
+            let book = if faststart {
+                [ftyp.clone(), moov(ftyp.len() + moov(0).len() + 8), mdat.clone()].concat()
+            } else {
+                [ftyp.clone(), mdat.clone(), moov(ftyp.len() + 8)].concat()
+            };
+            fs::write(&path, &book).unwrap();
+            link_chapter_images(&path).unwrap();
+
+            let written = fs::read(&path).unwrap();
+            assert_eq!(written.len(), book.len() + 20);
+            let top = boxes(&written).unwrap();
+            let kinds: Vec<&[u8; 4]> = top.iter().map(|header| &header.kind).collect();
+            assert_eq!(kinds, if faststart { [b"ftyp", b"moov", b"mdat"] } else { [b"ftyp", b"mdat", b"moov"] });
+            let mdat = top.iter().find(|header| &header.kind == b"mdat").unwrap();
+            let moov = top.iter().find(|header| &header.kind == b"moov").unwrap();
+            let (offsets, _, chap) = summary(&written[moov.start..moov.end]);
+            assert_eq!(&written[offsets[0][0] as usize..offsets[0][0] as usize + 5], b"audio");
+            assert_eq!(offsets[0][0] as usize, mdat.payload().start);
+            assert_eq!(chap, vec![2]);
+        }
+    }
+}
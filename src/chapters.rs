@@ -0,0 +1,625 @@
+use std::fs;
+
+use m4btool::{read_ffmetadata, FfMetadata, TimedChapter};
+
+use crate::inspect::ChapterInfo;
+use crate::table::format_duration;
+
+/// The maximum chapter count used when `--max-chapters` is not given, since some players
+/// misbehave beyond 255 chapters.
+pub const DEFAULT_MAX_CHAPTERS: usize = 255;
+
+/// The shortest chapter used when `--chapter-minimum-gap` is not given: players need each chapter
+/// to end after it starts.
+pub const DEFAULT_MINIMUM_GAP_MS: u64 = 1;
+
+/// The shortest chapter ffmpeg reliably writes: a shorter last chapter can silently go missing
+/// from the book, so the book would not have the chapters that were planned.
+pub const MIN_CHAPTER_MS: u64 = 1000;
+
+/// How chapter titles are formed when adjacent chapters are merged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoalesceTitles {
+    /// Keep the title of the first chapter in each group.
+    First,
+    /// Keep the first title and add the range of merged chapters, e.g. "Storm (Chapters 12–15)".
+    Range,
+}
+
+/// How far the chapters of a `--metadata-file` may end from the end of the encoded audio,
+/// which comes out slightly different from the book the file was made for after a re-encode.
+pub const METADATA_FILE_TOLERANCE_MS: u64 = 2000;
+
+/// How `--equal-chapters` and `--fixed-chapter-length` divide the book into chapters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSplit {
+    /// This many chapters of equal length.
+    Count(usize),
+    /// Chapters of this many milliseconds, and a shorter last chapter for what is left.
+    Length(u64),
+}
+
+/// A remainder shorter than this is added to the last chapter of a `TimeSplit::Length` split
+/// instead of becoming a chapter of its own.
+const MIN_REMAINDER_MS: u64 = 1_000;
+
+/// Divides the whole book into chapters by time alone, ignoring file boundaries, for content
+/// without natural chapters. Chapters are titled "Chapter 1", "Chapter 2", and so on.
+///
+/// With a count, the milliseconds that do not divide evenly go one each to the first chapters,
+/// so lengths differ by at most 1 ms; a book shorter than the count gets one chapter per
+/// millisecond. With a length, the remainder becomes a last, shorter chapter, unless it is
+/// under a second and is added to the chapter before it.
+///
+/// # Arguments
+///
+/// * `total_ms` - The duration of the whole book.
+/// * `split` - How to divide it.
+///
+/// # Returns
+///
+/// The chapter titles with their durations in milliseconds, adding up to `total_ms`.
+pub fn split_by_time(total_ms: u64, split: TimeSplit) -> Vec<(String, u64)> {
+    if total_ms == 0 {
+        return Vec::new();
+    }
+    let durations: Vec<u64> = match split {
+        TimeSplit::Count(count) => {
+            let count = (count as u64).clamp(1, total_ms);
+            let (base_ms, extra) = (total_ms / count, total_ms % count);
+            (0..count).map(|index| base_ms + u64::from(index < extra)).collect()
+        }
+        TimeSplit::Length(length_ms) => {
+            let length_ms = length_ms.max(1);
+            let mut durations = vec![length_ms; (total_ms / length_ms) as usize];
+            let remainder_ms = total_ms % length_ms;
+            match durations.last_mut() {
+                Some(last) if remainder_ms < MIN_REMAINDER_MS => *last += remainder_ms,
+                _ if remainder_ms > 0 => durations.push(remainder_ms),
+                _ => {}
+            }
+            durations
+        }
+    };
+    durations.into_iter()
+        .enumerate()
+        .map(|(index, duration_ms)| (format!("Chapter {}", index + 1), duration_ms))
+        .collect()
+}
+
+/// Merges adjacent chapters into at most `max_chapters` chapters of as even a size as possible.
+///
+/// Chapters are only ever combined whole, so a source file never spans two chapters, and each
+/// merged chapter lasts exactly as long as the chapters it replaces, so the timeline is
+/// unchanged. When the count does not divide evenly, the larger groups are spread evenly
+/// through the book.
+///
+/// # Arguments
+///
+/// * `chapters` - The planned chapter titles with their durations in milliseconds.
+/// * `max_chapters` - The largest number of chapters to produce; at least 1.
+/// * `titles` - How merged chapters are titled.
+///
+/// # Returns
+///
+/// The merged chapters, or the original chapters if they are already within the limit.
+pub fn coalesce_chapters(chapters: &[(String, u64)], max_chapters: usize, titles: CoalesceTitles) -> Vec<(String, u64)> {
+    let count = chapters.len();
+    if count <= max_chapters || max_chapters == 0 {
+        return chapters.to_vec();
+    }
+    (0..max_chapters)
+        .map(|group| {
+            let range = group * count / max_chapters..(group + 1) * count / max_chapters;
+            let duration_ms = chapters[range.clone()].iter().map(|(_, duration_ms)| duration_ms).sum();
+            let first_title = &chapters[range.start].0;
+            let title = match titles {
+                CoalesceTitles::Range if range.len() > 1 => {
+                    format!("{} (Chapters {}–{})", first_title, range.start + 1, range.end)
+                }
+                _ => first_title.clone(),
+            };
+            (title, duration_ms)
+        })
+        .collect()
+}
+
+/// Splits a file's chapter into the chapters embedded in the file, such as ID3 `CHAP` frames in
+/// an MP3, for `--preserve-chapters`.
+///
+/// Embedded times are relative to the source, so they are moved back by `offset_ms` when the
+/// start of the file was trimmed, and chapters starting outside the encoded file are dropped.
+/// The first chapter is extended to the start of the file and the last to its end, so the
+/// chapters cover the file exactly; the book's timeline offsets them by the running start time.
+///
+/// # Arguments
+///
+/// * `title` - The file's own chapter title, used for untitled embedded chapters as "Title (2)".
+/// * `duration_ms` - The duration of the encoded file.
+/// * `embedded` - The chapters read from the source file.
+/// * `offset_ms` - How much was trimmed from the start of the source.
+///
+/// # Returns
+///
+/// The file's chapters, or just `(title, duration_ms)` when it has fewer than two embedded chapters.
+pub fn expand_embedded_chapters(title: &str, duration_ms: u64, embedded: &[ChapterInfo], offset_ms: u64) -> Vec<(String, u64)> {
+    let mut starts: Vec<(u64, &str)> = embedded.iter()
+        .filter(|chapter| chapter.end_ms > offset_ms)
+        .map(|chapter| (chapter.start_ms.saturating_sub(offset_ms), chapter.title.as_str()))
+        .filter(|(start_ms, _)| *start_ms < duration_ms)
+        .collect();
+    starts.sort_by_key(|(start_ms, _)| *start_ms);
+    starts.dedup_by_key(|(start_ms, _)| *start_ms);
+    if starts.len() < 2 {
+        return vec![(title.to_string(), duration_ms)];
+    }
+    starts[0].0 = 0;
+    starts.iter()
+        .enumerate()
+        .map(|(index, (start_ms, embedded_title))| {
+            let end_ms = starts.get(index + 1).map_or(duration_ms, |(next_start_ms, _)| *next_start_ms);
+            let chapter_title = match embedded_title.trim() {
+                "" => format!("{} ({})", title, index + 1),
+                embedded_title => embedded_title.to_string(),
+            };
+            (chapter_title, end_ms - start_ms)
+        })
+        .collect()
+}
+
+/// Merges chapters without any duration into a neighbor, since ffmpeg rejects a chapter whose
+/// end is not after its start.
+///
+/// A very short file can measure as 0 ms once encoded and trimmed. Such a chapter is folded into
+/// the chapter before it, or into the next one when it comes first, so the neighbor keeps its
+/// title and the timeline is unchanged.
+///
+/// # Returns
+///
+/// The remaining chapters, and the titles of the chapters that were merged away.
+pub fn merge_empty_chapters(chapters: &[(String, u64)]) -> (Vec<(String, u64)>, Vec<String>) {
+    let mut kept: Vec<(String, u64)> = Vec::new();
+    let mut merged = Vec::new();
+    for (title, duration_ms) in chapters {
+        if *duration_ms == 0 {
+            merged.push(title.clone());
+        } else {
+            kept.push((title.clone(), *duration_ms));
+        }
+    }
+    (kept, merged)
+}
+
+/// Merges chapters shorter than `minimum_ms`, such as a half-second "The End" file, into the
+/// chapter before them, or into the next one when they come first, so the neighbor keeps its
+/// title and the timeline is unchanged. A book of only short chapters becomes one chapter.
+///
+/// # Returns
+///
+/// The remaining chapters, and the titles of the chapters that were merged away.
+pub fn merge_tiny_chapters(chapters: &[(String, u64)], minimum_ms: u64) -> (Vec<(String, u64)>, Vec<String>) {
+    let mut kept: Vec<(String, u64)> = Vec::new();
+    let mut merged = Vec::new();
+    let mut leading_ms = 0;
+    for (title, duration_ms) in chapters {
+        if *duration_ms >= minimum_ms {
+            kept.push((title.clone(), leading_ms + duration_ms));
+            leading_ms = 0;
+            continue;
+        }
+        match kept.last_mut() {
+            Some((_, previous_ms)) => *previous_ms += duration_ms,
+            None => leading_ms += duration_ms,
+        }
+        merged.push(title.clone());
+    }
+    if kept.is_empty() && !merged.is_empty() {
+        kept.push((merged.remove(0), leading_ms));
+    }
+    (kept, merged)
+}
+
+/// The shortest chapter `enforce_minimum_gap` makes: `--chapter-minimum-gap`, or
+/// `DEFAULT_MINIMUM_GAP_MS`, raised to `MIN_CHAPTER_MS` with `--keep-tiny-chapters`, which pads
+/// the short chapters it keeps to a length ffmpeg writes.
+pub fn minimum_chapter_gap(chapter_minimum_gap: Option<u64>, keep_tiny_chapters: bool) -> u64 {
+    let gap_ms = chapter_minimum_gap.unwrap_or(DEFAULT_MINIMUM_GAP_MS);
+    if keep_tiny_chapters { gap_ms.max(MIN_CHAPTER_MS) } else { gap_ms }
+}
+
+/// Nudges chapter boundaries so that every chapter lasts at least `minimum_gap_ms`, keeping the
+/// total duration.
+///
+/// Boundaries are moved as little as needed: a short chapter ends later and takes the time from
+/// the next one, and a boundary that already leaves enough room stays where it is, so nudges do
+/// not accumulate into drift. Chapters near the end move their start earlier instead. A book too
+/// short to give every chapter the gap uses the largest gap it has room for.
+pub fn enforce_minimum_gap(chapters: &[(String, u64)], minimum_gap_ms: u64) -> Vec<(String, u64)> {
+    let Some(count) = u64::try_from(chapters.len()).ok().filter(|count| *count > 0) else { return Vec::new() };
+    let total_ms: u64 = chapters.iter().map(|(_, duration_ms)| duration_ms).sum();
+    let gap_ms = minimum_gap_ms.min(total_ms / count);
+    let mut ends: Vec<u64> = chapter_spans(chapters).into_iter().map(|(_, end_ms)| end_ms).collect();
+    for index in 0..ends.len() - 1 {
+        let start_ms = if index == 0 { 0 } else { ends[index - 1] };
+        ends[index] = ends[index].max(start_ms + gap_ms);
+    }
+    for index in (0..ends.len() - 1).rev() {
+        ends[index] = ends[index].min(ends[index + 1] - gap_ms);
+    }
+    let mut start_ms = 0;
+    chapters.iter()
+        .zip(ends)
+        .map(|((title, _), end_ms)| {
+            let chapter = (title.clone(), end_ms - start_ms);
+            start_ms = end_ms;
+            chapter
+        })
+        .collect()
+}
+
+/// Returns the `(start, end)` of each chapter in milliseconds, laid out back to back from 0.
+pub fn chapter_spans(chapters: &[(String, u64)]) -> Vec<(u64, u64)> {
+    let mut start_ms = 0;
+    chapters.iter()
+        .map(|(_, duration_ms)| {
+            let span = (start_ms, start_ms + duration_ms);
+            start_ms = span.1;
+            span
+        })
+        .collect()
+}
+
+/// Checks that chapter spans form one timeline: starting at 0, each chapter ending after it
+/// starts, and each starting exactly where the previous one ended, without gaps or overlaps.
+/// Each chapter must also last at least `minimum_ms`, unless the whole book is too short to
+/// give every chapter that much, as `enforce_minimum_gap` allows.
+///
+/// # Returns
+///
+/// An error message naming the first chapter (counted from 1) that breaks the timeline.
+pub fn check_timeline(spans: &[(u64, u64)], minimum_ms: u64) -> Result<(), String> {
+    let total_ms = spans.last().map_or(0, |&(_, end_ms)| end_ms);
+    let minimum_ms = minimum_ms.min(total_ms / spans.len().max(1) as u64);
+    let mut expected_start_ms = 0;
+    for (index, &(start_ms, end_ms)) in spans.iter().enumerate() {
+        if end_ms <= start_ms {
+            return Err(format!("chapter {} ends at {} ms, not after its start at {} ms", index + 1, end_ms, start_ms));
+        }
+        if end_ms - start_ms < minimum_ms {
+            return Err(format!("chapter {} lasts {} ms, shorter than the {} ms every chapter needs", index + 1, end_ms - start_ms, minimum_ms));
+        }
+        if start_ms != expected_start_ms {
+            let problem = if start_ms < expected_start_ms { "overlaps the previous chapter" } else { "leaves a gap after the previous chapter" };
+            return Err(format!("chapter {} starts at {} ms and {}, which ends at {} ms", index + 1, start_ms, problem, expected_start_ms));
+        }
+        expected_start_ms = end_ms;
+    }
+    Ok(())
+}
+
+/// Reads a `--metadata-file`, checking that it is an FFMETADATA file with a sound chapter list:
+/// one timeline, as `check_timeline` checks it, without chapters under a second that ffmpeg
+/// drops.
+///
+/// # Returns
+///
+/// The file's text, to be muxed as it is, and what it sets, or an error message naming the file.
+pub fn load_metadata_file(path: &str) -> Result<(String, FfMetadata), String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("Could not read the metadata file '{}': {}", path, err))?;
+    // ffmpeg only recognizes the file by the header on its first line, which a BOM hides.
+    let text = text.strip_prefix('\u{feff}').map(str::to_string).unwrap_or(text);
+    let metadata = read_ffmetadata(&text).map_err(|err| format!("Invalid metadata file '{}': {}", path, err))?;
+    let spans: Vec<(u64, u64)> = metadata.chapters.iter().map(|chapter| (chapter.start_ms, chapter.end_ms)).collect();
+    check_timeline(&spans, MIN_CHAPTER_MS).map_err(|err| format!("Invalid metadata file '{}': {}", path, err))?;
+    Ok((text, metadata))
+}
+
+/// Checks the chapters of a `--metadata-file` against the length of the encoded audio, with
+/// `METADATA_FILE_TOLERANCE_MS` of slack.
+///
+/// # Returns
+///
+/// An error message when a chapter ends past the audio, which players cannot seek to; otherwise a
+/// warning message when the last chapter ends early and leaves the rest of the audio without a
+/// chapter, or `None`.
+pub fn check_chapter_file_length(chapters: &[TimedChapter], audio_ms: u64) -> Result<Option<String>, String> {
+    let Some(last) = chapters.last() else { return Ok(None) };
+    if let Some((index, chapter)) = chapters.iter().enumerate().find(|(_, chapter)| chapter.end_ms > audio_ms + METADATA_FILE_TOLERANCE_MS) {
+        return Err(format!(
+            "chapter {} ends at {}, past the end of the audio at {}",
+            index + 1, format_ms(chapter.end_ms), format_ms(audio_ms)
+        ));
+    }
+    Ok((last.end_ms + METADATA_FILE_TOLERANCE_MS < audio_ms).then(|| format!(
+        "the last chapter ends at {}, before the end of the audio at {}",
+        format_ms(last.end_ms), format_ms(audio_ms)
+    )))
+}
+
+/// Formats milliseconds as `H:MM:SS.mmm`, precise enough to tell near misses apart.
+fn format_ms(ms: u64) -> String {
+    format!("{}.{:03}", format_duration(ms), ms % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(count: usize) -> Vec<(String, u64)> {
+        (1..=count).map(|n| (format!("Part {}", n), n as u64 * 1000)).collect()
+    }
+
+    /// Tests group sizes for uneven divisions, and that no chapter is lost or split.
+    #[test]
+    fn test_coalesce_uneven_groups() {
+        let chapters = numbered(10);
+        let merged = coalesce_chapters(&chapters, 4, CoalesceTitles::First);
+        let titles: Vec<&str> = merged.iter().map(|(title, _)| title.as_str()).collect();
+        assert_eq!(titles, vec!["Part 1", "Part 3", "Part 6", "Part 8"]);
+        // Groups of 2, 3, 2, 3 chapters.
+        let durations: Vec<u64> = merged.iter().map(|(_, duration)| *duration).collect();
+        assert_eq!(durations, vec![3_000, 12_000, 13_000, 27_000]);
+        assert_eq!(durations.iter().sum::<u64>(), chapters.iter().map(|(_, d)| d).sum::<u64>());
+
+        for (count, max) in [(1000, 255), (256, 255), (7, 3), (999, 998)] {
+            let merged = coalesce_chapters(&numbered(count), max, CoalesceTitles::First);
+            assert_eq!(merged.len(), max);
+            assert_eq!(merged.iter().map(|(_, d)| d).sum::<u64>(), (1..=count as u64).sum::<u64>() * 1000);
+        }
+    }
+
+    fn embedded(chapters: &[(u64, u64, &str)]) -> Vec<ChapterInfo> {
+        chapters.iter()
+            .map(|&(start_ms, end_ms, title)| ChapterInfo { start_ms, end_ms, title: title.to_string() })
+            .collect()
+    }
+
+    /// Tests splitting an MP3 by its ID3 chapters as read back by ffprobe, including untitled and
+    /// unsorted chapters and a first chapter that does not start at 0.
+    #[test]
+    fn test_expand_embedded_chapters() {
+        let output = r#"format.format_name="mp3"
+chapters.chapter.0.start_time="0.500000"
+chapters.chapter.0.end_time="600.000000"
+chapters.chapter.0.tags.title="Prologue"
+chapters.chapter.1.start_time="1200.000000"
+chapters.chapter.1.end_time="1800.000000"
+chapters.chapter.2.start_time="600.000000"
+chapters.chapter.2.end_time="1200.000000"
+chapters.chapter.2.tags.title="The Storm"
+"#;
+        let chapters = crate::inspect::parse_flat_output(output).chapters;
+        assert_eq!(
+            expand_embedded_chapters("Part 1", 1_800_000, &chapters, 0),
+            vec![
+                ("Prologue".to_string(), 600_000),
+                ("The Storm".to_string(), 600_000),
+                ("Part 1 (3)".to_string(), 600_000),
+            ]
+        );
+    }
+
+    /// Tests trimmed files, and files without enough embedded chapters to split.
+    #[test]
+    fn test_expand_embedded_chapters_trimmed() {
+        let chapters = embedded(&[(0, 10_000, "Credits"), (10_000, 70_000, "One"), (70_000, 130_000, "Two")]);
+        // 15 s trimmed from the start and the file now lasts 100 s: "Credits" is gone.
+        assert_eq!(
+            expand_embedded_chapters("Part 1", 100_000, &chapters, 15_000),
+            vec![("One".to_string(), 55_000), ("Two".to_string(), 45_000)]
+        );
+        assert_eq!(expand_embedded_chapters("Part 1", 5_000, &chapters, 0), vec![("Part 1".to_string(), 5_000)]);
+        assert_eq!(expand_embedded_chapters("Part 1", 5_000, &[], 0), vec![("Part 1".to_string(), 5_000)]);
+    }
+
+    /// Tests that empty chapters are merged into a neighbor without changing the total duration.
+    #[test]
+    fn test_merge_empty_chapters() {
+        let chapters = vec![
+            ("Intro".to_string(), 0),
+            ("One".to_string(), 5_000),
+            ("Blip".to_string(), 0),
+            ("Two".to_string(), 7_000),
+            ("Outro".to_string(), 0),
+        ];
+        let (kept, merged) = merge_empty_chapters(&chapters);
+        assert_eq!(kept, vec![("One".to_string(), 5_000), ("Two".to_string(), 7_000)]);
+        assert_eq!(merged, vec!["Intro", "Blip", "Outro"]);
+        assert_eq!(merge_empty_chapters(&numbered(3)), (numbered(3), Vec::new()));
+        assert_eq!(merge_empty_chapters(&[("Only".to_string(), 0)]), (Vec::new(), vec!["Only".to_string()]));
+    }
+
+    /// Tests merging chapters under a second into the previous chapter, or into the next one at
+    /// the start, keeping the total duration.
+    #[test]
+    fn test_merge_tiny_chapters() {
+        let chapters = vec![
+            ("Credits".to_string(), 300),
+            ("One".to_string(), 5_000),
+            ("Blip".to_string(), 999),
+            ("Two".to_string(), 1_000),
+            ("The End".to_string(), 400),
+        ];
+        let (kept, merged) = merge_tiny_chapters(&chapters, MIN_CHAPTER_MS);
+        assert_eq!(kept, vec![("One".to_string(), 6_299), ("Two".to_string(), 1_400)]);
+        assert_eq!(merged, vec!["Credits", "Blip", "The End"]);
+        assert_eq!(merge_tiny_chapters(&numbered(3), MIN_CHAPTER_MS), (numbered(3), Vec::new()));
+        let only_tiny = vec![("Jingle".to_string(), 400), ("Sting".to_string(), 300)];
+        assert_eq!(merge_tiny_chapters(&only_tiny, MIN_CHAPTER_MS), (vec![("Jingle".to_string(), 700)], vec!["Sting".to_string()]));
+        assert_eq!(merge_tiny_chapters(&[], MIN_CHAPTER_MS), (Vec::new(), Vec::new()));
+    }
+
+    /// Tests that adjacent zero-length chapters get the minimum gap from their neighbors, without
+    /// moving boundaries further away.
+    #[test]
+    fn test_enforce_minimum_gap() {
+        let chapters = vec![
+            ("One".to_string(), 5_000),
+            ("Blip".to_string(), 0),
+            ("Blop".to_string(), 0),
+            ("Two".to_string(), 7_000),
+            ("Three".to_string(), 3_000),
+        ];
+        let nudged = enforce_minimum_gap(&chapters, 1);
+        let durations: Vec<u64> = nudged.iter().map(|(_, duration_ms)| *duration_ms).collect();
+        assert_eq!(durations, vec![5_000, 1, 1, 6_998, 3_000]);
+        assert!(check_timeline(&chapter_spans(&nudged), 1).is_ok());
+
+        // Short chapters at the very end start earlier instead.
+        let tail = vec![("One".to_string(), 2_000), ("Two".to_string(), 0), ("Three".to_string(), 0)];
+        let durations: Vec<u64> = enforce_minimum_gap(&tail, 500).iter().map(|(_, duration_ms)| *duration_ms).collect();
+        assert_eq!(durations, vec![1_000, 500, 500]);
+
+        assert_eq!(enforce_minimum_gap(&numbered(4), 1_000), numbered(4));
+        let durations: Vec<u64> = enforce_minimum_gap(&[("A".to_string(), 0), ("B".to_string(), 3)], 10).iter().map(|(_, d)| *d).collect();
+        assert_eq!(durations, vec![1, 2]);
+        assert!(enforce_minimum_gap(&[], 1).is_empty());
+        assert_eq!(minimum_chapter_gap(None, true), MIN_CHAPTER_MS);
+        assert_eq!(minimum_chapter_gap(Some(2_500), true), 2_500);
+        assert_eq!(minimum_chapter_gap(Some(250), false), 250);
+    }
+
+    /// Tests loading a metadata file written by hand, kept verbatim, and the errors for a file
+    /// without the header, one whose last chapter ffmpeg would drop, and one that does not exist.
+    #[test]
+    fn test_load_metadata_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dune.ffmetadata");
+        let text = ";FFMETADATA1\n\
+                    title=Dune\n\
+                    artist=Frank Herbert\n\
+                    \n\
+                    # Book One, split by hand\n\
+                    [CHAPTER]\n\
+                    TIMEBASE=1/1000\n\
+                    START=0\n\
+                    END=754000\n\
+                    title=Book One: Dune\n\
+                    [CHAPTER]\n\
+                    TIMEBASE=1/1000\n\
+                    START=754000\n\
+                    END=1500500\n\
+                    title=Book Two: Muad\\'Dib\n";
+        fs::write(&path, text).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let (read, metadata) = load_metadata_file(&path).unwrap();
+        assert_eq!(read, text);
+        assert_eq!(metadata.global_tag("title"), Some("Dune"));
+        assert_eq!(metadata.chapters, vec![
+            TimedChapter::new(0, 754_000, Some("Book One: Dune")),
+            TimedChapter::new(754_000, 1_500_500, Some("Book Two: Muad'Dib")),
+        ]);
+
+        // A BOM from a Windows editor is dropped, so ffmpeg still finds the header.
+        fs::write(&path, format!("\u{feff}{}", text)).unwrap();
+        let (read, metadata) = load_metadata_file(&path).unwrap();
+        assert_eq!(read, text);
+        assert_eq!(metadata.chapters[0].title.as_deref(), Some("Book One: Dune"));
+
+        fs::write(&path, "title=Dune\n[CHAPTER]\nSTART=0\nEND=1000\n").unwrap();
+        assert_eq!(
+            load_metadata_file(&path).unwrap_err(),
+            format!("Invalid metadata file '{}': the file does not start with ';FFMETADATA1'", path)
+        );
+        fs::write(&path, format!("{}[CHAPTER]\nTIMEBASE=1/1000\nSTART=1500500\nEND=1500900\ntitle=The End\n", text)).unwrap();
+        assert_eq!(
+            load_metadata_file(&path).unwrap_err(),
+            format!("Invalid metadata file '{}': chapter 3 lasts 400 ms, shorter than the 1000 ms every chapter needs", path)
+        );
+        assert!(load_metadata_file(&dir.path().join("missing").to_string_lossy()).unwrap_err().starts_with("Could not read"));
+    }
+
+    /// Tests the tolerance at the end of the audio, a chapter past it, and a last chapter that
+    /// ends early.
+    #[test]
+    fn test_check_chapter_file_length() {
+        let chapters = [TimedChapter::new(0, 60_000, Some("One")), TimedChapter::new(60_000, 120_000, None)];
+        assert_eq!(check_chapter_file_length(&chapters, 120_000), Ok(None));
+        assert_eq!(check_chapter_file_length(&chapters, 118_500), Ok(None));
+        assert_eq!(check_chapter_file_length(&chapters, 121_900), Ok(None));
+        assert_eq!(check_chapter_file_length(&[], 120_000), Ok(None));
+        assert_eq!(
+            check_chapter_file_length(&chapters, 90_000),
+            Err("chapter 2 ends at 0:02:00.000, past the end of the audio at 0:01:30.000".to_string())
+        );
+        assert_eq!(
+            check_chapter_file_length(&chapters, 125_250),
+            Ok(Some("the last chapter ends at 0:02:00.000, before the end of the audio at 0:02:05.250".to_string()))
+        );
+    }
+
+    /// Tests that planned spans pass the timeline check, and each way a timeline can be broken.
+    #[test]
+    fn test_check_timeline() {
+        let spans = chapter_spans(&numbered(3));
+        assert_eq!(spans, vec![(0, 1_000), (1_000, 3_000), (3_000, 6_000)]);
+        assert_eq!(check_timeline(&spans, 1), Ok(()));
+        assert_eq!(check_timeline(&[], 1), Ok(()));
+
+        assert_eq!(
+            check_timeline(&[(0, 1_000), (1_000, 1_000)], 1),
+            Err("chapter 2 ends at 1000 ms, not after its start at 1000 ms".to_string())
+        );
+        assert_eq!(
+            check_timeline(&[(0, 1_000), (2_000, 1_500)], 1),
+            Err("chapter 2 ends at 1500 ms, not after its start at 2000 ms".to_string())
+        );
+        assert_eq!(
+            check_timeline(&[(0, 1_000), (900, 2_000)], 1),
+            Err("chapter 2 starts at 900 ms and overlaps the previous chapter, which ends at 1000 ms".to_string())
+        );
+        assert_eq!(
+            check_timeline(&[(0, 1_000), (1_100, 2_000)], 1),
+            Err("chapter 2 starts at 1100 ms and leaves a gap after the previous chapter, which ends at 1000 ms".to_string())
+        );
+        assert!(check_timeline(&[(500, 1_000)], 1).unwrap_err().starts_with("chapter 1 starts at 500 ms"));
+
+        // Sub-second chapters, which ffmpeg may drop, fail unless the book is too short for more.
+        assert_eq!(check_timeline(&spans, MIN_CHAPTER_MS), Ok(()));
+        assert_eq!(
+            check_timeline(&[(0, 61_000), (61_000, 120_000), (120_000, 120_400)], MIN_CHAPTER_MS),
+            Err("chapter 3 lasts 400 ms, shorter than the 1000 ms every chapter needs".to_string())
+        );
+        assert!(check_timeline(&[(0, 999), (999, 5_000)], MIN_CHAPTER_MS).unwrap_err().starts_with("chapter 1 lasts 999 ms"));
+        assert_eq!(check_timeline(&[(0, 400)], MIN_CHAPTER_MS), Ok(()));
+        assert_eq!(check_timeline(&[(0, 750), (750, 1_500)], MIN_CHAPTER_MS), Ok(()));
+    }
+
+    /// Tests range titles, and that a chapter list within the limit is left alone.
+    #[test]
+    fn test_coalesce_range_titles() {
+        let merged = coalesce_chapters(&numbered(5), 3, CoalesceTitles::Range);
+        let titles: Vec<&str> = merged.iter().map(|(title, _)| title.as_str()).collect();
+        assert_eq!(titles, vec!["Part 1", "Part 2 (Chapters 2–3)", "Part 4 (Chapters 4–5)"]);
+        assert_eq!(coalesce_chapters(&numbered(3), 3, CoalesceTitles::Range), numbered(3));
+    }
+
+    fn durations(chapters: &[(String, u64)]) -> Vec<u64> {
+        chapters.iter().map(|(_, duration_ms)| *duration_ms).collect()
+    }
+
+    /// Tests equal splits, with the remainder spread one millisecond each over the first chapters.
+    #[test]
+    fn test_split_by_count() {
+        let chapters = split_by_time(10_000, TimeSplit::Count(4));
+        assert_eq!(chapters[0].0, "Chapter 1");
+        assert_eq!(chapters[3].0, "Chapter 4");
+        assert_eq!(durations(&chapters), vec![2_500; 4]);
+        assert_eq!(durations(&split_by_time(10_003, TimeSplit::Count(4))), vec![2_501, 2_501, 2_501, 2_500]);
+        assert_eq!(durations(&split_by_time(3, TimeSplit::Count(5))), vec![1, 1, 1]);
+        assert_eq!(durations(&split_by_time(10_000, TimeSplit::Count(0))), vec![10_000]);
+        assert!(split_by_time(0, TimeSplit::Count(3)).is_empty());
+    }
+
+    /// Tests fixed-length splits with a shorter last chapter, and a tiny remainder added to the last.
+    #[test]
+    fn test_split_by_length() {
+        let minutes = |count: u64| count * 60_000;
+        assert_eq!(durations(&split_by_time(minutes(25), TimeSplit::Length(minutes(10)))), vec![minutes(10), minutes(10), minutes(5)]);
+        assert_eq!(durations(&split_by_time(minutes(20), TimeSplit::Length(minutes(10)))), vec![minutes(10), minutes(10)]);
+        assert_eq!(durations(&split_by_time(minutes(20) + 400, TimeSplit::Length(minutes(10)))), vec![minutes(10), minutes(10) + 400]);
+        assert_eq!(durations(&split_by_time(400, TimeSplit::Length(minutes(10)))), vec![400]);
+        let total: u64 = durations(&split_by_time(7_654_321, TimeSplit::Length(minutes(7)))).iter().sum();
+        assert_eq!(total, 7_654_321);
+    }
+}
@@ -0,0 +1,1379 @@
+use std::fs;
+use std::path::Path;
+
+use m4btool::{BracketKind, CleanOptions, CleanStrategy, Numbering, TitleCase};
+
+use crate::chapters::{CoalesceTitles, TimeSplit};
+use crate::collage::CoverLayout;
+use crate::config::BuildConfig;
+use crate::encode::{AacEncoder, AacProfile, EncodeSettings};
+use crate::ffmpeg_warnings::WarningCheck;
+use crate::language::parse_language;
+use crate::mux::Brand;
+use crate::overrides::parse_bitrate;
+use crate::preset::{parse_preset, Preset};
+use crate::shell;
+use crate::tag_encoding::{parse_tag_encoding, TagEncoding};
+use crate::table::{parse_table_format, TableFormat};
+use crate::tags::{parse_date, parse_year, BookTags};
+
+/// Options for building an audiobook from a directory of audio files.
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    /// The input directories in priority order, or a single zip archive.
+    pub input_directories: Vec<String>,
+    /// Where to write the book; required with several input directories. Without it the book is
+    /// named after its title.
+    pub output: Option<String>,
+    /// Replace an existing book at the title-derived output even if another build wrote it.
+    pub overwrite: bool,
+    /// With several input directories, sort all files by name instead of directory by directory.
+    pub interleave_sort: bool,
+    /// Lead each chapter title with the subdirectories its file lies in.
+    pub title_include_dirs: bool,
+    /// Ignore a `chapters.txt` in the input directory and clean the file names instead.
+    pub no_chapters_file: bool,
+    pub tags: BookTags,
+    /// Explicit cover images; when absent a `cover.*` file in the input directory is used, or
+    /// else the picture embedded in the first source that has one.
+    /// Several images are combined into one according to `cover_layout`.
+    pub covers: Vec<String>,
+    pub cover_layout: CoverLayout,
+    /// Write the book without any cover, neither found nor embedded.
+    pub no_cover: bool,
+    /// Shrink the cover until its JPEG is at most this many bytes.
+    pub max_cover_bytes: Option<u64>,
+    /// Attach the cover as stored instead of turning a JPEG upright by its EXIF orientation.
+    pub no_auto_orient_cover: bool,
+    /// Show a thumbnail of the cover during every chapter in a timed image track.
+    pub chapter_thumbnails: bool,
+    /// Re-decode tags and embedded chapter titles read from the sources.
+    pub tag_encoding: Option<TagEncoding>,
+    /// Sidecar file mapping file names to bitrates that override the source-derived bitrate.
+    pub bitrate_overrides: Option<String>,
+    /// Build one version of the book per bitrate, each encoded from the sources, with the bitrate
+    /// in its file name.
+    pub bitrate_ladder: Vec<u64>,
+    /// Set for one version of a `--bitrate-ladder` build: the bitrate in bits per second that
+    /// every file is encoded at, over the source's and any override, and put into its file name.
+    pub ladder_rung: Option<u64>,
+    /// Normalize the loudness in two passes: measure each file first, then apply the measurement
+    /// while encoding. Needs `encode.normalize`, which `--normalize-two-pass` turns on.
+    pub normalize_two_pass: bool,
+    pub encode: EncodeSettings,
+    /// Probe the inputs and print the planned chapters without encoding anything.
+    pub dry_run: bool,
+    /// Predict the output size and encode time, measured with a short benchmark encode, without building.
+    pub estimate: bool,
+    /// Print each file's name next to its cleaned title and what cleaning removed, without building.
+    pub compare: bool,
+    pub table_format: TableFormat,
+    /// Files shorter than this many milliseconds are dropped from the input set.
+    pub min_file_duration_ms: Option<u64>,
+    pub clean: CleanOptions,
+    /// Concatenate the audio only, without chapters, tags, or cover.
+    pub no_metadata: bool,
+    /// Milliseconds cut from the start of every file, e.g. a recap of the previous episode.
+    pub trim_start_ms: u64,
+    /// Milliseconds cut from the end of every file.
+    pub trim_end_ms: u64,
+    /// Print the final ffmpeg command as a shell command and keep its input files for re-running it.
+    pub print_command: bool,
+    /// Measure bitrate, loudness, and size of the finished book; costs one full decode.
+    pub stats: bool,
+    /// Print how long each build phase and each file's encode took.
+    pub profile: bool,
+    /// Reuse the encodes of unchanged files from a cache in the input directory, and skip the
+    /// build when nothing changed.
+    pub incremental: bool,
+    /// Wait for another run writing the same output to finish instead of failing.
+    pub wait_for_lock: bool,
+    /// Fail the build when the cover cannot be attached instead of retrying without it.
+    pub require_cover: bool,
+    /// Extra ffmpeg arguments appended to the final mux, just before the output path.
+    pub mux_args: Vec<String>,
+    /// Fail an ffmpeg run that exits with 0 but prints a warning matching `ffmpeg_warning_patterns`.
+    pub fail_on_warning: bool,
+    /// The warnings `fail_on_warning` looks for, as regular expressions; the defaults when empty.
+    pub ffmpeg_warning_patterns: Vec<String>,
+    /// For a zip input, keep the order of the files in the archive instead of sorting by file name.
+    pub archive_order: bool,
+    /// Order the files by their disc and track tags instead of by file name.
+    pub sort_by_tags: bool,
+    /// Directory for the work files instead of the system temp directory.
+    pub temp_dir: Option<String>,
+    /// Also write the chapters as a WebVTT file next to the output, for HTML5 players.
+    pub write_vtt: bool,
+    /// Also write the tags as a `metadata.opf` next to the output, for library managers.
+    pub write_opf: bool,
+    /// Also write the tags and chapters as an Audiobookshelf `metadata.json` next to the output.
+    pub write_audiobookshelf_metadata: bool,
+    /// Also write each encoded file as a tagged `.m4a` track into this directory.
+    pub export_tracks: Option<String>,
+    /// File name globs of files pinned to the start of the book, titled by their file names.
+    pub front_matter: Vec<String>,
+    /// File name globs of files pinned to the end of the book, titled by their file names.
+    pub back_matter: Vec<String>,
+    /// Split files with embedded chapters, such as ID3 chapters in MP3s, into those chapters.
+    pub preserve_chapters: bool,
+    /// How many files to encode at once; one at a time when not given.
+    pub jobs: Option<usize>,
+    /// Keep the work files and encode logs after the build.
+    pub keep_temp: bool,
+    /// Directory to copy the concat list and FFMETADATA file into before the mux.
+    pub dump_intermediate: Option<String>,
+    /// Transliterate chapter titles to ASCII, keeping the originals as a second chapter tag.
+    pub transliterate: bool,
+    /// Also scan hidden files and folders and NAS junk folders such as `@eaDir`.
+    pub include_hidden: bool,
+    /// Skip files whose contents are identical to an earlier file's.
+    pub dedupe_files: bool,
+    /// Follow symlinked files and folders below the input directories while scanning.
+    pub follow_symlinks: bool,
+    /// Also use mp4, mkv, and webm video files, taking only their audio.
+    pub extract_audio: bool,
+    /// The chapter count above which to warn or coalesce; `DEFAULT_MAX_CHAPTERS` when not given.
+    pub max_chapters: Option<usize>,
+    /// Merge adjacent chapters to stay within the maximum, titled this way.
+    pub coalesce_chapters: Option<CoalesceTitles>,
+    /// The shortest chapter in milliseconds; `DEFAULT_MINIMUM_GAP_MS` when not given.
+    pub chapter_minimum_gap: Option<u64>,
+    /// Pad chapters shorter than a second to a second instead of merging them into a neighbor.
+    pub keep_tiny_chapters: bool,
+    /// Milliseconds of silence before the first chapter, which starts after it.
+    pub lead_in_ms: Option<u64>,
+    /// An FFMETADATA file muxed as it is instead of the generated chapters and file-derived tags.
+    pub metadata_file: Option<String>,
+    /// Where `--config` reads the build configuration from, or `CONFIG_STDIN`.
+    pub config: Option<String>,
+    /// The configuration read from `config` before the build, so that stdin is read only once
+    /// even for several versions of a `--bitrate-ladder`.
+    pub loaded_config: Option<BuildConfig>,
+    /// A user-supplied command that prints book metadata as JSON, run before the build.
+    pub metadata_command: Option<String>,
+    /// Fail instead of finishing a degraded book, such as one without its cover.
+    pub strict: bool,
+    /// Divide the book into chapters by time instead of by file.
+    pub time_split: Option<TimeSplit>,
+    /// MP4 major brand of the book; `M4B ` for an `.m4b` output when not given.
+    pub brand: Option<Brand>,
+    /// Move the index to the front of the book with `-movflags +faststart`.
+    pub faststart: bool,
+    /// The `--preset` that `encode` and `faststart` start from, before the other flags.
+    pub preset: Option<Preset>,
+}
+
+/// Options for the `retag` subcommand, which rewrites the tags of an existing m4b.
+#[derive(Debug, Default)]
+pub struct RetagOptions {
+    pub input_file: String,
+    pub tags: BookTags,
+    pub cover: Option<String>,
+}
+
+/// Options for the hidden `clean-titles` subcommand, which cleans titles read from stdin.
+#[derive(Debug, Default)]
+pub struct CleanTitlesOptions {
+    pub clean: CleanOptions,
+}
+
+/// Options for the `diff` subcommand, which compares the chapters of two plans, books, or input
+/// directories.
+#[derive(Debug, Default)]
+pub struct DiffOptions {
+    /// Each side is a `plan.json`, an existing book, or an input directory or zip archive, which
+    /// is planned as a build would, without encoding.
+    pub left: String,
+    pub right: String,
+    /// Print only the differing chapters as `-` and `+` lines instead of two columns.
+    pub unified: bool,
+    /// The build options a directory side is planned with, such as the title options.
+    pub build: BuildOptions,
+}
+
+/// Options for the `doctor` subcommand, also run by `--version`.
+#[derive(Debug, Default, PartialEq)]
+pub struct DoctorOptions {
+    /// Print the report as JSON.
+    pub json: bool,
+}
+
+/// Console options accepted by every subcommand.
+#[derive(Debug, Default, PartialEq)]
+pub struct GlobalOptions {
+    /// Never color warnings and errors, even on a terminal.
+    pub no_color: bool,
+    /// Forward ffmpeg's own output to the console.
+    pub verbose: bool,
+    /// Also write all messages with timestamps to this file.
+    pub log_file: Option<String>,
+    /// Append to the log file instead of replacing it.
+    pub log_append: bool,
+    /// Answer yes to every confirmation prompt.
+    pub yes: bool,
+    /// Write progress events as JSON lines: `Some(None)` to stdout, `Some(Some(path))` to a file
+    /// or named pipe.
+    pub progress_json: Option<Option<String>>,
+}
+
+/// The action selected on the command line.
+#[derive(Debug)]
+pub enum Invocation {
+    Build(Box<BuildOptions>),
+    Retag(RetagOptions),
+    CleanTitles(CleanTitlesOptions),
+    Doctor(DoctorOptions),
+    Diff(Box<DiffOptions>),
+}
+
+/// Returns the usage text for the given program name.
+pub fn usage(program: &str) -> String {
+    format!(
+        "Usage: {program} <input_directory...|archive.zip> [options]\n\
+         \x20      {program} retag <file.m4b> [--title <title>] [--author <author>] [--year <year>] [--date <date>] [--language <code>] [--cover <path>]\n\
+         \x20      {program} <file.m4b> --rewrite-existing-metadata-only [tag options]   (same as retag)\n\
+         \x20      {program} doctor [--json]\n\
+         \x20      {program} diff <plan.json|book.m4b|dir> <plan.json|book.m4b|dir> [--unified] [build options]\n\
+         \x20      {program} --version\n\
+         \n\
+         Tag options (build and retag):\n\
+         \x20 --title <title>     Book title\n\
+         \x20 --author <author>   Book author\n\
+         \x20 --year <year>       Release year (four digits)\n\
+         \x20 --date <date>       Publish date as YYYY or YYYY-MM-DD; overrides --year\n\
+         \x20 --language <code>   ISO 639-1 or 639-2 language code, e.g. en or eng; by default the language\n\
+         \x20                     most source files are tagged with (build only)\n\
+         \x20 --cover <path>      Cover image to embed; repeat to combine several (build only)\n\
+         \n\
+         Title options:\n\
+         \x20 --clean-strategy <name>     frequency (default), common-prefix, or auto (common-prefix for small sets)\n\
+         \x20 --threshold <0-1>           Fraction of titles a leading word must appear in to be removed (default 0.8)\n\
+         \x20 --keep <words>              Comma-separated words never removed by cleaning\n\
+         \x20 --strip <words>             Comma-separated words always removed\n\
+         \x20 --stopwords <file|words>    Words always removed, ignoring case and accents like the word counts;\n\
+         \x20                             a file lists one or more per line\n\
+         \x20 --keep-leading-number       Keep each file's leading number in its title\n\
+         \x20 --normalize-filenames-first Count words case-insensitively, treating '_' and '.' as spaces\n\
+         \x20 --case-sensitive-tokens     Count words with different case or accents (Chapter, chápter) apart\n\
+         \x20 --title-strip-extension-artifacts\n\
+         \x20                             Strip leftover audio extensions such as '.mp3' or '.mp3.1' from titles\n\
+         \x20 --protect-brackets <kinds>  Bracket styles kept from frequency removal: square, round, all (default), or none\n\
+         \x20 --start-chapter-number <n>  Number untitled chapters \"Chapter <n>\" onward from <n> (default 1)\n\
+         \x20 --chapter-template <text>   Title chapters like \"Chapter {{n}}: {{title}}\"; {{n}} skips front and back matter\n\
+         \x20 --unnumbered-titles <words> Comma-separated words marking front and back matter for {{n}}\n\
+         \x20                             (default Prologue, Introduction, Preface, Epilogue, Afterword, Credits)\n\
+         \x20 --title-include-dirs        Lead titles with their subfolders, e.g. \"Part One – 01\" for Part One/01.mp3\n\
+         \x20 --no-chapters-file          Ignore chapters.txt (one title per file, in order) in the input directory\n\
+         \x20 --title-case <case>         Recase cleaned titles: keep (default), title, sentence, lower, or upper\n\
+         \n\
+         Build options:\n\
+         \x20 --output <path>             Where to write the book (required with several input directories;\n\
+         \x20                             default <Title>.m4b in the input directory or next to the archive)\n\
+         \x20 --overwrite                 Replace a book at <Title>.m4b that another build wrote\n\
+         \x20 --interleave-sort           With several input directories, sort all files by name together\n\
+         \x20 --bitrate-overrides <file>  Per-file bitrates, one 'filename = bitrate' per line\n\
+         \x20 --bitrate-ladder <rates>    Build one version per comma-separated bitrate, e.g. 64k,128k, each\n\
+         \x20                             encoded from the sources into <Title>.64k.m4b, <Title>.128k.m4b, ...\n\
+         \x20 --jobs <n>                  Encode <n> files at once (default 1); each is probed as soon as it is done\n\
+         \x20 --normalize-two-pass        Normalize, measuring each file's loudness first and applying it exactly\n\
+         \x20                             while encoding, instead of adjusting the gain as the audio goes by\n\
+         \x20 --sample-rate <hz>          Resample every file to this rate\n\
+         \x20 --channels <n>              Convert every file to <n> channels (default: the fewest among the files)\n\
+         \x20 --mono                      Downmix every file to mono, the same as --channels 1\n\
+         \x20 --preserve-source-bitrate-exactly\n\
+         \x20                             Encode at the exact source bitrate instead of rounding to whole kbps\n\
+         \x20 --codec <name>              AAC encoder: libfdk_aac (default) or aac (ffmpeg's built-in encoder)\n\
+         \x20 --aac-vbr <0.1-2.0>         With --codec aac, encode at this VBR quality instead of a constant bitrate\n\
+         \x20 --bitrate <rate>            Encode every file at this bitrate, e.g. 64k (default: each source's);\n\
+         \x20                             --bitrate-overrides and --config still set single files\n\
+         \x20 --aac-profile <profile>     AAC profile: lc (default) or he, HE-AAC, which keeps speech clear at\n\
+         \x20                             low bitrates (libfdk_aac only)\n\
+         \x20 --normalize                 Even out the loudness of every file at -18 LUFS (--no-normalize to turn off)\n\
+         \x20 --faststart                 Put the index at the front, so players can start the book before it has\n\
+         \x20                             downloaded (--no-faststart to turn off)\n\
+         \x20 --preset <name>             Encode settings for a kind of book; the flags given with it win:\n\
+         \x20                             voice (mono 64k HE-AAC, normalized), voice-hq (mono 96k, normalized),\n\
+         \x20                             music (stereo 128k), tiny (mono 32k HE-AAC at 22.05 kHz, normalized),\n\
+         \x20                             or archive (stereo at the source's bitrate and rate, no faststart)\n\
+         \x20 --dry-run                   Probe the files and print the planned chapters without encoding\n\
+         \x20 --estimate                  Predict the output size and encode time without building (approximate)\n\
+         \x20 --compare                   Print each file name next to its cleaned title, with the words removed\n\
+         \x20                             and why, without building\n\
+         \x20 --table-format <format>     Dry-run table format: plain (default), tsv, or json\n\
+         \x20 --no-metadata               Concatenate the audio only, without chapters, tags, or cover\n\
+         \x20 --min-file-duration <s>     Skip input files shorter than this many seconds\n\
+         \x20 --trim-start <s>            Cut this many seconds from the start of every file\n\
+         \x20 --trim-end <s>              Cut this many seconds from the end of every file\n\
+         \x20 --print-command             Print the final ffmpeg command ready to copy and re-run\n\
+         \x20 --archive-order             For a .zip input, keep the archive's file order instead of sorting by name\n\
+         \x20 --include-hidden            Also use hidden files and NAS folders like @eaDir (skipped by default)\n\
+         \x20 --dedupe-files              Skip files with the same contents as an earlier file, e.g. a chapter\n\
+         \x20                             saved twice under different names\n\
+         \x20 --follow-symlinks           Also use symlinked files and folders in the input directories\n\
+         \x20 --extract-audio             Also use .mp4, .mkv, and .webm videos, taking only their audio\n\
+         \x20 --sort-by-tags              Order files by their disc and track number tags instead of by name\n\
+         \x20 --front-matter <glob>       Put files whose names match <glob> (e.g. '*opening credits*') first,\n\
+         \x20                             titled by their file names; may be repeated\n\
+         \x20 --back-matter <glob>        Likewise put matching files, e.g. 'zzz_authors_note*', last\n\
+         \x20 --temp-dir <dir>            Directory for work files (default: system temp). May be inside the\n\
+         \x20                             input directory: it is skipped when scanning, and free space is\n\
+         \x20                             checked once for it and the output when they share a disk\n\
+         \x20 --keep-temp                 Keep the encoded files, chapter list, and per-file ffmpeg logs\n\
+         \x20 --dump-intermediate <dir>   Copy the concat list and FFMETADATA file given to ffmpeg into <dir>\n\
+         \x20 --tag-encoding <encoding>   Re-decode source tags written in a legacy encoding, e.g. windows-1252\n\
+         \x20                             or gbk, or auto to guess it per tag\n\
+         \x20 --preserve-chapters         Use the chapters embedded in a file (e.g. ID3 chapters in MP3s) instead\n\
+         \x20                             of one chapter for the whole file\n\
+         \x20 --equal-chapters <n>        Divide the whole book into <n> chapters of equal length, ignoring files\n\
+         \x20 --fixed-chapter-length <min>\n\
+         \x20                             Divide the whole book into chapters of this many minutes\n\
+         \x20 --max-chapters <n>          Warn when the book would have more chapters than this (default 255)\n\
+         \x20 --coalesce-chapters <how>   Instead, merge adjacent chapters to stay within --max-chapters, titled\n\
+         \x20                             by their first chapter (first) or also its range (range)\n\
+         \x20 --chapter-minimum-gap <ms>  Make every chapter at least this long, nudging its neighbors (default 1)\n\
+         \x20 --keep-tiny-chapters        Pad chapters shorter than a second to a second instead of merging them\n\
+         \x20                             into the previous chapter\n\
+         \x20 --lead-in <ms>              Start the book with this much silence and the first chapter after it\n\
+         \x20 --write-vtt                 Also write the chapters to a WebVTT file next to the book\n\
+         \x20 --write-opf                 Also write the tags to metadata.opf next to the book, for library managers\n\
+         \x20 --write-audiobookshelf-metadata\n\
+         \x20                             Also write the tags and chapters to an Audiobookshelf metadata.json next to the book\n\
+         \x20 --also-export-tracks <dir> Also write each file as a tagged .m4a track named after its chapter into\n\
+         \x20                             <dir>, remuxed from the encoded files\n\
+         \x20 --transliterate             Write chapter titles in ASCII (e.g. pinyin for Chinese), keeping the\n\
+         \x20                             original titles as an original_title chapter tag\n\
+         \x20 --stats                     Report bitrate, loudness, and size of the finished book (one extra decode)\n\
+         \x20 --profile                   Report how long scanning, probing, encoding, and muxing took\n\
+         \x20 --incremental               Reuse encodes of unchanged files; do nothing if the book is up to date\n\
+         \x20 --wait-for-lock             Wait for another run writing the same output instead of failing\n\
+         \x20 --config <path|->           Read the tags of a metadata defaults file, a chapters list, and a file\n\
+         \x20                             order as TOML or JSON from <path>, or from stdin for -; other\n\
+         \x20                             options take precedence\n\
+         \x20 --metadata-command <cmd>    Run <cmd> <title> <author> <input_directory> and read book metadata\n\
+         \x20                             as JSON from its output; tag and cover options take precedence\n\
+         \x20 --metadata-file <path>      Mux this FFMETADATA file as it is instead of generating chapters, e.g.\n\
+         \x20                             one kept with --dump-intermediate and edited (aliases --ffmetadata,\n\
+         \x20                             --from-ffmetadata)\n\
+         \x20 --brand <brand>             MP4 major brand: M4B (default for .m4b, so Apple devices treat the\n\
+         \x20                             file as an audiobook), M4A, or mp42\n\
+         \x20 --cover-layout <layout>     Arrangement of several --cover images: h (default), v, or grid\n\
+         \x20 --no-cover                  Do not attach a cover, not even one found next to or inside the files\n\
+         \x20 --max-cover-bytes <n>       Downscale and recompress the cover until it is at most <n> bytes\n\
+         \x20 --no-auto-orient-cover      Ignore the EXIF rotation of a JPEG cover (default: turn it upright)\n\
+         \x20 --chapter-thumbnails        Show a thumbnail of the cover during every chapter, for players that\n\
+         \x20                             read a timed image track\n\
+         \x20 --no-cover-optional         Fail if the cover cannot be attached (default: retry without the cover)\n\
+         \x20 --strict                    Fail instead of writing a book with parts left out, such as the cover\n\
+         \x20 --encoder-arg <key=value>   Pass an encoder option such as afterburner=1 right after the codec;\n\
+         \x20                             repeatable\n\
+         \x20 --encoder-after-args <args> Pass several encoder options at once, e.g. \"-afterburner 1 -cutoff 18000\"\n\
+         \x20 --ffmpeg-encode-args <args> Extra ffmpeg output options for every per-file encode\n\
+         \x20 --ffmpeg-mux-args <args>    Extra ffmpeg output options for the final mux\n\
+         \x20 --fail-on-warning           Fail an ffmpeg run that succeeds but warns of damage, e.g. Non-monotonous DTS\n\
+         \x20 --continue-on-ffmpeg-warning\n\
+         \x20                             Only report such warnings (default)\n\
+         \x20 --ffmpeg-warning-pattern <regex>\n\
+         \x20                             A warning --fail-on-warning looks for instead of the defaults; repeatable\n\
+         \n\
+         Global options:\n\
+         \x20 --no-color        Do not color warnings and errors (also honors the NO_COLOR environment variable)\n\
+         \x20 --verbose         Show ffmpeg's own output\n\
+         \x20 --log-file <path> Also write all messages, ffmpeg commands, and results to <path> with timestamps\n\
+         \x20 --log-append      Append to the log file instead of replacing it\n\
+         \x20 --progress-json   Write a build's progress as JSON lines to stdout, or to a file or named\n\
+         \x20                   pipe with --progress-json=<path>; results meant for stdout go to stderr\n\
+         \x20 --yes, -y         Do not ask before overwriting the output or starting a long encode (prompts\n\
+         \x20                   are only shown when stdin and stderr are terminals)"
+    )
+}
+
+/// Handles the global console flags, which every subcommand accepts.
+///
+/// # Returns
+///
+/// `Ok(true)` if `arg` was a global flag and has been consumed together with its value.
+fn parse_global_flag<'a>(
+    arg: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+    global: &mut GlobalOptions,
+) -> Result<bool, String> {
+    match arg {
+        "--no-color" => global.no_color = true,
+        "--verbose" => global.verbose = true,
+        "--log-file" => global.log_file = Some(take_value(arg, iter)?),
+        "--log-append" => global.log_append = true,
+        "--yes" | "-y" => global.yes = true,
+        "--progress-json" => global.progress_json = Some(None),
+        // The path is optional, so it is only taken inline; `split_inline_values` leaves it attached.
+        _ => match arg.strip_prefix("--progress-json=") {
+            Some(path) => global.progress_json = Some(Some(path.to_string())),
+            None => return Ok(false),
+        },
+    }
+    Ok(true)
+}
+
+/// Parses the command-line arguments (excluding the program name).
+///
+/// Flags accept their value either as the next argument or inline as `--flag=value`. The global
+/// console flags are read into `global` as they come, before or after the subcommand, so one
+/// given as the value of another flag (`--title --verbose`) stays that flag's value.
+///
+/// # Returns
+///
+/// The selected `Invocation`, or an error message suitable for printing above the usage text.
+pub fn parse_args(args: &[String], global: &mut GlobalOptions) -> Result<Invocation, String> {
+    let expanded = split_inline_values(args);
+    // Global flags may also come before the subcommand.
+    let mut rest = expanded.as_slice();
+    while let Some((arg, after)) = rest.split_first() {
+        let mut iter = after.iter();
+        if !parse_global_flag(arg, &mut iter, global)? {
+            break;
+        }
+        rest = iter.as_slice();
+    }
+    let args = rest;
+    // `<file.m4b> --rewrite-existing-metadata-only` is the build-style spelling of `retag <file.m4b>`.
+    let metadata_only = args.iter().any(|arg| arg == "--rewrite-existing-metadata-only");
+    if args.first().map(String::as_str) == Some("retag") || metadata_only {
+        let mut options = RetagOptions::default();
+        let mut covers = Vec::new();
+        let skip = usize::from(!metadata_only);
+        let mut iter = args[skip..].iter().filter(|arg| *arg != "--rewrite-existing-metadata-only");
+        while let Some(arg) = iter.next() {
+            if parse_global_flag(arg, &mut iter, global)? {
+                continue;
+            }
+            if parse_tag_flag(arg, &mut iter, &mut options.tags, &mut covers)? {
+                continue;
+            }
+            if arg.starts_with("--") || !options.input_file.is_empty() {
+                return Err(format!("Unexpected argument '{}'", arg));
+            }
+            options.input_file = arg.clone();
+        }
+        if options.input_file.is_empty() {
+            return Err("Missing input file".to_string());
+        }
+        if covers.len() > 1 {
+            return Err("retag accepts a single --cover".to_string());
+        }
+        options.cover = covers.pop();
+        return Ok(Invocation::Retag(options));
+    }
+
+    if matches!(args.first().map(String::as_str), Some("doctor" | "--version" | "-V")) {
+        let mut options = DoctorOptions::default();
+        let mut iter = args[1..].iter();
+        while let Some(arg) = iter.next() {
+            if parse_global_flag(arg, &mut iter, global)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--json" if args[0] == "doctor" => options.json = true,
+                _ => return Err(format!("Unexpected argument '{}'", arg)),
+            }
+        }
+        return Ok(Invocation::Doctor(options));
+    }
+
+    if args.first().map(String::as_str) == Some("diff") {
+        let mut options = DiffOptions::default();
+        let mut inputs = Vec::new();
+        let mut iter = args[1..].iter();
+        while let Some(arg) = iter.next() {
+            if arg == "--unified" {
+                options.unified = true;
+                continue;
+            }
+            if parse_global_flag(arg, &mut iter, global)? {
+                continue;
+            }
+            if parse_tag_flag(arg, &mut iter, &mut options.build.tags, &mut options.build.covers)? {
+                continue;
+            }
+            if parse_build_flag(arg, &mut iter, &mut options.build)? {
+                continue;
+            }
+            if parse_clean_flag(arg, &mut iter, &mut options.build.clean)? {
+                continue;
+            }
+            if arg.starts_with("--") || inputs.len() == 2 {
+                return Err(format!("Unexpected argument '{}'", arg));
+            }
+            inputs.push(arg.clone());
+        }
+        let [left, right] = <[String; 2]>::try_from(inputs).map_err(|_| "diff needs two plans, books, or directories to compare".to_string())?;
+        (options.left, options.right) = (left, right);
+        return Ok(Invocation::Diff(Box::new(options)));
+    }
+
+    if args.first().map(String::as_str) == Some("clean-titles") {
+        let mut options = CleanTitlesOptions::default();
+        let mut iter = args[1..].iter();
+        while let Some(arg) = iter.next() {
+            if !parse_global_flag(arg, &mut iter, global)? && !parse_clean_flag(arg, &mut iter, &mut options.clean)? {
+                return Err(format!("Unexpected argument '{}'", arg));
+            }
+        }
+        return Ok(Invocation::CleanTitles(options));
+    }
+
+    let mut options = BuildOptions::default();
+    parse_build_args(args, &mut options, global)?;
+    // A preset only fills in the defaults, so the flags given with it win wherever they stand:
+    // parse them again over the preset's settings.
+    if let Some(preset) = options.preset.take() {
+        options = BuildOptions { encode: preset.settings.clone(), faststart: preset.faststart, ..BuildOptions::default() };
+        parse_build_args(args, &mut options, global)?;
+    }
+    if options.input_directories.is_empty() {
+        return Err("Missing input directory".to_string());
+    }
+    if options.input_directories.len() > 1 {
+        if options.input_directories.iter().any(|input| input.to_lowercase().ends_with(".zip")) {
+            return Err("A zip archive must be the only input".to_string());
+        }
+        if options.output.is_none() {
+            return Err("--output is required with several input directories".to_string());
+        }
+    }
+    if options.no_metadata && (!options.tags.is_empty() || !options.covers.is_empty()) {
+        return Err("--no-metadata cannot be combined with tag or cover options".to_string());
+    }
+    if options.no_metadata && options.compare {
+        return Err("--no-metadata cannot be combined with --compare, which shows the chapter titles".to_string());
+    }
+    if options.no_cover && !options.covers.is_empty() {
+        return Err("--no-cover cannot be combined with --cover".to_string());
+    }
+    if options.normalize_two_pass && !options.encode.normalize {
+        return Err("--normalize-two-pass cannot be combined with --no-normalize".to_string());
+    }
+    if options.chapter_thumbnails && (options.no_cover || options.no_metadata) {
+        return Err("--chapter-thumbnails needs the cover; it cannot be combined with --no-cover or --no-metadata".to_string());
+    }
+    if options.no_metadata && options.write_vtt {
+        return Err("--no-metadata cannot be combined with --write-vtt".to_string());
+    }
+    if options.no_metadata && options.write_opf {
+        return Err("--no-metadata cannot be combined with --write-opf".to_string());
+    }
+    if options.no_metadata && options.write_audiobookshelf_metadata {
+        return Err("--no-metadata cannot be combined with --write-audiobookshelf-metadata".to_string());
+    }
+    if options.export_tracks.is_some() && (options.no_metadata || !options.bitrate_ladder.is_empty()) {
+        return Err("--also-export-tracks cannot be combined with --no-metadata or --bitrate-ladder".to_string());
+    }
+    if !options.ffmpeg_warning_patterns.is_empty() {
+        if !options.fail_on_warning {
+            return Err("--ffmpeg-warning-pattern only applies with --fail-on-warning".to_string());
+        }
+        WarningCheck::new(&options.ffmpeg_warning_patterns)?;
+    }
+    if options.no_metadata && options.preserve_chapters {
+        return Err("--no-metadata cannot be combined with --preserve-chapters".to_string());
+    }
+    if options.no_metadata && options.transliterate {
+        return Err("--no-metadata cannot be combined with --transliterate".to_string());
+    }
+    if options.time_split.is_some() && (options.no_metadata || options.preserve_chapters) {
+        return Err("--equal-chapters and --fixed-chapter-length cannot be combined with --no-metadata or --preserve-chapters".to_string());
+    }
+    if options.metadata_file.is_some() && options.lead_in_ms.is_some() {
+        return Err("--metadata-file cannot be combined with --lead-in, which would move the audio away from its chapters".to_string());
+    }
+    if options.metadata_file.is_some()
+        && (options.no_metadata || options.preserve_chapters || options.transliterate || options.time_split.is_some() || options.coalesce_chapters.is_some())
+    {
+        return Err("--metadata-file cannot be combined with --no-metadata or options that make chapters, such as --preserve-chapters".to_string());
+    }
+    if !options.bitrate_ladder.is_empty() && (options.incremental || options.encode.aac_vbr.is_some()) {
+        return Err("--bitrate-ladder cannot be combined with --incremental or --aac-vbr".to_string());
+    }
+    if options.encode.aac_profile == AacProfile::He && options.encode.encoder != AacEncoder::Fdk {
+        let source = match &options.preset {
+            Some(preset) if preset.settings.aac_profile == AacProfile::He => format!("--preset {}", preset.name),
+            _ => "--aac-profile he".to_string(),
+        };
+        return Err(format!("{} needs --codec libfdk_aac; ffmpeg's built-in aac encoder only writes LC", source));
+    }
+    if options.archive_order && options.sort_by_tags {
+        return Err("--archive-order cannot be combined with --sort-by-tags".to_string());
+    }
+    Ok(Invocation::Build(Box::new(options)))
+}
+
+/// Parses the arguments of a build into `options`, over the values already there.
+fn parse_build_args(args: &[String], options: &mut BuildOptions, global: &mut GlobalOptions) -> Result<(), String> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if parse_global_flag(arg, &mut iter, global)? {
+            continue;
+        }
+        if parse_tag_flag(arg, &mut iter, &mut options.tags, &mut options.covers)? {
+            continue;
+        }
+        if parse_build_flag(arg, &mut iter, options)? {
+            continue;
+        }
+        if parse_clean_flag(arg, &mut iter, &mut options.clean)? {
+            continue;
+        }
+        if arg.starts_with("--") {
+            return Err(format!("Unexpected argument '{}'", arg));
+        }
+        options.input_directories.push(arg.clone());
+    }
+    Ok(())
+}
+
+/// Handles the flags that only apply to a build.
+///
+/// # Returns
+///
+/// `Ok(true)` if `arg` was a build flag and has been consumed together with its value.
+fn parse_build_flag<'a>(
+    arg: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+    options: &mut BuildOptions,
+) -> Result<bool, String> {
+    match arg {
+        "--bitrate-overrides" => options.bitrate_overrides = Some(take_value(arg, iter)?),
+        "--bitrate-ladder" => options.bitrate_ladder = parse_bitrate_ladder(&take_value(arg, iter)?)?,
+        // ffmpeg's AAC encoders take no pass log, so a second pass would only encode the file again.
+        "--two-pass" => return Err("--two-pass is not supported: neither aac nor libfdk_aac has two-pass rate control; for loudness normalization in two passes use --normalize-two-pass".to_string()),
+        "--normalize-two-pass" => (options.normalize_two_pass, options.encode.normalize) = (true, true),
+        "--sample-rate" => options.encode.sample_rate = Some(parse_sample_rate(&take_value(arg, iter)?)?),
+        "--channels" => options.encode.channels = Some(parse_channels(&take_value(arg, iter)?)?),
+        "--mono" => options.encode.channels = Some(1),
+        "--preserve-source-bitrate-exactly" => options.encode.exact_bitrate = true,
+        "--codec" => options.encode.encoder = parse_encoder(&take_value(arg, iter)?)?,
+        "--bitrate" => options.encode.bitrate = Some(parse_bitrate(&take_value(arg, iter)?).map_err(|err| format!("Invalid --bitrate: {}", err))?),
+        "--aac-profile" => options.encode.aac_profile = parse_aac_profile(&take_value(arg, iter)?)?,
+        "--normalize" => options.encode.normalize = true,
+        "--no-normalize" => options.encode.normalize = false,
+        "--faststart" => options.faststart = true,
+        "--no-faststart" => options.faststart = false,
+        // `parse_args` applies the other flags over the preset.
+        "--preset" => options.preset = Some(parse_preset(&take_value(arg, iter)?)?),
+        "--aac-vbr" => options.encode.aac_vbr = Some(parse_aac_vbr(&take_value(arg, iter)?)?),
+        "--no-metadata" => options.no_metadata = true,
+        "--dry-run" => options.dry_run = true,
+        "--estimate" => options.estimate = true,
+        "--compare" => options.compare = true,
+        "--print-command" => options.print_command = true,
+        "--stats" => options.stats = true,
+        "--profile" => options.profile = true,
+        "--incremental" => options.incremental = true,
+        "--wait-for-lock" => options.wait_for_lock = true,
+        "--write-vtt" => options.write_vtt = true,
+        "--write-opf" => options.write_opf = true,
+        "--write-audiobookshelf-metadata" => options.write_audiobookshelf_metadata = true,
+        "--also-export-tracks" => options.export_tracks = Some(take_value(arg, iter)?),
+        "--keep-temp" => options.keep_temp = true,
+        "--preserve-chapters" => options.preserve_chapters = true,
+        "--jobs" => options.jobs = Some(parse_jobs(&take_value(arg, iter)?)?),
+        "--front-matter" => options.front_matter.push(take_value(arg, iter)?),
+        "--back-matter" => options.back_matter.push(take_value(arg, iter)?),
+        "--dump-intermediate" => options.dump_intermediate = Some(take_value(arg, iter)?),
+        "--transliterate" => options.transliterate = true,
+        "--archive-order" => options.archive_order = true,
+        "--sort-by-tags" => options.sort_by_tags = true,
+        "--include-hidden" => options.include_hidden = true,
+        "--dedupe-files" => options.dedupe_files = true,
+        "--follow-symlinks" => options.follow_symlinks = true,
+        "--extract-audio" => options.extract_audio = true,
+        "--temp-dir" => options.temp_dir = Some(take_value(arg, iter)?),
+        "--output" => options.output = Some(take_value(arg, iter)?),
+        "--overwrite" => options.overwrite = true,
+        "--interleave-sort" => options.interleave_sort = true,
+        "--title-include-dirs" => options.title_include_dirs = true,
+        "--no-chapters-file" => options.no_chapters_file = true,
+        "--config" => options.config = Some(take_value(arg, iter)?),
+        "--metadata-command" => options.metadata_command = Some(take_value(arg, iter)?),
+        "--metadata-file" | "--from-ffmetadata" | "--ffmetadata" => options.metadata_file = Some(take_value(arg, iter)?),
+        "--max-chapters" => options.max_chapters = Some(parse_chapter_count(&take_value(arg, iter)?)?),
+        "--keep-tiny-chapters" => options.keep_tiny_chapters = true,
+        "--lead-in" => options.lead_in_ms = Some(parse_lead_in(&take_value(arg, iter)?)?),
+        "--chapter-minimum-gap" => options.chapter_minimum_gap = Some(parse_minimum_gap(&take_value(arg, iter)?)?),
+        "--equal-chapters" | "--fixed-chapter-length" if options.time_split.is_some() => {
+            return Err("--equal-chapters and --fixed-chapter-length can only be given once, and not together".to_string());
+        }
+        "--equal-chapters" => options.time_split = Some(TimeSplit::Count(parse_chapter_count(&take_value(arg, iter)?)?)),
+        "--fixed-chapter-length" => options.time_split = Some(parse_chapter_length(&take_value(arg, iter)?)?),
+        "--coalesce-chapters" => options.coalesce_chapters = Some(parse_coalesce_titles(&take_value(arg, iter)?)?),
+        "--brand" => options.brand = Some(parse_brand(&take_value(arg, iter)?)?),
+        "--cover-layout" => options.cover_layout = parse_cover_layout(&take_value(arg, iter)?)?,
+        "--no-cover" => options.no_cover = true,
+        "--tag-encoding" => options.tag_encoding = Some(parse_tag_encoding(&take_value(arg, iter)?)?),
+        "--max-cover-bytes" => options.max_cover_bytes = Some(parse_max_cover_bytes(&take_value(arg, iter)?)?),
+        "--auto-orient-cover" => options.no_auto_orient_cover = false,
+        "--no-auto-orient-cover" => options.no_auto_orient_cover = true,
+        "--chapter-thumbnails" => options.chapter_thumbnails = true,
+        "--cover-optional" => options.require_cover = false,
+        "--no-cover-optional" => options.require_cover = true,
+        "--strict" => options.strict = true,
+        "--encoder-arg" => options.encode.encoder_args.extend(parse_encoder_arg(&take_value(arg, iter)?)?),
+        "--encoder-after-args" => {
+            let args = parse_extra_args(arg, &take_value(arg, iter)?)?;
+            check_encoder_args(arg, &args)?;
+            options.encode.encoder_args.extend(args);
+        }
+        "--ffmpeg-encode-args" => options.encode.extra_args.extend(parse_extra_args(arg, &take_value(arg, iter)?)?),
+        "--ffmpeg-mux-args" => options.mux_args.extend(parse_extra_args(arg, &take_value(arg, iter)?)?),
+        "--fail-on-warning" => options.fail_on_warning = true,
+        "--continue-on-ffmpeg-warning" => options.fail_on_warning = false,
+        "--ffmpeg-warning-pattern" => options.ffmpeg_warning_patterns.push(take_value(arg, iter)?),
+        "--trim-start" => options.trim_start_ms = parse_seconds(arg, &take_value(arg, iter)?)?,
+        "--trim-end" => options.trim_end_ms = parse_seconds(arg, &take_value(arg, iter)?)?,
+        "--min-file-duration" => options.min_file_duration_ms = Some(parse_seconds(arg, &take_value(arg, iter)?)?),
+        "--table-format" => options.table_format = parse_table_format(&take_value(arg, iter)?)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Handles the title-cleaning flags shared by the build and the `clean-titles` subcommand.
+///
+/// # Returns
+///
+/// `Ok(true)` if `arg` was a cleaning flag and has been consumed together with its value.
+fn parse_clean_flag<'a>(
+    arg: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+    clean: &mut CleanOptions,
+) -> Result<bool, String> {
+    match arg {
+        "--threshold" => {
+            let value = take_value(arg, iter)?;
+            clean.threshold = value.parse().ok().filter(|t| (0.0..=1.0).contains(t))
+                .ok_or_else(|| format!("Invalid threshold '{}': expected a number between 0 and 1", value))?;
+        }
+        "--keep" => clean.keep.extend(split_list(&take_value(arg, iter)?)),
+        "--strip" => clean.strip.extend(split_list(&take_value(arg, iter)?)),
+        "--stopwords" => clean.stopwords.extend(parse_stopwords(&take_value(arg, iter)?)?),
+        "--keep-leading-number" => clean.numbering = Numbering::KeepLeading,
+        "--start-chapter-number" => clean.first_chapter_number = parse_start_chapter_number(&take_value(arg, iter)?)?,
+        "--normalize-filenames-first" => clean.normalize_separators = true,
+        "--case-sensitive-tokens" => clean.fold_tokens = false,
+        "--title-strip-extension-artifacts" => clean.strip_extension_artifacts = true,
+        "--chapter-template" => clean.chapter_template = Some(take_value(arg, iter)?),
+        "--unnumbered-titles" => clean.unnumbered_titles = split_list(&take_value(arg, iter)?),
+        "--clean-strategy" => clean.strategy = parse_clean_strategy(&take_value(arg, iter)?)?,
+        "--title-case" => clean.title_case = parse_title_case(&take_value(arg, iter)?)?,
+        "--protect-brackets" => clean.protected_brackets = parse_protected_brackets(&take_value(arg, iter)?)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Parses a `--start-chapter-number` value, a positive whole number.
+fn parse_start_chapter_number(value: &str) -> Result<usize, String> {
+    value.parse().ok().filter(|&number| number >= 1)
+        .ok_or_else(|| format!("Invalid chapter number '{}': expected a whole number of at least 1", value))
+}
+
+/// Parses a `--jobs` value, a positive whole number.
+fn parse_jobs(value: &str) -> Result<usize, String> {
+    value.parse().ok().filter(|&jobs| jobs >= 1)
+        .ok_or_else(|| format!("Invalid job count '{}': expected a whole number of at least 1", value))
+}
+
+/// Parses a `--title-case` value.
+fn parse_title_case(value: &str) -> Result<TitleCase, String> {
+    match value {
+        "keep" => Ok(TitleCase::Keep),
+        "title" => Ok(TitleCase::Title),
+        "sentence" => Ok(TitleCase::Sentence),
+        "lower" => Ok(TitleCase::Lower),
+        "upper" => Ok(TitleCase::Upper),
+        _ => Err(format!("Invalid title case '{}': expected keep, title, sentence, lower, or upper", value)),
+    }
+}
+
+/// Parses a `--clean-strategy` value.
+fn parse_clean_strategy(value: &str) -> Result<CleanStrategy, String> {
+    match value {
+        "frequency" => Ok(CleanStrategy::Frequency),
+        "common-prefix" => Ok(CleanStrategy::CommonPrefix),
+        "auto" => Ok(CleanStrategy::Auto),
+        _ => Err(format!("Invalid clean strategy '{}': expected frequency, common-prefix, or auto", value)),
+    }
+}
+
+/// Parses a `--protect-brackets` value: a comma-separated list of `square` and `round`, or `all` or `none`.
+fn parse_protected_brackets(value: &str) -> Result<Vec<BracketKind>, String> {
+    let mut kinds = Vec::new();
+    for item in split_list(value) {
+        match item.as_str() {
+            "square" => kinds.push(BracketKind::Square),
+            "round" => kinds.push(BracketKind::Round),
+            "all" => kinds.extend([BracketKind::Square, BracketKind::Round]),
+            "none" => {}
+            _ => return Err(format!("Invalid bracket type '{}': expected square, round, all, or none", item)),
+        }
+    }
+    Ok(kinds)
+}
+
+/// Splits a comma-separated list, dropping empty entries.
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+/// Parses a `--bitrate-ladder` value, a comma-separated list of bitrates such as "64k,128k".
+fn parse_bitrate_ladder(value: &str) -> Result<Vec<u64>, String> {
+    let mut ladder: Vec<u64> = Vec::new();
+    for rate in split_list(value) {
+        let bits_per_second = parse_bitrate(&rate).map_err(|err| format!("Invalid --bitrate-ladder: {}", err))?;
+        if ladder.contains(&bits_per_second) {
+            return Err(format!("Invalid --bitrate-ladder: '{}' is listed twice", rate));
+        }
+        ladder.push(bits_per_second);
+    }
+    if ladder.is_empty() {
+        return Err("Invalid --bitrate-ladder: expected bitrates such as 64k,128k".to_string());
+    }
+    Ok(ladder)
+}
+
+/// Parses a `--stopwords` value: a file with words separated by lines or commas, or else a
+/// comma-separated list. Lines starting with `#` are comments.
+fn parse_stopwords(value: &str) -> Result<Vec<String>, String> {
+    if !Path::new(value).is_file() {
+        return Ok(split_list(value));
+    }
+    let text = fs::read_to_string(value).map_err(|err| format!("Could not read the stopwords file '{}': {}", value, err))?;
+    Ok(text.trim_start_matches('\u{feff}').lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(split_list)
+        .collect())
+}
+
+/// Handles the tag flags shared by the build and retag invocations.
+///
+/// # Returns
+///
+/// `Ok(true)` if `arg` was a tag flag and has been consumed together with its value.
+fn parse_tag_flag<'a>(
+    arg: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+    tags: &mut BookTags,
+    covers: &mut Vec<String>,
+) -> Result<bool, String> {
+    match arg {
+        "--title" => tags.title = Some(take_value(arg, iter)?),
+        "--author" => tags.author = Some(take_value(arg, iter)?),
+        "--year" => tags.year = Some(parse_year(&take_value(arg, iter)?)?),
+        "--date" => tags.date = Some(parse_date(&take_value(arg, iter)?)?),
+        "--language" => tags.language = Some(parse_language(&take_value(arg, iter)?)?),
+        "--cover" => covers.push(take_value(arg, iter)?),
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Parses an `--aac-profile` value.
+fn parse_aac_profile(value: &str) -> Result<AacProfile, String> {
+    match value {
+        "lc" => Ok(AacProfile::Lc),
+        "he" => Ok(AacProfile::He),
+        _ => Err(format!("Invalid AAC profile '{}': expected lc or he", value)),
+    }
+}
+
+/// Parses a `--sample-rate` value in Hz.
+fn parse_sample_rate(value: &str) -> Result<u32, String> {
+    match value.trim().parse::<u32>() {
+        Ok(rate) if (8000..=192000).contains(&rate) => Ok(rate),
+        _ => Err(format!("Invalid sample rate '{}': expected a value in Hz between 8000 and 192000", value)),
+    }
+}
+
+/// Parses a `--max-cover-bytes` value. Anything below 1000 bytes cannot hold a useful image.
+fn parse_max_cover_bytes(value: &str) -> Result<u64, String> {
+    match value.trim().parse::<u64>() {
+        Ok(bytes) if bytes >= 1000 => Ok(bytes),
+        _ => Err(format!("Invalid cover size '{}': expected a number of bytes of at least 1000", value)),
+    }
+}
+
+/// Parses a `--channels` value.
+fn parse_channels(value: &str) -> Result<u32, String> {
+    match value.trim().parse::<u32>() {
+        Ok(channels) if (1..=8).contains(&channels) => Ok(channels),
+        _ => Err(format!("Invalid channel count '{}': expected a number from 1 to 8", value)),
+    }
+}
+
+/// Validates a `--max-chapters` or `--equal-chapters` value, which must be a positive whole number.
+fn parse_chapter_count(value: &str) -> Result<usize, String> {
+    match value.trim().parse::<usize>() {
+        Ok(max) if max > 0 => Ok(max),
+        _ => Err(format!("Invalid chapter count '{}': expected a positive whole number", value)),
+    }
+}
+
+/// Validates a `--chapter-minimum-gap` value, a positive whole number of milliseconds.
+fn parse_minimum_gap(value: &str) -> Result<u64, String> {
+    match value.trim().parse::<u64>() {
+        Ok(gap_ms) if gap_ms > 0 => Ok(gap_ms),
+        _ => Err(format!("Invalid chapter gap '{}': expected a positive whole number of milliseconds", value)),
+    }
+}
+
+/// Parses a `--lead-in` value, a positive whole number of milliseconds.
+fn parse_lead_in(value: &str) -> Result<u64, String> {
+    match value.trim().parse::<u64>() {
+        Ok(lead_in_ms) if lead_in_ms > 0 => Ok(lead_in_ms),
+        _ => Err(format!("Invalid lead-in '{}': expected a positive whole number of milliseconds", value)),
+    }
+}
+
+/// Parses a `--fixed-chapter-length` value in minutes.
+fn parse_chapter_length(value: &str) -> Result<TimeSplit, String> {
+    match value.trim().parse::<f64>() {
+        Ok(minutes) if minutes.is_finite() && minutes * 60_000.0 >= 1.0 => Ok(TimeSplit::Length((minutes * 60_000.0).round() as u64)),
+        _ => Err(format!("Invalid chapter length '{}': expected a positive number of minutes", value)),
+    }
+}
+
+/// Parses a `--coalesce-chapters` value.
+fn parse_coalesce_titles(value: &str) -> Result<CoalesceTitles, String> {
+    match value {
+        "first" => Ok(CoalesceTitles::First),
+        "range" => Ok(CoalesceTitles::Range),
+        _ => Err(format!("Invalid chapter titles '{}': expected first or range", value)),
+    }
+}
+
+/// Parses a `--brand` value, ignoring case.
+fn parse_brand(value: &str) -> Result<Brand, String> {
+    match value.to_lowercase().as_str() {
+        "m4b" => Ok(Brand::M4b),
+        "m4a" => Ok(Brand::M4a),
+        "mp42" => Ok(Brand::Mp42),
+        _ => Err(format!("Invalid brand '{}': expected M4B, M4A, or mp42", value)),
+    }
+}
+
+/// Parses a `--cover-layout` value.
+fn parse_cover_layout(value: &str) -> Result<CoverLayout, String> {
+    match value {
+        "h" => Ok(CoverLayout::Horizontal),
+        "v" => Ok(CoverLayout::Vertical),
+        "grid" => Ok(CoverLayout::Grid),
+        _ => Err(format!("Invalid cover layout '{}': expected h, v, or grid", value)),
+    }
+}
+
+/// Parses a `--codec` value.
+fn parse_encoder(value: &str) -> Result<AacEncoder, String> {
+    match value {
+        "libfdk_aac" => Ok(AacEncoder::Fdk),
+        "aac" => Ok(AacEncoder::Native),
+        _ => Err(format!("Invalid codec '{}': expected libfdk_aac or aac", value)),
+    }
+}
+
+/// Validates an `--aac-vbr` quality, which the native encoder accepts from 0.1 to 2.0.
+fn parse_aac_vbr(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(quality) if (0.1..=2.0).contains(&quality) => Ok(quality),
+        _ => Err(format!("Invalid AAC VBR quality '{}': expected a number between 0.1 and 2.0", value)),
+    }
+}
+
+/// ffmpeg options that the tool manages itself and that extra arguments must not override.
+const MANAGED_FFMPEG_OPTIONS: [&str; 3] = ["-i", "-y", "-n"];
+
+/// Shell-splits an `--ffmpeg-encode-args` or `--ffmpeg-mux-args` value.
+///
+/// Inputs and the output path belong to the tool, so `-i`, `-y`, `-n`, and any bare argument
+/// that does not follow an option (which ffmpeg would take as another output file) are rejected.
+fn parse_extra_args(flag: &str, value: &str) -> Result<Vec<String>, String> {
+    let args = shell::split(value).map_err(|err| format!("Invalid {}: {}", flag, err))?;
+    let mut follows_option = false;
+    for arg in &args {
+        if MANAGED_FFMPEG_OPTIONS.contains(&arg.as_str()) {
+            return Err(format!("Invalid {}: '{}' is managed by m4btool", flag, arg));
+        }
+        let is_option = arg.len() > 1 && arg.starts_with('-');
+        if !is_option && !follows_option {
+            return Err(format!("Invalid {}: '{}' is not an option value and would add an output file", flag, arg));
+        }
+        follows_option = is_option;
+    }
+    Ok(args)
+}
+
+/// The encode options m4btool sets itself from the codec, bitrate, and VBR options, which
+/// encoder options must not set again, also with a stream specifier such as `-b:a:0`.
+const MANAGED_ENCODER_OPTIONS: [&str; 12] = ["-c", "-c:a", "-codec", "-codec:a", "-acodec", "-b", "-b:a", "-ab", "-q", "-q:a", "-aq", "-qscale:a"];
+
+/// Rejects encoder options that would override the codec or bitrate m4btool chose.
+fn check_encoder_args(flag: &str, args: &[String]) -> Result<(), String> {
+    let managed = |arg: &str| MANAGED_ENCODER_OPTIONS.iter().any(|option| arg == *option || arg.starts_with(&format!("{}:", option)));
+    match args.iter().find(|arg| managed(arg)) {
+        Some(arg) => Err(format!(
+            "Invalid {}: '{}' is managed by m4btool; use --codec, --bitrate, or --aac-vbr instead",
+            flag, arg
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Parses an `--encoder-arg` such as `afterburner=1` into the ffmpeg option `-afterburner 1`.
+fn parse_encoder_arg(value: &str) -> Result<Vec<String>, String> {
+    let Some((key, option_value)) = value.split_once('=') else {
+        return Err(format!("Invalid --encoder-arg '{}': expected <option>=<value>, e.g. afterburner=1", value));
+    };
+    let key = key.trim().trim_start_matches('-');
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(format!("Invalid --encoder-arg '{}': expected <option>=<value>, e.g. afterburner=1", value));
+    }
+    let args = vec![format!("-{}", key), option_value.to_string()];
+    check_encoder_args("--encoder-arg", &args)?;
+    Ok(args)
+}
+
+/// Parses a non-negative number of seconds into milliseconds.
+fn parse_seconds(flag: &str, value: &str) -> Result<u64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Ok((seconds * 1000.0).round() as u64),
+        _ => Err(format!("Invalid value '{}' for {}: expected a number of seconds", value, flag)),
+    }
+}
+
+/// Takes the value following `flag`, failing if the arguments ran out.
+fn take_value<'a>(flag: &str, iter: &mut impl Iterator<Item = &'a String>) -> Result<String, String> {
+    iter.next().cloned().ok_or_else(|| format!("Missing value for {}", flag))
+}
+
+/// Expands `--flag=value` arguments into separate `--flag` and `value` arguments.
+fn split_inline_values(args: &[String]) -> Vec<String> {
+    let mut expanded = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") && flag != "--progress-json" => {
+                expanded.push(flag.to_string());
+                expanded.push(value.to_string());
+            }
+            _ => expanded.push(arg.clone()),
+        }
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn parse_args(args: &[String]) -> Result<Invocation, String> {
+        super::parse_args(args, &mut GlobalOptions::default())
+    }
+
+    fn parse_global(args: &[&str]) -> (GlobalOptions, Result<Invocation, String>) {
+        let mut global = GlobalOptions::default();
+        let invocation = super::parse_args(&to_args(args), &mut global);
+        (global, invocation)
+    }
+
+    /// Tests parsing of the retag subcommand with inline and separate flag values, and of its
+    /// `--rewrite-existing-metadata-only` spelling.
+    #[test]
+    fn test_parse_retag() {
+        let parsed = parse_args(&to_args(&["retag", "book.m4b", "--author=Jane Doe", "--year", "2001"])).unwrap();
+        let Invocation::Retag(options) = parsed else { panic!("expected retag") };
+        assert_eq!(options.input_file, "book.m4b");
+        assert_eq!(options.tags.author.as_deref(), Some("Jane Doe"));
+        assert_eq!(options.tags.year.as_deref(), Some("2001"));
+        assert!(parse_args(&to_args(&["retag", "book.m4b", "--year", "01"])).is_err());
+        let parsed = parse_args(&to_args(&["book.m4b", "--title=Dune", "--rewrite-existing-metadata-only"])).unwrap();
+        let Invocation::Retag(options) = parsed else { panic!("expected retag") };
+        assert_eq!((options.input_file.as_str(), options.tags.title.as_deref()), ("book.m4b", Some("Dune")));
+        assert!(parse_args(&to_args(&["books/dune", "--rewrite-existing-metadata-only", "--stats"])).is_err());
+    }
+
+    /// Tests parsing of the diff subcommand, with build options for a directory side.
+    #[test]
+    fn test_parse_diff() {
+        let parsed = parse_args(&to_args(&["diff", "output.m4b", "books/dune", "--unified", "--threshold", "0.6"])).unwrap();
+        let Invocation::Diff(options) = parsed else { panic!("expected diff") };
+        assert_eq!((options.left.as_str(), options.right.as_str(), options.unified), ("output.m4b", "books/dune", true));
+        assert_eq!(options.build.clean.threshold, 0.6);
+        assert!(parse_args(&to_args(&["diff", "output.m4b"])).is_err());
+        assert!(parse_args(&to_args(&["diff", "a.json", "b.json", "c.json"])).is_err());
+        assert!(parse_args(&to_args(&["diff", "a.json", "b.json", "--json"])).is_err());
+    }
+
+    /// Tests that `--version` runs the doctor report and only `doctor` takes `--json`.
+    #[test]
+    fn test_parse_doctor() {
+        let Ok(Invocation::Doctor(options)) = parse_args(&to_args(&["doctor", "--json"])) else { panic!("expected doctor") };
+        assert!(options.json);
+        let Ok(Invocation::Doctor(options)) = parse_args(&to_args(&["--version"])) else { panic!("expected doctor") };
+        assert!(!options.json);
+        assert!(parse_args(&to_args(&["--version", "--json"])).is_err());
+    }
+
+    /// Tests that a preset fills in the encode settings and that the flags given with it win,
+    /// before or after it.
+    #[test]
+    fn test_parse_preset() {
+        let build = |args: &[&str]| match parse_args(&to_args(args)) {
+            Ok(Invocation::Build(options)) => Ok(*options),
+            Ok(_) => panic!("expected build"),
+            Err(err) => Err(err),
+        };
+        let voice = build(&["books/dune", "--preset", "voice"]).unwrap();
+        assert_eq!((&voice.encode, voice.faststart), (&crate::preset::VOICE.settings, true));
+        assert_eq!(voice.preset.map(|preset| preset.name), Some("voice"));
+
+        let overridden = build(&["books/dune", "--channels", "2", "--preset=voice", "--bitrate", "48k", "--no-normalize", "--no-faststart"]).unwrap();
+        assert_eq!(
+            (overridden.encode.channels, overridden.encode.bitrate, overridden.encode.normalize, overridden.faststart),
+            (Some(2), Some(48_000), false, false)
+        );
+        assert_eq!((overridden.encode.aac_profile, overridden.encode.sample_rate), (AacProfile::He, Some(44_100)));
+        let lc = build(&["books/dune", "--preset", "tiny", "--aac-profile", "lc"]).unwrap();
+        assert_eq!((lc.encode.aac_profile, lc.encode.bitrate), (AacProfile::Lc, Some(32_000)));
+        let archive = build(&["books/dune", "--preset", "archive", "--faststart", "--normalize"]).unwrap();
+        assert!(archive.faststart && archive.encode.normalize && archive.encode.bitrate.is_none());
+
+        assert_eq!(build(&["books/dune", "--preset", "voice", "--codec", "aac"]).unwrap_err(), "--preset voice needs --codec libfdk_aac; ffmpeg's built-in aac encoder only writes LC");
+        assert!(build(&["books/dune", "--preset", "music", "--codec", "aac"]).is_ok());
+        assert!(build(&["books/dune", "--aac-profile", "he", "--codec", "aac"]).unwrap_err().starts_with("--aac-profile he needs"));
+        assert!(build(&["books/dune", "--preset", "podcast"]).unwrap_err().starts_with("Invalid preset 'podcast'"));
+        assert_eq!(build(&["books/dune", "--preset"]).unwrap_err(), "Missing value for --preset");
+        let titled = build(&["books/dune", "--title", "--preset"]).unwrap();
+        assert_eq!((titled.preset, titled.encode), (None, EncodeSettings::default()));
+        assert!(build(&["books/dune", "--bitrate", "fast"]).unwrap_err().starts_with("Invalid --bitrate: "));
+    }
+
+    /// Tests that a plain directory argument selects the build path.
+    #[test]
+    fn test_parse_build() {
+        let parsed = parse_args(&to_args(&["books/dune", "--title", "Dune"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.input_directories, vec!["books/dune"]);
+        assert_eq!(options.tags.title.as_deref(), Some("Dune"));
+        assert!(parse_args(&[]).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--no-metadata", "--title", "Dune"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune.zip", "--archive-order", "--sort-by-tags"])).is_err());
+        let parsed = parse_args(&to_args(&["disk/Disc 1", "downloads/Disc 2", "--output", "dune.m4b"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.input_directories, vec!["disk/Disc 1", "downloads/Disc 2"]);
+        assert!(parse_args(&to_args(&["disk/Disc 1", "downloads/Disc 2"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--overwrite"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert!(options.overwrite && options.output.is_none());
+        assert!(parse_args(&to_args(&["disk/Disc 1", "disc2.zip", "--output", "dune.m4b"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--no-metadata", "--write-vtt"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--no-metadata", "--write-opf"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--no-cover", "--cover", "cover.jpg"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--two-pass", "--normalize"])).unwrap_err().starts_with("--two-pass is not supported"));
+        let parsed = parse_args(&to_args(&["books/dune", "--normalize-two-pass", "--preset", "music"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert!(options.normalize_two_pass && options.encode.normalize);
+        assert!(parse_args(&to_args(&["books/dune", "--normalize-two-pass", "--no-normalize"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--no-metadata", "--transliterate"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--no-metadata", "--preserve-chapters"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--codec", "aac", "--aac-vbr=1.2"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!((options.encode.encoder, options.encode.aac_vbr), (AacEncoder::Native, Some(1.2)));
+        assert!(parse_args(&to_args(&["books/dune", "--aac-vbr", "3"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--codec", "opus"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--max-chapters", "99", "--coalesce-chapters=range"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!((options.max_chapters, options.coalesce_chapters), (Some(99), Some(CoalesceTitles::Range)));
+        assert!(parse_args(&to_args(&["books/dune", "--max-chapters", "0"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--chapter-minimum-gap", "250"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.chapter_minimum_gap, Some(250));
+        assert!(parse_args(&to_args(&["books/dune", "--chapter-minimum-gap", "0"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--keep-tiny-chapters"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert!(options.keep_tiny_chapters);
+        let parsed = parse_args(&to_args(&["books/dune", "--lead-in", "1500"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.lead_in_ms, Some(1_500));
+        let parsed = parse_args(&to_args(&["books/dune", "--dedupe-files"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert!(options.dedupe_files);
+        let parsed = parse_args(&to_args(&["books/dune", "--compare", "--threshold", "0.6"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert!(options.compare && !options.dry_run);
+        assert!(parse_args(&to_args(&["books/dune", "--compare", "--no-metadata"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--config", "-", "--author", "Frank Herbert"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!((options.config.as_deref(), options.loaded_config), (Some("-"), None));
+        assert!(parse_args(&to_args(&["books/dune", "--lead-in", "0"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--lead-in", "1.5s"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--lead-in", "1500", "--metadata-file", "dune.txt"])).is_err());
+        let parsed = parse_args(&to_args(&["books/box", "--cover", "one.jpg", "--cover", "two.jpg", "--cover-layout", "grid"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!((options.covers, options.cover_layout), (to_args(&["one.jpg", "two.jpg"]), CoverLayout::Grid));
+        assert!(!options.no_auto_orient_cover);
+        let parsed = parse_args(&to_args(&["books/dune", "--no-auto-orient-cover"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert!(options.no_auto_orient_cover);
+        let parsed = parse_args(&to_args(&["books/dune", "--chapter-thumbnails"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert!(options.chapter_thumbnails);
+        assert!(parse_args(&to_args(&["books/dune", "--chapter-thumbnails", "--no-cover"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--also-export-tracks", "books/dune/tracks"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.export_tracks.as_deref(), Some("books/dune/tracks"));
+        assert!(parse_args(&to_args(&["books/dune", "--also-export-tracks", "tracks", "--bitrate-ladder", "64k,128k"])).is_err());
+        assert!(parse_args(&to_args(&["retag", "book.m4b", "--cover", "one.jpg", "--cover", "two.jpg"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--temp-dir=books/dune/.work"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.temp_dir.as_deref(), Some("books/dune/.work"));
+        let parsed = parse_args(&to_args(&["books/dune", "--brand", "mp42"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.brand, Some(Brand::Mp42));
+        assert!(parse_args(&to_args(&["books/dune", "--brand", "isom"])).is_err());
+        let parsed = parse_args(&to_args(&["books/lecture", "--fixed-chapter-length", "7.5"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.time_split, Some(TimeSplit::Length(450_000)));
+        assert!(parse_args(&to_args(&["books/lecture", "--equal-chapters", "10", "--fixed-chapter-length", "5"])).is_err());
+        assert!(parse_args(&to_args(&["books/lecture", "--equal-chapters", "0"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--from-ffmetadata", "dune.ffmetadata.txt"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.metadata_file.as_deref(), Some("dune.ffmetadata.txt"));
+        let parsed = parse_args(&to_args(&["books/dune", "--ffmetadata=dune.ffmetadata"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.metadata_file.as_deref(), Some("dune.ffmetadata"));
+        assert!(parse_args(&to_args(&["books/dune", "--metadata-file", "dune.txt", "--preserve-chapters"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--metadata-file", "dune.txt", "--no-metadata"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--bitrate-ladder", "64k, 128k,96000"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.bitrate_ladder, vec![64_000, 128_000, 96_000]);
+        assert!(parse_args(&to_args(&["books/dune", "--bitrate-ladder", "64k,64000"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--bitrate-ladder", ","])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--bitrate-ladder", "64k,fast"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--bitrate-ladder", "64k", "--incremental"])).is_err());
+    }
+
+    /// Tests that the global console flags are read anywhere in the command line, but never
+    /// out of another flag's value.
+    #[test]
+    fn test_parse_global_flags() {
+        let (global, invocation) = parse_global(&["retag", "--no-color", "book.m4b", "--verbose"]);
+        assert_eq!(global, GlobalOptions { no_color: true, verbose: true, ..Default::default() });
+        let Ok(Invocation::Retag(options)) = invocation else { panic!("expected retag") };
+        assert_eq!(options.input_file, "book.m4b");
+
+        let (global, invocation) = parse_global(&["--log-file", "/var/log/m4b.log", "books/dune", "--log-append", "--title=A=B"]);
+        assert_eq!(global.log_file.as_deref(), Some("/var/log/m4b.log"));
+        assert!(global.log_append);
+        let Ok(Invocation::Build(options)) = invocation else { panic!("expected build") };
+        assert_eq!((options.input_directories, options.tags.title), (vec!["books/dune".to_string()], Some("A=B".to_string())));
+
+        let (global, invocation) = parse_global(&["--verbose", "-y", "doctor", "--json"]);
+        assert!(global.verbose && global.yes);
+        assert!(matches!(invocation, Ok(Invocation::Doctor(DoctorOptions { json: true }))));
+        assert_eq!(parse_global(&["books/dune", "--log-file=m4b.log"]).0.log_file.as_deref(), Some("m4b.log"));
+        assert!(parse_global(&["books/dune", "--log-file"]).1.is_err());
+        assert_eq!(parse_global(&["books/dune", "--progress-json"]).0.progress_json, Some(None));
+        let (global, invocation) = parse_global(&["--progress-json=/tmp/progress.fifo", "books/dune"]);
+        assert_eq!(global.progress_json, Some(Some("/tmp/progress.fifo".to_string())));
+        assert!(matches!(invocation, Ok(Invocation::Build(_))));
+
+        // A global flag given as a value belongs to the flag before it.
+        let (global, invocation) = parse_global(&["books/dune", "--title", "--verbose", "--author", "--no-color"]);
+        assert_eq!(global, GlobalOptions::default());
+        let Ok(Invocation::Build(options)) = invocation else { panic!("expected build") };
+        assert_eq!((options.tags.title.as_deref(), options.tags.author.as_deref()), (Some("--verbose"), Some("--no-color")));
+        let (global, invocation) = parse_global(&["books/dune", "--log-file", "--yes"]);
+        assert_eq!((global.log_file.as_deref(), global.yes), (Some("--yes"), false));
+        assert!(invocation.is_ok());
+    }
+
+    /// Tests that extra ffmpeg arguments are shell-split and may not touch inputs or outputs.
+    #[test]
+    fn test_parse_extra_args() {
+        let parsed = parse_args(&to_args(&["books/dune", "--ffmpeg-mux-args", "-metadata 'comment=Read by Jane Doe'"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.mux_args, vec!["-metadata", "comment=Read by Jane Doe"]);
+        assert_eq!(parse_extra_args("--ffmpeg-encode-args", "-vn -af \"volume=2, lowpass=f=8000\"").unwrap(), vec!["-vn", "-af", "volume=2, lowpass=f=8000"]);
+        assert!(parse_extra_args("--ffmpeg-mux-args", "-i other.mp3").is_err());
+        assert!(parse_extra_args("--ffmpeg-mux-args", "-y").is_err());
+        assert!(parse_extra_args("--ffmpeg-mux-args", "-af volume=2 extra.m4b").is_err());
+        assert!(parse_extra_args("--ffmpeg-mux-args", "'unterminated").is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--fail-on-warning", "--ffmpeg-warning-pattern", "Header missing"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert!(options.fail_on_warning);
+        assert_eq!(options.ffmpeg_warning_patterns, vec!["Header missing"]);
+        let parsed = parse_args(&to_args(&["books/dune", "--fail-on-warning", "--continue-on-ffmpeg-warning"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert!(!options.fail_on_warning);
+        assert!(parse_args(&to_args(&["books/dune", "--ffmpeg-warning-pattern", "Header missing"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--fail-on-warning", "--ffmpeg-warning-pattern", "(unclosed"])).is_err());
+    }
+
+    /// Tests that encoder options keep their order across both flags and may not set the codec or
+    /// bitrate.
+    #[test]
+    fn test_encoder_args() {
+        let parsed = parse_args(&to_args(&[
+            "books/dune", "--encoder-arg", "afterburner=1", "--encoder-after-args", "-cutoff 18000", "--encoder-arg=-signaling=implicit",
+        ])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.encode.encoder_args, vec!["-afterburner", "1", "-cutoff", "18000", "-signaling", "implicit"]);
+        assert!(parse_args(&to_args(&["books/dune", "--encoder-arg", "afterburner"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--encoder-arg", "=1"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--encoder-arg", "b:a=96k"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--encoder-after-args", "-afterburner 1 -c:a aac"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--encoder-after-args", "-b:a:0 96k"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--encoder-after-args", "-i other.mp3"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--encoder-after-args", "-cutoff 18000 extra.m4a"])).is_err());
+    }
+
+    /// Tests the cleaning flags of the hidden clean-titles subcommand and the build.
+    #[test]
+    fn test_parse_clean_titles() {
+        let parsed = parse_args(&to_args(&["clean-titles", "--threshold", "0.5", "--keep", "Part, Book"])).unwrap();
+        let Invocation::CleanTitles(options) = parsed else { panic!("expected clean-titles") };
+        assert_eq!(options.clean.threshold, 0.5);
+        assert_eq!(options.clean.keep, vec!["Part", "Book"]);
+        let stopwords = tempfile::NamedTempFile::new().unwrap();
+        fs::write(stopwords.path(), "# Narration notes\nAudiobook\nUnabridged, Dramatized\n").unwrap();
+        let path = stopwords.path().to_string_lossy().to_string();
+        let parsed = parse_args(&to_args(&["clean-titles", "--stopwords", &path, "--stopwords", "Retail,MP3"])).unwrap();
+        let Invocation::CleanTitles(options) = parsed else { panic!("expected clean-titles") };
+        assert_eq!(options.clean.stopwords, vec!["Audiobook", "Unabridged", "Dramatized", "Retail", "MP3"]);
+        assert!(!options.clean.normalize_separators);
+        let parsed = parse_args(&to_args(&["clean-titles", "--normalize-filenames-first"])).unwrap();
+        let Invocation::CleanTitles(options) = parsed else { panic!("expected clean-titles") };
+        assert!(options.clean.normalize_separators);
+        assert!(options.clean.fold_tokens);
+        let parsed = parse_args(&to_args(&["clean-titles", "--case-sensitive-tokens"])).unwrap();
+        let Invocation::CleanTitles(options) = parsed else { panic!("expected clean-titles") };
+        assert!(!options.clean.fold_tokens);
+        assert!(parse_args(&to_args(&["clean-titles", "--threshold", "2"])).is_err());
+
+        let parsed = parse_args(&to_args(&["books/dune", "--protect-brackets", "square"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.clean.protected_brackets, vec![BracketKind::Square]);
+        let parsed = parse_args(&to_args(&["books/dune", "--clean-strategy=common-prefix"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.clean.strategy, CleanStrategy::CommonPrefix);
+        let parsed = parse_args(&to_args(&["clean-titles", "--title-case", "sentence"])).unwrap();
+        let Invocation::CleanTitles(options) = parsed else { panic!("expected clean-titles") };
+        assert_eq!(options.clean.title_case, TitleCase::Sentence);
+        assert!(parse_args(&to_args(&["clean-titles", "--title-case", "camel"])).is_err());
+        assert!(parse_args(&to_args(&["books/dune", "--protect-brackets", "curly"])).is_err());
+        let parsed = parse_args(&to_args(&["books/dune", "--start-chapter-number", "15"])).unwrap();
+        let Invocation::Build(options) = parsed else { panic!("expected build") };
+        assert_eq!(options.clean.first_chapter_number, 15);
+        assert!(parse_args(&to_args(&["books/dune", "--start-chapter-number", "0"])).is_err());
+    }
+}
@@ -0,0 +1,773 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tempfile::{Builder, TempPath};
+
+use crate::console;
+use crate::inspect::BookInfo;
+use crate::runner::CommandRunner;
+use crate::shell::os_args;
+
+/// The edge length in pixels each image is scaled to before the images are combined.
+const TILE_SIZE: u32 = 600;
+
+/// The longest side in pixels of the cover thumbnail shown per chapter with `--chapter-thumbnails`.
+pub const CHAPTER_THUMBNAIL_SIZE: u32 = 320;
+
+/// Covers smaller than this on either side look blurry in players and get a warning.
+pub const MIN_COVER_EDGE: u32 = 300;
+
+/// The image formats accepted as a cover.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Webp,
+    /// HEIC/HEIF, the default photo format of many phones; converted to JPEG before the mux.
+    Heic,
+    /// AVIF; converted to JPEG before the mux.
+    Avif,
+}
+
+impl ImageFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "JPEG",
+            ImageFormat::Png => "PNG",
+            ImageFormat::Webp => "WebP",
+            ImageFormat::Heic => "HEIC",
+            ImageFormat::Avif => "AVIF",
+        }
+    }
+
+    /// Whether players cannot show the format, so it is converted to JPEG first.
+    pub fn needs_conversion(self) -> bool {
+        matches!(self, ImageFormat::Heic | ImageFormat::Avif)
+    }
+}
+
+/// What the header of a cover image says about it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoverImage {
+    pub format: ImageFormat,
+    /// The width and height in pixels; `None` for HEIC and AVIF, whose dimensions are only known
+    /// once converted.
+    pub size: Option<(u32, u32)>,
+}
+
+/// Identifies a cover image from its contents, whatever its extension, and reads its dimensions.
+///
+/// # Returns
+///
+/// The format and dimensions, or an error message for a file that is not a JPEG, PNG, WebP,
+/// HEIC, or AVIF image, or whose header is cut short.
+pub fn identify_image(bytes: &[u8]) -> Result<CoverImage, String> {
+    let le16 = |at: usize| bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32);
+    let le24 = |at: usize| bytes.get(at..at + 3).map(|b| u32::from_le_bytes([b[0], b[1], b[2], 0]));
+    let be32 = |at: usize| bytes.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let (format, size) = if bytes.starts_with(&[0xFF, 0xD8]) {
+        (ImageFormat::Jpeg, jpeg_size(bytes))
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        (ImageFormat::Png, be32(16).zip(be32(20)).filter(|_| bytes.get(12..16) == Some(b"IHDR")))
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        let size = match bytes.get(12..16) {
+            Some(b"VP8 ") => le16(26).zip(le16(28)).map(|(width, height)| (width & 0x3FFF, height & 0x3FFF)),
+            Some(b"VP8L") => bytes.get(21..25).map(|b| {
+                let bits = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1)
+            }),
+            Some(b"VP8X") => le24(24).zip(le24(27)).map(|(width, height)| (width + 1, height + 1)),
+            _ => None,
+        };
+        (ImageFormat::Webp, size)
+    } else if bytes.get(4..8) == Some(b"ftyp") {
+        let box_end = be32(0).map_or(0, |size| size as usize).min(bytes.len());
+        let brands: Vec<&[u8]> = bytes.get(8..box_end).unwrap_or_default().chunks(4).collect();
+        if brands.iter().any(|brand| matches!(*brand, b"avif" | b"avis")) {
+            return Ok(CoverImage { format: ImageFormat::Avif, size: None });
+        }
+        if brands.iter().any(|brand| matches!(*brand, b"heic" | b"heix" | b"heim" | b"heis" | b"mif1" | b"msf1")) {
+            return Ok(CoverImage { format: ImageFormat::Heic, size: None });
+        }
+        return Err("not a JPEG, PNG, WebP, HEIC, or AVIF image".to_string());
+    } else {
+        return Err("not a JPEG, PNG, WebP, HEIC, or AVIF image".to_string());
+    };
+    match size {
+        Some((width, height)) if width > 0 && height > 0 => Ok(CoverImage { format, size: Some((width, height)) }),
+        Some(_) => Err(format!("the {} image has no pixels", format.name())),
+        None => Err(format!("the {} header is damaged or cut short", format.name())),
+    }
+}
+
+/// Reads the dimensions from a JPEG's start-of-frame segment, walking the segments before it.
+fn jpeg_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut at = 2;
+    loop {
+        if *bytes.get(at)? != 0xFF {
+            return None;
+        }
+        let marker = *bytes.get(at + 1)?;
+        match marker {
+            // Fill bytes before a marker.
+            0xFF => at += 1,
+            // Markers without a length.
+            0x01 | 0xD0..=0xD7 => at += 2,
+            // Start of frame, except DHT (C4), JPG (C8), and DAC (CC).
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let segment = bytes.get(at + 5..at + 9)?;
+                let height = u16::from_be_bytes([segment[0], segment[1]]) as u32;
+                let width = u16::from_be_bytes([segment[2], segment[3]]) as u32;
+                return Some((width, height));
+            }
+            _ => {
+                let length = bytes.get(at + 2..at + 4).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)?;
+                at += 2 + length;
+            }
+        }
+    }
+}
+
+/// Reads the EXIF orientation of a JPEG, the tag phones use to mark a photo taken sideways or
+/// upside down instead of rotating its pixels.
+///
+/// # Returns
+///
+/// The orientation from 1 (upright) to 8, or `None` for an image without the tag.
+pub fn exif_orientation(bytes: &[u8]) -> Option<u8> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut at = 2;
+    loop {
+        if *bytes.get(at)? != 0xFF {
+            return None;
+        }
+        let marker = *bytes.get(at + 1)?;
+        match marker {
+            0xFF => at += 1,
+            0x01 | 0xD0..=0xD7 => at += 2,
+            // The EXIF segment comes before the image data.
+            0xDA | 0xD9 => return None,
+            _ => {
+                let length = bytes.get(at + 2..at + 4).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)?;
+                let segment = bytes.get(at + 4..at + 2 + length).unwrap_or_default();
+                if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+                    return tiff_orientation(&segment[6..]);
+                }
+                at += 2 + length;
+            }
+        }
+    }
+}
+
+/// Finds the orientation tag (0x0112) in the first IFD of EXIF's TIFF structure, in either
+/// byte order.
+fn tiff_orientation(tiff: &[u8]) -> Option<u8> {
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| tiff.get(at..at + 2).map(|b| if big_endian { u16::from_be_bytes([b[0], b[1]]) } else { u16::from_le_bytes([b[0], b[1]]) });
+    let u32_at = |at: usize| tiff.get(at..at + 4).map(|b| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) }
+    });
+    if u16_at(2)? != 42 {
+        return None;
+    }
+    let ifd = u32_at(4)? as usize;
+    (0..u16_at(ifd)? as usize)
+        .map(|entry| ifd + 2 + entry * 12)
+        .find(|&entry| u16_at(entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+        .map(|orientation| orientation as u8)
+}
+
+/// The ffmpeg filter that turns an image with the given EXIF orientation upright, or `None` for
+/// one that already is.
+pub fn orientation_filter(orientation: u8) -> Option<&'static str> {
+    match orientation {
+        2 => Some("hflip"),
+        3 => Some("hflip,vflip"),
+        4 => Some("vflip"),
+        5 => Some("transpose=0"),
+        6 => Some("transpose=1"),
+        7 => Some("transpose=3"),
+        8 => Some("transpose=2"),
+        _ => None,
+    }
+}
+
+/// How several cover images are arranged into one.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CoverLayout {
+    /// Side by side, scaled to the same height.
+    #[default]
+    Horizontal,
+    /// Stacked top to bottom, scaled to the same width.
+    Vertical,
+    /// Rows of square tiles, as many columns as rows or one more.
+    Grid,
+}
+
+/// Builds the `-filter_complex` graph that combines `count` input images into `[cover]`.
+///
+/// Every image is first scaled to a common height (horizontal), width (vertical), or square
+/// tile (grid, padded to keep its aspect ratio), because the stack filters need matching sizes.
+///
+/// # Arguments
+///
+/// * `count` - The number of input images; at least 2.
+/// * `layout` - The arrangement.
+pub fn collage_filter(count: usize, layout: CoverLayout) -> String {
+    let scale = match layout {
+        CoverLayout::Horizontal => format!("scale=-2:{}", TILE_SIZE),
+        CoverLayout::Vertical => format!("scale={}:-2", TILE_SIZE),
+        CoverLayout::Grid => format!(
+            "scale={0}:{0}:force_original_aspect_ratio=decrease,pad={0}:{0}:(ow-iw)/2:(oh-ih)/2",
+            TILE_SIZE
+        ),
+    };
+    let mut filters: Vec<String> = (0..count).map(|i| format!("[{}:v]{},setsar=1[c{}]", i, scale, i)).collect();
+    let tiles: String = (0..count).map(|i| format!("[c{}]", i)).collect();
+    filters.push(match layout {
+        CoverLayout::Horizontal => format!("{}hstack=inputs={}[cover]", tiles, count),
+        CoverLayout::Vertical => format!("{}vstack=inputs={}[cover]", tiles, count),
+        CoverLayout::Grid => {
+            let columns = (1..=count).find(|columns| columns * columns >= count).unwrap_or(count);
+            let positions: Vec<String> = (0..count)
+                .map(|i| format!("{}_{}", i % columns * TILE_SIZE as usize, i / columns * TILE_SIZE as usize))
+                .collect();
+            format!("{}xstack=inputs={}:layout={}:fill=black[cover]", tiles, count, positions.join("|"))
+        }
+    });
+    filters.join(";")
+}
+
+/// Builds the ffmpeg arguments that write the combined cover to `output`, without the program name.
+pub fn collage_args(covers: &[String], layout: CoverLayout, output: &Path) -> Vec<OsString> {
+    let mut args = Vec::new();
+    for cover in covers {
+        args.extend(os_args(&["-i", cover]));
+    }
+    args.extend(os_args(&["-filter_complex", &collage_filter(covers.len(), layout), "-map", "[cover]", "-frames:v", "1", "-y"]));
+    args.push(output.into());
+    args
+}
+
+/// Combines several cover images into one JPEG in the work directory.
+///
+/// # Returns
+///
+/// The path of the combined image, removed when dropped, or an error message.
+pub fn compose_cover(covers: &[String], layout: CoverLayout, work_dir: &Path) -> Result<TempPath, String> {
+    let collage = Builder::new().suffix(".jpg").tempfile_in(work_dir)
+        .map_err(|err| format!("Could not create the combined cover: {}", err))?
+        .into_temp_path();
+    let output = console::run_captured(Command::new("ffmpeg").args(collage_args(covers, layout, &collage)))
+        .map_err(|err| format!("Could not execute ffmpeg: {}", err))?;
+    if !output.status.success() {
+        return Err(console::last_stderr_line(&output));
+    }
+    Ok(collage)
+}
+
+/// The ways to shrink a cover, tried in order until it fits: the longest edge in pixels and the
+/// JPEG quality (`-q:v`, from 2 for the best to 31 for the worst).
+const SHRINK_STEPS: [(u32, u32); 8] = [(3000, 2), (2000, 3), (1600, 4), (1200, 5), (1000, 7), (800, 9), (600, 12), (400, 15)];
+
+/// Builds the ffmpeg arguments that re-encode `cover` as a JPEG no larger than `max_edge` pixels
+/// on either side at the given quality, without the program name.
+pub fn shrink_cover_args(cover: &str, max_edge: u32, quality: u32, output: &Path) -> Vec<OsString> {
+    let scale = format!("scale='min(iw,{0})':'min(ih,{0})':force_original_aspect_ratio=decrease", max_edge);
+    let mut args = os_args(&["-i", cover, "-vf", &scale, "-frames:v", "1", "-c:v", "mjpeg", "-q:v", &quality.to_string(), "-y"]);
+    args.push(output.into());
+    args
+}
+
+/// Makes a cover fit within `max_bytes`, re-encoding it as a JPEG with ever smaller dimensions
+/// and lower quality until it does.
+///
+/// # Returns
+///
+/// `None` when the cover is a JPEG that already fits and can be attached as it is, the path of
+/// the re-encoded JPEG, removed when dropped, or an error message if no step fits.
+pub fn shrink_cover(cover: &str, max_bytes: u64, work_dir: &Path, runner: &dyn CommandRunner) -> Result<Option<TempPath>, String> {
+    let is_jpeg = Path::new(cover).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"));
+    let size = fs::metadata(cover).map_err(|err| format!("Could not read '{}': {}", cover, err))?.len();
+    if is_jpeg && size <= max_bytes {
+        return Ok(None);
+    }
+    let shrunk = Builder::new().suffix(".jpg").tempfile_in(work_dir)
+        .map_err(|err| format!("Could not create the shrunk cover: {}", err))?
+        .into_temp_path();
+    for (max_edge, quality) in SHRINK_STEPS {
+        let output = runner.run(Command::new("ffmpeg").args(shrink_cover_args(cover, max_edge, quality, &shrunk)))
+            .map_err(|err| format!("Could not execute ffmpeg: {}", err))?;
+        if !output.status.success() {
+            return Err(console::last_stderr_line(&output));
+        }
+        if fs::metadata(&shrunk).is_ok_and(|metadata| metadata.len() <= max_bytes) {
+            return Ok(Some(shrunk));
+        }
+    }
+    Err(format!("it is still larger than {} bytes at {} pixels", max_bytes, SHRINK_STEPS[SHRINK_STEPS.len() - 1].0))
+}
+
+/// Builds the ffmpeg arguments that re-encode `cover` as a JPEG with `filter` applied, without
+/// the program name. `-noautorotate` keeps ffmpeg versions that honor EXIF from rotating the
+/// image a second time.
+pub fn orient_cover_args(cover: &str, filter: &str, output: &Path) -> Vec<OsString> {
+    let quality = SHRINK_STEPS[0].1.to_string();
+    let mut args = os_args(&["-noautorotate", "-i", cover, "-vf", filter, "-frames:v", "1", "-c:v", "mjpeg", "-q:v", &quality, "-y"]);
+    args.push(output.into());
+    args
+}
+
+/// Bakes the EXIF orientation of a JPEG cover into its pixels, because players show the
+/// attached picture as stored and a phone photo taken sideways would appear sideways.
+///
+/// # Returns
+///
+/// `None` when the cover is already upright or has no orientation tag, the path of the rotated
+/// JPEG, removed when dropped, or an error message.
+pub fn orient_cover(cover: &str, work_dir: &Path, runner: &dyn CommandRunner) -> Result<Option<TempPath>, String> {
+    let bytes = fs::read(cover).map_err(|err| format!("Could not read '{}': {}", cover, err))?;
+    let Some(filter) = exif_orientation(&bytes).and_then(orientation_filter) else { return Ok(None) };
+    let oriented = Builder::new().suffix(".jpg").tempfile_in(work_dir)
+        .map_err(|err| format!("Could not create the rotated cover: {}", err))?
+        .into_temp_path();
+    let output = runner.run(Command::new("ffmpeg").args(orient_cover_args(cover, filter, &oriented)))
+        .map_err(|err| format!("Could not execute ffmpeg: {}", err))?;
+    if !output.status.success() {
+        return Err(console::last_stderr_line(&output));
+    }
+    Ok(Some(oriented))
+}
+
+/// Builds the ffmpeg arguments that scale `cover` down to a JPEG thumbnail of at most
+/// `CHAPTER_THUMBNAIL_SIZE` pixels, without the program name. Both sides come out even, which
+/// the video encoder of the image track needs.
+pub fn chapter_thumbnail_args(cover: &str, output: &Path) -> Vec<OsString> {
+    let filter = format!(
+        "scale={size}:{size}:force_original_aspect_ratio=decrease,scale=trunc(iw/2)*2:trunc(ih/2)*2",
+        size = CHAPTER_THUMBNAIL_SIZE
+    );
+    let mut args = os_args(&["-i", cover, "-vf", &filter, "-frames:v", "1", "-c:v", "mjpeg", "-q:v", "3", "-y"]);
+    args.push(output.into());
+    args
+}
+
+/// Scales the cover down to the thumbnail shown for each chapter with `--chapter-thumbnails`.
+///
+/// # Returns
+///
+/// The path of the thumbnail, removed when dropped, or an error message.
+pub fn make_chapter_thumbnail(cover: &str, work_dir: &Path, runner: &dyn CommandRunner) -> Result<TempPath, String> {
+    let thumbnail = Builder::new().suffix(".jpg").tempfile_in(work_dir)
+        .map_err(|err| format!("Could not create the chapter thumbnail: {}", err))?
+        .into_temp_path();
+    let output = runner.run(Command::new("ffmpeg").args(chapter_thumbnail_args(cover, &thumbnail)))
+        .map_err(|err| format!("Could not execute ffmpeg: {}", err))?;
+    if !output.status.success() {
+        return Err(console::last_stderr_line(&output));
+    }
+    Ok(thumbnail)
+}
+
+/// Finds the first file, in book order, that carries an attached picture, probing the files one
+/// at a time and stopping at the first hit.
+///
+/// # Arguments
+///
+/// * `files` - The source files in book order.
+/// * `probe` - Reads a file's container information, e.g. `inspect_book`.
+pub fn first_with_cover(files: &[PathBuf], probe: impl Fn(&Path) -> Option<BookInfo>) -> Option<&Path> {
+    files.iter().map(PathBuf::as_path).find(|file| probe(file).is_some_and(|info| info.has_cover))
+}
+
+/// Describes a checked cover, e.g. "JPEG, 1400×1400 pixels".
+pub fn describe_cover(image: &CoverImage) -> String {
+    match image.size {
+        Some((width, height)) => format!("{}, {}×{} pixels", image.format.name(), width, height),
+        None => image.format.name().to_string(),
+    }
+}
+
+/// Warns about a cover smaller than `MIN_COVER_EDGE` on either side, as a phrase that follows
+/// the cover's name.
+pub fn small_cover_warning(image: &CoverImage) -> Option<String> {
+    let (width, height) = image.size?;
+    (width < MIN_COVER_EDGE || height < MIN_COVER_EDGE).then(|| {
+        format!("is only {}×{} pixels and will look blurry; {}×{} or larger is recommended", width, height, MIN_COVER_EDGE, MIN_COVER_EDGE)
+    })
+}
+
+/// Reads a cover image's header to check that it is a supported image, before any encoding.
+pub fn inspect_cover(cover: &str) -> Result<CoverImage, String> {
+    let bytes = fs::read(cover).map_err(|err| format!("could not read it: {}", err))?;
+    identify_image(&bytes)
+}
+
+/// Converts a HEIC or AVIF cover into a JPEG in the work directory, with the first and largest
+/// step of the shrink ladder, so that ffmpeg's support for the format is known before the
+/// encode.
+///
+/// # Returns
+///
+/// The path of the JPEG, removed when dropped, or an error message.
+pub fn convert_cover(cover: &str, work_dir: &Path, runner: &dyn CommandRunner) -> Result<TempPath, String> {
+    let converted = Builder::new().suffix(".jpg").tempfile_in(work_dir)
+        .map_err(|err| format!("Could not create the converted cover: {}", err))?
+        .into_temp_path();
+    let (max_edge, quality) = SHRINK_STEPS[0];
+    let output = runner.run(Command::new("ffmpeg").args(shrink_cover_args(cover, max_edge, quality, &converted)))
+        .map_err(|err| format!("Could not execute ffmpeg: {}", err))?;
+    if !output.status.success() {
+        return Err(console::last_stderr_line(&output));
+    }
+    Ok(converted)
+}
+
+/// Builds the ffmpeg arguments that write the attached picture of `source` to `output`, without
+/// the program name: copied as it is stored with `copy`, or encoded as a JPEG with `mjpeg`.
+pub fn extract_cover_args(source: &Path, codec: &str, output: &Path) -> Vec<OsString> {
+    let mut args = os_args(&["-i"]);
+    args.push(source.into());
+    args.extend(os_args(&["-map", "0:v:0", "-frames:v", "1", "-c:v", codec]));
+    if codec == "mjpeg" {
+        args.extend(os_args(&["-q:v", "2"]));
+    }
+    args.extend(os_args(&["-f", "image2", "-y"]));
+    args.push(output.into());
+    args
+}
+
+/// Extracts the attached picture of a source file into the work directory as a JPEG, so that
+/// remuxing an existing book keeps its cover. A JPEG picture is copied bit for bit; any other is
+/// encoded as one.
+///
+/// # Returns
+///
+/// The path of the extracted image, removed when dropped, or an error message.
+pub fn extract_cover(source: &Path, work_dir: &Path, runner: &dyn CommandRunner) -> Result<TempPath, String> {
+    let cover = Builder::new().suffix(".jpg").tempfile_in(work_dir)
+        .map_err(|err| format!("Could not create the extracted cover: {}", err))?
+        .into_temp_path();
+    for codec in ["copy", "mjpeg"] {
+        let output = runner.run(Command::new("ffmpeg").args(extract_cover_args(source, codec, &cover)))
+            .map_err(|err| format!("Could not execute ffmpeg: {}", err))?;
+        if !output.status.success() {
+            return Err(console::last_stderr_line(&output));
+        }
+        let copied = fs::read(&cover).ok().and_then(|bytes| identify_image(&bytes).ok());
+        if codec == "mjpeg" || copied.is_some_and(|image| image.format == ImageFormat::Jpeg) {
+            break;
+        }
+    }
+    Ok(cover)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use {std::cell::RefCell, std::io, std::os::unix::process::ExitStatusExt, std::process::{ExitStatus, Output}};
+
+    /// Tests the filtergraph for each layout, including a grid with an empty cell.
+    #[test]
+    fn test_collage_filter() {
+        assert_eq!(
+            collage_filter(2, CoverLayout::Horizontal),
+            "[0:v]scale=-2:600,setsar=1[c0];[1:v]scale=-2:600,setsar=1[c1];[c0][c1]hstack=inputs=2[cover]"
+        );
+        assert_eq!(
+            collage_filter(2, CoverLayout::Vertical),
+            "[0:v]scale=600:-2,setsar=1[c0];[1:v]scale=600:-2,setsar=1[c1];[c0][c1]vstack=inputs=2[cover]"
+        );
+        let grid = collage_filter(3, CoverLayout::Grid);
+        assert!(grid.starts_with("[0:v]scale=600:600:force_original_aspect_ratio=decrease,pad=600:600:(ow-iw)/2:(oh-ih)/2,setsar=1[c0];"));
+        assert!(grid.ends_with(";[c0][c1][c2]xstack=inputs=3:layout=0_0|600_0|0_600:fill=black[cover]"));
+        assert!(collage_filter(5, CoverLayout::Grid).contains("layout=0_0|600_0|1200_0|0_600|600_600:"));
+    }
+
+    /// Golden test for the collage command.
+    #[test]
+    fn test_collage_args() {
+        let covers = vec!["box/one.jpg".to_string(), "box/two.png".to_string()];
+        let args: Vec<String> = collage_args(&covers, CoverLayout::Vertical, Path::new("/tmp/cover.jpg"))
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(args[..4], ["-i", "box/one.jpg", "-i", "box/two.png"]);
+        assert_eq!(args[4], "-filter_complex");
+        assert_eq!(args[6..], ["-map", "[cover]", "-frames:v", "1", "-y", "/tmp/cover.jpg"]);
+    }
+
+    /// Tests that the cover comes from the first file whose probe reports an attached picture,
+    /// and that later files are not probed.
+    #[test]
+    fn test_first_with_cover() {
+        let files = vec![PathBuf::from("01.mp3"), PathBuf::from("book.m4b"), PathBuf::from("03.m4b")];
+        let probed = std::cell::RefCell::new(Vec::new());
+        let probe = |file: &Path| {
+            probed.borrow_mut().push(file.to_path_buf());
+            Some(BookInfo { has_cover: file.extension().is_some_and(|ext| ext == "m4b"), ..Default::default() })
+        };
+        assert_eq!(first_with_cover(&files, probe), Some(Path::new("book.m4b")));
+        assert_eq!(probed.borrow().len(), 2);
+        assert_eq!(first_with_cover(&files[..1], |_| None), None);
+    }
+
+    /// Records the extraction commands and reports success or failure. A successful copy writes
+    /// `picture`, the stored picture.
+    #[cfg(unix)]
+    struct ExtractRunner {
+        commands: RefCell<Vec<Vec<OsString>>>,
+        fails: bool,
+        picture: &'static [u8],
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for ExtractRunner {
+        fn run(&self, command: &mut Command) -> io::Result<Output> {
+            let args: Vec<OsString> = command.get_args().map(OsString::from).collect();
+            if !self.fails && args.windows(2).any(|pair| pair == ["-c:v", "copy"]) {
+                fs::write(args.last().unwrap(), self.picture)?;
+            }
+            self.commands.borrow_mut().push(args);
+            Ok(Output {
+                status: ExitStatus::from_raw(if self.fails { 256 } else { 0 }),
+                stdout: Vec::new(),
+                stderr: if self.fails { b"Output file does not contain any stream\n".to_vec() } else { Vec::new() },
+            })
+        }
+    }
+
+    /// Tests that a JPEG picture is copied as it is stored, that any other is encoded as a JPEG,
+    /// and that a failed extraction is reported.
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_cover() {
+        let work = tempfile::tempdir().unwrap();
+        let runner = ExtractRunner { commands: RefCell::new(Vec::new()), fails: false, picture: &JPEG };
+        let cover = extract_cover(Path::new("/books/Dune.m4b"), work.path(), &runner).unwrap();
+        let commands = runner.commands.borrow();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0][..11], os_args(&["-i", "/books/Dune.m4b", "-map", "0:v:0", "-frames:v", "1", "-c:v", "copy", "-f", "image2", "-y"])[..]);
+        assert_eq!(commands[0].last().unwrap(), cover.as_os_str());
+        assert!(cover.starts_with(work.path()));
+
+        let runner = ExtractRunner { commands: RefCell::new(Vec::new()), fails: false, picture: b"\x89PNG\r\n\x1a\n" };
+        let cover = extract_cover(Path::new("/books/Dune.m4b"), work.path(), &runner).unwrap();
+        let commands = runner.commands.borrow();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[1][6..11], os_args(&["-c:v", "mjpeg", "-q:v", "2", "-f"])[..]);
+        assert_eq!(commands[1].last().unwrap(), cover.as_os_str());
+
+        let failing = ExtractRunner { commands: RefCell::new(Vec::new()), fails: true, picture: &JPEG };
+        assert_eq!(
+            extract_cover(Path::new("/books/Dune.m4b"), work.path(), &failing).err().as_deref(),
+            Some("Output file does not contain any stream")
+        );
+    }
+
+    /// A minimal JPEG: the JFIF header segment, then the start of frame of a 600×500 image.
+    const JPEG: [u8; 29] = [
+        0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0,
+        0xFF, 0xC0, 0x00, 0x11, 0x08, 0x01, 0xF4, 0x02, 0x58,
+    ];
+
+    /// A JPEG like `JPEG` with an EXIF segment before the frame, holding the orientation tag
+    /// after another tag in the given byte order.
+    fn exif_jpeg(orientation: u16, big_endian: bool) -> Vec<u8> {
+        let u16_bytes = |value: u16| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let u32_bytes = |value: u32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let mut tiff = if big_endian { b"MM".to_vec() } else { b"II".to_vec() };
+        tiff.extend(u16_bytes(42));
+        tiff.extend(u32_bytes(8));
+        tiff.extend(u16_bytes(2));
+        // ImageWidth, a LONG of 600, then Orientation, a SHORT.
+        tiff.extend(u16_bytes(0x0100));
+        tiff.extend(u16_bytes(4));
+        tiff.extend(u32_bytes(1));
+        tiff.extend(u32_bytes(600));
+        tiff.extend(u16_bytes(0x0112));
+        tiff.extend(u16_bytes(3));
+        tiff.extend(u32_bytes(1));
+        tiff.extend(u16_bytes(orientation));
+        tiff.extend([0, 0]);
+        tiff.extend(u32_bytes(0));
+
+        let mut bytes = JPEG[..20].to_vec();
+        bytes.extend([0xFF, 0xE1]);
+        bytes.extend((8 + tiff.len() as u16).to_be_bytes());
+        bytes.extend(b"Exif\0\0");
+        bytes.extend(tiff);
+        bytes.extend(&JPEG[20..]);
+        bytes
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        bytes.extend(width.to_be_bytes());
+        bytes.extend(height.to_be_bytes());
+        bytes
+    }
+
+    fn webp(chunk: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut bytes = b"RIFF\x00\x00\x00\x00WEBP".to_vec();
+        bytes.extend(chunk);
+        bytes.extend((payload.len() as u32).to_le_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    /// Tests that JPEG, PNG, each WebP flavor, HEIC, and AVIF headers are identified with their
+    /// dimensions, and that other files and cut-short headers are rejected.
+    #[test]
+    fn test_identify_image() {
+        let image = |format, width, height| Ok(CoverImage { format, size: Some((width, height)) });
+        assert_eq!(identify_image(&JPEG), image(ImageFormat::Jpeg, 600, 500));
+        assert_eq!(identify_image(&png(1400, 1400)), image(ImageFormat::Png, 1400, 1400));
+
+        let lossy = webp(b"VP8 ", &[0, 0, 0, 0x9D, 0x01, 0x2A, 0x20, 0x03, 0x58, 0x02]);
+        assert_eq!(identify_image(&lossy), image(ImageFormat::Webp, 800, 600));
+        let mut lossless = vec![0x2F];
+        lossless.extend((199u32 | 99 << 14).to_le_bytes());
+        assert_eq!(identify_image(&webp(b"VP8L", &lossless)), image(ImageFormat::Webp, 200, 100));
+        let extended = webp(b"VP8X", &[0, 0, 0, 0, 0x77, 0x05, 0x00, 0x77, 0x05, 0x00]);
+        assert_eq!(identify_image(&extended), image(ImageFormat::Webp, 1400, 1400));
+
+        let heic = b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic";
+        assert_eq!(identify_image(heic), Ok(CoverImage { format: ImageFormat::Heic, size: None }));
+        let avif = b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00avifmif1miaf";
+        assert_eq!(identify_image(avif).map(|image| image.format), Ok(ImageFormat::Avif));
+        assert!(ImageFormat::Avif.needs_conversion() && !ImageFormat::Webp.needs_conversion());
+
+        assert_eq!(identify_image(b"<html>Not Found</html>"), Err("not a JPEG, PNG, WebP, HEIC, or AVIF image".to_string()));
+        assert_eq!(identify_image(&png(1400, 1400)[..12]), Err("the PNG header is damaged or cut short".to_string()));
+        assert_eq!(identify_image(&JPEG[..24]), Err("the JPEG header is damaged or cut short".to_string()));
+        assert_eq!(identify_image(&png(0, 1400)), Err("the PNG image has no pixels".to_string()));
+        assert!(identify_image(b"\x00\x00\x00\x14ftypisom\x00\x00\x02\x00isom").is_err());
+    }
+
+    /// Tests the reported dimensions and the warning for covers below 300×300.
+    #[test]
+    fn test_small_cover_warning() {
+        let image = identify_image(&png(280, 400)).unwrap();
+        assert_eq!(describe_cover(&image), "PNG, 280×400 pixels");
+        assert_eq!(small_cover_warning(&image).unwrap(), "is only 280×400 pixels and will look blurry; 300×300 or larger is recommended");
+        assert_eq!(small_cover_warning(&identify_image(&JPEG).unwrap()), None);
+        assert_eq!(small_cover_warning(&CoverImage { format: ImageFormat::Heic, size: None }), None);
+    }
+
+    /// Pretends to be ffmpeg shrinking a cover: the JPEG it writes is 100 bytes per pixel of
+    /// the longest edge divided by the quality.
+    #[cfg(unix)]
+    struct ShrinkRunner {
+        attempts: RefCell<Vec<(u32, u32)>>,
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for ShrinkRunner {
+        fn run(&self, command: &mut Command) -> io::Result<Output> {
+            let args: Vec<String> = command.get_args().map(|arg| arg.to_string_lossy().to_string()).collect();
+            let value_after = |flag: &str| args.iter().position(|arg| arg == flag).map(|index| args[index + 1].clone()).unwrap();
+            let edge: u32 = value_after("-vf").split("min(iw,").nth(1).unwrap().split(')').next().unwrap().parse().unwrap();
+            let quality: u32 = value_after("-q:v").parse().unwrap();
+            self.attempts.borrow_mut().push((edge, quality));
+            fs::write(args.last().unwrap(), vec![0u8; (edge * 100 / quality) as usize])?;
+            Ok(Output { status: ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new() })
+        }
+    }
+
+    /// Tests that an oversized cover is re-encoded step by step until it is under the cap, and
+    /// that a JPEG within the cap is left alone.
+    #[cfg(unix)]
+    #[test]
+    fn test_shrink_cover() {
+        let work = tempfile::tempdir().unwrap();
+        let cover = work.path().join("cover.jpg");
+        fs::write(&cover, vec![0u8; 3_000_000]).unwrap();
+        let cover = cover.to_string_lossy().to_string();
+
+        let runner = ShrinkRunner { attempts: RefCell::new(Vec::new()) };
+        let shrunk = shrink_cover(&cover, 25_000, work.path(), &runner).unwrap().unwrap();
+        assert!(fs::metadata(&shrunk).unwrap().len() <= 25_000);
+        assert_eq!(*runner.attempts.borrow(), vec![(3000, 2), (2000, 3), (1600, 4), (1200, 5)]);
+
+        assert!(shrink_cover(&cover, 3_000_000, work.path(), &runner).unwrap().is_none());
+        assert!(shrink_cover(&cover, 100, work.path(), &runner).unwrap_err().contains("still larger than 100 bytes"));
+        let args = shrink_cover_args("cover.png", 800, 9, Path::new("/tmp/out.jpg"));
+        assert_eq!(args[3], "scale='min(iw,800)':'min(ih,800)':force_original_aspect_ratio=decrease");
+    }
+
+    /// Tests that a HEIC cover is converted with the first step of the shrink ladder.
+    #[cfg(unix)]
+    #[test]
+    fn test_convert_cover() {
+        let work = tempfile::tempdir().unwrap();
+        let runner = ShrinkRunner { attempts: RefCell::new(Vec::new()) };
+        let converted = convert_cover("IMG_0042.HEIC", work.path(), &runner).unwrap();
+        assert_eq!(*runner.attempts.borrow(), vec![(3000, 2)]);
+        assert!(converted.starts_with(work.path()) && converted.extension() == Some("jpg".as_ref()));
+    }
+
+    /// Tests the EXIF orientation in both byte orders, behind the other segments and tags, and
+    /// images without it.
+    #[test]
+    fn test_exif_orientation() {
+        assert_eq!(exif_orientation(&exif_jpeg(6, false)), Some(6));
+        assert_eq!(exif_orientation(&exif_jpeg(8, true)), Some(8));
+        assert_eq!(identify_image(&exif_jpeg(6, false)), identify_image(&JPEG));
+        assert_eq!(exif_orientation(&exif_jpeg(9, false)), None);
+        assert_eq!(exif_orientation(&JPEG), None);
+        assert_eq!(exif_orientation(&png(600, 500)), None);
+        assert_eq!(exif_orientation(&exif_jpeg(6, false)[..40]), None);
+        assert_eq!((orientation_filter(1), orientation_filter(6), orientation_filter(3)), (None, Some("transpose=1"), Some("hflip,vflip")));
+    }
+
+    /// Tests that a cover taken sideways is rotated with ffmpeg's autorotation off, and that an
+    /// upright cover is left alone.
+    #[cfg(unix)]
+    #[test]
+    fn test_orient_cover() {
+        let work = tempfile::tempdir().unwrap();
+        let sideways = work.path().join("IMG_0042.jpg");
+        fs::write(&sideways, exif_jpeg(6, true)).unwrap();
+        let runner = ExtractRunner { commands: RefCell::new(Vec::new()), fails: false, picture: &JPEG };
+        let oriented = orient_cover(&sideways.to_string_lossy(), work.path(), &runner).unwrap().unwrap();
+        let commands = runner.commands.borrow();
+        assert_eq!(commands[0][..5], os_args(&["-noautorotate", "-i", &sideways.to_string_lossy(), "-vf", "transpose=1"])[..]);
+        assert_eq!(commands[0].last().unwrap(), oriented.as_os_str());
+
+        let upright = work.path().join("cover.jpg");
+        fs::write(&upright, exif_jpeg(1, false)).unwrap();
+        assert!(orient_cover(&upright.to_string_lossy(), work.path(), &runner).unwrap().is_none());
+        assert_eq!(commands.len(), 1);
+    }
+
+    /// Tests that the chapter thumbnail is scaled into a 320-pixel box with even sides.
+    #[cfg(unix)]
+    #[test]
+    fn test_make_chapter_thumbnail() {
+        let work = tempfile::tempdir().unwrap();
+        let runner = ExtractRunner { commands: RefCell::new(Vec::new()), fails: false, picture: &JPEG };
+        let thumbnail = make_chapter_thumbnail("/books/dune/cover.png", work.path(), &runner).unwrap();
+        let commands = runner.commands.borrow();
+        assert_eq!(commands[0][..4], os_args(&[
+            "-i", "/books/dune/cover.png",
+            "-vf", "scale=320:320:force_original_aspect_ratio=decrease,scale=trunc(iw/2)*2:trunc(ih/2)*2",
+        ])[..]);
+        assert_eq!(commands[0].last().unwrap(), thumbnail.as_os_str());
+
+        let failing = ExtractRunner { commands: RefCell::new(Vec::new()), fails: true, picture: &JPEG };
+        assert!(make_chapter_thumbnail("/books/dune/cover.png", work.path(), &failing).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+
+use crate::console;
+use crate::runner::CommandRunner;
+
+/// Normalizes a path for a concat list: Windows verbatim prefixes (`\\?\`, `\\?\UNC\`) are
+/// dropped and every separator becomes a forward slash, which ffmpeg accepts on every platform,
+/// so `C:\Books\01.m4a` becomes `C:/Books/01.m4a` and `\\nas\books\01.m4a` becomes
+/// `//nas/books/01.m4a`.
+pub fn concat_path(path: &str) -> String {
+    let path = if let Some(unc) = path.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{}", unc)
+    } else {
+        path.strip_prefix(r"\\?\").unwrap_or(path).to_string()
+    };
+    path.replace('\\', "/")
+}
+
+/// Renders one `file` directive of a concat list.
+///
+/// The path is quoted with single quotes, the only quoting the concat demuxer's tokenizer
+/// treats literally. A single quote inside the path closes the quotes, is written escaped as
+/// `\'`, and reopens them.
+pub fn concat_line(path: &str) -> String {
+    format!("file '{}'", concat_path(path).replace('\'', r"'\''"))
+}
+
+/// Writes a concat list with one `file` directive per path, each made absolute and canonical
+/// first so that no directive depends on the working directory. Paths that cannot be
+/// canonicalized are written as given.
+pub fn write_concat_list(list: &mut impl Write, paths: &[String]) -> io::Result<()> {
+    for path in paths {
+        let canonical = fs::canonicalize(path).map(|canonical| canonical.to_string_lossy().to_string());
+        writeln!(list, "{}", concat_line(canonical.as_deref().unwrap_or(path)))?;
+    }
+    Ok(())
+}
+
+/// Renders a concat list that shows `image` once per chapter for as long as the chapter lasts,
+/// the timed image track of `--chapter-thumbnails`. The concat demuxer ignores the duration of
+/// the last entry, so the image is listed once more after it.
+///
+/// # Arguments
+///
+/// * `image` - The image shown for every chapter.
+/// * `durations_ms` - How long each chapter lasts, in order.
+pub fn write_image_list(image: &str, durations_ms: &[u64]) -> String {
+    let line = concat_line(image);
+    let mut list = String::new();
+    for duration_ms in durations_ms {
+        list.push_str(&format!("{}\nduration {}.{:03}\n", line, duration_ms / 1000, duration_ms % 1000));
+    }
+    if !durations_ms.is_empty() {
+        list.push_str(&format!("{}\n", line));
+    }
+    list
+}
+
+/// Checks a concat list with `ffprobe` before the mux, which reads the whole list but only
+/// opens the first file, so a broken directive fails in a second instead of at the end of the
+/// mux.
+///
+/// # Returns
+///
+/// An error message naming the offending line of the list when ffprobe rejects it.
+pub fn check_concat_list(list: &Path, runner: &dyn CommandRunner) -> Result<(), String> {
+    let mut command = Command::new("ffprobe");
+    command.args(["-v", "error", "-f", "concat", "-safe", "0", "-i"]).arg(list);
+    let output = runner.run(&mut command).map_err(|err| format!("Could not execute ffprobe: {}", err))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let reason = console::last_stderr_line(&output);
+    let text = fs::read_to_string(list).unwrap_or_default();
+    match offending_line(&text, &stderr) {
+        Some((number, line)) => Err(format!("The concat list is invalid at line {} ({}): {}", number, line, reason)),
+        None => Err(format!("The concat list is invalid: {}", reason)),
+    }
+}
+
+/// Finds the list line an ffmpeg error refers to, either by number ("Line 3: ...") or by the
+/// quoted path it names ("Unsafe file name '...'").
+///
+/// # Returns
+///
+/// The line number, counted from 1, and the line.
+fn offending_line(list: &str, stderr: &str) -> Option<(usize, String)> {
+    let lines: Vec<&str> = list.lines().collect();
+    let numbered = stderr.split("Line ").skip(1).find_map(|rest| {
+        let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+        digits.parse::<usize>().ok().filter(|number| (1..=lines.len()).contains(number))
+    });
+    let named = || {
+        stderr.split('\'').skip(1).step_by(2).filter(|name| !name.is_empty()).find_map(|name| {
+            lines.iter().position(|line| line.contains(name)).map(|index| index + 1)
+        })
+    };
+    numbered.or_else(named).map(|number| (number, lines[number - 1].to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use {std::os::unix::process::ExitStatusExt, std::process::{ExitStatus, Output}};
+
+    /// Tests drive letters, UNC and verbatim paths, spaces, and quotes.
+    #[test]
+    fn test_concat_line() {
+        assert_eq!(concat_line("/tmp/m4btool/tmpa1b2.m4a"), "file '/tmp/m4btool/tmpa1b2.m4a'");
+        assert_eq!(concat_line(r"C:\Users\Jane Doe\AppData\Local\Temp\tmpa1b2.m4a"), "file 'C:/Users/Jane Doe/AppData/Local/Temp/tmpa1b2.m4a'");
+        assert_eq!(concat_line(r"\\?\C:\Temp\tmpa1b2.m4a"), "file 'C:/Temp/tmpa1b2.m4a'");
+        assert_eq!(concat_line(r"\\nas\books\Dune\01.m4a"), "file '//nas/books/Dune/01.m4a'");
+        assert_eq!(concat_line(r"\\?\UNC\nas\books\Dune\01.m4a"), "file '//nas/books/Dune/01.m4a'");
+        assert_eq!(concat_line("/books/Ender's Game/01 - It's \"Here\".m4a"), r#"file '/books/Ender'\''s Game/01 - It'\''s "Here".m4a'"#);
+    }
+
+    /// Tests that relative paths are written absolute and missing files as given.
+    #[test]
+    fn test_write_concat_list() {
+        let work = tempfile::tempdir().unwrap();
+        let existing = work.path().join("it's here.m4a");
+        fs::write(&existing, b"").unwrap();
+        let paths = vec![existing.to_string_lossy().to_string(), "missing.m4a".to_string()];
+        let mut list = Vec::new();
+        write_concat_list(&mut list, &paths).unwrap();
+        let canonical = fs::canonicalize(&existing).unwrap().to_string_lossy().to_string();
+        assert_eq!(String::from_utf8(list).unwrap(), format!("{}\nfile 'missing.m4a'\n", concat_line(&canonical)));
+    }
+
+    /// Tests that every chapter shows the image for its duration, and the image is listed once
+    /// more at the end.
+    #[test]
+    fn test_write_image_list() {
+        assert_eq!(
+            write_image_list("/tmp/thumb.jpg", &[61_250, 120_000]),
+            "file '/tmp/thumb.jpg'\nduration 61.250\nfile '/tmp/thumb.jpg'\nduration 120.000\nfile '/tmp/thumb.jpg'\n"
+        );
+        assert_eq!(write_image_list("/tmp/thumb.jpg", &[]), "");
+    }
+
+    /// Fails every command with the given stderr.
+    #[cfg(unix)]
+    struct FailingRunner(&'static str);
+
+    #[cfg(unix)]
+    impl CommandRunner for FailingRunner {
+        fn run(&self, _: &mut Command) -> io::Result<Output> {
+            Ok(Output { status: ExitStatus::from_raw(256), stdout: Vec::new(), stderr: self.0.as_bytes().to_vec() })
+        }
+    }
+
+    /// Tests that a rejected list is reported with the line ffmpeg complained about.
+    #[cfg(unix)]
+    #[test]
+    fn test_check_concat_list_names_line() {
+        let work = tempfile::tempdir().unwrap();
+        let list = work.path().join("list.txt");
+        fs::write(&list, "file '/tmp/one.m4a'\nfile 'C:\\tmp\\two.m4a'\nfile '/tmp/three.m4a'\n").unwrap();
+
+        let unsafe_name = FailingRunner("[concat @ 0x5581] Unsafe file name 'C:\\tmp\\two.m4a'\nlist.txt: Operation not permitted\n");
+        assert_eq!(
+            check_concat_list(&list, &unsafe_name),
+            Err("The concat list is invalid at line 2 (file 'C:\\tmp\\two.m4a'): list.txt: Operation not permitted".to_string())
+        );
+        let by_number = FailingRunner("[concat @ 0x5581] Line 3: unknown keyword 'flie'\nlist.txt: Invalid data found when processing input\n");
+        assert!(check_concat_list(&list, &by_number).unwrap_err().starts_with("The concat list is invalid at line 3 (file '/tmp/three.m4a')"));
+        let unknown = FailingRunner("list.txt: Invalid data found when processing input\n");
+        assert_eq!(check_concat_list(&list, &unknown), Err("The concat list is invalid: list.txt: Invalid data found when processing input".to_string()));
+    }
+}
@@ -0,0 +1,379 @@
+use std::fs;
+use std::io::{self, Read};
+
+use std::collections::BTreeMap;
+
+use serde::de::Error as _;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+
+use crate::defaults::DefaultsFile;
+use crate::encode::{layout_name, EncodeSettings};
+use crate::overrides::parse_bitrate;
+use crate::tags::BookTags;
+
+/// The `--config` source that reads the configuration from stdin.
+pub const CONFIG_STDIN: &str = "-";
+
+/// The keys a configuration may set.
+const CONFIG_KEYS: [&str; 6] = ["author", "genre", "language", "chapters", "files", "overrides"];
+
+/// The keys a configuration shares with a metadata defaults file.
+const DEFAULTS_KEYS: [&str; 3] = ["author", "genre", "language"];
+
+/// The most channels a file may be encoded with.
+const MAX_CHANNELS: u64 = 8;
+
+/// A build configuration given with `--config`: the keys of a metadata defaults file, plus the
+/// chapter titles and the order of the files, which a `chapters.txt` or the file names decide
+/// otherwise, and the encode settings of single files.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildConfig {
+    pub tags: BookTags,
+    /// One title per file, in book order.
+    pub chapters: Option<Vec<String>>,
+    /// File names in book order.
+    pub files: Option<Vec<String>>,
+    /// Encode settings of single files, by file name or stem, in the order of their names.
+    pub overrides: Vec<(String, FileOverride)>,
+}
+
+/// The keys of a configuration besides those of a metadata defaults file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    chapters: Option<Vec<String>>,
+    files: Option<Vec<String>>,
+    overrides: Option<BTreeMap<String, FileOverride>>,
+}
+
+impl BuildConfig {
+    /// Looks up the settings of a file, matching its full name first and then its stem.
+    pub fn file_override(&self, file_name: &str, stem: &str) -> Option<&FileOverride> {
+        let find = |name: &str| self.overrides.iter().find(|(key, _)| key == name).map(|(_, settings)| settings);
+        find(file_name).or_else(|| find(stem))
+    }
+}
+
+/// Encode settings of one file that replace the book's, e.g. stereo at 192k for a music
+/// interlude in a mono book. Unset settings are the book's.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, expecting = "a table of settings")]
+pub struct FileOverride {
+    /// The constant bitrate in bits per second, over `--bitrate-overrides`.
+    #[serde(deserialize_with = "bitrate")]
+    pub bitrate: Option<u64>,
+    #[serde(deserialize_with = "channels")]
+    pub channels: Option<u32>,
+    /// How much louder the file is made, in decibels; negative values make it quieter.
+    pub gain_db: Option<f64>,
+    /// How much to cut from the start, over `--trim-start`.
+    #[serde(rename = "trim_start", deserialize_with = "seconds")]
+    pub trim_start_ms: Option<u64>,
+    /// How much to cut from the end, over `--trim-end`.
+    #[serde(rename = "trim_end", deserialize_with = "seconds")]
+    pub trim_end_ms: Option<u64>,
+}
+
+impl FileOverride {
+    /// The book's encode settings with this file's channels and gain.
+    pub fn apply(&self, settings: &EncodeSettings) -> EncodeSettings {
+        let mut settings = settings.clone();
+        settings.bitrate = self.bitrate.or(settings.bitrate);
+        settings.channels = self.channels.or(settings.channels);
+        settings.gain_db = self.gain_db.or(settings.gain_db);
+        settings
+    }
+
+    /// Describes what the file is encoded with for the dry-run plan, e.g. "encoded with its own
+    /// settings: 192k, stereo at 44100 Hz, +3 dB, 2.5 s trimmed from the start".
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The file's effective settings, from `apply`.
+    pub fn describe(&self, settings: &EncodeSettings) -> String {
+        let mut parts = Vec::new();
+        parts.extend(self.bitrate.map(|bits_per_second| format!("{}k", bits_per_second / 1000)));
+        let layout = settings.channels.map_or_else(|| "the source's channels".to_string(), layout_name);
+        match settings.sample_rate {
+            Some(rate) => parts.push(format!("{} at {} Hz", layout, rate)),
+            None => parts.push(layout),
+        }
+        parts.extend(self.gain_db.map(|gain| format!("{:+} dB", gain)));
+        let seconds = |ms: u64| format!("{} s", ms as f64 / 1000.0);
+        parts.extend(self.trim_start_ms.map(|ms| format!("{} trimmed from the start", seconds(ms))));
+        parts.extend(self.trim_end_ms.map(|ms| format!("{} trimmed from the end", seconds(ms))));
+        format!("encoded with its own settings: {}", parts.join(", "))
+    }
+}
+
+/// Parses a configuration written as TOML or, when it starts with `{`, as JSON, e.g.
+/// `{"author": "Frank Herbert", "files": ["02.mp3", "01.mp3"]}`. A JSON `null` leaves its key
+/// unset.
+///
+/// `overrides` is a table of tables keyed by file name, e.g.
+/// `[overrides."07 - Interlude.mp3"]` followed by `bitrate = "192k"` and `channels = 2`. The
+/// trims are in seconds, like `--trim-start`.
+///
+/// # Returns
+///
+/// The configuration, or an error message that starts with the path of the offending key, e.g.
+/// ``chapters[2]: invalid type: integer `3`, expected a string``.
+pub fn parse_config(text: &str) -> Result<BuildConfig, String> {
+    let text = text.trim_start_matches('\u{feff}');
+    let object: Map<String, Value> = if text.trim_start().starts_with('{') {
+        serde_json::from_str(text).map_err(|err| format!("invalid JSON: {}", err))?
+    } else {
+        let table: toml::Table = toml::from_str(text).map_err(|err| format!("invalid TOML: {}", err.message().trim_end()))?;
+        table.into_iter().map(|(key, value)| (key, toml_to_json(value))).collect()
+    };
+    let Value::Object(mut object) = without_nulls(Value::Object(object)) else {
+        unreachable!("a table stays a table");
+    };
+    if let Some(key) = object.keys().find(|key| !CONFIG_KEYS.contains(&key.as_str())) {
+        return Err(format!("{}: unknown key; expected one of {}", key, CONFIG_KEYS.join(", ")));
+    }
+    // The keys of a metadata defaults file are read as one, the rest as the build's own.
+    let defaults: Map<String, Value> = DEFAULTS_KEYS.iter().filter_map(|key| object.remove_entry(*key)).collect();
+    let tags = deserialize::<DefaultsFile>(Value::Object(defaults))?.into_tags().map_err(|err| format!("language: {}", err))?;
+    let file: ConfigFile = deserialize(Value::Object(object))?;
+
+    if let Some(index) = file.chapters.iter().flatten().position(|title| title.trim().is_empty()) {
+        return Err(format!("chapters[{}]: expected a title, found an empty string", index));
+    }
+    Ok(BuildConfig {
+        tags,
+        chapters: file.chapters.map(|titles| titles.iter().map(|title| title.trim().to_string()).collect()),
+        files: file.files,
+        overrides: file.overrides.unwrap_or_default().into_iter().collect(),
+    })
+}
+
+/// Reads the configuration of `--config`, from stdin for `CONFIG_STDIN`.
+///
+/// # Returns
+///
+/// The configuration, or an error message naming where it was read from.
+pub fn read_config(source: &str) -> Result<BuildConfig, String> {
+    let label = config_label(source);
+    let text = if source == CONFIG_STDIN {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).map(|_| text)
+    } else {
+        fs::read_to_string(source)
+    };
+    let text = text.map_err(|err| format!("Could not read the configuration {}: {}", label, err))?;
+    parse_config(&text).map_err(|err| format!("Invalid configuration {}: {}", label, err))
+}
+
+/// Names a `--config` source in messages and in the plan, e.g. "from stdin" or "'dune.toml'".
+pub fn config_label(source: &str) -> String {
+    if source == CONFIG_STDIN {
+        "from stdin".to_string()
+    } else {
+        format!("'{}'", source)
+    }
+}
+
+/// Orders the files by the configuration's `files` list. Files the list leaves out follow the
+/// listed ones in their usual order.
+///
+/// # Arguments
+///
+/// * `file_names` - The file names in the book's usual order.
+/// * `order` - The configuration's `files`.
+///
+/// # Returns
+///
+/// The new order as indices into `file_names`, or an error message naming the entry of `files`
+/// that matches no file, several files, or a file listed before.
+pub fn order_files(file_names: &[String], order: &[String]) -> Result<Vec<usize>, String> {
+    let mut ordered: Vec<usize> = Vec::with_capacity(file_names.len());
+    for (position, name) in order.iter().enumerate() {
+        let matches: Vec<usize> = file_names.iter().enumerate().filter(|(_, file)| *file == name).map(|(index, _)| index).collect();
+        let index = match matches[..] {
+            [] => return Err(format!("files[{}]: no audio file is named '{}'", position, name)),
+            [index] => index,
+            _ => return Err(format!("files[{}]: '{}' names {} files", position, name, matches.len())),
+        };
+        if ordered.contains(&index) {
+            return Err(format!("files[{}]: '{}' is listed twice", position, name));
+        }
+        ordered.push(index);
+    }
+    let rest: Vec<usize> = (0..file_names.len()).filter(|index| !ordered.contains(index)).collect();
+    ordered.extend(rest);
+    Ok(ordered)
+}
+
+/// A bitrate written as bits per second or as text such as "192k".
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "expected a bitrate such as \"192k\"")]
+enum Bitrate {
+    BitsPerSecond(u64),
+    Text(String),
+}
+
+/// Reads the bitrate of an entry of `overrides`.
+fn bitrate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let text = match Option::<Bitrate>::deserialize(deserializer)? {
+        Some(Bitrate::BitsPerSecond(bits_per_second)) => bits_per_second.to_string(),
+        Some(Bitrate::Text(text)) => text,
+        None => return Ok(None),
+    };
+    parse_bitrate(&text).map(Some).map_err(D::Error::custom)
+}
+
+/// Reads a channel count from 1 to `MAX_CHANNELS`.
+fn channels<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    match Option::<u64>::deserialize(deserializer)? {
+        Some(channels @ 1..=MAX_CHANNELS) => Ok(Some(channels as u32)),
+        Some(channels) => Err(D::Error::custom(format!("expected a channel count from 1 to {}, found {}", MAX_CHANNELS, channels))),
+        None => Ok(None),
+    }
+}
+
+/// Reads a number of seconds as milliseconds.
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(seconds) if seconds >= 0.0 => Ok(Some((seconds * 1000.0).round() as u64)),
+        Some(seconds) => Err(D::Error::custom(format!("expected a number of seconds, found {}", seconds))),
+        None => Ok(None),
+    }
+}
+
+/// Reads a value into `T`, or reports the path of the offending key, e.g. `files[0]`.
+fn deserialize<T: DeserializeOwned>(value: Value) -> Result<T, String> {
+    serde_path_to_error::deserialize(value).map_err(|err| format!("{}: {}", err.path(), err.inner()))
+}
+
+/// Drops the keys set to `null` from every table, leaving them unset.
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(object.into_iter().filter(|(_, value)| !value.is_null()).map(|(key, value)| (key, without_nulls(value))).collect()),
+        value => value,
+    }
+}
+
+/// Converts parsed TOML into the JSON tree both formats are checked on. A date keeps its text.
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(text) => Value::String(text),
+        toml::Value::Integer(number) => number.into(),
+        toml::Value::Float(number) => serde_json::Number::from_f64(number).map_or_else(|| 0.into(), Value::Number),
+        toml::Value::Boolean(flag) => flag.into(),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(items) => Value::Array(items.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(table.into_iter().map(|(key, value)| (key, toml_to_json(value))).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the same configuration written as TOML and as JSON.
+    #[test]
+    fn test_parse_config() {
+        let expected = BuildConfig {
+            tags: BookTags {
+                author: Some("Frank Herbert".to_string()),
+                genre: Some("Science Fiction; Classics".to_string()),
+                language: Some("eng".to_string()),
+                ..BookTags::default()
+            },
+            chapters: Some(vec!["Prologue".to_string(), "Arrakis".to_string()]),
+            files: Some(vec!["02 - Arrakis.mp3".to_string(), "01 - Prologue.mp3".to_string()]),
+            overrides: Vec::new(),
+        };
+        let toml = "author = \"Frank Herbert\"\n\
+            genre = [\"Science Fiction\", \"Classics\"]\n\
+            language = \"en\"\n\
+            chapters = [\"Prologue\", \" Arrakis \"]\n\
+            files = [\"02 - Arrakis.mp3\", \"01 - Prologue.mp3\"]\n";
+        assert_eq!(parse_config(toml), Ok(expected.clone()));
+        let json = r#"
+            {"author": "Frank Herbert", "genre": ["Science Fiction", "Classics"], "language": "en",
+             "chapters": ["Prologue", " Arrakis "], "files": ["02 - Arrakis.mp3", "01 - Prologue.mp3"],
+             "narrator": null}"#;
+        assert_eq!(parse_config(json), Ok(expected));
+
+        assert_eq!(parse_config("\u{feff}{\"genre\": \"Fantasy\"}").unwrap().tags.genre.as_deref(), Some("Fantasy"));
+        assert_eq!(parse_config(""), Ok(BuildConfig::default()));
+        assert_eq!(parse_config("{}"), Ok(BuildConfig::default()));
+    }
+
+    /// Tests that invalid input in either format is reported with the path of its key.
+    #[test]
+    fn test_parse_config_errors() {
+        let errors = [
+            ("narator = \"Scott Brick\"", "narator: unknown key; expected one of author, genre, language, chapters, files, overrides"),
+            (r#"{"author": ["Frank Herbert"]}"#, "author: invalid type: sequence, expected a string"),
+            ("genre = 42", "genre: expected a genre or a list of genres"),
+            (r#"{"genre": ["Fantasy", false]}"#, "genre: expected a genre or a list of genres"),
+            ("chapters = [\"Prologue\", \"Arrakis\", 3]", "chapters[2]: invalid type: integer `3`, expected a string"),
+            (r#"{"chapters": ["Prologue", "  "]}"#, "chapters[1]: expected a title, found an empty string"),
+            ("files = \"01.mp3\"", "files: invalid type: string \"01.mp3\", expected a sequence"),
+            (r#"{"files": [{"name": "01.mp3"}]}"#, "files[0]: invalid type: map, expected a string"),
+        ];
+        for (text, message) in errors {
+            assert_eq!(parse_config(text).unwrap_err(), message, "{}", text);
+        }
+        assert!(parse_config("language = \"klingon\"").unwrap_err().starts_with("language: Invalid language 'klingon'"));
+        assert!(parse_config("author = Frank Herbert").unwrap_err().starts_with("invalid TOML: "));
+        assert!(parse_config("{\"author\": \"Frank Herbert\",}").unwrap_err().starts_with("invalid JSON: "));
+    }
+
+    /// Tests the settings of single files in both formats, looked up by name or stem, and the
+    /// invalid ones reported with their path.
+    #[test]
+    fn test_parse_overrides() {
+        let interlude = FileOverride { bitrate: Some(192_000), channels: Some(2), gain_db: Some(-1.5), trim_start_ms: Some(2_500), trim_end_ms: None };
+        let toml = "[overrides.\"07 - Interlude.mp3\"]\n\
+            bitrate = \"192k\"\n\
+            channels = 2\n\
+            gain_db = -1.5\n\
+            trim_start = 2.5\n\
+            [overrides.\"12 - Finale\"]\n\
+            trim_end = 4\n";
+        let config = parse_config(toml).unwrap();
+        let finale = FileOverride { trim_end_ms: Some(4_000), ..FileOverride::default() };
+        assert_eq!(config.overrides, vec![("07 - Interlude.mp3".to_string(), interlude), ("12 - Finale".to_string(), finale)]);
+        assert_eq!(config.file_override("07 - Interlude.mp3", "07 - Interlude"), Some(&interlude));
+        assert_eq!(config.file_override("12 - Finale.mp3", "12 - Finale"), Some(&finale));
+        assert_eq!(config.file_override("08.mp3", "08"), None);
+        let json = r#"{"overrides": {"07 - Interlude.mp3": {"bitrate": 192000, "channels": 2, "gain_db": -1.5, "trim_start": 2.5, "trim_end": null}}}"#;
+        assert_eq!(parse_config(json).unwrap().overrides, vec![("07 - Interlude.mp3".to_string(), interlude)]);
+
+        let errors = [
+            ("overrides = [\"07.mp3\"]", "overrides: invalid type: sequence, expected a map"),
+            (r#"{"overrides": {"07.mp3": "stereo"}}"#, "overrides.07.mp3: invalid type: string \"stereo\", expected a table of settings"),
+            (r#"{"overrides": {"07.mp3": {"bitrate": "fast"}}}"#, "overrides.07.mp3.bitrate: invalid bitrate 'fast'"),
+            (r#"{"overrides": {"07.mp3": {"bitrate": true}}}"#, "overrides.07.mp3.bitrate: expected a bitrate such as \"192k\""),
+            (r#"{"overrides": {"07.mp3": {"channels": 0}}}"#, "overrides.07.mp3.channels: expected a channel count from 1 to 8, found 0"),
+            (r#"{"overrides": {"07.mp3": {"gain_db": "loud"}}}"#, "overrides.07.mp3.gain_db: invalid type: string \"loud\", expected f64"),
+            (r#"{"overrides": {"07.mp3": {"trim_start": -2}}}"#, "overrides.07.mp3.trim_start: expected a number of seconds, found -2"),
+            (r#"{"overrides": {"07.mp3": {"volume": 3}}}"#, "overrides.07.mp3.volume: unknown field `volume`, expected one of `bitrate`, `channels`, `gain_db`, `trim_start`, `trim_end`"),
+        ];
+        for (text, message) in errors {
+            assert_eq!(parse_config(text).unwrap_err(), message, "{}", text);
+        }
+    }
+
+    /// Tests ordering the files by the list, with the unlisted files after the listed ones, and
+    /// the entries that cannot be matched.
+    #[test]
+    fn test_order_files() {
+        let names: Vec<String> = ["01.mp3", "02.mp3", "03.mp3", "04.mp3"].iter().map(|name| name.to_string()).collect();
+        let order = |list: &[&str]| order_files(&names, &list.iter().map(|name| name.to_string()).collect::<Vec<_>>());
+        assert_eq!(order(&["03.mp3", "01.mp3", "02.mp3", "04.mp3"]), Ok(vec![2, 0, 1, 3]));
+        assert_eq!(order(&["04.mp3", "02.mp3"]), Ok(vec![3, 1, 0, 2]));
+        assert_eq!(order(&[]), Ok(vec![0, 1, 2, 3]));
+        assert_eq!(order(&["01.mp3", "05.mp3"]), Err("files[1]: no audio file is named '05.mp3'".to_string()));
+        assert_eq!(order(&["02.mp3", "02.mp3"]), Err("files[1]: '02.mp3' is listed twice".to_string()));
+
+        let discs: Vec<String> = vec!["01.mp3".to_string(), "01.mp3".to_string()];
+        assert_eq!(order_files(&discs, &["01.mp3".to_string()]), Err("files[0]: '01.mp3' names 2 files".to_string()));
+    }
+}
@@ -0,0 +1,444 @@
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use m4btool::Event;
+
+use crate::progress;
+use crate::shell;
+
+/// Serializes all human-readable console output through one writer.
+///
+/// On a terminal, progress is a single line redrawn with carriage returns, and any other
+/// message first clears that line and then redraws it, so progress never interleaves with
+/// warnings or forwarded ffmpeg output. When the writer is not a terminal (cron, CI, pipes),
+/// every progress update is written as its own plain line instead. With `--log-file`, every
+/// message is also appended to the log as a plain line with a UTC timestamp.
+pub struct Console {
+    state: Mutex<ConsoleState>,
+    color: bool,
+    interactive: bool,
+    verbose: bool,
+    /// Whether `confirm` asks; otherwise every confirmation is taken as given.
+    prompts: bool,
+}
+
+struct ConsoleState {
+    out: Box<dyn Write + Send>,
+    /// The progress line currently shown on an interactive console.
+    progress: Option<String>,
+    /// The `--log-file` writer, if any.
+    log: Option<Box<dyn Write + Send>>,
+}
+
+/// ANSI escape that returns to the start of the line and clears it.
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+impl Console {
+    /// Creates a console writing to `out`.
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - Where all console output goes.
+    /// * `color` - Whether to color the warning and error prefixes.
+    /// * `interactive` - Whether `out` is a terminal that supports redrawing the progress line.
+    /// * `verbose` - Whether ffmpeg's own output is forwarded to the console.
+    pub fn new(out: Box<dyn Write + Send>, color: bool, interactive: bool, verbose: bool) -> Self {
+        Console { state: Mutex::new(ConsoleState { out, progress: None, log: None }), color, interactive, verbose, prompts: false }
+    }
+
+    /// Makes `confirm` ask before destructive or expensive steps. Prompts are only shown on a
+    /// terminal, so this has no effect when the console is not interactive.
+    pub fn with_prompts(mut self, prompts: bool) -> Self {
+        self.prompts = prompts && self.interactive;
+        self
+    }
+
+    /// Also writes every message, without colors, to `log`.
+    pub fn with_log(self, log: Box<dyn Write + Send>) -> Self {
+        self.lock().log = Some(log);
+        self
+    }
+
+    /// Creates a console on stderr, detecting whether it is a terminal and honoring `NO_COLOR`.
+    pub fn for_stderr(no_color: bool, verbose: bool) -> Self {
+        let interactive = io::stderr().is_terminal();
+        let color = color_enabled(no_color, env::var_os("NO_COLOR"), interactive);
+        Console::new(Box::new(io::stderr()), color, interactive, verbose)
+    }
+
+    /// Whether ffmpeg's own output should be forwarded to the console.
+    pub fn is_verbose(&self) -> bool {
+        self.verbose
+    }
+
+    /// Writes a warning, prefixed with "Warning:".
+    pub fn warn(&self, message: &str) {
+        let prefix = self.paint("Warning:", "33");
+        self.write_line(&format!("{} {}", prefix, message));
+        self.record(&format!("Warning: {}", message));
+    }
+
+    /// Writes an error, prefixed with "Error:".
+    pub fn error(&self, message: &str) {
+        let prefix = self.paint("Error:", "31");
+        self.write_line(&format!("{} {}", prefix, message));
+        self.record(&format!("Error: {}", message));
+    }
+
+    /// Writes an informational line.
+    pub fn line(&self, message: &str) {
+        self.write_line(message);
+        self.record(message);
+    }
+
+    /// Writes a message to the log file only, such as output that went to stdout or a command
+    /// line that is not shown without `--verbose`.
+    pub fn record(&self, message: &str) {
+        let mut state = self.lock();
+        if let Some(log) = state.log.as_mut() {
+            let stamp = log_timestamp(SystemTime::now());
+            for text in message.lines() {
+                let _ = writeln!(log, "{} {}", stamp, text);
+            }
+            let _ = log.flush();
+        }
+    }
+
+    /// Whether `confirm` will actually ask.
+    pub fn prompts(&self) -> bool {
+        self.prompts
+    }
+
+    /// Asks a yes/no question and reads the answer from `answers`; only "y" or "yes" confirms.
+    /// Without prompts the question is not shown and counts as confirmed.
+    pub fn confirm(&self, question: &str, answers: &mut dyn BufRead) -> bool {
+        if !self.prompts {
+            return true;
+        }
+        let question = format!("{} [y/N] ", question);
+        let mut state = self.lock();
+        let _ = write!(state.out, "{}", question);
+        let _ = state.out.flush();
+        drop(state);
+        let mut answer = String::new();
+        let confirmed = answers.read_line(&mut answer).is_ok() && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+        self.record(&format!("{}{}", question, answer.trim()));
+        confirmed
+    }
+
+    /// Shows progress of a multi-step phase, e.g. `[3/12] Encoding 03 - Storm.mp3`.
+    pub fn progress(&self, current: usize, total: usize, label: &str) {
+        let text = format!("[{}/{}] {}", current, total, label);
+        let mut state = self.lock();
+        if self.interactive {
+            let _ = write!(state.out, "{}{}", CLEAR_LINE, text);
+            state.progress = Some(text.clone());
+        } else {
+            let _ = writeln!(state.out, "{}", text);
+        }
+        let _ = state.out.flush();
+        drop(state);
+        self.record(&text);
+    }
+
+    /// Ends the current progress line so later output starts on a fresh line.
+    pub fn finish_progress(&self) {
+        let mut state = self.lock();
+        if state.progress.take().is_some() {
+            let _ = writeln!(state.out);
+            let _ = state.out.flush();
+        }
+    }
+
+    fn write_line(&self, text: &str) {
+        let mut state = self.lock();
+        match state.progress.clone() {
+            // Replace the progress line with the message, then redraw progress below it.
+            Some(progress) => {
+                let _ = write!(state.out, "{}{}\n{}", CLEAR_LINE, text, progress);
+            }
+            None => {
+                let _ = writeln!(state.out, "{}", text);
+            }
+        }
+        let _ = state.out.flush();
+    }
+
+    fn paint(&self, text: &str, color_code: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", color_code, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ConsoleState> {
+        // A panic while holding the lock leaves the writer usable, so recover it.
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Decides whether to use colors: never with `--no-color`, never when `NO_COLOR` is set to a
+/// non-empty value (see https://no-color.org), and only on a terminal.
+pub fn color_enabled(no_color_flag: bool, no_color_env: Option<OsString>, is_terminal: bool) -> bool {
+    let env_disabled = no_color_env.is_some_and(|value| !value.is_empty());
+    is_terminal && !no_color_flag && !env_disabled
+}
+
+/// Formats a time as a UTC timestamp for the log file, e.g. "2024-02-29T12:00:00Z".
+fn log_timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, second_of_day) = ((seconds / 86_400) as i64, seconds % 86_400);
+    // Converts days since 1970-01-01 to a civil date (Howard Hinnant's days_from_civil, inverted).
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, second_of_day / 3_600, second_of_day % 3_600 / 60, second_of_day % 60
+    )
+}
+
+static CONSOLE: OnceLock<Console> = OnceLock::new();
+
+/// Installs the process-wide console. Only the first call has an effect.
+pub fn init(console: Console) {
+    let _ = CONSOLE.set(console);
+}
+
+/// Returns the process-wide console, defaulting to stderr with automatic detection.
+pub fn console() -> &'static Console {
+    CONSOLE.get_or_init(|| Console::for_stderr(false, false))
+}
+
+/// Writes a warning to the process-wide console and the `--progress-json` stream.
+pub fn warn(message: impl AsRef<str>) {
+    console().warn(message.as_ref());
+    progress::emit(Event::Warning { message: message.as_ref().to_string() });
+}
+
+/// Writes an error to the process-wide console and the `--progress-json` stream.
+pub fn error(message: impl AsRef<str>) {
+    console().error(message.as_ref());
+    progress::emit(Event::Error { message: message.as_ref().to_string() });
+}
+
+/// Writes an informational line to the process-wide console.
+pub fn line(message: impl AsRef<str>) {
+    console().line(message.as_ref());
+}
+
+/// Asks a yes/no question on the process-wide console, reading the answer from stdin.
+pub fn confirm(question: impl AsRef<str>) -> bool {
+    console().confirm(question.as_ref(), &mut io::stdin().lock())
+}
+
+/// Prints a result line to stdout and records it in the log file.
+pub fn print(message: impl AsRef<str>) {
+    out(&format!("{}\n", message.as_ref()));
+    console().record(message.as_ref());
+}
+
+/// Writes text as it is to stdout, or to stderr while stdout carries the `--progress-json` events.
+pub fn out(text: &str) {
+    if progress::owns_stdout() {
+        eprint!("{}", text);
+    } else {
+        print!("{}", text);
+    }
+}
+
+/// Runs a command to completion with its stderr captured instead of inherited.
+///
+/// In verbose mode the composed command line is echoed first, and each stderr line is also forwarded through the console as it arrives,
+/// so ffmpeg's own output never tears the progress line. Carriage-return separated status
+/// updates are treated as separate lines. With `--progress-json` the times in those status
+/// updates are passed on as mux progress.
+///
+/// # Returns
+///
+/// The command's `Output` with the captured stderr; stdout is discarded.
+pub fn run_captured(command: &mut Command) -> io::Result<Output> {
+    let command_line = format!("Running: {}", shell::command_line(command));
+    if console().is_verbose() {
+        line(command_line);
+    } else {
+        console().record(&command_line);
+    }
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::piped()).spawn()?;
+    let mut stderr = Vec::new();
+    if let Some(mut pipe) = child.stderr.take() {
+        let verbose = console().is_verbose();
+        if verbose || progress::enabled() {
+            let mut chunk = [0u8; 4096];
+            let mut lines = progress::StderrLines::default();
+            loop {
+                let read = pipe.read(&mut chunk)?;
+                if read == 0 {
+                    break;
+                }
+                stderr.extend_from_slice(&chunk[..read]);
+                for text in lines.push(&chunk[..read]) {
+                    if let Some(out_time_ms) = progress::parse_status_time(&text) {
+                        progress::ffmpeg_time(out_time_ms);
+                    }
+                    if verbose && !text.trim().is_empty() {
+                        line(text);
+                    }
+                }
+            }
+            if let Some(rest) = lines.rest().filter(|_| verbose) {
+                line(rest);
+            }
+        } else {
+            pipe.read_to_end(&mut stderr)?;
+        }
+    }
+    let status = child.wait()?;
+    Ok(Output { status, stdout: Vec::new(), stderr })
+}
+
+/// Returns the last non-empty line of a command's stderr, which is usually ffmpeg's actual error.
+pub fn last_stderr_line(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr)
+        .split(['\n', '\r'])
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// A writer that can be read back after the console has written to it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn fake_run(console: &Console) {
+        console.progress(1, 2, "Encoding 01 - Intro.mp3");
+        console.warn("Could not retrieve duration for file '01 - Intro.mp3'");
+        console.progress(2, 2, "Encoding 02 - Storm.mp3");
+        console.finish_progress();
+        console.line("Success: Audiobook created at 'book/output.m4b'");
+    }
+
+    /// Tests the plain-mode output of a fake run, as seen in cron jobs and CI logs.
+    #[test]
+    fn test_plain_mode_snapshot() {
+        let buffer = SharedBuffer::default();
+        let console = Console::new(Box::new(buffer.clone()), false, false, false);
+        fake_run(&console);
+        assert_eq!(
+            buffer.contents(),
+            "[1/2] Encoding 01 - Intro.mp3\n\
+             Warning: Could not retrieve duration for file '01 - Intro.mp3'\n\
+             [2/2] Encoding 02 - Storm.mp3\n\
+             Success: Audiobook created at 'book/output.m4b'\n"
+        );
+    }
+
+    /// Tests that on a terminal, messages replace the progress line and progress is redrawn after them.
+    #[test]
+    fn test_interactive_mode_redraws_progress() {
+        let buffer = SharedBuffer::default();
+        let console = Console::new(Box::new(buffer.clone()), true, true, false);
+        fake_run(&console);
+        assert_eq!(
+            buffer.contents(),
+            "\r\x1b[K[1/2] Encoding 01 - Intro.mp3\
+             \r\x1b[K\x1b[33mWarning:\x1b[0m Could not retrieve duration for file '01 - Intro.mp3'\n[1/2] Encoding 01 - Intro.mp3\
+             \r\x1b[K[2/2] Encoding 02 - Storm.mp3\n\
+             Success: Audiobook created at 'book/output.m4b'\n"
+        );
+    }
+
+    /// Tests that the log file gets every message, including progress, without colors and with timestamps.
+    #[test]
+    fn test_log_file_mirrors_messages() {
+        let buffer = SharedBuffer::default();
+        let log = SharedBuffer::default();
+        let console = Console::new(Box::new(buffer.clone()), true, true, false).with_log(Box::new(log.clone()));
+        fake_run(&console);
+        console.record("Executing ffmpeg command: ffmpeg -f concat");
+        let lines: Vec<String> = log.contents().lines().map(|line| line[21..].to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "[1/2] Encoding 01 - Intro.mp3",
+                "Warning: Could not retrieve duration for file '01 - Intro.mp3'",
+                "[2/2] Encoding 02 - Storm.mp3",
+                "Success: Audiobook created at 'book/output.m4b'",
+                "Executing ffmpeg command: ffmpeg -f concat",
+            ]
+        );
+        assert!(log.contents().lines().all(|line| line.as_bytes()[10] == b'T' && &line[19..21] == "Z "));
+        assert!(!buffer.contents().contains("Executing"));
+    }
+
+    /// Tests that only a yes confirms, and that without prompts nothing is asked or read.
+    #[test]
+    fn test_confirm() {
+        let buffer = SharedBuffer::default();
+        let console = Console::new(Box::new(buffer.clone()), false, true, false).with_prompts(true);
+        assert!(console.confirm("Overwrite 'book.m4b'?", &mut "Yes\n".as_bytes()));
+        assert!(!console.confirm("Overwrite 'book.m4b'?", &mut "\n".as_bytes()));
+        assert!(!console.confirm("Overwrite 'book.m4b'?", &mut "".as_bytes()));
+        assert_eq!(buffer.contents(), "Overwrite 'book.m4b'? [y/N] ".repeat(3));
+
+        let buffer = SharedBuffer::default();
+        let piped = Console::new(Box::new(buffer.clone()), false, false, false).with_prompts(true);
+        assert!(!piped.prompts());
+        assert!(piped.confirm("Overwrite 'book.m4b'?", &mut "n\n".as_bytes()));
+        assert!(buffer.contents().is_empty());
+    }
+
+    /// Tests UTC timestamps, including a leap day.
+    #[test]
+    fn test_log_timestamp() {
+        use std::time::Duration;
+        let at = |seconds: u64| log_timestamp(UNIX_EPOCH + Duration::from_secs(seconds));
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(at(1_709_208_000), "2024-02-29T12:00:00Z");
+        assert_eq!(at(1_735_689_599), "2024-12-31T23:59:59Z");
+    }
+
+    /// Tests that colors are only used on a terminal without --no-color or a non-empty NO_COLOR.
+    #[test]
+    fn test_color_enabled() {
+        assert!(color_enabled(false, None, true));
+        assert!(!color_enabled(false, None, false));
+        assert!(!color_enabled(true, None, true));
+        assert!(!color_enabled(false, Some(OsString::from("1")), true));
+        assert!(color_enabled(false, Some(OsString::new()), true));
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::language::parse_language;
+use crate::tags::BookTags;
+
+/// The names of a metadata defaults file, looked for in this order in the book's folder and in
+/// the folder above it, e.g. `Frank Herbert/m4btool.toml` for `Frank Herbert/Dune`.
+pub const DEFAULTS_FILES: [&str; 2] = ["m4btool.toml", "author.toml"];
+
+/// The tags a metadata defaults file may set, also the tags of a `--config`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultsFile {
+    author: Option<String>,
+    /// One genre, or a list joined like the genre tag.
+    genre: Option<Genres>,
+    language: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged, expecting = "expected a genre or a list of genres")]
+enum Genres {
+    One(String),
+    Several(Vec<String>),
+}
+
+/// Parses a metadata defaults file, e.g. `author = "Frank Herbert"` and `genre = ["Science
+/// Fiction", "Classics"]`, validating the language like `--language`.
+///
+/// # Returns
+///
+/// The tags the file sets, or an error message for invalid TOML, an unknown key, or a bad language.
+pub fn parse_defaults(text: &str) -> Result<BookTags, String> {
+    let file: DefaultsFile = toml::from_str(text.trim_start_matches('\u{feff}')).map_err(|err| err.message().to_string())?;
+    file.into_tags()
+}
+
+impl DefaultsFile {
+    /// The tags the file sets, with a genre list joined like the genre tag.
+    ///
+    /// # Returns
+    ///
+    /// The tags, or an error message for a bad language.
+    pub fn into_tags(self) -> Result<BookTags, String> {
+        let genre = self.genre.map(|genre| match genre {
+            Genres::One(genre) => genre,
+            Genres::Several(genres) => genres.join("; "),
+        });
+        Ok(BookTags {
+            author: self.author,
+            genre: genre.filter(|genre| !genre.trim().is_empty()),
+            language: self.language.as_deref().map(parse_language).transpose()?,
+            ..BookTags::default()
+        })
+    }
+}
+
+/// Reads the metadata defaults file in `dir`, if it has one.
+///
+/// # Returns
+///
+/// The file and the tags it sets, or an error message naming the file that could not be used.
+pub fn load_defaults(dir: &Path) -> Result<Option<(PathBuf, BookTags)>, String> {
+    let Some(path) = DEFAULTS_FILES.iter().map(|name| dir.join(name)).find(|path| path.is_file()) else {
+        return Ok(None);
+    };
+    let text = fs::read_to_string(&path).map_err(|err| format!("Could not read '{}': {}", path.display(), err))?;
+    let tags = parse_defaults(&text).map_err(|err| format!("Invalid metadata defaults in '{}': {}", path.display(), err))?;
+    Ok(Some((path, tags)))
+}
+
+/// The book tags gathered from each source in order of precedence, remembering which source set
+/// each tag shown in the plan.
+///
+/// The chain, from the highest precedence:
+/// 1. the tag options on the command line,
+/// 2. the configuration of `--config`,
+/// 3. the metadata defaults file in the book's folder,
+/// 4. the metadata defaults file in the folder above, shared by an author's books,
+/// 5. the `--metadata-command` lookup,
+/// 6. the tags of the source files, for the date and language only.
+///
+/// Each source only fills the tags the ones before it left unset.
+#[derive(Debug, Default)]
+pub struct LayeredTags {
+    pub tags: BookTags,
+    /// Where each tag shown in the plan came from, by label.
+    origins: Vec<(&'static str, String)>,
+}
+
+impl LayeredTags {
+    /// Starts the chain with the tags given on the command line.
+    pub fn new(command_line: &BookTags) -> Self {
+        let mut layered = LayeredTags::default();
+        layered.fill(command_line, "the command line");
+        layered
+    }
+
+    /// Fills the tags still unset from the next source in the chain.
+    ///
+    /// # Arguments
+    ///
+    /// * `tags` - The tags the source sets.
+    /// * `origin` - The source as the plan names it, e.g. "the command line" or a quoted path.
+    pub fn fill(&mut self, tags: &BookTags, origin: &str) {
+        let mut source = tags.clone();
+        for ((label, tag), (_, value)) in fields(&mut self.tags).into_iter().zip(fields(&mut source)) {
+            if tag.is_none() && value.is_some() {
+                *tag = value.take();
+                if let Some(label) = label {
+                    self.origins.push((label, origin.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Lists the tags shown in the plan with where each came from, e.g.
+    /// "Author:   Frank Herbert (from 'Frank Herbert/m4btool.toml')".
+    pub fn describe(&self) -> Vec<String> {
+        let mut tags = self.tags.clone();
+        fields(&mut tags)
+            .into_iter()
+            .filter_map(|(label, value)| {
+                let label = label?;
+                let (_, origin) = self.origins.iter().find(|(shown, _)| *shown == label)?;
+                Some(format!("{:<9} {} (from {})", format!("{}:", label), value.as_ref()?, origin))
+            })
+            .collect()
+    }
+}
+
+/// The text tags, labelled when the plan shows them.
+fn fields(tags: &mut BookTags) -> [(Option<&'static str>, &mut Option<String>); 9] {
+    [
+        (Some("Title"), &mut tags.title),
+        (Some("Author"), &mut tags.author),
+        (Some("Narrator"), &mut tags.narrator),
+        (Some("Series"), &mut tags.series),
+        (Some("Genre"), &mut tags.genre),
+        (Some("Language"), &mut tags.language),
+        (None, &mut tags.year),
+        (None, &mut tags.date),
+        (None, &mut tags.description),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the keys of a defaults file, a genre list, and the errors for unknown keys and bad
+    /// languages.
+    #[test]
+    fn test_parse_defaults() {
+        let tags = parse_defaults("author = \"Frank Herbert\"\ngenre = [\"Science Fiction\", \"Classics\"]\nlanguage = \"en\"\n").unwrap();
+        assert_eq!(tags, BookTags {
+            author: Some("Frank Herbert".to_string()),
+            genre: Some("Science Fiction; Classics".to_string()),
+            language: Some("eng".to_string()),
+            ..BookTags::default()
+        });
+        assert_eq!(parse_defaults("# shared by every book\ngenre = \"Fantasy\"").unwrap().genre.as_deref(), Some("Fantasy"));
+        assert_eq!(parse_defaults("").unwrap(), BookTags::default());
+        assert_eq!(parse_defaults("\u{feff}author = \"Frank Herbert\"").unwrap().author.as_deref(), Some("Frank Herbert"));
+        assert!(parse_defaults("narator = \"Scott Brick\"").unwrap_err().contains("narator"));
+        assert!(parse_defaults("language = \"klingon\"").is_err());
+        assert!(parse_defaults("author = Frank Herbert").is_err());
+    }
+
+    /// Tests that `m4btool.toml` is read before `author.toml`, and that a folder without either
+    /// has no defaults.
+    #[test]
+    fn test_load_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(load_defaults(dir.path()), Ok(None));
+        fs::write(dir.path().join("author.toml"), "author = \"From author.toml\"").unwrap();
+        fs::write(dir.path().join("m4btool.toml"), "author = \"From m4btool.toml\"").unwrap();
+        let (path, tags) = load_defaults(dir.path()).unwrap().unwrap();
+        assert_eq!(path, dir.path().join("m4btool.toml"));
+        assert_eq!(tags.author.as_deref(), Some("From m4btool.toml"));
+        fs::write(dir.path().join("m4btool.toml"), "author = ").unwrap();
+        assert!(load_defaults(dir.path()).unwrap_err().contains("m4btool.toml"));
+    }
+
+    /// Tests the precedence chain: the command line beats the book's folder, which beats the
+    /// author's folder, which beats the metadata command, and the plan names each tag's source.
+    #[test]
+    fn test_layered_tags() {
+        let command_line = BookTags { title: Some("Dune".to_string()), ..BookTags::default() };
+        let book = BookTags { genre: Some("Classics".to_string()), ..BookTags::default() };
+        let author = BookTags {
+            author: Some("Frank Herbert".to_string()),
+            genre: Some("Science Fiction".to_string()),
+            language: Some("eng".to_string()),
+            ..BookTags::default()
+        };
+        let lookup = BookTags {
+            title: Some("Dune (Unabridged)".to_string()),
+            author: Some("F. Herbert".to_string()),
+            narrator: Some("Scott Brick".to_string()),
+            description: Some("A desert planet.".to_string()),
+            ..BookTags::default()
+        };
+        let mut layered = LayeredTags::new(&command_line);
+        layered.fill(&book, "'Dune/m4btool.toml'");
+        layered.fill(&author, "'m4btool.toml'");
+        layered.fill(&lookup, "--metadata-command");
+        assert_eq!(layered.tags, BookTags {
+            title: Some("Dune".to_string()),
+            author: Some("Frank Herbert".to_string()),
+            narrator: Some("Scott Brick".to_string()),
+            genre: Some("Classics".to_string()),
+            language: Some("eng".to_string()),
+            description: Some("A desert planet.".to_string()),
+            ..BookTags::default()
+        });
+        assert_eq!(layered.describe(), vec![
+            "Title:    Dune (from the command line)",
+            "Author:   Frank Herbert (from 'm4btool.toml')",
+            "Narrator: Scott Brick (from --metadata-command)",
+            "Genre:    Classics (from 'Dune/m4btool.toml')",
+            "Language: eng (from 'm4btool.toml')",
+        ]);
+    }
+}
@@ -0,0 +1,197 @@
+use m4btool::plan::Chapter;
+
+use crate::table::{format_duration, truncate};
+
+/// Start or end times that move by more than this count as a retimed chapter; smaller shifts
+/// come from rounding and from probing a file instead of reading its chapters.
+pub const RETIME_TOLERANCE_MS: u64 = 1000;
+
+/// How one chapter index compares between the two sides of `diff`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChapterDiff {
+    Same(Chapter),
+    /// Only the right side has a chapter at this index.
+    Added(Chapter),
+    /// Only the left side has a chapter at this index.
+    Removed(Chapter),
+    /// Both sides have the chapter, with a different title, different times, or both.
+    Changed { left: Chapter, right: Chapter, renamed: bool, retimed: bool },
+}
+
+impl ChapterDiff {
+    pub fn is_same(&self) -> bool {
+        matches!(self, ChapterDiff::Same(_))
+    }
+}
+
+/// Aligns two chapter lists by index and compares the chapters at each index.
+///
+/// Titles must match exactly; times are compared with `RETIME_TOLERANCE_MS` of slack.
+pub fn diff_chapters(left: &[Chapter], right: &[Chapter]) -> Vec<ChapterDiff> {
+    let moved = |a: u64, b: u64| a.abs_diff(b) > RETIME_TOLERANCE_MS;
+    (0..left.len().max(right.len()))
+        .map(|index| match (left.get(index), right.get(index)) {
+            (Some(left), Some(right)) => {
+                let renamed = left.title != right.title;
+                let retimed = moved(left.start_ms, right.start_ms) || moved(left.end_ms, right.end_ms);
+                if renamed || retimed {
+                    ChapterDiff::Changed { left: left.clone(), right: right.clone(), renamed, retimed }
+                } else {
+                    ChapterDiff::Same(left.clone())
+                }
+            }
+            (Some(left), None) => ChapterDiff::Removed(left.clone()),
+            (None, Some(right)) => ChapterDiff::Added(right.clone()),
+            (None, None) => unreachable!("the index is below the longer list's length"),
+        })
+        .collect()
+}
+
+/// Counts the differences, e.g. "3 of 4 chapters differ: 1 added, 1 renamed, 1 retimed", or reports
+/// that there are none.
+pub fn summarize(diffs: &[ChapterDiff]) -> String {
+    let count = |matches: fn(&ChapterDiff) -> bool| diffs.iter().filter(|diff| matches(diff)).count();
+    let differing = count(|diff| !diff.is_same());
+    if differing == 0 {
+        return format!("The {} chapters are the same", diffs.len());
+    }
+    let parts = [
+        (count(|diff| matches!(diff, ChapterDiff::Added(_))), "added"),
+        (count(|diff| matches!(diff, ChapterDiff::Removed(_))), "removed"),
+        (count(|diff| matches!(diff, ChapterDiff::Changed { renamed: true, .. })), "renamed"),
+        (count(|diff| matches!(diff, ChapterDiff::Changed { retimed: true, .. })), "retimed"),
+    ];
+    let parts: Vec<String> = parts.iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, label)| format!("{} {}", count, label))
+        .collect();
+    format!("{} of {} chapters differ: {}", differing, diffs.len(), parts.join(", "))
+}
+
+/// Renders every chapter in two columns, left side first, marking each differing row with `-`
+/// (removed), `+` (added), or `~` (renamed or retimed).
+///
+/// # Arguments
+///
+/// * `diffs` - The aligned chapters from `diff_chapters`.
+/// * `width` - The terminal width the two columns share.
+/// * `color` - Whether to color the differing rows.
+pub fn render_side_by_side(diffs: &[ChapterDiff], width: usize, color: bool) -> String {
+    let number_width = diffs.len().to_string().len();
+    // The marker, the number, and the separator between the columns.
+    let column = width.saturating_sub(number_width + 5) / 2;
+    let cell = |chapter: Option<&Chapter>| {
+        let text = chapter.map(|chapter| format!("{} {}", format_duration(chapter.start_ms), chapter.title)).unwrap_or_default();
+        let text = truncate(&text, column);
+        let pad = column.saturating_sub(text.chars().count());
+        format!("{}{}", text, " ".repeat(pad))
+    };
+    let mut output = String::new();
+    for (index, diff) in diffs.iter().enumerate() {
+        let (marker, left, right, color_code) = match diff {
+            ChapterDiff::Same(chapter) => (' ', Some(chapter), Some(chapter), None),
+            ChapterDiff::Added(chapter) => ('+', None, Some(chapter), Some("32")),
+            ChapterDiff::Removed(chapter) => ('-', Some(chapter), None, Some("31")),
+            ChapterDiff::Changed { left, right, .. } => ('~', Some(left), Some(right), Some("33")),
+        };
+        let line = format!("{} {:>number_width$} {} │ {}", marker, index + 1, cell(left), cell(right));
+        output.push_str(&paint(line.trim_end(), color_code.filter(|_| color)));
+        output.push('\n');
+    }
+    output
+}
+
+/// Renders only the differing chapters, as `-` lines for the left side and `+` lines for the
+/// right side.
+pub fn render_unified(diffs: &[ChapterDiff], color: bool) -> String {
+    let line = |sign: char, index: usize, chapter: &Chapter| {
+        let text = format!(
+            "{}{:>3} {}-{} {}",
+            sign, index + 1, format_duration(chapter.start_ms), format_duration(chapter.end_ms), chapter.title
+        );
+        paint(&text, Some(if sign == '-' { "31" } else { "32" }).filter(|_| color)) + "\n"
+    };
+    let mut output = String::new();
+    for (index, diff) in diffs.iter().enumerate() {
+        match diff {
+            ChapterDiff::Same(_) => {}
+            ChapterDiff::Added(chapter) => output.push_str(&line('+', index, chapter)),
+            ChapterDiff::Removed(chapter) => output.push_str(&line('-', index, chapter)),
+            ChapterDiff::Changed { left, right, .. } => {
+                output.push_str(&line('-', index, left));
+                output.push_str(&line('+', index, right));
+            }
+        }
+    }
+    output
+}
+
+fn paint(text: &str, color_code: Option<&str>) -> String {
+    match color_code {
+        Some(code) => format!("\x1b[{}m{}\x1b[0m", code, text),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book() -> Vec<Chapter> {
+        vec![
+            Chapter::new(1, "Prologue", 0, 60_000),
+            Chapter::new(2, "Chapter 1", 60_000, 120_000),
+            Chapter::new(3, "Chapter 2", 120_000, 180_000),
+        ]
+    }
+
+    /// Tests added, removed, renamed, and retimed chapters, and the tolerance for small shifts.
+    #[test]
+    fn test_diff_chapters() {
+        let left = book();
+        assert!(diff_chapters(&left, &left).iter().all(ChapterDiff::is_same));
+
+        let mut right = book();
+        right[1].title = "The Desert".to_string();
+        right[2].end_ms = 181_500;
+        right.push(Chapter::new(4, "Epilogue", 181_500, 200_000));
+        let diffs = diff_chapters(&left, &right);
+        assert!(diffs[0].is_same());
+        assert!(matches!(diffs[1], ChapterDiff::Changed { renamed: true, retimed: false, .. }));
+        assert!(matches!(diffs[2], ChapterDiff::Changed { renamed: false, retimed: true, .. }));
+        assert_eq!(diffs[3], ChapterDiff::Added(right[3].clone()));
+        assert_eq!(summarize(&diffs), "3 of 4 chapters differ: 1 added, 1 renamed, 1 retimed");
+
+        let diffs = diff_chapters(&right, &left);
+        assert_eq!(diffs[3], ChapterDiff::Removed(right[3].clone()));
+
+        let mut shifted = book();
+        shifted[2].start_ms += RETIME_TOLERANCE_MS;
+        assert!(diff_chapters(&left, &shifted).iter().all(ChapterDiff::is_same));
+        assert_eq!(summarize(&diff_chapters(&left, &shifted)), "The 3 chapters are the same");
+    }
+
+    /// Tests both layouts without colors, and that colors only wrap the differing rows.
+    #[test]
+    fn test_render() {
+        let left = book();
+        let mut right = book()[..2].to_vec();
+        right[1].title = "The Desert".to_string();
+        let diffs = diff_chapters(&left, &right);
+        assert_eq!(
+            render_side_by_side(&diffs, 60, false),
+            "  1 0:00:00 Prologue            │ 0:00:00 Prologue\n\
+             ~ 2 0:01:00 Chapter 1           │ 0:01:00 The Desert\n\
+             - 3 0:02:00 Chapter 2           │\n"
+        );
+        assert_eq!(
+            render_unified(&diffs, false),
+            "-  2 0:01:00-0:02:00 Chapter 1\n\
+             +  2 0:01:00-0:02:00 The Desert\n\
+             -  3 0:02:00-0:03:00 Chapter 2\n"
+        );
+        let colored = render_side_by_side(&diffs, 60, true);
+        assert!(colored.starts_with("  1 0:00:00 Prologue"));
+        assert!(colored.contains("\x1b[33m~ 2 ") && colored.contains("\x1b[31m- 3 "));
+    }
+}
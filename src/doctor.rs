@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+use serde::Serialize;
+
+use crate::runner::CommandRunner;
+use crate::space::filesystem_space;
+
+/// The encoders a bug report needs to know about: the preferred AAC encoder, ffmpeg's built-in
+/// fallback, and Opus.
+const ENCODERS: [&str; 3] = ["libfdk_aac", "aac", "libopus"];
+
+/// A self-diagnostic of the environment m4btool runs in, printed by `doctor` and `--version` and
+/// saved in post-mortem bundles.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostics {
+    /// The m4btool version.
+    pub version: String,
+    /// The operating system and architecture, e.g. "linux-x86_64".
+    pub platform: String,
+    /// The first line of `ffmpeg -version`, or `None` if ffmpeg cannot be run.
+    pub ffmpeg: Option<String>,
+    /// The first line of `ffprobe -version`, or `None` if ffprobe cannot be run.
+    pub ffprobe: Option<String>,
+    /// Whether ffmpeg lists each encoder of interest.
+    pub encoders: BTreeMap<String, bool>,
+    /// The default directory for work files.
+    pub temp_dir: PathBuf,
+    /// Free bytes in the temp directory, if known.
+    pub temp_free_bytes: Option<u64>,
+    /// The number of CPUs available to the process, if known.
+    pub cpus: Option<usize>,
+}
+
+impl Diagnostics {
+    /// Renders the diagnostics as one "name: value" line each.
+    pub fn render(&self) -> String {
+        let found = |version: &Option<String>| version.clone().unwrap_or_else(|| "not found".to_string());
+        let encoders: Vec<String> = ENCODERS.iter()
+            .map(|name| format!("{} {}", name, if self.encoders.get(*name) == Some(&true) { "yes" } else { "no" }))
+            .collect();
+        let free = self.temp_free_bytes.map_or("free space unknown".to_string(), |bytes| format!("{} MB free", bytes / 1_000_000));
+        format!(
+            "m4btool {}\nplatform: {}\nffmpeg: {}\nffprobe: {}\nencoders: {}\ntemp dir: {} ({})\ncpus: {}\n",
+            self.version,
+            self.platform,
+            found(&self.ffmpeg),
+            found(&self.ffprobe),
+            encoders.join(", "),
+            self.temp_dir.display(),
+            free,
+            self.cpus.map_or("unknown".to_string(), |cpus| cpus.to_string())
+        )
+    }
+}
+
+/// Detects ffmpeg, ffprobe, and the encoders the same way a build runs them, and gathers the
+/// temp directory's free space and the CPU count.
+pub fn diagnose(runner: &dyn CommandRunner, temp_dir: &Path) -> Diagnostics {
+    let encoder_list = query(runner, "ffmpeg", &["-hide_banner", "-encoders"]).unwrap_or_default();
+    Diagnostics {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+        ffmpeg: first_line(query(runner, "ffmpeg", &["-version"])),
+        ffprobe: first_line(query(runner, "ffprobe", &["-version"])),
+        encoders: ENCODERS.iter().map(|name| (name.to_string(), lists_encoder(&encoder_list, name))).collect(),
+        temp_dir: temp_dir.to_path_buf(),
+        temp_free_bytes: filesystem_space(temp_dir).map(|(_, free)| free),
+        cpus: thread::available_parallelism().ok().map(|cpus| cpus.get()),
+    }
+}
+
+/// Runs a tool and returns its stdout if it succeeds.
+fn query(runner: &dyn CommandRunner, program: &str, args: &[&str]) -> Option<String> {
+    let output = runner.query(Command::new(program).args(args)).ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn first_line(text: Option<String>) -> Option<String> {
+    text?.lines().next().map(str::to_string)
+}
+
+/// Whether `ffmpeg -encoders` output lists an encoder, e.g. " A....D libfdk_aac  Fraunhofer FDK AAC".
+fn lists_encoder(encoder_list: &str, name: &str) -> bool {
+    encoder_list.lines().any(|line| line.split_whitespace().nth(1) == Some(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use {std::io, std::os::unix::process::ExitStatusExt, std::process::{ExitStatus, Output}};
+
+    /// Answers `-version` and `-encoders` with canned ffmpeg output; ffprobe is missing.
+    #[cfg(unix)]
+    struct CannedRunner;
+
+    #[cfg(unix)]
+    impl CommandRunner for CannedRunner {
+        fn run(&self, _: &mut Command) -> io::Result<Output> {
+            unreachable!("diagnostics only query")
+        }
+
+        fn query(&self, command: &mut Command) -> io::Result<Output> {
+            if command.get_program() == "ffprobe" {
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+            let args: Vec<_> = command.get_args().collect();
+            let stdout = if args.contains(&"-encoders".as_ref()) {
+                "Encoders:\n V..... = Video\n ------\n A....D aac                  AAC (Advanced Audio Coding)\n A....D libopus              libopus Opus\n"
+            } else {
+                "ffmpeg version 7.1 Copyright (c) 2000-2024 the FFmpeg developers\nbuilt with gcc 14\n"
+            };
+            Ok(Output { status: ExitStatus::from_raw(0), stdout: stdout.as_bytes().to_vec(), stderr: Vec::new() })
+        }
+    }
+
+    /// Tests detection from canned tool output, the text report, and the JSON shape.
+    #[cfg(unix)]
+    #[test]
+    fn test_diagnose() {
+        let diagnostics = diagnose(&CannedRunner, Path::new("/nonexistent/tmp"));
+        assert_eq!(diagnostics.ffmpeg.as_deref(), Some("ffmpeg version 7.1 Copyright (c) 2000-2024 the FFmpeg developers"));
+        assert_eq!(diagnostics.ffprobe, None);
+        assert_eq!(diagnostics.temp_free_bytes, None);
+
+        let report = diagnostics.render();
+        assert!(report.starts_with(&format!("m4btool {}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(report.contains("\nffprobe: not found\n"));
+        assert!(report.contains("\nencoders: libfdk_aac no, aac yes, libopus yes\n"));
+        assert!(report.contains("\ntemp dir: /nonexistent/tmp (free space unknown)\n"));
+
+        let json = serde_json::to_value(&diagnostics).unwrap();
+        assert_eq!(json["encoders"]["libfdk_aac"], false);
+        assert_eq!(json["ffprobe"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,881 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use tempfile::{Builder, NamedTempFile, TempPath};
+
+pub use m4btool::plan::{AacEncoder, AacProfile, EncodeSettings};
+
+use crate::console;
+use crate::ffmpeg_warnings::WarningCheck;
+use crate::postmortem::append_command_log;
+use crate::shell::os_args;
+use crate::stats;
+use crate::probe::AudioInfo;
+use crate::runner::CommandRunner;
+
+/// The loudness filter of `--normalize`: -18 LUFS with true peaks at most -3 dBFS, the range
+/// audiobook stores ask for.
+pub const NORMALIZE_FILTER: &str = "loudnorm=I=-18:TP=-3:LRA=11";
+
+/// What the first pass of `--normalize-two-pass` measured of a file, so that the second pass can apply
+/// `NORMALIZE_FILTER` linearly instead of adjusting the gain as the audio goes by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS.
+    pub integrated: f64,
+    /// True peak in dBTP.
+    pub true_peak: f64,
+    /// Loudness range in LU.
+    pub range: f64,
+    /// The gating threshold in LUFS.
+    pub threshold: f64,
+    /// The gain the filter suggests for the second pass, in dB.
+    pub offset: f64,
+}
+
+impl Loudness {
+    /// Reads the report that the first pass's `loudnorm` filter prints.
+    ///
+    /// # Returns
+    ///
+    /// The measurement, or `None` if a value is missing or infinite, as for a silent file.
+    pub fn parse(report: &str) -> Option<Self> {
+        let field = |key| stats::loudnorm_field(report, key).filter(|value| value.is_finite());
+        Some(Loudness {
+            integrated: field("input_i")?,
+            true_peak: field("input_tp")?,
+            range: field("input_lra")?,
+            threshold: field("input_thresh")?,
+            offset: field("target_offset")?,
+        })
+    }
+
+    /// The loudness filter of the second pass, which applies this measurement.
+    fn filter(&self) -> String {
+        format!(
+            "{}:measured_I={:.2}:measured_TP={:.2}:measured_LRA={:.2}:measured_thresh={:.2}:offset={:.2}:linear=true",
+            NORMALIZE_FILTER, self.integrated, self.true_peak, self.range, self.threshold, self.offset
+        )
+    }
+}
+
+/// Describes book-wide encode settings for the dry-run plan, e.g. "libfdk_aac HE-AAC at 64k,
+/// mono, 44100 Hz, loudness normalized".
+pub fn describe_settings(settings: &EncodeSettings) -> String {
+    let rate = match (settings.encoder, settings.aac_vbr, settings.bitrate) {
+        (AacEncoder::Native, Some(quality), _) => format!("VBR quality {}", quality),
+        (_, _, Some(bits_per_second)) => format!("{}k", bits_per_second / 1000),
+        _ => "the source's bitrate".to_string(),
+    };
+    let mut parts = vec![
+        format!("{} {} at {}", settings.encoder.codec_name(), settings.aac_profile.name(), rate),
+        settings.channels.map_or_else(|| "the source's channels".to_string(), layout_name),
+        settings.sample_rate.map_or_else(|| "the source's sample rate".to_string(), |rate| format!("{} Hz", rate)),
+    ];
+    if settings.normalize {
+        parts.push("loudness normalized".to_string());
+    }
+    parts.join(", ")
+}
+
+/// Resolves the constant bitrate of one file's encode with `settings`: the override if present,
+/// otherwise the source's bitrate kept within what the encoder accepts and speech needs, and
+/// `FALLBACK_BIT_RATE` if neither is known.
+///
+/// A corrupt header can report an absurd bitrate, so a source value below
+/// `MIN_SOURCE_BIT_RATE` counts as unknown, and one above `MAX_BIT_RATE_PER_CHANNEL` per output
+/// channel or above `MAX_BIT_RATE` is lowered to that ceiling.
+///
+/// # Arguments
+///
+/// * `bitrate_override` - A bitrate in bits per second from `--bitrate-ladder` or the overrides
+///   file, used as it is, as is `--bitrate` without one.
+/// * `source_bit_rate` - The bitrate the source reports, if any.
+/// * `source_channels` - The source's channel count, used unless `--channels` sets one.
+///
+/// # Returns
+///
+/// The bitrate, with a warning when the source's value was not used as it is.
+pub fn resolve_bit_rate(settings: &EncodeSettings, bitrate_override: Option<u64>, source_bit_rate: Option<u64>, source_channels: Option<u32>) -> TargetBitRate {
+    if let Some(bits_per_second) = bitrate_override.or(settings.bitrate) {
+        return TargetBitRate { bits_per_second, warning: None };
+    }
+    let Some(source) = source_bit_rate else {
+        return TargetBitRate { bits_per_second: FALLBACK_BIT_RATE, warning: None };
+    };
+    if source < MIN_SOURCE_BIT_RATE {
+        return TargetBitRate {
+            bits_per_second: FALLBACK_BIT_RATE,
+            warning: Some(format!(
+                "the source reports {} bps, below {} kbps; encoding at the default {} kbps",
+                source, MIN_SOURCE_BIT_RATE / 1000, FALLBACK_BIT_RATE / 1000
+            )),
+        };
+    }
+    let channels = settings.channels.or(source_channels).unwrap_or(2).max(1);
+    let ceiling = (MAX_BIT_RATE_PER_CHANNEL * u64::from(channels)).min(MAX_BIT_RATE);
+    if source > ceiling {
+        return TargetBitRate {
+            bits_per_second: ceiling,
+            warning: Some(format!(
+                "the source reports {} kbps, more than the {} kbps ceiling for {}; encoding at {} kbps",
+                source / 1000, ceiling / 1000, layout_name(channels), ceiling / 1000
+            )),
+        };
+    }
+    TargetBitRate { bits_per_second: source, warning: None }
+}
+
+/// Builds the ffmpeg output arguments for the resampling and downmixing of `settings` and the
+/// filters of `pass`, followed by any extra arguments. The analysis pass writes no audio and
+/// takes none of the extra arguments.
+fn settings_args(settings: &EncodeSettings, pass: EncodePass) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(rate) = settings.sample_rate {
+        args.extend(["-ar".to_string(), rate.to_string()]);
+    }
+    if let Some(channels) = settings.channels {
+        args.extend(["-ac".to_string(), channels.to_string()]);
+    }
+    // The gain follows the normalization so that it still sets a file apart from the rest.
+    let mut filters = Vec::new();
+    match pass {
+        _ if !settings.normalize => {}
+        EncodePass::Single => filters.push(NORMALIZE_FILTER.to_string()),
+        EncodePass::Analysis => filters.push(format!("{}:print_format=json", NORMALIZE_FILTER)),
+        EncodePass::Final(loudness) => filters.push(loudness.filter()),
+    }
+    if let Some(gain) = settings.gain_db {
+        filters.push(format!("volume={}dB", gain));
+    }
+    if !filters.is_empty() {
+        args.extend(["-af".to_string(), filters.join(",")]);
+    }
+    if pass != EncodePass::Analysis {
+        args.extend(settings.extra_args.iter().cloned());
+    }
+    args
+}
+
+/// Roughly how many times faster than real time one encode job runs, used to warn before long
+/// encodes.
+pub const ENCODE_SPEED: u64 = 40;
+
+/// Estimates how long encoding this much audio takes, in milliseconds.
+pub fn estimate_encode_ms(total_ms: u64, jobs: usize, two_pass: bool) -> u64 {
+    let passes = if two_pass { 2 } else { 1 };
+    total_ms * passes / ENCODE_SPEED / jobs.max(1) as u64
+}
+
+/// Picks the channel count for a book whose layout was not forced with `--channels`: the smallest
+/// count among the sources, so a mix of mono and stereo files becomes mono rather than having its
+/// mono speech upmixed into fake stereo at twice the size.
+///
+/// # Returns
+///
+/// `None` when every source has the same known count and nothing needs converting, or when no
+/// source could be probed. If some sources could not be probed, the smallest known count is
+/// forced so that they cannot break the concatenation.
+pub fn common_channels(sources: &[Option<u32>]) -> Option<u32> {
+    let smallest = *sources.iter().flatten().min()?;
+    let mixed = sources.iter().any(|channels| *channels != Some(smallest));
+    mixed.then_some(smallest)
+}
+
+/// Describes the channel count chosen by `common_channels`, e.g. "mono, the smallest channel
+/// count among the files; 2 of 5 files are converted".
+pub fn describe_channels(sources: &[Option<u32>], channels: u32) -> String {
+    let converted = sources.iter().filter(|source| **source != Some(channels)).count();
+    format!(
+        "{}, the smallest channel count among the files; {} of {} files are converted",
+        layout_name(channels), converted, sources.len()
+    )
+}
+
+/// Picks the sample rate every file is resampled to when some files are encoded with their own
+/// settings: the concat only copies files of one rate together, so the book takes the most
+/// common rate among the sources, the higher one on ties.
+///
+/// # Returns
+///
+/// The rate in Hz, or `None` when no source could be probed.
+pub fn common_sample_rate(sources: &[Option<u32>]) -> Option<u32> {
+    let mut counts: Vec<(u32, usize)> = Vec::new();
+    for rate in sources.iter().flatten() {
+        match counts.iter_mut().find(|(known, _)| known == rate) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((*rate, 1)),
+        }
+    }
+    counts.into_iter().max_by_key(|&(rate, count)| (count, rate)).map(|(rate, _)| rate)
+}
+
+/// Names a channel count: "mono", "stereo", or e.g. "6 channels".
+pub fn layout_name(channels: u32) -> String {
+    match channels {
+        1 => "mono".to_string(),
+        2 => "stereo".to_string(),
+        _ => format!("{} channels", channels),
+    }
+}
+
+/// The part of a source file kept after trimming, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrimWindow {
+    pub start_ms: u64,
+    pub length_ms: u64,
+}
+
+/// Computes the part of a file that remains after trimming its start and end.
+///
+/// # Arguments
+///
+/// * `duration_ms` - The full duration of the source file.
+/// * `trim_start_ms` - How much to cut from the start.
+/// * `trim_end_ms` - How much to cut from the end.
+///
+/// # Returns
+///
+/// The remaining window, or an error message if the trims leave nothing of the file.
+pub fn plan_trim(duration_ms: u64, trim_start_ms: u64, trim_end_ms: u64) -> Result<TrimWindow, String> {
+    let trimmed_ms = trim_start_ms + trim_end_ms;
+    if trimmed_ms >= duration_ms {
+        return Err(format!(
+            "trimming {} ms leaves nothing of a {} ms file",
+            trimmed_ms, duration_ms
+        ));
+    }
+    Ok(TrimWindow { start_ms: trim_start_ms, length_ms: duration_ms - trimmed_ms })
+}
+
+/// Formats milliseconds as seconds for ffmpeg time options, e.g. 1500 as "1.500".
+fn format_seconds(ms: u64) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+/// Re-encodes an audio file to AAC with the selected encoder, at a constant bitrate that
+/// matches the source file's bitrate within sane limits (see `EncodeSettings::resolve_bit_rate`),
+/// or at the native
+/// encoder's VBR quality when one is set.
+/// The output is written to a temporary file in the work directory.
+///
+/// # Arguments
+///
+/// * `file_path` - The file path of the source audio file.
+/// * `settings` - Book-wide resampling and downmixing settings.
+/// * `target` - The bitrate to encode at, from `target_bits_per_second`.
+/// * `passlog` - When set and the settings normalize, measure the loudness in a first pass
+///   whose report goes to this pass log (see `passlog_path`), and apply it in the second.
+/// * `trim` - When set, only this part of the source is encoded.
+/// * `tools` - What runs ffmpeg, and where its output and log go.
+///
+/// # Returns
+///
+/// The temporary file with the re-encoded audio, or why there is none.
+pub fn reencode_audio(file_path: &str, settings: &EncodeSettings, target: &TargetBitRate, passlog: Option<&Path>, trim: Option<TrimWindow>, tools: &EncodeTools) -> Result<NamedTempFile, EncodeFailure> {
+    // Create a temporary file for the re-encoded output with a .m4a extension.
+    let tmpfile = Builder::new().suffix(".m4a").tempfile_in(tools.work_dir).map_err(|_| EncodeFailure::Failed)?;
+    if let Some(warning) = &target.warning {
+        console::warn(format!("'{}': {}", file_path, warning));
+    }
+    let bitrate_str = format_bitrate(target.bits_per_second, settings.exact_bitrate);
+    let job = EncodeJob { source: Path::new(file_path), settings, bitrate: &bitrate_str, trim };
+
+    // For two-pass encoding, run an analysis pass that only measures the loudness, and keep
+    // its report in the pass log for the second pass.
+    let mut pass = EncodePass::Single;
+    if let Some(passlog) = passlog.filter(|_| settings.normalize) {
+        let mut command = Command::new("ffmpeg");
+        command.args(encode_args(&job, EncodePass::Analysis, tmpfile.path()));
+        let output = tools.run(&mut command)?;
+        if !output.status.success() {
+            console::error(format!("First encoding pass failed for '{}': {}", file_path, console::last_stderr_line(&output)));
+            return Err(EncodeFailure::Failed);
+        }
+        let report = fs::write(passlog, &output.stderr).and_then(|()| fs::read_to_string(passlog));
+        match report.ok().as_deref().and_then(Loudness::parse) {
+            Some(loudness) => pass = EncodePass::Final(loudness),
+            None => console::warn(format!("Could not measure the loudness of '{}'; normalizing it in one pass", file_path)),
+        }
+    }
+
+    // Execute ffmpeg to re-encode the audio stream at the desired bitrate or quality.
+    let mut command = Command::new("ffmpeg");
+    command.args(encode_args(&job, pass, tmpfile.path()));
+    let output = tools.run(&mut command)?;
+    if output.status.success() {
+        Ok(tmpfile)
+    } else {
+        console::error(format!("Re-encoding failed for '{}': {}", file_path, console::last_stderr_line(&output)));
+        Err(EncodeFailure::Failed)
+    }
+}
+
+/// What runs a file's encode, and where its output and log go.
+#[derive(Clone, Copy)]
+pub struct EncodeTools<'a> {
+    pub runner: &'a dyn CommandRunner,
+    /// The check of `--fail-on-warning`, if given.
+    pub warnings: Option<&'a WarningCheck>,
+    /// The directory in which the encoded file is created.
+    pub work_dir: &'a Path,
+    /// The log file that receives each ffmpeg run's command line and full stderr.
+    pub log: &'a Path,
+}
+
+impl EncodeTools<'_> {
+    /// Runs an encode command and appends its full stderr to the file's log. A run that
+    /// succeeds with a warning the check looks for is an `EncodeFailure::Warned`.
+    fn run(&self, command: &mut Command) -> Result<Output, EncodeFailure> {
+        let output = self.runner.run(command).map_err(|_| EncodeFailure::Failed)?;
+        if let Err(err) = append_command_log(self.log, command, &output) {
+            console::warn(format!("Could not write the encode log '{}': {}", self.log.display(), err));
+        }
+        match self.warnings.filter(|_| output.status.success()).and_then(|check| check.find(&output.stderr)) {
+            Some(warning) => Err(EncodeFailure::Warned(warning)),
+            None => Ok(output),
+        }
+    }
+}
+
+/// Why a file was not encoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodeFailure {
+    /// ffmpeg failed or could not be run. The error has been reported, and the book can use
+    /// the source file instead.
+    Failed,
+    /// ffmpeg succeeded but printed this warning of a damaged result, which with
+    /// `--fail-on-warning` fails the build.
+    Warned(String),
+}
+
+/// The bitrate used when neither an override nor the source's bitrate is known.
+const FALLBACK_BIT_RATE: u64 = 128_000;
+
+/// Source bitrates below this come from a broken header and count as unknown.
+const MIN_SOURCE_BIT_RATE: u64 = 8_000;
+
+/// More than this per channel adds nothing audible to speech.
+const MAX_BIT_RATE_PER_CHANNEL: u64 = 160_000;
+
+/// The highest constant bitrate either AAC encoder is asked for, whatever the channel count.
+const MAX_BIT_RATE: u64 = 512_000;
+
+/// The bitrate chosen for one file's encode by `EncodeSettings::resolve_bit_rate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetBitRate {
+    pub bits_per_second: u64,
+    /// Why the source's bitrate was not used as it is.
+    pub warning: Option<String>,
+}
+
+/// Picks the encode bitrate of a file from its probed audio details, or `None` when it could
+/// not be probed (see `EncodeSettings::resolve_bit_rate`).
+pub fn target_bits_per_second(source: Option<&AudioInfo>, settings: &EncodeSettings, bitrate_override: Option<u64>) -> TargetBitRate {
+    let info = source.cloned().unwrap_or_default();
+    resolve_bit_rate(settings, bitrate_override, info.bit_rate, info.channels)
+}
+
+/// Formats bits per second as an ffmpeg bitrate: rounded down to whole kbps ("130k") for
+/// readable logs, or the exact integer ("130500") when `exact` is set.
+fn format_bitrate(bits_per_second: u64, exact: bool) -> String {
+    if exact {
+        bits_per_second.to_string()
+    } else {
+        format!("{}k", bits_per_second / 1000)
+    }
+}
+
+/// Everything about one file's encode that does not depend on the pass.
+#[derive(Debug, Clone, Copy)]
+pub struct EncodeJob<'a> {
+    pub source: &'a Path,
+    pub settings: &'a EncodeSettings,
+    /// The target bitrate as an ffmpeg value, e.g. "128k".
+    pub bitrate: &'a str,
+    pub trim: Option<TrimWindow>,
+}
+
+/// Which ffmpeg run of a per-file encode to build arguments for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodePass {
+    /// A normal single-pass encode.
+    Single,
+    /// The first of two passes, which only measures the loudness and prints its report.
+    Analysis,
+    /// The second of two passes, which normalizes with the first pass's measurement.
+    Final(Loudness),
+}
+
+/// Builds the ffmpeg arguments for one run of a per-file encode, without the program name.
+///
+/// The order is fixed: the input-side seek, the input, the tool's codec options, the encoder
+/// options of `--encoder-arg`, the output-side
+/// length limit, `--sample-rate`/`--channels`, the filters, `--ffmpeg-encode-args`, and finally
+/// the output. The analysis pass encodes nothing, so it has no codec options and ends in `-f null -`.
+pub fn encode_args(job: &EncodeJob, pass: EncodePass, output: &Path) -> Vec<OsString> {
+    // Seek on the input side and limit the output length to apply the trim window.
+    let mut args = Vec::new();
+    if let Some(window) = job.trim {
+        args.extend(os_args(&["-ss", &format_seconds(window.start_ms)]));
+    }
+    args.push("-i".into());
+    args.push(job.source.into());
+    args.extend(os_args(&["-vn", "-map", "0:a"]));
+    if pass != EncodePass::Analysis {
+        args.extend(os_args(&["-c:a", job.settings.encoder.codec_name()]));
+        args.extend(profile_args(job.settings.aac_profile));
+        match (job.settings.encoder, job.settings.aac_vbr) {
+            (AacEncoder::Native, Some(quality)) => args.extend(os_args(&["-q:a", &quality.to_string()])),
+            _ => args.extend(os_args(&["-b:a", job.bitrate])),
+        }
+        args.extend(job.settings.encoder_args.iter().map(OsString::from));
+    }
+    if let Some(window) = job.trim {
+        args.extend(os_args(&["-t", &format_seconds(window.length_ms)]));
+    }
+    args.extend(settings_args(job.settings, pass).into_iter().map(OsString::from));
+
+    if pass == EncodePass::Analysis {
+        args.extend(os_args(&["-f", "null", "-y", "-"]));
+    } else {
+        args.push("-y".into());
+        args.push(output.into());
+    }
+    args
+}
+
+/// The codec option that selects `profile`; none for LC, the encoders' default.
+fn profile_args(profile: AacProfile) -> Vec<OsString> {
+    match profile {
+        AacProfile::Lc => Vec::new(),
+        AacProfile::He => os_args(&["-profile:a", "aac_he"]),
+    }
+}
+
+/// The format `--lead-in` silence is encoded in, that of the encoded files, so that the concat
+/// can copy it together with them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LeadInFormat {
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bits_per_second: u64,
+}
+
+impl LeadInFormat {
+    /// Takes the format from the first encoded file of the book, or from `--sample-rate` and
+    /// `--channels`, which every encoded file has. Without either, CD audio's 44.1 kHz stereo.
+    pub fn matching(settings: &EncodeSettings, first_encoded: Option<&AudioInfo>) -> Self {
+        let sample_rate = settings.sample_rate.or(first_encoded.and_then(|info| info.sample_rate)).unwrap_or(44_100);
+        let channels = settings.channels.or(first_encoded.and_then(|info| info.channels)).unwrap_or(2);
+        let source_bit_rate = first_encoded.and_then(|info| info.bit_rate);
+        let bits_per_second = resolve_bit_rate(settings, None, source_bit_rate, Some(channels)).bits_per_second;
+        LeadInFormat { sample_rate, channels, bits_per_second }
+    }
+}
+
+/// Builds the ffmpeg arguments that encode `length_ms` of silence from ffmpeg's `anullsrc`
+/// source in `format`, with the book's encoder and encoder options, without the program name.
+pub fn lead_in_args(settings: &EncodeSettings, format: LeadInFormat, length_ms: u64, output: &Path) -> Vec<OsString> {
+    let layout = match format.channels {
+        1 => "mono".to_string(),
+        2 => "stereo".to_string(),
+        channels => format!("{}c", channels),
+    };
+    let source = format!("anullsrc=r={}:cl={}", format.sample_rate, layout);
+    let mut args = os_args(&["-f", "lavfi", "-i", &source, "-t", &format_seconds(length_ms), "-c:a", settings.encoder.codec_name()]);
+    args.extend(profile_args(settings.aac_profile));
+    match (settings.encoder, settings.aac_vbr) {
+        (AacEncoder::Native, Some(quality)) => args.extend(os_args(&["-q:a", &quality.to_string()])),
+        _ => args.extend(os_args(&["-b:a", &format_bitrate(format.bits_per_second, settings.exact_bitrate)])),
+    }
+    args.extend(settings.encoder_args.iter().map(OsString::from));
+    args.push("-y".into());
+    args.push(output.into());
+    args
+}
+
+/// Encodes the silence of `--lead-in`, which the mux puts before the first file.
+///
+/// # Returns
+///
+/// The path of the encoded silence, removed when dropped, or an error message.
+pub fn make_lead_in(settings: &EncodeSettings, format: LeadInFormat, length_ms: u64, work_dir: &Path, runner: &dyn CommandRunner) -> Result<TempPath, String> {
+    let lead_in = Builder::new().suffix(".m4a").tempfile_in(work_dir)
+        .map_err(|err| format!("Could not create the lead-in: {}", err))?
+        .into_temp_path();
+    let output = runner.run(Command::new("ffmpeg").args(lead_in_args(settings, format, length_ms, &lead_in)))
+        .map_err(|err| format!("Could not execute ffmpeg: {}", err))?;
+    if !output.status.success() {
+        return Err(console::last_stderr_line(&output));
+    }
+    Ok(lead_in)
+}
+
+/// Returns the pass log prefix for the two-pass encode of one input file.
+///
+/// Each file gets its own prefix inside the run's work directory, so encodes that run
+/// at the same time never read or overwrite each other's pass logs.
+///
+/// # Arguments
+///
+/// * `work_dir` - The temporary directory holding this run's intermediate files.
+/// * `job_index` - The position of the file in the input list.
+pub fn passlog_path(work_dir: &Path, job_index: usize) -> PathBuf {
+    work_dir.join(format!("passlog-{:04}", job_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FileOverride;
+    use tempfile::tempdir;
+
+    /// Tests that a 60-second file trimmed by 10 seconds at each end becomes a 40-second chapter.
+    #[test]
+    fn test_plan_trim() {
+        assert_eq!(plan_trim(60_000, 10_000, 10_000), Ok(TrimWindow { start_ms: 10_000, length_ms: 40_000 }));
+        assert_eq!(format_seconds(10_000), "10.000");
+        assert_eq!(format_seconds(1_234), "1.234");
+        assert!(plan_trim(60_000, 30_000, 30_000).is_err());
+        assert!(plan_trim(5_000, 15_000, 0).is_err());
+    }
+
+    /// Tests that exact bitrates keep the bits per second that kbps rounding drops.
+    #[test]
+    fn test_format_bitrate() {
+        assert_eq!(format_bitrate(130_500, false), "130k");
+        assert_eq!(format_bitrate(130_500, true), "130500");
+        assert_eq!(format_bitrate(128_000, false), "128k");
+        assert_eq!(format_bitrate(128_000, true), "128000");
+    }
+
+    /// Tests the bitrate resolution at the edges: the floor, the per-channel ceiling, the
+    /// encoder's ceiling, and that overrides and `--channels` take precedence.
+    #[test]
+    fn test_resolve_bit_rate() {
+        let settings = EncodeSettings::default();
+        let resolve = |settings: &EncodeSettings, source: Option<u64>, channels: Option<u32>| resolve_bit_rate(settings, None, source, channels);
+        let used = |target: TargetBitRate| (target.bits_per_second, target.warning.is_some());
+
+        // Unknown, and below or at the floor.
+        assert_eq!(used(resolve(&settings, None, Some(2))), (FALLBACK_BIT_RATE, false));
+        assert_eq!(used(resolve(&settings, Some(0), Some(2))), (FALLBACK_BIT_RATE, true));
+        assert_eq!(used(resolve(&settings, Some(7_999), Some(1))), (FALLBACK_BIT_RATE, true));
+        assert_eq!(used(resolve(&settings, Some(8_000), Some(1))), (8_000, false));
+
+        // The per-channel ceiling, with an unknown layout counted as stereo.
+        assert_eq!(used(resolve(&settings, Some(160_000), Some(1))), (160_000, false));
+        assert_eq!(used(resolve(&settings, Some(160_001), Some(1))), (160_000, true));
+        assert_eq!(used(resolve(&settings, Some(320_000), Some(2))), (320_000, false));
+        assert_eq!(used(resolve(&settings, Some(4_608_000), Some(2))), (320_000, true));
+        assert_eq!(used(resolve(&settings, Some(4_608_000), None)), (320_000, true));
+        assert_eq!(used(resolve(&settings, Some(4_608_000), Some(0))), (160_000, true));
+
+        // The encoder's ceiling beats the per-channel one for many channels.
+        assert_eq!(used(resolve(&settings, Some(960_000), Some(6))), (MAX_BIT_RATE, true));
+        assert_eq!(used(resolve(&settings, Some(u64::MAX), Some(u32::MAX))), (MAX_BIT_RATE, true));
+
+        // --channels decides the ceiling over the source's layout.
+        let mono = EncodeSettings { channels: Some(1), ..Default::default() };
+        assert_eq!(used(resolve(&mono, Some(320_000), Some(2))), (160_000, true));
+
+        // An override is used as it is.
+        assert_eq!(used(resolve_bit_rate(&settings, Some(4_608_000), Some(64_000), Some(1))), (4_608_000, false));
+        assert_eq!(used(resolve_bit_rate(&settings, Some(4_000), None, None)), (4_000, false));
+
+        assert_eq!(
+            resolve(&settings, Some(4_608_000), Some(2)).warning.as_deref(),
+            Some("the source reports 4608 kbps, more than the 320 kbps ceiling for stereo; encoding at 320 kbps")
+        );
+    }
+
+    /// Tests the resampling and downmixing arguments.
+    #[test]
+    fn test_encode_settings_args() {
+        assert!(settings_args(&EncodeSettings::default(), EncodePass::Single).is_empty());
+        let settings = EncodeSettings { sample_rate: Some(44100), channels: Some(1), ..Default::default() };
+        assert_eq!(settings_args(&settings, EncodePass::Single), vec!["-ar", "44100", "-ac", "1"]);
+    }
+
+    /// Tests that the estimate scales with passes and parallel jobs.
+    #[test]
+    fn test_estimate_encode_ms() {
+        let twenty_hours = 20 * 3_600_000;
+        assert_eq!(estimate_encode_ms(twenty_hours, 1, false), 30 * 60_000);
+        assert_eq!(estimate_encode_ms(twenty_hours, 1, true), 60 * 60_000);
+        assert_eq!(estimate_encode_ms(twenty_hours, 4, false), 450_000);
+        assert_eq!(estimate_encode_ms(twenty_hours, 0, false), 30 * 60_000);
+    }
+
+    /// Tests that mixed layouts settle on the smallest channel count and uniform ones are kept.
+    #[test]
+    fn test_common_channels() {
+        assert_eq!(common_channels(&[Some(1), Some(2), Some(2)]), Some(1));
+        assert_eq!(common_channels(&[Some(6), Some(2)]), Some(2));
+        assert_eq!(common_channels(&[Some(2), Some(2)]), None);
+        assert_eq!(common_channels(&[Some(2), None]), Some(2));
+        assert_eq!(common_channels(&[None, None]), None);
+        assert_eq!(common_channels(&[]), None);
+        assert_eq!(
+            describe_channels(&[Some(1), Some(2), Some(1), None], 1),
+            "mono, the smallest channel count among the files; 2 of 4 files are converted"
+        );
+        assert_eq!(layout_name(6), "6 channels");
+    }
+
+    /// Tests that every encode job gets its own pass log inside the work directory.
+    #[test]
+    fn test_passlog_paths_are_distinct_per_job() {
+        let work_dir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = (0..8).map(|job| passlog_path(work_dir.path(), job)).collect();
+        for (i, path) in paths.iter().enumerate() {
+            assert!(path.starts_with(work_dir.path()));
+            assert!(paths[i + 1..].iter().all(|other| other != path));
+        }
+    }
+
+    fn strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter().map(|arg| arg.to_string_lossy().to_string()).collect()
+    }
+
+    /// Tests matching the lead-in to the encoded files, and golden tests for encoding it.
+    #[test]
+    fn test_lead_in() {
+        let encoded = AudioInfo { sample_rate: Some(22_050), channels: Some(1), bit_rate: Some(64_000), ..AudioInfo::default() };
+        let settings = EncodeSettings::default();
+        let format = LeadInFormat::matching(&settings, Some(&encoded));
+        assert_eq!(format, LeadInFormat { sample_rate: 22_050, channels: 1, bits_per_second: 64_000 });
+        assert_eq!(
+            strings(lead_in_args(&settings, format, 2_500, Path::new("/tmp/lead.m4a"))),
+            ["-f", "lavfi", "-i", "anullsrc=r=22050:cl=mono", "-t", "2.500", "-c:a", "libfdk_aac", "-b:a", "64k", "-y", "/tmp/lead.m4a"]
+        );
+
+        let resampled = EncodeSettings { encoder: AacEncoder::Native, aac_vbr: Some(1.2), sample_rate: Some(44_100), channels: Some(6), ..Default::default() };
+        let format = LeadInFormat::matching(&resampled, Some(&encoded));
+        assert_eq!((format.sample_rate, format.channels), (44_100, 6));
+        assert_eq!(
+            strings(lead_in_args(&resampled, format, 500, Path::new("/tmp/lead.m4a"))),
+            ["-f", "lavfi", "-i", "anullsrc=r=44100:cl=6c", "-t", "0.500", "-c:a", "aac", "-q:a", "1.2", "-y", "/tmp/lead.m4a"]
+        );
+        assert_eq!(LeadInFormat::matching(&settings, None), LeadInFormat { sample_rate: 44_100, channels: 2, bits_per_second: 128_000 });
+    }
+
+    /// Golden test for a plain single-pass encode.
+    #[test]
+    fn test_encode_args_single_pass() {
+        let settings = EncodeSettings::default();
+        let job = EncodeJob { source: Path::new("in/01 Intro.mp3"), settings: &settings, bitrate: "64k", trim: None };
+        assert_eq!(
+            strings(encode_args(&job, EncodePass::Single, Path::new("/tmp/out.m4a"))),
+            ["-i", "in/01 Intro.mp3", "-vn", "-map", "0:a", "-c:a", "libfdk_aac", "-b:a", "64k", "-y", "/tmp/out.m4a"]
+        );
+    }
+
+    /// Golden test for a trimmed, resampled encode with extra arguments.
+    #[test]
+    fn test_encode_args_trim_settings_and_extra_args() {
+        let settings = EncodeSettings { sample_rate: Some(44100), channels: Some(1), extra_args: vec!["-af".to_string(), "volume=2".to_string()], ..Default::default() };
+        let trim = Some(TrimWindow { start_ms: 12_500, length_ms: 60_000 });
+        let job = EncodeJob { source: Path::new("in.mp3"), settings: &settings, bitrate: "128k", trim };
+        assert_eq!(
+            strings(encode_args(&job, EncodePass::Single, Path::new("out.m4a"))),
+            [
+                "-ss", "12.500", "-i", "in.mp3", "-vn", "-map", "0:a", "-c:a", "libfdk_aac", "-b:a", "128k",
+                "-t", "60.000", "-ar", "44100", "-ac", "1", "-af", "volume=2", "-y", "out.m4a",
+            ]
+        );
+    }
+
+    /// Golden tests for the codec options of each encoder, with and without a VBR quality.
+    #[test]
+    fn test_encode_args_per_encoder() {
+        let codec_args = |encoder, aac_vbr| {
+            let settings = EncodeSettings { encoder, aac_vbr, ..Default::default() };
+            let job = EncodeJob { source: Path::new("in.mp3"), settings: &settings, bitrate: "64k", trim: None };
+            strings(encode_args(&job, EncodePass::Single, Path::new("out.m4a")))[5..9].to_vec()
+        };
+        assert_eq!(codec_args(AacEncoder::Fdk, None), ["-c:a", "libfdk_aac", "-b:a", "64k"]);
+        assert_eq!(codec_args(AacEncoder::Fdk, Some(1.5)), ["-c:a", "libfdk_aac", "-b:a", "64k"]);
+        assert_eq!(codec_args(AacEncoder::Native, None), ["-c:a", "aac", "-b:a", "64k"]);
+        assert_eq!(codec_args(AacEncoder::Native, Some(1.5)), ["-c:a", "aac", "-q:a", "1.5"]);
+        assert_eq!(codec_args(AacEncoder::Native, Some(2.0)), ["-c:a", "aac", "-q:a", "2"]);
+    }
+
+    /// Golden test for encoder options: right after the codec and bitrate, before the trim,
+    /// the settings, and `--ffmpeg-encode-args`.
+    #[test]
+    fn test_encode_args_encoder_args() {
+        let settings = EncodeSettings {
+            channels: Some(1),
+            encoder_args: vec!["-afterburner".to_string(), "1".to_string(), "-cutoff".to_string(), "18000".to_string()],
+            extra_args: vec!["-af".to_string(), "volume=2".to_string()],
+            ..Default::default()
+        };
+        let trim = Some(TrimWindow { start_ms: 0, length_ms: 60_000 });
+        let job = EncodeJob { source: Path::new("in.mp3"), settings: &settings, bitrate: "64k", trim };
+        assert_eq!(
+            strings(encode_args(&job, EncodePass::Single, Path::new("out.m4a"))),
+            [
+                "-ss", "0.000", "-i", "in.mp3", "-vn", "-map", "0:a", "-c:a", "libfdk_aac", "-b:a", "64k",
+                "-afterburner", "1", "-cutoff", "18000",
+                "-t", "60.000", "-ac", "1", "-af", "volume=2", "-y", "out.m4a",
+            ]
+        );
+    }
+
+    /// Tests the book-wide rate: the most common one, the higher on ties, ignoring unprobed files.
+    #[test]
+    fn test_common_sample_rate() {
+        assert_eq!(common_sample_rate(&[Some(44_100), Some(48_000), Some(44_100), None]), Some(44_100));
+        assert_eq!(common_sample_rate(&[Some(22_050), Some(44_100)]), Some(44_100));
+        assert_eq!(common_sample_rate(&[None, None]), None);
+    }
+
+    /// Golden test for a file with its own settings in `--config`: stereo at a higher bitrate,
+    /// louder, and trimmed, next to a mono book resampled to one rate.
+    #[test]
+    fn test_encode_args_file_override() {
+        let book = EncodeSettings { sample_rate: Some(44_100), channels: Some(1), ..Default::default() };
+        let interlude = FileOverride { bitrate: Some(192_000), channels: Some(2), gain_db: Some(-1.5), trim_start_ms: Some(2_000), trim_end_ms: None };
+        let settings = interlude.apply(&book);
+        let bitrate = format_bitrate(interlude.bitrate.unwrap(), false);
+        let trim = Some(TrimWindow { start_ms: 2_000, length_ms: 58_000 });
+        let job = EncodeJob { source: Path::new("in/07 - Interlude.flac"), settings: &settings, bitrate: &bitrate, trim };
+        assert_eq!(
+            strings(encode_args(&job, EncodePass::Single, Path::new("out.m4a"))),
+            [
+                "-ss", "2.000", "-i", "in/07 - Interlude.flac", "-vn", "-map", "0:a", "-c:a", "libfdk_aac", "-b:a", "192k",
+                "-t", "58.000", "-ar", "44100", "-ac", "2", "-af", "volume=-1.5dB", "-y", "out.m4a",
+            ]
+        );
+        assert_eq!(interlude.describe(&settings), "encoded with its own settings: 192k, stereo at 44100 Hz, -1.5 dB, 2 s trimmed from the start");
+
+        let narration = EncodeJob { source: Path::new("in/08.mp3"), settings: &book, bitrate: "64k", trim: None };
+        assert_eq!(
+            strings(encode_args(&narration, EncodePass::Single, Path::new("out.m4a")))[9..],
+            ["-ar", "44100", "-ac", "1", "-y", "out.m4a"]
+        );
+    }
+
+    /// Golden test for both runs of a two-pass encode: the first only measures the loudness, and
+    /// the second applies what it measured.
+    #[test]
+    fn test_encode_args_two_pass() {
+        let settings = EncodeSettings {
+            normalize: true,
+            gain_db: Some(2.0),
+            extra_args: vec!["-cutoff".to_string(), "18000".to_string()],
+            ..EncodeSettings::default()
+        };
+        let trim = Some(TrimWindow { start_ms: 1_500, length_ms: 60_000 });
+        let job = EncodeJob { source: Path::new("in.mp3"), settings: &settings, bitrate: "96k", trim };
+        assert_eq!(
+            strings(encode_args(&job, EncodePass::Analysis, Path::new("out.m4a"))),
+            [
+                "-ss", "1.500", "-i", "in.mp3", "-vn", "-map", "0:a", "-t", "60.000",
+                "-af", "loudnorm=I=-18:TP=-3:LRA=11:print_format=json,volume=2dB",
+                "-f", "null", "-y", "-",
+            ]
+        );
+        let loudness = Loudness { integrated: -23.456, true_peak: -1.07, range: 6.3, threshold: -33.8, offset: 0.25 };
+        assert_eq!(
+            strings(encode_args(&job, EncodePass::Final(loudness), Path::new("out.m4a"))),
+            [
+                "-ss", "1.500", "-i", "in.mp3", "-vn", "-map", "0:a", "-c:a", "libfdk_aac", "-b:a", "96k", "-t", "60.000",
+                "-af", "loudnorm=I=-18:TP=-3:LRA=11:measured_I=-23.46:measured_TP=-1.07:measured_LRA=6.30:measured_thresh=-33.80:offset=0.25:linear=true,volume=2dB",
+                "-cutoff", "18000", "-y", "out.m4a",
+            ]
+        );
+    }
+
+    /// Tests reading the first pass's loudness report, which a silent file cannot give.
+    #[test]
+    fn test_parse_loudness() {
+        let report = r#"[Parsed_loudnorm_0 @ 0x55d5c8a0c1c0]
+{
+	"input_i" : "-23.46",
+	"input_tp" : "-1.07",
+	"input_lra" : "6.30",
+	"input_thresh" : "-33.80",
+	"output_i" : "-18.02",
+	"output_tp" : "-3.00",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.25"
+}
+"#;
+        assert_eq!(
+            Loudness::parse(report),
+            Some(Loudness { integrated: -23.46, true_peak: -1.07, range: 6.3, threshold: -33.8, offset: 0.25 })
+        );
+        assert_eq!(Loudness::parse(&report.replace("-23.46", "-inf")), None);
+        assert_eq!(Loudness::parse(&report.replace("target_offset", "offset")), None);
+    }
+
+    /// Records every command and runs it successfully, printing the same stderr each time.
+    #[cfg(unix)]
+    struct CannedRunner {
+        stderr: &'static str,
+        commands: std::cell::RefCell<Vec<String>>,
+    }
+
+    #[cfg(unix)]
+    impl CannedRunner {
+        fn new(stderr: &'static str) -> Self {
+            CannedRunner { stderr, commands: Default::default() }
+        }
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for CannedRunner {
+        fn run(&self, command: &mut Command) -> std::io::Result<Output> {
+            use std::os::unix::process::ExitStatusExt;
+            self.commands.borrow_mut().push(crate::shell::command_line(command));
+            Ok(Output { status: std::process::ExitStatus::from_raw(0), stdout: Vec::new(), stderr: self.stderr.as_bytes().to_vec() })
+        }
+    }
+
+    /// Tests that with --fail-on-warning an encode that succeeds with a warning is a
+    /// `Warned` failure rather than a failed encode the source stands in for, and that
+    /// without it, or without a warning, the encode succeeds.
+    #[cfg(unix)]
+    #[test]
+    fn test_warned_encode() {
+        let work = tempdir().unwrap();
+        let log = work.path().join("001-one.mp3.log");
+        let settings = EncodeSettings { bitrate: Some(64_000), ..EncodeSettings::default() };
+        let check = WarningCheck::new(&[]).unwrap();
+        let warned = CannedRunner::new("[mp3float @ 0x5581] Header missing\nsize=  1024kB time=00:01:05.30\n");
+        let tools = EncodeTools { runner: &warned, warnings: Some(&check), work_dir: work.path(), log: &log };
+        let target = target_bits_per_second(None, &settings, None);
+        assert_eq!(
+            reencode_audio("one.mp3", &settings, &target, None, None, &tools).unwrap_err(),
+            EncodeFailure::Warned("[mp3float @ 0x5581] Header missing".to_string())
+        );
+        assert!(fs::read_to_string(&log).unwrap().contains("Header missing"));
+
+        assert!(reencode_audio("one.mp3", &settings, &target, None, None, &EncodeTools { warnings: None, ..tools }).is_ok());
+        let clean = CannedRunner::new("size=  1024kB time=00:01:05.30\n");
+        assert!(reencode_audio("one.mp3", &settings, &target, None, None, &EncodeTools { runner: &clean, ..tools }).is_ok());
+    }
+
+    /// Tests that a two-pass encode keeps the first pass's report in its pass log and normalizes
+    /// with the measured values in the second pass.
+    #[cfg(unix)]
+    #[test]
+    fn test_two_pass_encode() {
+        let work = tempdir().unwrap();
+        let log = work.path().join("001-one.mp3.log");
+        let passlog = passlog_path(work.path(), 0);
+        let settings = EncodeSettings { bitrate: Some(64_000), normalize: true, ..EncodeSettings::default() };
+        let runner = CannedRunner::new("{\n\"input_i\" : \"-23.46\",\n\"input_tp\" : \"-1.07\",\n\"input_lra\" : \"6.30\",\n\"input_thresh\" : \"-33.80\",\n\"target_offset\" : \"0.25\"\n}\n");
+        let tools = EncodeTools { runner: &runner, warnings: None, work_dir: work.path(), log: &log };
+        assert!(reencode_audio("one.mp3", &settings, &target_bits_per_second(None, &settings, None), Some(&passlog), None, &tools).is_ok());
+        assert!(fs::read_to_string(&passlog).unwrap().contains("\"input_i\" : \"-23.46\""));
+        let commands = runner.commands.borrow();
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].contains("print_format=json") && commands[0].ends_with("-f null -y -"));
+        assert!(commands[1].contains("measured_I=-23.46:measured_TP=-1.07:measured_LRA=6.30:measured_thresh=-33.80:offset=0.25:linear=true"));
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::Path;
+
+use crate::encode::{reencode_audio, EncodeSettings, EncodeTools, TargetBitRate, TrimWindow, ENCODE_SPEED};
+use crate::profile;
+use crate::runner::SystemRunner;
+use crate::table::format_duration;
+
+/// How much of one file `--estimate` encodes to measure the encoder's speed on this machine.
+pub const SAMPLE_MS: u64 = 20_000;
+
+/// What `--estimate` knows about one source file: how much of it is encoded, and at what bitrate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceEstimate {
+    /// The encoded length in milliseconds, after any trim.
+    pub duration_ms: u64,
+    /// The target bitrate in bits per second.
+    pub bits_per_second: u64,
+}
+
+/// The predicted size and encode time of a book, shown with `--estimate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub file_count: usize,
+    /// Files whose duration could not be probed and that are left out of the totals.
+    pub unknown_files: usize,
+    pub audio_ms: u64,
+    /// The projected size of the audio in bytes, without the cover and chapter metadata.
+    pub output_bytes: u64,
+    pub encode_ms: u64,
+    /// How many times faster than real time one encode job runs.
+    pub speed: f64,
+    /// Whether `speed` was measured by a benchmark encode rather than assumed.
+    pub measured: bool,
+    pub jobs: usize,
+}
+
+impl Estimate {
+    /// Adds up the sources and projects the encode time.
+    ///
+    /// # Arguments
+    ///
+    /// * `sources` - Each source file, or `None` for one that could not be probed.
+    /// * `measured_speed` - The speed from `benchmark_speed`; without it, a typical speed is assumed.
+    /// * `jobs` - How many files are encoded at the same time.
+    /// * `two_pass` - Whether every file is encoded twice.
+    pub fn new(sources: &[Option<SourceEstimate>], measured_speed: Option<f64>, jobs: usize, two_pass: bool) -> Self {
+        let known: Vec<&SourceEstimate> = sources.iter().flatten().collect();
+        let audio_ms = known.iter().map(|source| source.duration_ms).sum();
+        let output_bytes = known.iter().map(|source| source.duration_ms * source.bits_per_second / 8_000).sum();
+        let speed = measured_speed.filter(|speed| *speed > 0.0).unwrap_or(ENCODE_SPEED as f64);
+        let passes = if two_pass { 2.0 } else { 1.0 };
+        // Files are encoded whole, so more jobs than files do not help.
+        let jobs = jobs.clamp(1, known.len().max(1));
+        let encode_ms = (audio_ms as f64 * passes / speed / jobs as f64).round() as u64;
+        Estimate {
+            file_count: sources.len(),
+            unknown_files: sources.len() - known.len(),
+            audio_ms,
+            output_bytes,
+            encode_ms,
+            speed,
+            measured: measured_speed.is_some_and(|speed| speed > 0.0),
+            jobs,
+        }
+    }
+
+    /// Renders the estimate as the indented summary printed instead of building.
+    pub fn render(&self) -> String {
+        let mut report = format!("Estimate for {} files (approximate):\n", self.file_count);
+        let speed = format!("{:.0}x realtime, {}", self.speed, if self.measured { "measured" } else { "assumed" });
+        let rows = [
+            ("Audio", format_duration(self.audio_ms)),
+            ("Output size", format!("about {:.0} MB", self.output_bytes as f64 / 1_000_000.0)),
+            ("Encode time", format!(
+                "about {} minutes ({}, {} {})",
+                self.encode_ms.div_ceil(60_000), speed, self.jobs, if self.jobs == 1 { "job" } else { "jobs" }
+            )),
+        ];
+        for (label, value) in rows {
+            report.push_str(&format!("  {:<13} {}\n", format!("{}:", label), value));
+        }
+        if self.unknown_files > 0 {
+            report.push_str(&format!("  {} of {} files could not be probed and are not counted\n", self.unknown_files, self.file_count));
+        }
+        report
+    }
+}
+
+/// Measures how many times faster than real time this machine encodes with the chosen settings,
+/// by encoding up to `SAMPLE_MS` of one file into the work directory.
+///
+/// # Returns
+///
+/// The speed, or `None` if the benchmark encode failed or was too quick to time.
+pub fn benchmark_speed(file_path: &str, duration_ms: u64, settings: &EncodeSettings, target: &TargetBitRate, work_dir: &Path) -> Option<f64> {
+    let sample = TrimWindow { start_ms: 0, length_ms: duration_ms.min(SAMPLE_MS) };
+    let dir = tempfile::tempdir_in(work_dir).ok()?;
+    let log = dir.path().join("benchmark.log");
+    let tools = EncodeTools { runner: &SystemRunner, warnings: None, work_dir: dir.path(), log: &log };
+    let (encoded, elapsed) = profile::measure(|| reencode_audio(file_path, settings, target, None, Some(sample), &tools));
+    encoded.ok()?;
+    (!elapsed.is_zero()).then(|| sample.length_ms as f64 / 1000.0 / elapsed.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the projected size and time: ten hours at 64 kbps is 288 MB, and at 120x realtime
+    /// over two jobs it encodes in two and a half minutes.
+    #[test]
+    fn test_estimate() {
+        let hour = SourceEstimate { duration_ms: 3_600_000, bits_per_second: 64_000 };
+        let sources = vec![Some(hour); 10];
+        let estimate = Estimate::new(&sources, Some(120.0), 2, false);
+        assert_eq!(estimate.audio_ms, 36_000_000);
+        assert_eq!(estimate.output_bytes, 288_000_000);
+        assert_eq!(estimate.encode_ms, 150_000);
+        assert!(estimate.measured);
+
+        let two_pass = Estimate::new(&sources, Some(120.0), 2, true);
+        assert_eq!(two_pass.encode_ms, 300_000);
+        let assumed = Estimate::new(&[Some(hour), None], None, 8, false);
+        assert_eq!((assumed.speed, assumed.measured, assumed.jobs, assumed.unknown_files), (ENCODE_SPEED as f64, false, 1, 1));
+        assert_eq!(assumed.encode_ms, 90_000);
+    }
+
+    /// Tests the printed summary.
+    #[test]
+    fn test_render() {
+        let sources = [Some(SourceEstimate { duration_ms: 5_400_000, bits_per_second: 128_000 }), None];
+        assert_eq!(
+            Estimate::new(&sources, Some(60.0), 1, false).render(),
+            "Estimate for 2 files (approximate):\n\
+             \x20 Audio:        1:30:00\n\
+             \x20 Output size:  about 86 MB\n\
+             \x20 Encode time:  about 2 minutes (60x realtime, measured, 1 job)\n\
+             \x20 1 of 2 files could not be probed and are not counted\n"
+        );
+    }
+}
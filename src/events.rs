@@ -0,0 +1,133 @@
+//! Progress events of a build.
+//!
+//! With `--progress-json` the command-line tool writes one `Event` per line as a JSON object,
+//! tagged by its `event` field, e.g. `{"event":"phase","phase":"encode"}`. Programs that wrap
+//! the tool read the same types back with `parse_event_line` instead of parsing its
+//! human-readable output, which changes between releases.
+//!
+//! # Stability
+//!
+//! Within a major version the events keep their names and fields. New events and new optional
+//! fields may be added in minor releases, so readers should skip events they do not know.
+
+/// One step of a build as it happens.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Event {
+    /// A build phase started and the previous one, if any, ended. A phase may start more than once.
+    Phase { phase: BuildPhase },
+    /// One source file finished encoding, in the order files finish rather than book order.
+    FileDone {
+        /// The file's position in the book, counted from 1.
+        index: usize,
+        total: usize,
+        /// The file name, without its folder.
+        file: String,
+        elapsed_ms: u64,
+        outcome: FileOutcome,
+    },
+    /// How much of the book the final mux has written, at most once a second.
+    MuxProgress { out_time_ms: u64 },
+    Warning { message: String },
+    Error { message: String },
+    /// The build ended; always the last event.
+    Result {
+        /// The process exit status, e.g. 0 for success or 2 for a book written without parts.
+        exit_code: u8,
+        /// The book written, if the build got as far as choosing one.
+        #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+        output: Option<String>,
+    },
+}
+
+/// A phase of a build, written in snake case, e.g. `"encode"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum BuildPhase {
+    /// Finding the source files and reading the metadata options.
+    Scan,
+    /// Reading the sources' durations and tags.
+    Probe,
+    Encode,
+    /// Laying out the chapters and preparing the cover.
+    Metadata,
+    Mux,
+    /// Checking the written book.
+    Verify,
+}
+
+impl BuildPhase {
+    /// The phase's name, as the events write it.
+    pub fn name(self) -> &'static str {
+        match self {
+            BuildPhase::Scan => "scan",
+            BuildPhase::Probe => "probe",
+            BuildPhase::Encode => "encode",
+            BuildPhase::Metadata => "metadata",
+            BuildPhase::Mux => "mux",
+            BuildPhase::Verify => "verify",
+        }
+    }
+}
+
+/// How a source file ended up in the book.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FileOutcome {
+    Encoded,
+    /// Taken unchanged from an `--incremental` cache.
+    Cached,
+    /// The encode failed and the source file is used as it is.
+    Original,
+}
+
+/// Renders an event as one line of JSON, without the line break.
+#[cfg(feature = "serde")]
+pub fn event_line(event: &Event) -> String {
+    serde_json::to_string(event).expect("events always serialize")
+}
+
+/// Reads one line written by `event_line`.
+///
+/// # Returns
+///
+/// The event, or an error message for a line that is not a known event.
+#[cfg(feature = "serde")]
+pub fn parse_event_line(line: &str) -> Result<Event, String> {
+    serde_json::from_str(line).map_err(|err| err.to_string())
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    /// Tests the JSON shape of each event and that every line reads back as the same event.
+    #[test]
+    fn test_event_lines() {
+        let events = [
+            (Event::Phase { phase: BuildPhase::Encode }, r#"{"event":"phase","phase":"encode"}"#),
+            (
+                Event::FileDone { index: 3, total: 12, file: "03 - Storm.mp3".to_string(), elapsed_ms: 1_250, outcome: FileOutcome::Encoded },
+                r#"{"event":"file_done","index":3,"total":12,"file":"03 - Storm.mp3","elapsed_ms":1250,"outcome":"encoded"}"#,
+            ),
+            (Event::MuxProgress { out_time_ms: 83_450 }, r#"{"event":"mux_progress","out_time_ms":83450}"#),
+            (Event::Warning { message: "Cover is small".to_string() }, r#"{"event":"warning","message":"Cover is small"}"#),
+            (Event::Result { exit_code: 0, output: Some("Dune.m4b".to_string()) }, r#"{"event":"result","exit_code":0,"output":"Dune.m4b"}"#),
+            (Event::Result { exit_code: 1, output: None }, r#"{"event":"result","exit_code":1}"#),
+        ];
+        for (event, line) in events {
+            assert_eq!(event_line(&event), line);
+            assert_eq!(parse_event_line(line), Ok(event));
+        }
+        assert!(parse_event_line(r#"{"event":"launch"}"#).is_err());
+        assert!(parse_event_line(r#"{"event":"phase","phase":"launch"}"#).is_err());
+        for phase in [BuildPhase::Scan, BuildPhase::Probe, BuildPhase::Encode, BuildPhase::Metadata, BuildPhase::Mux, BuildPhase::Verify] {
+            assert_eq!(event_line(&Event::Phase { phase }), format!(r#"{{"event":"phase","phase":"{}"}}"#, phase.name()));
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! FFMETADATA generation and reading.
+//!
+//! ffmpeg reads chapters and global tags from a text file in its `FFMETADATA1` format. This
+//! module renders that text from plain chapter titles and durations or from a plan's chapters,
+//! and reads the chapters back from such a file, without touching the filesystem or running
+//! ffmpeg.
+
+use crate::book_tags::BookTags;
+use crate::plan::{lay_out_chapters, Chapter};
+
+/// Book-level tags written at the top of an FFMETADATA file.
+///
+/// Construct with `GlobalTags::default()` and set the fields you need; new fields may be added
+/// in minor releases.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[non_exhaustive]
+pub struct GlobalTags {
+    /// The book title (`title`).
+    pub title: Option<String>,
+    /// The author (`artist`).
+    pub artist: Option<String>,
+    /// The release year or date (`date`).
+    pub date: Option<String>,
+}
+
+impl From<&BookTags> for GlobalTags {
+    /// The title, author, and date of a book, or its year when the date is unknown.
+    fn from(tags: &BookTags) -> Self {
+        GlobalTags { title: tags.title.clone(), artist: tags.author.clone(), date: tags.date.clone().or_else(|| tags.year.clone()) }
+    }
+}
+
+impl GlobalTags {
+    fn pairs(&self) -> [(&'static str, Option<&String>); 3] {
+        [("title", self.title.as_ref()), ("artist", self.artist.as_ref()), ("date", self.date.as_ref())]
+    }
+}
+
+/// Renders an FFMETADATA file with the global tags and one chapter per `(title, duration)` entry.
+///
+/// Chapters are laid out back to back starting at zero, in milliseconds (`TIMEBASE=1/1000`).
+/// Characters with a meaning in the format (`=`, `;`, `#`, `\` and newlines) are escaped.
+///
+/// # Arguments
+///
+/// * `chapters` - The chapter titles with their durations in milliseconds, in order.
+/// * `global` - The book-level tags; unset tags are omitted.
+///
+/// # Returns
+///
+/// The complete file contents.
+///
+/// # Example
+///
+/// ```
+/// use m4btool::{write_ffmetadata, GlobalTags};
+///
+/// let chapters = vec![("Intro".to_string(), 1500)];
+/// let text = write_ffmetadata(&chapters, &GlobalTags::default());
+/// assert_eq!(text, ";FFMETADATA1\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=1500\ntitle=Intro\n");
+/// ```
+pub fn write_ffmetadata(chapters: &[(String, u64)], global: &GlobalTags) -> String {
+    write_ffmetadata_chapters(&lay_out_chapters(chapters), global)
+}
+
+/// Renders an FFMETADATA file like `write_ffmetadata`, from a plan's chapters, each at its own
+/// place on the timeline. A chapter's original title, such as the title before
+/// transliteration, is written after its title as `original_title`; Matroska keeps it, while
+/// MP4 chapter lists only store `title`.
+pub fn write_ffmetadata_chapters(chapters: &[Chapter], global: &GlobalTags) -> String {
+    let mut text = String::from(";FFMETADATA1\n");
+    for (key, value) in global.pairs() {
+        if let Some(value) = value {
+            text.push_str(&format!("{}={}\n", key, escape_value(value)));
+        }
+    }
+
+    for chapter in chapters {
+        text.push_str("[CHAPTER]\n");
+        text.push_str("TIMEBASE=1/1000\n");
+        text.push_str(&format!("START={}\n", chapter.start_ms));
+        text.push_str(&format!("END={}\n", chapter.end_ms));
+        text.push_str(&format!("title={}\n", escape_value(&chapter.title)));
+        if let Some(original_title) = &chapter.original_title {
+            text.push_str(&format!("original_title={}\n", escape_value(original_title)));
+        }
+    }
+    text
+}
+
+/// The header an FFMETADATA file starts with.
+const HEADER: &str = ";FFMETADATA1";
+
+/// A chapter read from an FFMETADATA file, with its times converted to milliseconds.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct TimedChapter {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    /// The `title` key, if the chapter has one.
+    pub title: Option<String>,
+}
+
+impl TimedChapter {
+    /// Creates a chapter from its start and end in milliseconds.
+    pub fn new(start_ms: u64, end_ms: u64, title: Option<&str>) -> Self {
+        TimedChapter { start_ms, end_ms, title: title.map(str::to_string) }
+    }
+}
+
+/// The contents of an FFMETADATA file.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct FfMetadata {
+    /// The global `key=value` tags before the first section, unescaped, in file order.
+    pub global: Vec<(String, String)>,
+    pub chapters: Vec<TimedChapter>,
+}
+
+impl FfMetadata {
+    /// Looks up a global tag by key, ignoring case like ffmpeg.
+    pub fn global_tag(&self, key: &str) -> Option<&str> {
+        self.global.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads an FFMETADATA file as ffmpeg would, and checks that its chapters can be muxed.
+///
+/// Lines starting with `;` or `#` are comments, and a backslash escapes the next character,
+/// including a newline that continues the value on the next line. Chapter times are in units of
+/// the chapter's `TIMEBASE`, ffmpeg's default of nanoseconds when it has none. `[STREAM]` and
+/// `[PROGRAM]` sections are accepted and skipped.
+///
+/// # Returns
+///
+/// The global tags and chapters, or an error message naming the line or chapter (counted from 1)
+/// that is wrong: a missing header, a line that is not `key=value`, a chapter without `START` or
+/// `END`, one that ends before it starts, or one that starts before the previous one ends.
+///
+/// # Example
+///
+/// ```
+/// use m4btool::ffmetadata::{read_ffmetadata, TimedChapter};
+///
+/// let metadata = read_ffmetadata(";FFMETADATA1\ntitle=Dune\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=1500\ntitle=Intro\n").unwrap();
+/// assert_eq!(metadata.global_tag("title"), Some("Dune"));
+/// assert_eq!(metadata.chapters, vec![TimedChapter::new(0, 1500, Some("Intro"))]);
+/// ```
+pub fn read_ffmetadata(text: &str) -> Result<FfMetadata, String> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let Some(body) = text.strip_prefix(HEADER) else {
+        return Err(format!("the file does not start with '{}'", HEADER));
+    };
+    let mut metadata = FfMetadata::default();
+    let mut section: Option<String> = None;
+    // The keys of the chapter being read, with the line it starts on.
+    let mut chapter: Option<(usize, Vec<(String, String)>)> = None;
+    let mut chapters = Vec::new();
+    for (line_number, line) in logical_lines(body) {
+        match line {
+            Line::Comment => {}
+            Line::Section(name) => {
+                chapters.extend(chapter.take());
+                if name == "CHAPTER" {
+                    chapter = Some((line_number, Vec::new()));
+                }
+                section = Some(name);
+            }
+            Line::Entry(key, value) => match (&section, &mut chapter) {
+                (None, _) => metadata.global.push((key, value)),
+                (Some(_), Some((_, keys))) => keys.push((key, value)),
+                (Some(_), None) => {}
+            },
+            Line::Invalid(raw) => return Err(format!("line {}: expected 'key=value', found '{}'", line_number, raw)),
+        }
+    }
+    chapters.extend(chapter);
+
+    for (index, (line_number, keys)) in chapters.iter().enumerate() {
+        let number = index + 1;
+        let value = |key: &str| keys.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)).map(|(_, value)| value.trim());
+        let (numerator, denominator) = match value("TIMEBASE") {
+            Some(timebase) => parse_timebase(timebase)
+                .ok_or_else(|| format!("chapter {} (line {}): invalid TIMEBASE '{}'", number, line_number, timebase))?,
+            None => (1, 1_000_000_000),
+        };
+        let time_ms = |key: &str| -> Result<u64, String> {
+            let raw = value(key).ok_or_else(|| format!("chapter {} (line {}): missing {}", number, line_number, key))?;
+            let units: u64 = raw.parse().map_err(|_| format!("chapter {} (line {}): invalid {} '{}'", number, line_number, key, raw))?;
+            Ok((units as u128 * numerator as u128 * 1000 / denominator as u128) as u64)
+        };
+        let (start_ms, end_ms) = (time_ms("START")?, time_ms("END")?);
+        if end_ms <= start_ms {
+            return Err(format!("chapter {} ends at {} ms, not after its start at {} ms", number, end_ms, start_ms));
+        }
+        if let Some(previous) = metadata.chapters.last() {
+            if start_ms < previous.end_ms {
+                return Err(format!("chapter {} starts at {} ms, before the previous chapter ends at {} ms", number, start_ms, previous.end_ms));
+            }
+        }
+        metadata.chapters.push(TimedChapter::new(start_ms, end_ms, value("title")));
+    }
+    Ok(metadata)
+}
+
+/// A line of an FFMETADATA file, with continued lines joined.
+enum Line {
+    Comment,
+    Section(String),
+    Entry(String, String),
+    Invalid(String),
+}
+
+/// Splits FFMETADATA text after the header into lines, each with the number of the line it
+/// starts on, resolving escapes and splitting entries at their first unescaped `=`.
+fn logical_lines(body: &str) -> Vec<(usize, Line)> {
+    let mut lines = Vec::new();
+    // The header is line 1, and the body starts at its end.
+    let (mut line_number, mut start_line) = (1, 1);
+    // The line as written, and its text before and after the first unescaped `=`.
+    let (mut raw, mut key, mut value) = (String::new(), String::new(), None::<String>);
+    let mut chars = body.chars().peekable();
+    loop {
+        let c = chars.next();
+        match c {
+            Some('\\') => {
+                let escaped = chars.next().unwrap_or('\\');
+                if escaped == '\n' {
+                    line_number += 1;
+                }
+                raw.extend(['\\', escaped]);
+                value.as_mut().unwrap_or(&mut key).push(escaped);
+            }
+            Some('\r') if chars.peek() == Some(&'\n') => {}
+            Some('=') if value.is_none() => {
+                raw.push('=');
+                value = Some(String::new());
+            }
+            Some(c) if c != '\n' => {
+                raw.push(c);
+                value.as_mut().unwrap_or(&mut key).push(c);
+            }
+            // The end of a line or of the file.
+            _ => {
+                if !raw.trim().is_empty() {
+                    lines.push((start_line, classify_line(&raw, std::mem::take(&mut key), value.take())));
+                }
+                if c.is_none() {
+                    return lines;
+                }
+                raw.clear();
+                key.clear();
+                value = None;
+                line_number += 1;
+                start_line = line_number;
+            }
+        }
+    }
+}
+
+fn classify_line(raw: &str, key: String, value: Option<String>) -> Line {
+    if raw.starts_with(';') || raw.starts_with('#') {
+        return Line::Comment;
+    }
+    if let Some(name) = raw.trim().strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        return Line::Section(name.to_string());
+    }
+    match value {
+        Some(value) => Line::Entry(key, value),
+        None => Line::Invalid(raw.trim().to_string()),
+    }
+}
+
+/// Parses a `TIMEBASE` such as `1/1000` into its numerator and a non-zero denominator.
+fn parse_timebase(timebase: &str) -> Option<(u64, u64)> {
+    let (numerator, denominator) = timebase.split_once('/')?;
+    let numerator = numerator.trim().parse().ok()?;
+    let denominator: u64 = denominator.trim().parse().ok()?;
+    (denominator > 0).then_some((numerator, denominator))
+}
+
+/// Escapes a value for the FFMETADATA format by prefixing special characters with a backslash.
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the exact output for global tags and consecutive chapters.
+    #[test]
+    fn test_write_ffmetadata() {
+        let chapters = vec![("Arrakis".to_string(), 61_250), ("The Desert".to_string(), 120_000)];
+        let global = GlobalTags { title: Some("Dune".to_string()), date: Some("1965-08-01".to_string()), ..GlobalTags::default() };
+        assert_eq!(
+            write_ffmetadata(&chapters, &global),
+            ";FFMETADATA1\n\
+             title=Dune\n\
+             date=1965-08-01\n\
+             [CHAPTER]\n\
+             TIMEBASE=1/1000\n\
+             START=0\n\
+             END=61250\n\
+             title=Arrakis\n\
+             [CHAPTER]\n\
+             TIMEBASE=1/1000\n\
+             START=61250\n\
+             END=181250\n\
+             title=The Desert\n"
+        );
+    }
+
+    /// Tests chapters placed after a lead-in and with a gap: each is written at its own times.
+    #[test]
+    fn test_write_ffmetadata_chapters() {
+        let chapters = [Chapter::new(1, "Arrakis", 2_000, 63_250), Chapter::new(2, "The Desert", 64_000, 184_000)];
+        let text = write_ffmetadata_chapters(&chapters, &GlobalTags::default());
+        let read_back = read_ffmetadata(&text).unwrap().chapters;
+        let spans: Vec<(u64, u64)> = read_back.iter().map(|chapter| (chapter.start_ms, chapter.end_ms)).collect();
+        assert_eq!(spans, vec![(2_000, 63_250), (64_000, 184_000)]);
+    }
+
+    /// Tests that a chapter's original title is written after its title.
+    #[test]
+    fn test_original_title() {
+        let mut chapter = Chapter::new(1, "Xu Zhang", 0, 1000);
+        chapter.original_title = Some("序章".to_string());
+        let text = write_ffmetadata_chapters(&[chapter], &GlobalTags::default());
+        assert!(text.ends_with("END=1000\ntitle=Xu Zhang\noriginal_title=序章\n"));
+    }
+
+    /// Tests that a written file reads back with the same tags and chapter times.
+    #[test]
+    fn test_read_ffmetadata_round_trip() {
+        let chapters = vec![("Q&A; part=1 #2".to_string(), 61_250), ("The Desert".to_string(), 120_000)];
+        let global = GlobalTags { title: Some("Dune".to_string()), ..GlobalTags::default() };
+        let metadata = read_ffmetadata(&write_ffmetadata(&chapters, &global)).unwrap();
+        assert_eq!(metadata.global, vec![("title".to_string(), "Dune".to_string())]);
+        assert_eq!(metadata.chapters, vec![
+            TimedChapter::new(0, 61_250, Some("Q&A; part=1 #2")),
+            TimedChapter::new(61_250, 181_250, Some("The Desert")),
+        ]);
+    }
+
+    /// Tests comments, continued lines, stream sections, CRLF line ends, and timebases other
+    /// than milliseconds, including ffmpeg's default of nanoseconds.
+    #[test]
+    fn test_read_ffmetadata() {
+        let text = ";FFMETADATA1\r\n\
+                    # made by hand\r\n\
+                    artist=Frank Herbert\r\n\
+                    comment=two\\\nlines\r\n\
+                    [STREAM]\r\n\
+                    title=ignored\r\n\
+                    [CHAPTER]\r\n\
+                    TIMEBASE=1/44100\r\n\
+                    START=0\r\n\
+                    END=88200\r\n\
+                    title=Prologue\r\n\
+                    \r\n\
+                    [CHAPTER]\r\n\
+                    START=2000000000\r\n\
+                    END=3500000000\r\n";
+        let metadata = read_ffmetadata(text).unwrap();
+        assert_eq!(metadata.global_tag("ARTIST"), Some("Frank Herbert"));
+        assert_eq!(metadata.global_tag("comment"), Some("two\nlines"));
+        assert_eq!(metadata.chapters, vec![TimedChapter::new(0, 2000, Some("Prologue")), TimedChapter::new(2000, 3500, None)]);
+    }
+
+    /// Tests the errors for files ffmpeg would reject or mux into a broken chapter list.
+    #[test]
+    fn test_read_ffmetadata_errors() {
+        let chapter = |start: &str, end: &str| format!("[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\n", start, end);
+        let read = |body: &str| read_ffmetadata(&format!(";FFMETADATA1\n{}", body)).unwrap_err();
+        assert_eq!(read_ffmetadata("title=Dune\n").unwrap_err(), "the file does not start with ';FFMETADATA1'");
+        assert_eq!(read("title Dune\n"), "line 2: expected 'key=value', found 'title Dune'");
+        assert_eq!(read("[CHAPTER]\nSTART=0\n"), "chapter 1 (line 2): missing END");
+        assert_eq!(read("[CHAPTER]\nTIMEBASE=1/0\nSTART=0\nEND=1\n"), "chapter 1 (line 2): invalid TIMEBASE '1/0'");
+        assert_eq!(read(&chapter("-5", "10")), "chapter 1 (line 2): invalid START '-5'");
+        assert_eq!(read(&chapter("1000", "1000")), "chapter 1 ends at 1000 ms, not after its start at 1000 ms");
+        assert_eq!(
+            read(&(chapter("0", "5000") + &chapter("4000", "9000"))),
+            "chapter 2 starts at 4000 ms, before the previous chapter ends at 5000 ms"
+        );
+        // A gap between chapters is allowed.
+        assert!(read_ffmetadata(&format!(";FFMETADATA1\n{}{}", chapter("0", "5000"), chapter("6000", "9000"))).is_ok());
+    }
+
+    /// Tests escaping of the format's special characters.
+    #[test]
+    fn test_escape_value() {
+        let chapters = vec![("Q&A; part=1 #2 \\ end\nnext".to_string(), 10)];
+        let text = write_ffmetadata(&chapters, &GlobalTags::default());
+        assert!(text.ends_with("title=Q&A\\; part\\=1 \\#2 \\\\ end\\\nnext\n"));
+    }
+}
@@ -0,0 +1,84 @@
+use regex::{Regex, RegexBuilder};
+
+/// Warnings ffmpeg prints while still exiting with status 0 that point to a damaged or
+/// misaligned result, matched case-insensitively against each line of its stderr.
+pub const DEFAULT_WARNING_PATTERNS: [&str; 7] = [
+    r"Non-monotonous DTS",
+    r"non monotonically increasing dts",
+    r"Queue input is backward in time",
+    r"Error while decoding stream",
+    r"Packet corrupt",
+    r"Header missing",
+    r"Too many packets buffered",
+];
+
+/// Checks ffmpeg's stderr for warnings that `--fail-on-warning` turns into failures.
+#[derive(Debug)]
+pub struct WarningCheck {
+    patterns: Vec<Regex>,
+}
+
+impl WarningCheck {
+    /// Compiles the patterns, or `DEFAULT_WARNING_PATTERNS` when none are given.
+    ///
+    /// # Returns
+    ///
+    /// The check, or an error message naming a pattern that is not a valid regular expression.
+    pub fn new(patterns: &[String]) -> Result<Self, String> {
+        let patterns: Vec<&str> = if patterns.is_empty() {
+            DEFAULT_WARNING_PATTERNS.to_vec()
+        } else {
+            patterns.iter().map(String::as_str).collect()
+        };
+        let patterns = patterns.into_iter()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|err| format!("Invalid --ffmpeg-warning-pattern '{}': {}", pattern, err))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(WarningCheck { patterns })
+    }
+
+    /// Finds the first line of `stderr` that matches a pattern.
+    pub fn find(&self, stderr: &[u8]) -> Option<String> {
+        String::from_utf8_lossy(stderr)
+            .split(['\n', '\r'])
+            .map(str::trim)
+            .find(|line| self.patterns.iter().any(|pattern| pattern.is_match(line)))
+            .map(str::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ffmpeg's stderr of a concat whose timestamps went backwards, which still exits with 0.
+    const WARNED: &str = "Input #0, concat, from 'list.txt':\n\
+        Stream mapping:\n  Stream #0:0 -> #0:0 (copy)\n\
+        [ipod @ 0x55d0c] Application provided invalid, non monotonically increasing dts to muxer in stream 0: 4410 >= 4410\n\
+        size=  10240kB time=00:11:02.10 bitrate= 126.6kbits/s speed= 412x\n";
+    /// The same run without the warning.
+    const CLEAN: &str = "Input #0, concat, from 'list.txt':\n\
+        Stream mapping:\n  Stream #0:0 -> #0:0 (copy)\n\
+        size=  10240kB time=00:11:02.10 bitrate= 126.6kbits/s speed= 412x\n";
+
+    /// Tests finding the default and custom patterns in canned stderr, with and without a warning.
+    #[test]
+    fn test_find_warning() {
+        let check = WarningCheck::new(&[]).unwrap();
+        assert_eq!(
+            check.find(WARNED.as_bytes()).as_deref(),
+            Some("[ipod @ 0x55d0c] Application provided invalid, non monotonically increasing dts to muxer in stream 0: 4410 >= 4410")
+        );
+        assert_eq!(check.find(CLEAN.as_bytes()), None);
+        assert_eq!(check.find(b"[mp3 @ 0x1] Header missing\r"), Some("[mp3 @ 0x1] Header missing".to_string()));
+
+        let custom = WarningCheck::new(&["speed= *4\\d\\dx".to_string()]).unwrap();
+        assert!(custom.find(CLEAN.as_bytes()).is_some());
+        assert_eq!(custom.find(b"[mp3 @ 0x1] Header missing"), None);
+        assert!(WarningCheck::new(&["(unclosed".to_string()]).unwrap_err().starts_with("Invalid --ffmpeg-warning-pattern '(unclosed'"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Turning titles into file names that are safe on every common filesystem.
+//!
+//! Every file name m4btool derives from a title or another file's name goes through
+//! `sanitize_filename`, so the rules cannot diverge between features.
+
+/// Device names Windows reserves in every directory, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters that are path separators or not allowed in Windows file names.
+const FORBIDDEN: [char; 9] = ['/', '\\', '<', '>', ':', '"', '|', '?', '*'];
+
+/// The longest extension kept whole when a name is truncated, e.g. ".ffmetadata".
+const MAX_EXTENSION_BYTES: usize = 12;
+
+/// Options controlling `sanitize_filename`.
+///
+/// Construct with `FilenameOptions::default()` and adjust the fields you need; new fields may be
+/// added in minor releases.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct FilenameOptions {
+    /// The longest name in bytes of UTF-8. Defaults to 255, the limit of most filesystems.
+    pub max_bytes: usize,
+    /// Spell the name in ASCII (e.g. "Café" becomes "Cafe"), for devices that mangle other
+    /// characters. Defaults to `false`.
+    pub ascii: bool,
+    /// The name used when nothing is left of the title. Defaults to "untitled".
+    pub fallback: String,
+}
+
+impl Default for FilenameOptions {
+    fn default() -> Self {
+        FilenameOptions { max_bytes: 255, ascii: false, fallback: "untitled".to_string() }
+    }
+}
+
+/// Turns a title into a file name that is safe on Windows, macOS, and Linux.
+///
+/// Path separators and the characters Windows forbids (`< > : " | ? *`) become `_`, control
+/// characters are dropped, and leading spaces and trailing dots and spaces, which Windows strips
+/// silently, are removed. Names Windows reserves for devices, such as `CON` or `com1.txt`, get a
+/// `_` after the device name. The result is cut to `max_bytes` on a character boundary, keeping
+/// a short extension.
+///
+/// # Example
+///
+/// ```
+/// use m4btool::{sanitize_filename, FilenameOptions};
+///
+/// assert_eq!(sanitize_filename("Dune: Book 1/3?", &FilenameOptions::default()), "Dune_ Book 1_3_");
+/// assert_eq!(sanitize_filename("nul.m4b", &FilenameOptions::default()), "nul_.m4b");
+/// ```
+pub fn sanitize_filename(title: &str, options: &FilenameOptions) -> String {
+    let title = if options.ascii { deunicode::deunicode(title) } else { title.to_string() };
+    let mut name: String = title.chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if FORBIDDEN.contains(&c) { '_' } else { c })
+        .collect();
+    name = trim_name(&name).to_string();
+
+    if name.len() > options.max_bytes {
+        let extension = name.rfind('.')
+            .map(|dot| &name[dot..])
+            .filter(|extension| extension.len() <= MAX_EXTENSION_BYTES.min(options.max_bytes / 2))
+            .filter(|extension| extension[1..].chars().all(|c| c.is_ascii_alphanumeric()))
+            .unwrap_or_default()
+            .to_string();
+        let stem = truncate_bytes(&name[..name.len() - extension.len()], options.max_bytes - extension.len());
+        name = format!("{}{}", trim_name(stem), extension);
+    }
+
+    let device = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(device.trim_end())) {
+        let stem_end = device.trim_end().len();
+        name.insert(stem_end, '_');
+        if name.len() > options.max_bytes {
+            name = truncate_bytes(&name, options.max_bytes).to_string();
+        }
+    }
+
+    if name.is_empty() || name.chars().all(|c| c == '.') {
+        return options.fallback.clone();
+    }
+    name
+}
+
+/// Returns `true` for a name that `sanitize_filename` leaves unchanged with the default options,
+/// i.e. one that is safe to create on every common filesystem.
+pub fn is_safe_filename(name: &str) -> bool {
+    !name.is_empty() && sanitize_filename(name, &FilenameOptions::default()) == name
+}
+
+/// Removes leading spaces and trailing dots and spaces.
+fn trim_name(name: &str) -> &str {
+    name.trim_start_matches(' ').trim_end_matches(['.', ' '])
+}
+
+/// Cuts a string to at most `max_bytes` bytes without splitting a character.
+fn truncate_bytes(text: &str, max_bytes: usize) -> &str {
+    let mut end = max_bytes.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitize(title: &str) -> String {
+        sanitize_filename(title, &FilenameOptions::default())
+    }
+
+    /// Tests separators, forbidden characters, and control characters.
+    #[test]
+    fn test_forbidden_characters() {
+        assert_eq!(sanitize("AC/DC"), "AC_DC");
+        assert_eq!(sanitize(r"C:\Books\Dune"), "C__Books_Dune");
+        assert_eq!(sanitize("What? <Really> \"Yes\" | No *"), "What_ _Really_ _Yes_ _ No _");
+        assert_eq!(sanitize("Tab\there\nnewline\u{7}bell\u{85}"), "Tabherenewlinebell");
+        assert_eq!(sanitize("Café – 第一章"), "Café – 第一章");
+    }
+
+    /// Tests Windows device names in any case, with an extension, and as part of a longer name.
+    #[test]
+    fn test_reserved_names() {
+        assert_eq!(sanitize("CON"), "CON_");
+        assert_eq!(sanitize("nul"), "nul_");
+        assert_eq!(sanitize("Com1.m4b"), "Com1_.m4b");
+        assert_eq!(sanitize("LPT9.concat.txt"), "LPT9_.concat.txt");
+        assert_eq!(sanitize("aux "), "aux_");
+        assert_eq!(sanitize("CONSOLE"), "CONSOLE");
+        assert_eq!(sanitize("COM10"), "COM10");
+        assert_eq!(sanitize("Con Game"), "Con Game");
+    }
+
+    /// Tests trailing dots and spaces, leading spaces, and names with nothing left.
+    #[test]
+    fn test_trimming_and_fallback() {
+        assert_eq!(sanitize("  To be continued... "), "To be continued");
+        assert_eq!(sanitize(".hidden"), ".hidden");
+        assert_eq!(sanitize(""), "untitled");
+        assert_eq!(sanitize("..."), "untitled");
+        assert_eq!(sanitize("\u{1}\u{2}"), "untitled");
+        let options = FilenameOptions { fallback: "book".to_string(), ..FilenameOptions::default() };
+        assert_eq!(sanitize_filename(" . ", &options), "book");
+    }
+
+    /// Tests byte-length truncation on character boundaries, keeping a short extension.
+    #[test]
+    fn test_truncation() {
+        let options = FilenameOptions { max_bytes: 10, ..FilenameOptions::default() };
+        assert_eq!(sanitize_filename("abcdefghijklmno", &options), "abcdefghij");
+        assert_eq!(sanitize_filename("Chapter 12.m4a", &options), "Chapte.m4a");
+        assert_eq!(sanitize_filename("第一章风暴", &options), "第一章");
+        assert_eq!(sanitize_filename("ab        cdefgh", &options), "ab");
+        assert_eq!(sanitize_filename("Über alles", &options), "Über alle");
+        let long = "ü".repeat(200);
+        let name = sanitize(&long);
+        assert_eq!(name.len(), 254);
+        assert!(is_safe_filename(&name));
+    }
+
+    /// Tests ASCII folding.
+    #[test]
+    fn test_ascii() {
+        let options = FilenameOptions { ascii: true, ..FilenameOptions::default() };
+        assert_eq!(sanitize_filename("Café: Ærø", &options), "Cafe_ AEro");
+        assert!(sanitize_filename("第一章", &options).is_ascii());
+    }
+
+    /// Tests the check shared by the tests of every feature that creates files.
+    #[test]
+    fn test_is_safe_filename() {
+        assert!(is_safe_filename("001-01 - Intro.mp3.log"));
+        assert!(!is_safe_filename("a/b"));
+        assert!(!is_safe_filename("PRN.txt"));
+        assert!(!is_safe_filename("end."));
+        assert!(!is_safe_filename(""));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The folder inside the input directory that holds the `--incremental` cache. Its leading dot
+/// keeps it out of scans by default.
+pub const CACHE_DIR: &str = ".m4btool-cache";
+
+const STATE_FILE: &str = "state.json";
+
+/// A 64-bit FNV-1a hash, stable across platforms and Rust releases, so cache keys written by one
+/// build still match in the next.
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv(u64);
+
+impl Fnv {
+    pub fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// The hash as 16 hex digits.
+    pub fn hex(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hashes the contents of a file.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hash = Fnv::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hash.hex());
+        }
+        hash.update(&buffer[..read]);
+    }
+}
+
+/// Builds the cache key of a source file's encode from its size, modification time, contents,
+/// and everything that shapes the encode, such as the codec, bitrate, and trim.
+///
+/// # Arguments
+///
+/// * `source` - The source audio file.
+/// * `settings` - A description of the encode settings for this file; any change misses the cache.
+pub fn source_key(source: &Path, settings: &str) -> io::Result<String> {
+    let metadata = fs::metadata(source)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+    let mut hash = Fnv::new();
+    for part in [metadata.len().to_string(), modified.to_string(), hash_file(source)?, settings.to_string()] {
+        hash.update(part.as_bytes());
+        hash.update(&[0]);
+    }
+    Ok(hash.hex())
+}
+
+/// Combines everything a book is built from into one fingerprint: the source keys, the options,
+/// the tags, and the contents of the cover and sidecar files.
+pub fn fingerprint(parts: &[String]) -> String {
+    let mut hash = Fnv::new();
+    hash.update(env!("CARGO_PKG_VERSION").as_bytes());
+    for part in parts {
+        hash.update(&[0]);
+        hash.update(part.as_bytes());
+    }
+    hash.hex()
+}
+
+/// The `--incremental` cache of one book: the encoded files by source key, and the fingerprint
+/// of the last finished build.
+#[derive(Debug)]
+pub struct IncrementalCache {
+    dir: PathBuf,
+}
+
+impl IncrementalCache {
+    /// Opens the cache in the input directory, creating it if needed.
+    pub fn open(input_dir: &Path) -> io::Result<Self> {
+        let dir = input_dir.join(CACHE_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(IncrementalCache { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn encode_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.m4a", key))
+    }
+
+    /// The cached encode for a source key, if there is one.
+    pub fn encoded(&self, key: &str) -> Option<PathBuf> {
+        Some(self.encode_path(key)).filter(|path| path.is_file())
+    }
+
+    /// Copies a fresh encode into the cache under its source key.
+    pub fn store(&self, key: &str, encoded: &Path) -> io::Result<PathBuf> {
+        let path = self.encode_path(key);
+        fs::copy(encoded, &path)?;
+        Ok(path)
+    }
+
+    /// Removes the cached encodes of sources that are gone or changed.
+    ///
+    /// # Returns
+    ///
+    /// How many encodes were removed.
+    pub fn prune(&self, keys: &[String]) -> io::Result<usize> {
+        let keep: HashSet<String> = keys.iter().map(|key| format!("{}.m4a", key)).collect();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".m4a") && !keep.contains(&name) {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Whether the last finished build had this fingerprint and its output is still there.
+    pub fn is_up_to_date(&self, fingerprint: &str, output: &Path) -> bool {
+        let Ok(text) = fs::read_to_string(self.dir.join(STATE_FILE)) else { return false };
+        let state: serde_json::Value = serde_json::from_str(&text).unwrap_or_default();
+        state["fingerprint"] == fingerprint && output.is_file()
+    }
+
+    /// Records a finished build, with how many files were reused from the cache and encoded anew.
+    pub fn save_state(&self, fingerprint: &str, reused: usize, encoded: usize) -> io::Result<()> {
+        let state = serde_json::json!({ "fingerprint": fingerprint, "reused": reused, "encoded": encoded });
+        fs::write(self.dir.join(STATE_FILE), serde_json::to_string_pretty(&state)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the FNV-1a reference values, so keys stay stable between releases.
+    #[test]
+    fn test_fnv() {
+        assert_eq!(Fnv::new().hex(), "cbf29ce484222325");
+        let mut hash = Fnv::new();
+        hash.update(b"a");
+        assert_eq!(hash.hex(), "af63dc4c8601ec8c");
+    }
+
+    /// Tests that the key changes with the contents, even at the same size, and with the settings.
+    #[test]
+    fn test_source_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("01.mp3");
+        fs::write(&source, b"first take").unwrap();
+        let key = source_key(&source, "aac 64k").unwrap();
+        assert_eq!(source_key(&source, "aac 64k").unwrap(), key);
+        assert_ne!(source_key(&source, "aac 96k").unwrap(), key);
+        fs::write(&source, b"other take").unwrap();
+        assert_ne!(source_key(&source, "aac 64k").unwrap(), key);
+    }
+
+    /// Tests storing, finding, and pruning encodes, and the up-to-date check.
+    #[test]
+    fn test_incremental_cache() {
+        let book = tempfile::tempdir().unwrap();
+        let cache = IncrementalCache::open(book.path()).unwrap();
+        let encode = book.path().join("encode.m4a");
+        fs::write(&encode, b"encoded").unwrap();
+
+        assert_eq!(cache.encoded("aaaa"), None);
+        let stored = cache.store("aaaa", &encode).unwrap();
+        cache.store("bbbb", &encode).unwrap();
+        assert_eq!(cache.encoded("aaaa"), Some(stored));
+        assert_eq!(cache.prune(&["aaaa".to_string()]).unwrap(), 1);
+        assert_eq!(cache.encoded("bbbb"), None);
+
+        let output = book.path().join("output.m4b");
+        let first = fingerprint(&["aaaa".to_string(), "--title Dune".to_string()]);
+        assert!(!cache.is_up_to_date(&first, &output));
+        cache.save_state(&first, 0, 1).unwrap();
+        assert!(!cache.is_up_to_date(&first, &output));
+        fs::write(&output, b"book").unwrap();
+        assert!(cache.is_up_to_date(&first, &output));
+        assert!(!cache.is_up_to_date(&fingerprint(&["aaaa".to_string(), "--title Dune Messiah".to_string()]), &output));
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::{BTreeMap, HashMap};
+use std::process::Command;
+
+use crate::console;
+use crate::runner::{CommandRunner, SystemRunner};
+use crate::tag_encoding::{redecode, TagEncoding};
+
+/// A chapter as read back from an existing file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChapterInfo {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub title: String,
+}
+
+/// Container-level information about an existing audio file, as reported by `ffprobe`.
+#[derive(Debug, Clone, Default)]
+pub struct BookInfo {
+    /// The demuxer name list, e.g. "mov,mp4,m4a,3gp,3g2,mj2".
+    pub format_name: String,
+    /// Format-level tags keyed by their lowercase ffmpeg name.
+    pub tags: HashMap<String, String>,
+    pub chapters: Vec<ChapterInfo>,
+    /// Whether a stream is flagged as an attached picture.
+    pub has_cover: bool,
+    /// The first audio stream's language tag, or else the container's; `und` counts as none.
+    pub language: Option<String>,
+}
+
+impl BookInfo {
+    /// Returns `true` when the file was demuxed as an MP4/QuickTime container.
+    pub fn is_mp4(&self) -> bool {
+        self.format_name.split(',').any(|name| name == "mp4" || name == "mov")
+    }
+
+    /// Re-decodes the tag values and chapter titles that ffmpeg decoded with the wrong encoding.
+    pub fn redecode_tags(mut self, encoding: TagEncoding) -> Self {
+        for value in self.tags.values_mut() {
+            *value = redecode(value, encoding);
+        }
+        for chapter in &mut self.chapters {
+            chapter.title = redecode(&chapter.title, encoding);
+        }
+        self
+    }
+
+    /// The MP4 major brand, such as "M4B", without its padding.
+    pub fn major_brand(&self) -> Option<&str> {
+        self.tags.get("major_brand").map(|brand| brand.trim())
+    }
+}
+
+/// Reads the container format, tags, chapters, and cover presence of a file using `ffprobe`.
+///
+/// # Arguments
+///
+/// * `file_path` - The file to inspect.
+///
+/// # Returns
+///
+/// A `BookInfo`, or `None` if `ffprobe` could not read the file.
+pub fn inspect_book(file_path: &str) -> Option<BookInfo> {
+    probe_book_info(file_path, &SystemRunner)
+}
+
+/// Reads a file's container information like `inspect_book`, running `ffprobe` with `runner`.
+pub fn probe_book_info(file_path: &str, runner: &dyn CommandRunner) -> Option<BookInfo> {
+    let output = runner.query(Command::new("ffprobe").args([
+        "-v", "error",
+        "-show_format",
+        "-show_streams",
+        "-show_chapters",
+        "-of", "flat",
+        file_path,
+    ]))
+    .ok()?;
+    if !output.status.success() {
+        console::warn(format!("ffprobe error for {}: {}", file_path, String::from_utf8_lossy(&output.stderr).trim()));
+        return None;
+    }
+    Some(parse_flat_output(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses the `-of flat` output of `ffprobe` into a `BookInfo`.
+///
+/// Each line has the form `section.path.key="value"` (or an unquoted number).
+pub fn parse_flat_output(output: &str) -> BookInfo {
+    let mut info = BookInfo::default();
+    let mut chapters: BTreeMap<usize, ChapterInfo> = BTreeMap::new();
+    let mut audio_streams = Vec::new();
+    let mut stream_languages: BTreeMap<usize, String> = BTreeMap::new();
+
+    for (key, value) in flat_pairs(output) {
+        let key = key.as_str();
+        if key == "format.format_name" {
+            info.format_name = value;
+        } else if let Some(tag) = key.strip_prefix("format.tags.") {
+            info.tags.insert(tag.to_lowercase(), value);
+        } else if let Some(rest) = key.strip_prefix("chapters.chapter.") {
+            let Some((index, field)) = rest.split_once('.') else { continue };
+            let Ok(index) = index.parse::<usize>() else { continue };
+            let chapter = chapters.entry(index).or_insert(ChapterInfo { start_ms: 0, end_ms: 0, title: String::new() });
+            match field {
+                "start_time" => chapter.start_ms = seconds_to_ms(&value),
+                "end_time" => chapter.end_ms = seconds_to_ms(&value),
+                "tags.title" => chapter.title = value,
+                _ => {}
+            }
+        } else if let Some(rest) = key.strip_prefix("streams.stream.") {
+            let Some((index, field)) = rest.split_once('.') else { continue };
+            let Ok(index) = index.parse::<usize>() else { continue };
+            match field {
+                "disposition.attached_pic" if value == "1" => info.has_cover = true,
+                "codec_type" if value == "audio" => audio_streams.push(index),
+                "tags.language" => {
+                    stream_languages.insert(index, value);
+                }
+                _ => {}
+            }
+        }
+    }
+    info.chapters = chapters.into_values().collect();
+    info.language = audio_streams.first()
+        .and_then(|index| stream_languages.get(index))
+        .or_else(|| info.tags.get("language"))
+        .filter(|language| *language != "und")
+        .cloned();
+    info
+}
+
+/// Splits `ffprobe -of flat` output into unescaped `(key, value)` pairs.
+pub fn flat_pairs(output: &str) -> Vec<(String, String)> {
+    output.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, raw_value)| (key.to_string(), unescape_flat_value(raw_value)))
+        .collect()
+}
+
+/// Strips the surrounding quotes from a flat value and undoes ffprobe's escaping.
+fn unescape_flat_value(raw: &str) -> String {
+    let Some(quoted) = raw.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return raw.to_string();
+    };
+    let mut value = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('r') => value.push('\r'),
+            Some(other) => value.push(other),
+            None => value.push('\\'),
+        }
+    }
+    value
+}
+
+/// Converts a seconds string such as "12.345000" to whole milliseconds.
+fn seconds_to_ms(value: &str) -> u64 {
+    value.trim().parse::<f64>().map(|sec| (sec * 1000.0).round() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests parsing of format tags, the brand, the language, chapters, and cover detection from flat output.
+    #[test]
+    fn test_parse_flat_output() {
+        let output = r#"streams.stream.0.codec_type="audio"
+streams.stream.0.disposition.attached_pic=0
+streams.stream.0.tags.language="deu"
+streams.stream.1.codec_type="video"
+streams.stream.1.disposition.attached_pic=1
+chapters.chapter.0.start_time="0.000000"
+chapters.chapter.0.end_time="61.250000"
+chapters.chapter.0.tags.title="The \"Beginning\""
+chapters.chapter.1.start_time="61.250000"
+chapters.chapter.1.end_time="120.000000"
+chapters.chapter.1.tags.title="End"
+format.format_name="mov,mp4,m4a,3gp,3g2,mj2"
+format.tags.major_brand="M4B "
+format.tags.title="My Book"
+format.tags.artist="Jane Doe"
+"#;
+        let info = parse_flat_output(output);
+        assert!(info.is_mp4());
+        assert!(info.has_cover);
+        assert_eq!(info.major_brand(), Some("M4B"));
+        assert_eq!(info.language.as_deref(), Some("deu"));
+        assert_eq!(info.tags.get("artist").map(String::as_str), Some("Jane Doe"));
+        assert_eq!(info.chapters.len(), 2);
+        assert_eq!(info.chapters[0], ChapterInfo { start_ms: 0, end_ms: 61250, title: "The \"Beginning\"".to_string() });
+        assert_eq!(info.chapters[1].end_ms, 120000);
+    }
+}
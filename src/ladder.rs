@@ -0,0 +1,67 @@
+use std::path::Path;
+
+use crate::cli::BuildOptions;
+
+/// Names a bitrate for file names and messages: "64k" for whole kbps, bits per second otherwise.
+pub fn bitrate_label(bits_per_second: u64) -> String {
+    if bits_per_second.is_multiple_of(1000) {
+        format!("{}k", bits_per_second / 1000)
+    } else {
+        bits_per_second.to_string()
+    }
+}
+
+/// The output of one version of a `--bitrate-ladder`: the bitrate inserted before the
+/// extension, e.g. `output.64k.m4b` for `output.m4b`.
+pub fn rung_output(output: &str, bits_per_second: u64) -> String {
+    let path = Path::new(output);
+    let label = bitrate_label(bits_per_second);
+    let name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!("{}.{}.{}", stem.to_string_lossy(), label, extension.to_string_lossy()),
+        _ => format!("{}.{}", path.file_name().unwrap_or_default().to_string_lossy(), label),
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+/// Plans one build per bitrate of `--bitrate-ladder`, each encoding every file from its source
+/// at that bitrate into its own output next to where the book would be written without the
+/// ladder (see `output::output_path`).
+///
+/// # Returns
+///
+/// The options of each build, in ladder order, without the ladder themselves.
+pub fn ladder_builds(options: &BuildOptions) -> Vec<BuildOptions> {
+    options.bitrate_ladder.iter()
+        .map(|&bits_per_second| BuildOptions {
+            ladder_rung: Some(bits_per_second),
+            bitrate_ladder: Vec::new(),
+            ..options.clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::output_path;
+
+    /// Tests that a ladder plans one output per bitrate next to the book, each at its bitrate.
+    #[test]
+    fn test_ladder_builds() {
+        let options = BuildOptions {
+            input_directories: vec!["books/dune".to_string()],
+            bitrate_ladder: vec![64_000, 128_000, 96_500],
+            ..BuildOptions::default()
+        };
+        let builds = ladder_builds(&options);
+        let planned: Vec<(String, Option<u64>)> = builds.iter().map(|build| (output_path(build, Some("Dune")), build.ladder_rung)).collect();
+        assert_eq!(planned, vec![
+            ("books/dune/Dune.64k.m4b".to_string(), Some(64_000)),
+            ("books/dune/Dune.128k.m4b".to_string(), Some(128_000)),
+            ("books/dune/Dune.96500.m4b".to_string(), Some(96_500)),
+        ]);
+        assert_eq!(rung_output("books/dune/output.m4b", 64_000), "books/dune/output.64k.m4b");
+        assert!(builds.iter().all(|build| build.bitrate_ladder.is_empty() && build.input_directories == options.input_directories));
+        assert_eq!(rung_output("Dune", 64_000), "Dune.64k");
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+/// ISO 639-1 languages as `(639-1 code, 639-2/T code, English name)`.
+const LANGUAGES: &[(&str, &str, &str)] = &[
+    ("aa", "aar", "Afar"), ("ab", "abk", "Abkhazian"), ("ae", "ave", "Avestan"), ("af", "afr", "Afrikaans"),
+    ("ak", "aka", "Akan"), ("am", "amh", "Amharic"), ("an", "arg", "Aragonese"), ("ar", "ara", "Arabic"),
+    ("as", "asm", "Assamese"), ("av", "ava", "Avaric"), ("ay", "aym", "Aymara"), ("az", "aze", "Azerbaijani"),
+    ("ba", "bak", "Bashkir"), ("be", "bel", "Belarusian"), ("bg", "bul", "Bulgarian"), ("bi", "bis", "Bislama"),
+    ("bm", "bam", "Bambara"), ("bn", "ben", "Bengali"), ("bo", "bod", "Tibetan"), ("br", "bre", "Breton"),
+    ("bs", "bos", "Bosnian"), ("ca", "cat", "Catalan"), ("ce", "che", "Chechen"), ("ch", "cha", "Chamorro"),
+    ("co", "cos", "Corsican"), ("cr", "cre", "Cree"), ("cs", "ces", "Czech"), ("cu", "chu", "Church Slavic"),
+    ("cv", "chv", "Chuvash"), ("cy", "cym", "Welsh"), ("da", "dan", "Danish"), ("de", "deu", "German"),
+    ("dv", "div", "Divehi"), ("dz", "dzo", "Dzongkha"), ("ee", "ewe", "Ewe"), ("el", "ell", "Greek"),
+    ("en", "eng", "English"), ("eo", "epo", "Esperanto"), ("es", "spa", "Spanish"), ("et", "est", "Estonian"),
+    ("eu", "eus", "Basque"), ("fa", "fas", "Persian"), ("ff", "ful", "Fulah"), ("fi", "fin", "Finnish"),
+    ("fj", "fij", "Fijian"), ("fo", "fao", "Faroese"), ("fr", "fra", "French"), ("fy", "fry", "Western Frisian"),
+    ("ga", "gle", "Irish"), ("gd", "gla", "Scottish Gaelic"), ("gl", "glg", "Galician"), ("gn", "grn", "Guarani"),
+    ("gu", "guj", "Gujarati"), ("gv", "glv", "Manx"), ("ha", "hau", "Hausa"), ("he", "heb", "Hebrew"),
+    ("hi", "hin", "Hindi"), ("ho", "hmo", "Hiri Motu"), ("hr", "hrv", "Croatian"), ("ht", "hat", "Haitian"),
+    ("hu", "hun", "Hungarian"), ("hy", "hye", "Armenian"), ("hz", "her", "Herero"), ("ia", "ina", "Interlingua"),
+    ("id", "ind", "Indonesian"), ("ie", "ile", "Interlingue"), ("ig", "ibo", "Igbo"), ("ii", "iii", "Sichuan Yi"),
+    ("ik", "ipk", "Inupiaq"), ("io", "ido", "Ido"), ("is", "isl", "Icelandic"), ("it", "ita", "Italian"),
+    ("iu", "iku", "Inuktitut"), ("ja", "jpn", "Japanese"), ("jv", "jav", "Javanese"), ("ka", "kat", "Georgian"),
+    ("kg", "kon", "Kongo"), ("ki", "kik", "Kikuyu"), ("kj", "kua", "Kuanyama"), ("kk", "kaz", "Kazakh"),
+    ("kl", "kal", "Kalaallisut"), ("km", "khm", "Khmer"), ("kn", "kan", "Kannada"), ("ko", "kor", "Korean"),
+    ("kr", "kau", "Kanuri"), ("ks", "kas", "Kashmiri"), ("ku", "kur", "Kurdish"), ("kv", "kom", "Komi"),
+    ("kw", "cor", "Cornish"), ("ky", "kir", "Kirghiz"), ("la", "lat", "Latin"), ("lb", "ltz", "Luxembourgish"),
+    ("lg", "lug", "Ganda"), ("li", "lim", "Limburgish"), ("ln", "lin", "Lingala"), ("lo", "lao", "Lao"),
+    ("lt", "lit", "Lithuanian"), ("lu", "lub", "Luba-Katanga"), ("lv", "lav", "Latvian"), ("mg", "mlg", "Malagasy"),
+    ("mh", "mah", "Marshallese"), ("mi", "mri", "Maori"), ("mk", "mkd", "Macedonian"), ("ml", "mal", "Malayalam"),
+    ("mn", "mon", "Mongolian"), ("mr", "mar", "Marathi"), ("ms", "msa", "Malay"), ("mt", "mlt", "Maltese"),
+    ("my", "mya", "Burmese"), ("na", "nau", "Nauru"), ("nb", "nob", "Norwegian Bokmål"), ("nd", "nde", "North Ndebele"),
+    ("ne", "nep", "Nepali"), ("ng", "ndo", "Ndonga"), ("nl", "nld", "Dutch"), ("nn", "nno", "Norwegian Nynorsk"),
+    ("no", "nor", "Norwegian"), ("nr", "nbl", "South Ndebele"), ("nv", "nav", "Navajo"), ("ny", "nya", "Chichewa"),
+    ("oc", "oci", "Occitan"), ("oj", "oji", "Ojibwa"), ("om", "orm", "Oromo"), ("or", "ori", "Oriya"),
+    ("os", "oss", "Ossetian"), ("pa", "pan", "Punjabi"), ("pi", "pli", "Pali"), ("pl", "pol", "Polish"),
+    ("ps", "pus", "Pashto"), ("pt", "por", "Portuguese"), ("qu", "que", "Quechua"), ("rm", "roh", "Romansh"),
+    ("rn", "run", "Rundi"), ("ro", "ron", "Romanian"), ("ru", "rus", "Russian"), ("rw", "kin", "Kinyarwanda"),
+    ("sa", "san", "Sanskrit"), ("sc", "srd", "Sardinian"), ("sd", "snd", "Sindhi"), ("se", "sme", "Northern Sami"),
+    ("sg", "sag", "Sango"), ("si", "sin", "Sinhala"), ("sk", "slk", "Slovak"), ("sl", "slv", "Slovenian"),
+    ("sm", "smo", "Samoan"), ("sn", "sna", "Shona"), ("so", "som", "Somali"), ("sq", "sqi", "Albanian"),
+    ("sr", "srp", "Serbian"), ("ss", "ssw", "Swati"), ("st", "sot", "Southern Sotho"), ("su", "sun", "Sundanese"),
+    ("sv", "swe", "Swedish"), ("sw", "swa", "Swahili"), ("ta", "tam", "Tamil"), ("te", "tel", "Telugu"),
+    ("tg", "tgk", "Tajik"), ("th", "tha", "Thai"), ("ti", "tir", "Tigrinya"), ("tk", "tuk", "Turkmen"),
+    ("tl", "tgl", "Tagalog"), ("tn", "tsn", "Tswana"), ("to", "ton", "Tongan"), ("tr", "tur", "Turkish"),
+    ("ts", "tso", "Tsonga"), ("tt", "tat", "Tatar"), ("tw", "twi", "Twi"), ("ty", "tah", "Tahitian"),
+    ("ug", "uig", "Uyghur"), ("uk", "ukr", "Ukrainian"), ("ur", "urd", "Urdu"), ("uz", "uzb", "Uzbek"),
+    ("ve", "ven", "Venda"), ("vi", "vie", "Vietnamese"), ("vo", "vol", "Volapük"), ("wa", "wln", "Walloon"),
+    ("wo", "wol", "Wolof"), ("xh", "xho", "Xhosa"), ("yi", "yid", "Yiddish"), ("yo", "yor", "Yoruba"),
+    ("za", "zha", "Zhuang"), ("zh", "zho", "Chinese"), ("zu", "zul", "Zulu"),
+];
+
+/// ISO 639-2/B codes that differ from the /T code, as `(B code, T code)`.
+const BIBLIOGRAPHIC_CODES: &[(&str, &str)] = &[
+    ("alb", "sqi"), ("arm", "hye"), ("baq", "eus"), ("bur", "mya"), ("chi", "zho"),
+    ("cze", "ces"), ("dut", "nld"), ("fre", "fra"), ("geo", "kat"), ("ger", "deu"),
+    ("gre", "ell"), ("ice", "isl"), ("mac", "mkd"), ("mao", "mri"), ("may", "msa"),
+    ("per", "fas"), ("rum", "ron"), ("slo", "slk"), ("tib", "bod"), ("wel", "cym"),
+];
+
+/// Looks up a two- or three-letter code, ignoring case.
+fn lookup(code: &str) -> Option<&'static (&'static str, &'static str, &'static str)> {
+    let code = code.trim().to_lowercase();
+    let code = BIBLIOGRAPHIC_CODES.iter().find(|(b, _)| *b == code).map_or(code.as_str(), |(_, t)| t);
+    LANGUAGES.iter().find(|(one, three, _)| *one == code || *three == code)
+}
+
+/// Validates a `--language` value, an ISO 639-1 (`en`) or ISO 639-2 (`eng`, or `ger` for `deu`)
+/// code.
+///
+/// # Returns
+///
+/// The ISO 639-2/T code that MP4 and Matroska store, or an error message suggesting the closest
+/// known codes.
+pub fn parse_language(value: &str) -> Result<String, String> {
+    if let Some((_, three, _)) = lookup(value) {
+        return Ok(three.to_string());
+    }
+    let suggestions = suggest(value);
+    let hint = if suggestions.is_empty() { String::new() } else { format!("; did you mean {}?", suggestions.join(", ")) };
+    Err(format!("Invalid language '{}': expected an ISO 639-1 or 639-2 code such as en or eng{}", value, hint))
+}
+
+/// Lists up to three languages whose code or name is within two edits of `value`, or whose name
+/// starts with it, as "eng (English)".
+fn suggest(value: &str) -> Vec<String> {
+    let value = value.trim().to_lowercase();
+    let mut scored: Vec<(usize, &(&str, &str, &str))> = LANGUAGES.iter()
+        .filter_map(|language @ (one, three, name)| {
+            let name = name.to_lowercase();
+            let distance = [*one, *three, name.as_str()].iter().map(|candidate| edit_distance(&value, candidate)).min()?;
+            match distance {
+                _ if value.len() >= 3 && name.starts_with(&value) => Some((0, language)),
+                0..=2 if value.len() > distance => Some((distance, language)),
+                _ => None,
+            }
+        })
+        .collect();
+    scored.sort_by_key(|(distance, _)| *distance);
+    scored.into_iter().take(3).map(|(_, (_, three, name))| format!("{} ({})", three, name)).collect()
+}
+
+/// The Levenshtein distance between two strings, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Picks the language most of the source files are tagged with, for when `--language` is not
+/// given. Unknown codes and `und` are ignored; a tie goes to the language seen first.
+pub fn majority_language(tags: &[Option<String>]) -> Option<String> {
+    let mut counts: HashMap<String, (usize, usize)> = HashMap::new();
+    for (position, code) in tags.iter().flatten().filter_map(|tag| parse_language(tag).ok()).enumerate() {
+        counts.entry(code).or_insert((0, position)).0 += 1;
+    }
+    counts.into_iter()
+        .max_by(|(_, (a_count, a_first)), (_, (b_count, b_first))| a_count.cmp(b_count).then(b_first.cmp(a_first)))
+        .map(|(code, _)| code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests two- and three-letter codes, bibliographic codes, and case.
+    #[test]
+    fn test_parse_language() {
+        assert_eq!(parse_language("en"), Ok("eng".to_string()));
+        assert_eq!(parse_language("ENG"), Ok("eng".to_string()));
+        assert_eq!(parse_language("ger"), Ok("deu".to_string()));
+        assert_eq!(parse_language(" zh "), Ok("zho".to_string()));
+        assert!(parse_language("und").is_err());
+    }
+
+    /// Tests suggestions for misspelled codes and language names.
+    #[test]
+    fn test_parse_language_suggestions() {
+        assert_eq!(
+            parse_language("englsh"),
+            Err("Invalid language 'englsh': expected an ISO 639-1 or 639-2 code such as en or eng; did you mean eng (English)?".to_string())
+        );
+        assert!(parse_language("French").unwrap_err().ends_with("did you mean fra (French)?"));
+        assert!(parse_language("klingon").unwrap_err().ends_with("such as en or eng"));
+        assert!(parse_language("engl").unwrap_err().contains("eng (English)"));
+    }
+
+    /// Tests that the most common valid language wins, and the first one on a tie.
+    #[test]
+    fn test_majority_language() {
+        let tags = |values: &[Option<&str>]| values.iter().map(|value| value.map(str::to_string)).collect::<Vec<_>>();
+        assert_eq!(majority_language(&tags(&[Some("de"), Some("eng"), None, Some("ger"), Some("und")])), Some("deu".to_string()));
+        assert_eq!(majority_language(&tags(&[Some("fre"), Some("en")])), Some("fra".to_string()));
+        assert_eq!(majority_language(&tags(&[None, Some("und")])), None);
+    }
+}
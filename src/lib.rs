@@ -0,0 +1,29 @@
+//! Library interface of m4btool.
+//!
+//! The command-line tool merges a directory of audio files into a single chaptered m4b. Parts of
+//! its logic that are useful on their own, such as chapter title cleaning, FFMETADATA reading and
+//! writing, WebVTT chapter generation, book tags, and file name sanitizing, are exported here so other tools can reuse them without running
+//! any audio processing. The `plan` module holds the serializable `BookPlan` describing a build, and
+//! the `events` module the progress events written with `--progress-json`.
+
+pub mod book_tags;
+pub mod events;
+pub mod ffmetadata;
+pub mod filename;
+pub mod plan;
+pub mod title;
+pub mod title_case;
+pub mod transliterate;
+pub mod webvtt;
+
+pub use book_tags::BookTags;
+pub use events::{BuildPhase, Event, FileOutcome};
+#[cfg(feature = "serde")]
+pub use events::{event_line, parse_event_line};
+pub use ffmetadata::{read_ffmetadata, write_ffmetadata, write_ffmetadata_chapters, FfMetadata, GlobalTags, TimedChapter};
+pub use filename::{is_safe_filename, sanitize_filename, FilenameOptions};
+pub use plan::{BookPlan, Chapter};
+pub use title::{clean_titles, clean_titles_with_dirs, is_unnumbered_title, strip_invisible_characters, trace_clean_titles, BracketKind, CleanOptions, CleanStrategy, Numbering, RemovedToken, Removal, TitleTrace};
+pub use title_case::{apply_title_case, TitleCase};
+pub use transliterate::transliterate_title;
+pub use webvtt::{write_vtt_chapters, write_vtt_chapters_at};
@@ -0,0 +1,153 @@
+use m4btool::plan::Chapter;
+use serde_json::json;
+
+use crate::tags::BookTags;
+
+/// The file name of the OPF sidecar written by `--write-opf`.
+pub const OPF_FILE: &str = "metadata.opf";
+/// The file name of the Audiobookshelf sidecar written by `--write-audiobookshelf-metadata`.
+pub const AUDIOBOOKSHELF_FILE: &str = "metadata.json";
+
+/// Renders the book tags as an OPF 2.0 package document, the sidecar format of Calibre that
+/// Audiobookshelf and other library managers read from the book's folder.
+///
+/// The author and narrator are `dc:creator` entries with the roles `aut` and `nrt`, each genre is
+/// a `dc:subject`, and the series is Calibre's `calibre:series` meta. OPF has no chapter list;
+/// library managers read the chapters from the book itself.
+pub fn write_opf(tags: &BookTags) -> String {
+    let mut entries = Vec::new();
+    if let Some(title) = &tags.title {
+        entries.push(format!("<dc:title>{}</dc:title>", xml_escape(title)));
+    }
+    if let Some(author) = &tags.author {
+        entries.push(format!("<dc:creator opf:role=\"aut\">{}</dc:creator>", xml_escape(author)));
+    }
+    if let Some(narrator) = &tags.narrator {
+        entries.push(format!("<dc:creator opf:role=\"nrt\">{}</dc:creator>", xml_escape(narrator)));
+    }
+    if let Some(date) = tags.date.as_ref().or(tags.year.as_ref()) {
+        entries.push(format!("<dc:date>{}</dc:date>", xml_escape(date)));
+    }
+    if let Some(description) = &tags.description {
+        entries.push(format!("<dc:description>{}</dc:description>", xml_escape(description)));
+    }
+    if let Some(language) = &tags.language {
+        entries.push(format!("<dc:language>{}</dc:language>", xml_escape(language)));
+    }
+    for genre in genres(tags) {
+        entries.push(format!("<dc:subject>{}</dc:subject>", xml_escape(genre)));
+    }
+    if let Some(series) = &tags.series {
+        entries.push(format!("<meta name=\"calibre:series\" content=\"{}\"/>", xml_escape(series)));
+    }
+
+    let mut opf = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"2.0\">\n\
+         \x20 <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">\n",
+    );
+    for entry in entries {
+        opf.push_str(&format!("    {}\n", entry));
+    }
+    opf.push_str("  </metadata>\n</package>\n");
+    opf
+}
+
+/// Renders the book tags and chapters as an Audiobookshelf `metadata.json`, which Audiobookshelf
+/// reads from the book's folder when "Store metadata with item" is used. Chapter times are in
+/// seconds.
+pub fn write_audiobookshelf_metadata(tags: &BookTags, chapters: &[Chapter]) -> String {
+    let list = |value: &Option<String>| value.iter().cloned().collect::<Vec<String>>();
+    let chapters: Vec<serde_json::Value> = chapters.iter()
+        .enumerate()
+        .map(|(id, chapter)| json!({
+            "id": id,
+            "start": chapter.start_ms as f64 / 1000.0,
+            "end": chapter.end_ms as f64 / 1000.0,
+            "title": chapter.title,
+        }))
+        .collect();
+    let metadata = json!({
+        "title": tags.title,
+        "authors": list(&tags.author),
+        "narrators": list(&tags.narrator),
+        "series": list(&tags.series),
+        "genres": genres(tags),
+        "publishedYear": tags.year.clone().or_else(|| tags.date.as_ref().map(|date| date.chars().take(4).collect())),
+        "publishedDate": tags.date,
+        "description": tags.description,
+        "language": tags.language,
+        "chapters": chapters,
+    });
+    let mut json = serde_json::to_string_pretty(&metadata).unwrap_or_default();
+    json.push('\n');
+    json
+}
+
+/// Splits the genre tag, which joins several genres with `; `.
+fn genres(tags: &BookTags) -> Vec<&str> {
+    tags.genre.iter()
+        .flat_map(|genre| genre.split(';'))
+        .map(str::trim)
+        .filter(|genre| !genre.is_empty())
+        .collect()
+}
+
+/// Escapes text for XML content and attribute values.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dune() -> BookTags {
+        BookTags {
+            title: Some("Dune".to_string()),
+            author: Some("Frank Herbert".to_string()),
+            narrator: Some("Scott Brick & Others".to_string()),
+            series: Some("Dune".to_string()),
+            date: Some("1965-08-01".to_string()),
+            genre: Some("Science Fiction; Classics".to_string()),
+            language: Some("eng".to_string()),
+            ..BookTags::default()
+        }
+    }
+
+    /// Tests the OPF document, including escaping and the roles of the creators.
+    #[test]
+    fn test_write_opf() {
+        assert_eq!(
+            write_opf(&dune()),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"2.0\">\n\
+             \x20 <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">\n\
+             \x20   <dc:title>Dune</dc:title>\n\
+             \x20   <dc:creator opf:role=\"aut\">Frank Herbert</dc:creator>\n\
+             \x20   <dc:creator opf:role=\"nrt\">Scott Brick &amp; Others</dc:creator>\n\
+             \x20   <dc:date>1965-08-01</dc:date>\n\
+             \x20   <dc:language>eng</dc:language>\n\
+             \x20   <dc:subject>Science Fiction</dc:subject>\n\
+             \x20   <dc:subject>Classics</dc:subject>\n\
+             \x20   <meta name=\"calibre:series\" content=\"Dune\"/>\n\
+             \x20 </metadata>\n\
+             </package>\n"
+        );
+    }
+
+    /// Tests the Audiobookshelf fields and the chapter list in seconds.
+    #[test]
+    fn test_write_audiobookshelf_metadata() {
+        let chapters = vec![Chapter::new(1, "Prologue", 0, 61_250), Chapter::new(2, "Part One", 61_250, 181_250)];
+        let json: serde_json::Value = serde_json::from_str(&write_audiobookshelf_metadata(&dune(), &chapters)).unwrap();
+        assert_eq!(json["authors"], json!(["Frank Herbert"]));
+        assert_eq!(json["genres"], json!(["Science Fiction", "Classics"]));
+        assert_eq!(json["publishedYear"], "1965");
+        assert_eq!(json["description"], serde_json::Value::Null);
+        assert_eq!(json["chapters"][1], json!({"id": 1, "start": 61.25, "end": 181.25, "title": "Part One"}));
+    }
+}
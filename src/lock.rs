@@ -0,0 +1,254 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::console;
+use crate::table::format_duration;
+
+/// How often `--wait-for-lock` checks whether the other run has finished.
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A lock file that stays unreadable for longer than this was left behind by a run that died
+/// while writing it, and is broken like a stale lock.
+const UNREADABLE_LOCK_AGE: Duration = Duration::from_secs(5);
+
+/// The run holding a lock, as recorded in the lock file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LockHolder {
+    pub pid: u32,
+    /// When the run took the lock, in seconds since the Unix epoch.
+    pub started: u64,
+}
+
+impl LockHolder {
+    fn current() -> Self {
+        LockHolder { pid: std::process::id(), started: unix_seconds(SystemTime::now()) }
+    }
+
+    fn render(&self) -> String {
+        format!("pid={}\nstarted={}\n", self.pid, self.started)
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let value = |key: &str| text.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix('=')?.trim().parse().ok());
+        Some(LockHolder { pid: value("pid")? as u32, started: value("started")? })
+    }
+
+    /// Describes the holder for a message, e.g. "PID 4242, running for 0:05:12".
+    pub fn describe(&self, now: SystemTime) -> String {
+        let running_ms = unix_seconds(now).saturating_sub(self.started) * 1000;
+        format!("PID {}, running for {}", self.pid, format_duration(running_ms))
+    }
+}
+
+/// The lock file of an output: its name with `.lock` appended, next to it.
+pub fn lock_path(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    output.with_file_name(name)
+}
+
+/// An advisory lock on an output file, held from before the old book is replaced until the new
+/// one is written, so that two runs on the same output cannot interleave. The lock file is
+/// removed when this is dropped.
+#[derive(Debug)]
+pub struct OutputLock {
+    path: PathBuf,
+}
+
+impl OutputLock {
+    /// Takes the lock on `output` with an atomic create, breaking a stale lock whose run is gone.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - The output file to lock.
+    /// * `is_running` - Whether the process with the given PID still runs, e.g. `process_running`.
+    ///
+    /// # Returns
+    ///
+    /// The lock, the run that holds it, or an I/O error from creating the lock file.
+    pub fn try_acquire(output: &Path, is_running: impl Fn(u32) -> bool) -> io::Result<Result<OutputLock, LockHolder>> {
+        let path = lock_path(output);
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let lock = OutputLock { path: path.clone() };
+                    file.write_all(LockHolder::current().render().as_bytes())?;
+                    return Ok(Ok(lock));
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err),
+            }
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                // Released in the meantime.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            match LockHolder::parse(&text) {
+                Some(holder) if is_running(holder.pid) => return Ok(Err(holder)),
+                Some(_) => break_stale_lock(&path, &text)?,
+                None => {
+                    let age = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()
+                        .and_then(|modified| modified.elapsed().ok());
+                    match age {
+                        Some(age) if age > UNREADABLE_LOCK_AGE => break_stale_lock(&path, &text)?,
+                        // The other run is still writing it.
+                        _ => thread::sleep(Duration::from_millis(10)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes the lock on `output`, failing right away while another run holds it, or waiting for
+    /// that run to finish with `wait`.
+    ///
+    /// # Returns
+    ///
+    /// The lock, or an error message naming the run that holds it.
+    pub fn acquire(output: &Path, wait: bool) -> Result<OutputLock, String> {
+        let mut announced = false;
+        loop {
+            let holder = match OutputLock::try_acquire(output, process_running) {
+                Ok(Ok(lock)) => return Ok(lock),
+                Ok(Err(holder)) => holder,
+                Err(err) => return Err(format!("Could not create the lock file '{}': {}", lock_path(output).display(), err)),
+            };
+            if !wait {
+                return Err(format!(
+                    "Another m4btool run ({}) is writing '{}'; pass --wait-for-lock to wait for it, or remove '{}' if that run is gone",
+                    holder.describe(SystemTime::now()),
+                    output.display(),
+                    lock_path(output).display()
+                ));
+            }
+            if !announced {
+                console::line(format!("Waiting for another m4btool run ({}) to finish writing '{}'", holder.describe(SystemTime::now()), output.display()));
+                announced = true;
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Removes a stale lock file whose contents were `seen`. The file is first renamed out of the
+/// way and checked, so that a lock another run took in the meantime is put back instead of
+/// being removed.
+fn break_stale_lock(path: &Path, seen: &str) -> io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".stale-{}", std::process::id()));
+    let moved = path.with_file_name(name);
+    match fs::rename(path, &moved) {
+        Ok(()) => {}
+        // Another run broke it first.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    }
+    if fs::read_to_string(&moved).is_ok_and(|text| text != seen) {
+        // A hard link does not replace a lock taken in the meantime by yet another run.
+        let _ = fs::hard_link(&moved, path);
+    }
+    fs::remove_file(&moved)
+}
+
+/// Whether a process with the given PID is running. Only known on Unix; elsewhere every lock
+/// counts as held, and a stale one has to be removed by hand.
+pub fn process_running(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        let Ok(pid) = libc::pid_t::try_from(pid) else { return false };
+        // Signal 0 only checks that the process exists; EPERM means it belongs to another user.
+        let signalled = unsafe { libc::kill(pid, 0) } == 0;
+        signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+
+    /// Tests the lock file's name and contents.
+    #[test]
+    fn test_lock_holder() {
+        assert_eq!(lock_path(Path::new("/books/Dune/output.m4b")), Path::new("/books/Dune/output.m4b.lock"));
+        let holder = LockHolder { pid: 4242, started: 1_700_000_000 };
+        assert_eq!(LockHolder::parse(&holder.render()), Some(holder));
+        assert_eq!(LockHolder::parse("pid=4242\n"), None);
+        assert_eq!(LockHolder::parse(""), None);
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_312);
+        assert_eq!(holder.describe(now), "PID 4242, running for 0:05:12");
+    }
+
+    /// Tests two builds starting on the same output at once: exactly one gets the lock, the
+    /// other is told who holds it, and the lock is free again once the first is done.
+    #[test]
+    fn test_concurrent_builds() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.m4b");
+        let barrier = Arc::new(Barrier::new(2));
+        let builds: Vec<_> = (0..2)
+            .map(|_| {
+                let (output, barrier) = (output.clone(), Arc::clone(&barrier));
+                thread::spawn(move || {
+                    barrier.wait();
+                    OutputLock::try_acquire(&output, |_| true).unwrap()
+                })
+            })
+            .collect();
+        let results: Vec<_> = builds.into_iter().map(|build| build.join().unwrap()).collect();
+        let holders: Vec<&LockHolder> = results.iter().filter_map(|result| result.as_ref().err()).collect();
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].pid, std::process::id());
+        assert!(lock_path(&output).is_file());
+
+        drop(results);
+        assert!(!lock_path(&output).exists());
+        assert!(OutputLock::try_acquire(&output, |_| true).unwrap().is_ok());
+    }
+
+    /// Tests that a lock left by a run that is gone, or left unreadable, is broken.
+    #[test]
+    fn test_stale_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.m4b");
+        fs::write(lock_path(&output), LockHolder { pid: 999_999, started: 1 }.render()).unwrap();
+        let lock = OutputLock::try_acquire(&output, |pid| pid != 999_999).unwrap();
+        assert!(lock.is_ok());
+        let text = fs::read_to_string(lock_path(&output)).unwrap();
+        assert_eq!(LockHolder::parse(&text).map(|holder| holder.pid), Some(std::process::id()));
+
+        let other = dir.path().join("other.m4b");
+        let file = fs::File::create(lock_path(&other)).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        let other_lock = OutputLock::try_acquire(&other, |_| true).unwrap();
+        assert!(other_lock.is_ok());
+        // Only the two new lock files are left.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    /// Tests the process check against this process.
+    #[cfg(unix)]
+    #[test]
+    fn test_process_running() {
+        assert!(process_running(std::process::id()));
+    }
+}
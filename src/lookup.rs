@@ -0,0 +1,187 @@
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::shell;
+use crate::tags::BookTags;
+
+/// How long `--metadata-command` may run before it is stopped.
+pub const METADATA_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Book metadata returned by a `--metadata-command` script.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookLookup {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    pub description: Option<String>,
+    pub genres: Vec<String>,
+    /// Path of a cover image the script found or downloaded.
+    pub cover: Option<String>,
+}
+
+impl BookLookup {
+    /// Fills the tags that are not set yet, so values given on the command line take precedence.
+    pub fn fill_missing(&self, tags: &mut BookTags) {
+        let joined = |values: &[String], separator: &str| (!values.is_empty()).then(|| values.join(separator));
+        let fills = [
+            (&mut tags.title, self.title.clone()),
+            (&mut tags.author, joined(&self.authors, ", ")),
+            (&mut tags.narrator, self.narrator.clone()),
+            (&mut tags.series, self.series.clone()),
+            (&mut tags.description, self.description.clone()),
+            (&mut tags.genre, joined(&self.genres, "; ")),
+        ];
+        for (tag, value) in fills {
+            if tag.is_none() {
+                *tag = value;
+            }
+        }
+    }
+}
+
+/// Runs the user's metadata command and reads the book metadata it prints.
+///
+/// The command line is split like a shell would, and the inferred title, the inferred author
+/// (empty when unknown), and the input directory are appended as three more arguments. The
+/// script's stderr is passed through so its own messages stay visible.
+///
+/// # Arguments
+///
+/// * `command_line` - The `--metadata-command` value.
+/// * `title`, `author`, `directory` - The arguments passed to the script.
+/// * `timeout` - How long to wait before stopping the script.
+///
+/// # Returns
+///
+/// The parsed `BookLookup`, or an error message for a timeout, a failed run, or malformed output.
+pub fn run_metadata_command(command_line: &str, title: &str, author: &str, directory: &str, timeout: Duration) -> Result<BookLookup, String> {
+    let mut words = shell::split(command_line)?;
+    if words.is_empty() {
+        return Err("the command is empty".to_string());
+    }
+    let program = words.remove(0);
+    let mut child = Command::new(&program)
+        .args(&words)
+        .args([title, author, directory])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|err| format!("could not run '{}': {}", program, err))?;
+
+    // Read stdout on a separate thread so a large document cannot fill the pipe and stall the script.
+    let mut stdout = child.stdout.take().ok_or("could not read the command's output")?;
+    let reader = thread::spawn(move || {
+        let mut text = String::new();
+        stdout.read_to_string(&mut text).map(|_| text)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|err| err.to_string())? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out after {} s", timeout.as_secs_f64()));
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    if !status.success() {
+        return Err(format!("the command failed ({})", status));
+    }
+    let text = reader.join()
+        .map_err(|_| "could not read the command's output".to_string())?
+        .map_err(|err| format!("could not read the command's output: {}", err))?;
+    parse_lookup(&text)
+}
+
+/// Parses the JSON document printed by a metadata command.
+///
+/// Every field is optional. `authors` and `genres` may be a single string or a list of strings.
+///
+/// # Returns
+///
+/// The `BookLookup`, or an error message naming the malformed part.
+pub fn parse_lookup(text: &str) -> Result<BookLookup, String> {
+    let document: Value = serde_json::from_str(text).map_err(|err| format!("malformed JSON: {}", err))?;
+    let Value::Object(fields) = document else {
+        return Err("expected a JSON object".to_string());
+    };
+    let string = |key: &str| match fields.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.trim().to_string()).filter(|value| !value.is_empty())),
+        Some(_) => Err(format!("'{}' must be a string", key)),
+    };
+    let list = |key: &str| match fields.get(key) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::String(value)) => Ok(vec![value.trim().to_string()]),
+        Some(Value::Array(values)) => values.iter()
+            .map(|value| value.as_str().map(|value| value.trim().to_string()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("'{}' must be a list of strings", key)),
+        Some(_) => Err(format!("'{}' must be a list of strings", key)),
+    };
+    Ok(BookLookup {
+        title: string("title")?,
+        authors: list("authors")?.into_iter().filter(|author| !author.is_empty()).collect(),
+        narrator: string("narrator")?,
+        series: string("series")?,
+        description: string("description")?,
+        genres: list("genres")?.into_iter().filter(|genre| !genre.is_empty()).collect(),
+        cover: string("cover")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests parsing a full document, and that fields set on the command line are kept.
+    #[test]
+    fn test_parse_lookup_and_fill_missing() {
+        let lookup = parse_lookup(
+            r#"{"title": "Dune", "authors": ["Frank Herbert"], "narrator": "Scott Brick", "series": "Dune 1",
+                "description": "Spice.", "genres": ["Science Fiction", "Classics"], "cover": "/covers/dune.jpg", "isbn": "x"}"#,
+        ).unwrap();
+        assert_eq!(lookup.cover.as_deref(), Some("/covers/dune.jpg"));
+
+        let mut tags = BookTags { title: Some("Dune (Unabridged)".to_string()), ..Default::default() };
+        lookup.fill_missing(&mut tags);
+        assert_eq!(tags.title.as_deref(), Some("Dune (Unabridged)"));
+        assert_eq!(tags.author.as_deref(), Some("Frank Herbert"));
+        assert_eq!(tags.narrator.as_deref(), Some("Scott Brick"));
+        assert_eq!(tags.genre.as_deref(), Some("Science Fiction; Classics"));
+    }
+
+    /// Tests that missing fields are allowed and wrong types are reported.
+    #[test]
+    fn test_parse_lookup_errors() {
+        assert_eq!(parse_lookup(r#"{"authors": "Jane Doe", "series": null}"#).unwrap().authors, vec!["Jane Doe"]);
+        assert_eq!(parse_lookup("{}"), Ok(BookLookup::default()));
+        assert!(parse_lookup("not json").unwrap_err().starts_with("malformed JSON"));
+        assert_eq!(parse_lookup("[]"), Err("expected a JSON object".to_string()));
+        assert_eq!(parse_lookup(r#"{"title": 1}"#), Err("'title' must be a string".to_string()));
+        assert_eq!(parse_lookup(r#"{"genres": ["a", 2]}"#), Err("'genres' must be a list of strings".to_string()));
+    }
+
+    /// Tests the arguments passed to the command, a failing command, and a command that hangs.
+    #[cfg(unix)]
+    #[test]
+    fn test_run_metadata_command() {
+        let echo_args = r#"sh -c 'printf "{\"title\": \"%s|%s|%s\"}" "$0" "$1" "$2"'"#;
+        let lookup = run_metadata_command(echo_args, "Dune", "", "/books/dune", METADATA_COMMAND_TIMEOUT).unwrap();
+        assert_eq!(lookup.title.as_deref(), Some("Dune||/books/dune"));
+
+        let failing = run_metadata_command("sh -c 'exit 3'", "Dune", "", "/books", METADATA_COMMAND_TIMEOUT);
+        assert!(failing.unwrap_err().starts_with("the command failed"));
+        let hanging = run_metadata_command("sh -c 'sleep 5'", "Dune", "", "/books", Duration::from_millis(100));
+        assert_eq!(hanging, Err("timed out after 0.1 s".to_string()));
+    }
+}
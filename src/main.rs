@@ -1,472 +1,2190 @@
-use regex::Regex;
-use std::collections::HashMap;
+mod archive;
+mod chapter_track;
+mod chapters;
+mod collage;
+mod concat;
+mod config;
+mod cli;
+mod console;
+mod defaults;
+mod diff;
+mod doctor;
+mod encode;
+mod estimate;
+mod ffmpeg_warnings;
+mod incremental;
+mod inspect;
+mod ladder;
+mod language;
+mod library;
+mod lock;
+mod lookup;
+mod matter;
+mod overrides;
+mod mux;
+mod output;
+mod pipeline;
+mod postmortem;
+mod preset;
+mod probe;
+mod profile;
+mod progress;
+mod retag;
+mod runner;
+mod scan;
+mod shell;
+mod space;
+mod stats;
+mod table;
+mod tag_encoding;
+mod tags;
+mod track_order;
+mod tracks;
+
 use std::env;
 use std::fs;
-use std::io::{Write, BufWriter};
-use std::path::Path;
-use std::process::Command;
-use tempfile::{NamedTempFile, Builder};
-use walkdir::WalkDir;
-
-/// Represents a token parsed from a chapter title.
-/// A token may either be bracketed (e.g. "[Intro]") or not.
-/// The flag `is_bracketed` helps distinguish between tokens that should be treated differently.
-#[derive(Debug)]
-struct TitleToken {
-    is_bracketed: bool,
-    text: String,
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tempfile::{Builder, NamedTempFile, TempDir};
+
+use archive::{extract_archive, is_zip_archive, ExtractedArchive};
+use chapter_track::link_chapter_images;
+use chapters::{load_metadata_file, split_by_time, chapter_spans, check_chapter_file_length, expand_embedded_chapters, check_timeline, coalesce_chapters, merge_empty_chapters, merge_tiny_chapters, enforce_minimum_gap, minimum_chapter_gap, DEFAULT_MAX_CHAPTERS, MIN_CHAPTER_MS};
+use collage::{compose_cover, convert_cover, describe_cover, extract_cover, first_with_cover, inspect_cover, make_chapter_thumbnail, orient_cover, shrink_cover, small_cover_warning};
+use concat::{check_concat_list, write_concat_list, write_image_list};
+use config::{config_label, order_files, read_config, FileOverride};
+use defaults::{load_defaults, LayeredTags};
+use diff::{diff_chapters, render_side_by_side, render_unified, summarize};
+use cli::{BuildOptions, CleanTitlesOptions, DiffOptions, GlobalOptions, Invocation};
+use m4btool::plan::{self, assign_sources, attach_warnings, delay_chapters, lay_out_chapters, FileSettings, Warning, WarningKind};
+use m4btool::{BuildPhase, Event, FileOutcome, clean_titles, clean_titles_with_dirs, CleanOptions, trace_clean_titles, is_unnumbered_title, strip_invisible_characters, transliterate_title, write_ffmetadata_chapters, BookPlan, FfMetadata, GlobalTags, TimedChapter};
+use encode::{common_channels, common_sample_rate, describe_channels, describe_settings, estimate_encode_ms, make_lead_in, EncodeSettings, passlog_path, plan_trim, reencode_audio, target_bits_per_second, AacEncoder, EncodeFailure, EncodeTools, LeadInFormat, TrimWindow};
+use estimate::{benchmark_speed, Estimate, SourceEstimate};
+use ffmpeg_warnings::WarningCheck;
+use mux::{dump_intermediate, run_mux, Brand, MuxInput, MuxPlan};
+use incremental::{fingerprint, hash_file, source_key, IncrementalCache};
+use inspect::{inspect_book, BookInfo, ChapterInfo};
+use tag_encoding::{looks_like_mojibake, TagEncoding};
+use ladder::{bitrate_label, ladder_builds};
+use language::majority_language;
+use library::{write_audiobookshelf_metadata, write_opf, AUDIOBOOKSHELF_FILE, OPF_FILE};
+use lock::OutputLock;
+use lookup::{run_metadata_command, METADATA_COMMAND_TIMEOUT};
+use matter::{pin_matter, Placement};
+use overrides::{load_chapter_titles, match_chapter_titles, BitrateOverrides, CHAPTERS_FILE};
+use pipeline::encode_and_probe;
+use postmortem::{encode_log_name, report_fatal, PostMortem};
+use probe::{get_audio_info, probe_duration_ms, AudioInfo, SourceProbes};
+use progress::ProgressStream;
+use profile::PhaseTimer;
+use runner::{CommandRunner, SystemRunner};
+use scan::{collect_audio_files, dedupe_identical_files, dedupe_linked_files, drop_silent_videos, COVER_EXTENSIONS, WORK_DIR_PREFIX};
+use space::{check_space, filesystem_space, place_work_dir, SystemSpace, WorkDirPlacement};
+use table::{compare_streams, flag_outliers, render_compare, render_preview, render_warning_recap, terminal_width, CompareRow, PreviewRow};
+use output::{check_replace, input_title, output_path, provenance};
+use tags::{parse_date, BookTags, PROVENANCE_KEY};
+use track_order::{order_by_tags, track_position};
+use tracks::{export_tracks, ExportTrack};
+
+/// The directories between the input directory and a scanned file, outermost first.
+fn subdirectories(entry: &walkdir::DirEntry) -> Vec<String> {
+    let mut dirs: Vec<String> = entry.path().ancestors()
+        .skip(1)
+        .take(entry.depth().saturating_sub(1))
+        .filter_map(|dir| dir.file_name().map(|name| name.to_string_lossy().to_string()))
+        .collect();
+    dirs.reverse();
+    dirs
 }
 
-/// Standardizes different types of bracket characters in the input string
-/// by replacing them with the common bracket characters "[" and "]".
-///
-/// # Arguments
-///
-/// * `input` - A string slice that potentially contains various bracket styles.
+/// Inspects a source file, re-decoding its tags with `--tag-encoding` if given.
+fn inspect_source(file_path: &str, tag_encoding: Option<TagEncoding>) -> Option<BookInfo> {
+    let info = inspect_book(file_path)?;
+    Some(match tag_encoding {
+        Some(encoding) => info.redecode_tags(encoding),
+        None => info,
+    })
+}
+
+/// Removes zero-width, bidi control, and other invisible characters from each title, so that
+/// players show it as written.
 ///
 /// # Returns
 ///
-/// A `String` with all bracket types standardized to square brackets.
-fn standardize_brackets(input: &str) -> String {
-    input.replace("（", "[")
-         .replace("）", "]")
-         .replace("(", "[")
-         .replace(")", "]")
-         .replace("【", "[")
-         .replace("】", "]")
+/// How many titles changed.
+fn strip_invisible_titles<'a>(titles: impl Iterator<Item = &'a mut String>) -> usize {
+    let mut changed = 0;
+    for title in titles {
+        let stripped = strip_invisible_characters(title);
+        if stripped != *title {
+            *title = stripped;
+            changed += 1;
+        }
+    }
+    changed
 }
 
-/// Splits a chapter title into tokens using regular expressions.
-/// Tokens can either be bracketed segments (like "[Intro]" or "(Overview)")
-/// or non-bracketed text segments. This function leverages `standardize_brackets`
-/// to ensure consistent processing.
+/// Removes the items at the given indices, keeping the others in order.
+fn without_indices<T>(items: Vec<T>, indices: &[usize]) -> Vec<T> {
+    items.into_iter().enumerate().filter(|(index, _)| !indices.contains(index)).map(|(_, item)| item).collect()
+}
+
+/// Splits files into those that are long enough to keep and those shorter than `min_duration_ms`.
+/// Files whose duration could not be probed are kept, since their length is unknown.
 ///
 /// # Arguments
 ///
-/// * `title` - The chapter title as a string slice.
+/// * `files` - The input files, in order.
+/// * `durations` - The probed duration of each file in milliseconds, aligned with `files`.
+/// * `min_duration_ms` - The minimum duration a file needs to be kept.
 ///
 /// # Returns
 ///
-/// A vector of `TitleToken` instances representing the parsed tokens.
-fn split_title_tokens(title: &str) -> Vec<TitleToken> {
-    let standardized_title = standardize_brackets(title);
-    // Regex pattern captures either bracketed expressions or continuous non-numeric and non-bracketed text.
-    let token_pattern = Regex::new(r"(\(.*?\)|\[.*?\])|([^0-9\s\-:：\(\)\[\]]+)").unwrap();
-    let mut tokens = Vec::new();
-
-    for capture in token_pattern.captures_iter(&standardized_title) {
-        if let Some(bracketed) = capture.get(1) {
-            tokens.push(TitleToken { is_bracketed: true, text: bracketed.as_str().to_string() });
-        } else if let Some(non_bracketed) = capture.get(2) {
-            tokens.push(TitleToken { is_bracketed: false, text: non_bracketed.as_str().to_string() });
+/// The kept files in their original order, and the skipped files with their durations.
+fn drop_short_files<T>(files: Vec<T>, durations: &[Option<u64>], min_duration_ms: u64) -> (Vec<T>, Vec<(T, u64)>) {
+    let mut kept = Vec::new();
+    let mut skipped = Vec::new();
+    for (file, duration) in files.into_iter().zip(durations) {
+        match duration {
+            Some(duration_ms) if *duration_ms < min_duration_ms => skipped.push((file, *duration_ms)),
+            _ => kept.push(file),
         }
     }
-    tokens
+    (kept, skipped)
 }
 
-/// Builds a frequency map of non-bracketed tokens across multiple chapter titles.
-/// This is used later to decide if a token should be removed based on its occurrence frequency.
-///
-/// # Arguments
-///
-/// * `titles` - A slice of chapter title strings.
+/// Lays out the book's chapters from each file's cleaned title and duration, as a build writes
+/// them: the chapters embedded in the files with `--preserve-chapters`, split by time, with empty
+/// and tiny chapters merged, coalesced, spaced apart, and sanitized. Files of unknown duration
+/// are left out.
 ///
 /// # Returns
 ///
-/// A `HashMap` where each key is a non-bracketed token and the value is the occurrence count.
-fn build_token_frequency(titles: &[String]) -> HashMap<String, usize> {
-    let mut token_frequency = HashMap::new();
-    for title in titles {
-        for token in split_title_tokens(title) {
-            // Only count non-bracketed tokens to avoid removing significant descriptive parts.
-            if !token.is_bracketed {
-                *token_frequency.entry(token.text).or_insert(0) += 1;
-            }
+/// The chapters, and how many there were before `--coalesce-chapters` merged them.
+fn plan_chapters(files: &[(String, Option<u64>)], embedded_chapters: &[(Vec<ChapterInfo>, u64)], options: &BuildOptions) -> (Vec<(String, u64)>, usize) {
+    let mut chapters = Vec::new();
+    for (index, (title, duration_ms)) in files.iter().enumerate() {
+        let Some(duration_ms) = *duration_ms else { continue };
+        match embedded_chapters.get(index) {
+            Some((embedded, offset_ms)) => chapters.extend(expand_embedded_chapters(title, duration_ms, embedded, *offset_ms)),
+            None => chapters.push((title.clone(), duration_ms)),
+        }
+    }
+
+    // With --equal-chapters or --fixed-chapter-length the chapters ignore the files altogether.
+    if let Some(split) = options.time_split {
+        chapters = split_by_time(chapters.iter().map(|(_, duration_ms)| duration_ms).sum(), split);
+    }
+
+    // ffmpeg rejects chapters that end where they start, e.g. a tiny file trimmed to nothing.
+    let (kept, merged) = merge_empty_chapters(&chapters);
+    if !merged.is_empty() {
+        console::warn(format!("Merged chapters without any duration into their neighbors: {}", merged.join(", ")));
+        chapters = kept;
+    }
+    // ffmpeg may silently drop a last chapter under a second, so such chapters are merged
+    // into their neighbors, or with --keep-tiny-chapters padded to a second below.
+    if !options.keep_tiny_chapters {
+        let (kept, merged) = merge_tiny_chapters(&chapters, MIN_CHAPTER_MS);
+        if !merged.is_empty() {
+            console::warn(format!(
+                "Merged chapters shorter than a second into their neighbors: {}; pass --keep-tiny-chapters to keep them",
+                merged.join(", ")
+            ));
+            chapters = kept;
         }
     }
-    token_frequency
+
+    // Some players misbehave with very many chapters: merge whole chapters, or warn.
+    let planned_chapter_count = chapters.len();
+    let max_chapters = options.max_chapters.unwrap_or(DEFAULT_MAX_CHAPTERS);
+    match options.coalesce_chapters {
+        Some(titles) => chapters = coalesce_chapters(&chapters, max_chapters, titles),
+        None if chapters.len() > max_chapters => console::warn(format!(
+            "The book has {} chapters, more than the {} some players handle; pass --coalesce-chapters to merge them",
+            chapters.len(),
+            max_chapters
+        )),
+        None => {}
+    }
+    // Some players choke on chapters that start where the previous one does.
+    chapters = enforce_minimum_gap(&chapters, minimum_chapter_gap(options.chapter_minimum_gap, options.keep_tiny_chapters));
+    // Titles from chapters.txt, --config, or the files' own chapters have not been sanitized yet.
+    let sanitized = strip_invisible_titles(chapters.iter_mut().map(|(title, _)| title));
+    if sanitized > 0 {
+        console::warn(format!("Removed invisible or bidi control characters from {} chapter titles", sanitized));
+    }
+    (chapters, planned_chapter_count)
 }
 
-/// Cleans up a chapter title dynamically by removing common tokens that exceed a given frequency threshold.
-/// This helps in removing redundant words from the beginning of titles (e.g., repeated "Chapter" labels).
-///
-/// # Arguments
-///
-/// * `title` - The original chapter title as a string slice.
-/// * `token_frequency` - A frequency map of tokens obtained from `build_token_frequency`.
-/// * `total_titles` - Total number of chapter titles processed.
-/// * `threshold` - A fractional threshold (e.g., 0.8) that determines token removal based on frequency.
-///
-/// # Returns
+/// The chapters laid out back to back from zero. With `transliterate` the ASCII titles are shown,
+/// and the originals are kept as a second chapter tag; `chapters` then holds the shown titles.
+fn transliterated_chapters(chapters: &mut [(String, u64)], transliterate: bool) -> Vec<plan::Chapter> {
+    let mut planned_chapters = lay_out_chapters(chapters);
+    if !transliterate {
+        return planned_chapters;
+    }
+    for (chapter, (title, _)) in planned_chapters.iter_mut().zip(chapters.iter_mut()) {
+        let ascii_title = transliterate_title(title);
+        if ascii_title != *title {
+            chapter.original_title = Some(std::mem::replace(title, ascii_title.clone()));
+            chapter.title = ascii_title;
+        }
+    }
+    planned_chapters
+}
+
+/// The chapters of a `--metadata-file`, with "Chapter 2" and so on for those without a title.
+fn metadata_file_chapters(timed: &[TimedChapter]) -> Vec<plan::Chapter> {
+    timed.iter()
+        .enumerate()
+        .map(|(index, chapter)| {
+            let title = chapter.title.clone().unwrap_or_else(|| format!("Chapter {}", index + 1));
+            plan::Chapter::new(index + 1, title, chapter.start_ms, chapter.end_ms)
+        })
+        .collect()
+}
+
+/// Reads the brand of the finished book back with ffprobe and warns if it is not the one asked
+/// for, e.g. because `--ffmpeg-mux-args` selected a muxer that ignores `-brand`.
+fn check_brand(output: &str, brand: Brand) {
+    let Some(info) = inspect_book(output) else { return };
+    match info.major_brand() {
+        Some(written) if written == brand.code().trim() => {}
+        written => console::warn(format!(
+            "'{}' has major brand '{}' instead of '{}'; some players may not treat it as an audiobook",
+            output,
+            written.unwrap_or("none"),
+            brand.code().trim()
+        )),
+    }
+}
+
+/// Warns when the written book lacks some of its tags, such as the custom ones an MP4 only
+/// keeps with `-movflags +use_metadata_tags`, or the provenance, which the next build into the
+/// same output looks for before replacing it.
+fn check_written_tags(output: &str, tags: &BookTags) {
+    let Some(info) = inspect_book(output) else { return };
+    let missing = tags.missing_from(&info.tags);
+    if missing.is_empty() {
+        return;
+    }
+    let consequence = if missing.contains(&PROVENANCE_KEY) {
+        "; the next build of these inputs will need --overwrite to replace it"
+    } else {
+        ""
+    };
+    console::warn(format!("'{}' was written without the tags {}{}", output, missing.join(", "), consequence));
+}
+
+/// Exit status of a build that wrote the book but had to leave out part of it, such as a cover
+/// that ffmpeg could not attach or a file whose processing failed.
+const EXIT_DEGRADED: u8 = 2;
+
+/// Exit status when the inputs hold no usable audio, such as a folder with only a cover. Nothing
+/// is written then, and an existing book is left in place.
+const EXIT_NO_AUDIO: u8 = 3;
+
+/// Exit status of `diff` when the chapters differ, so that scripts can tell it from a failure.
+const EXIT_CHAPTERS_DIFFER: u8 = 4;
+
+/// Encodes estimated to take longer than this ask for confirmation first.
+const LONG_ENCODE_MS: u64 = 30 * 60_000;
+
+/// Main entry point of the audiobook creation tool.
 ///
-/// A cleaned-up title string with the common tokens removed.
-fn dynamic_clean_title(title: &str, token_frequency: &HashMap<String, usize>, total_titles: usize, threshold: f64) -> String {
-    let tokens = split_title_tokens(title);
-    let mut cleaned_tokens = Vec::new();
-    let mut in_removal_phase = true;
-
-    for token in tokens {
-        // In the removal phase, skip tokens that are overly common.
-        if in_removal_phase && !token.is_bracketed {
-            let frequency = token_frequency.get(&token.text).copied().unwrap_or(0);
-            if (frequency as f64) / (total_titles as f64) >= threshold {
-                continue;
+/// Parses the command line and dispatches to either the audiobook build or the `retag` subcommand.
+/// Exits with 0 on success, 1 on failure, `EXIT_DEGRADED` if the book lacks its cover, and
+/// `EXIT_NO_AUDIO` if there was nothing to build. `diff` exits with `EXIT_CHAPTERS_DIFFER` when
+/// the chapters differ.
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    let program = args.first().map(String::as_str).unwrap_or("m4btool");
+    let args = args.get(1..).unwrap_or_default();
+    let mut global = GlobalOptions::default();
+    let invocation = cli::parse_args(args, &mut global);
+    let mut console = console::Console::for_stderr(global.no_color, global.verbose);
+    if let Some(log_path) = &global.log_file {
+        let log_file = fs::OpenOptions::new().create(true).write(true).append(global.log_append).truncate(!global.log_append).open(log_path);
+        match log_file {
+            Ok(log_file) => console = console.with_log(Box::new(log_file)),
+            Err(err) => {
+                console::init(console);
+                console::error(format!("Could not open log file '{}': {}", log_path, err));
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    console::init(console.with_prompts(!global.yes && io::stdin().is_terminal()));
+    console::console().record(&format!("Started: {} {}", program, args.join(" ")));
+    if let Some(target) = &global.progress_json {
+        if !matches!(invocation, Ok(Invocation::Build(_))) {
+            console::error("--progress-json only applies to builds");
+            return ExitCode::FAILURE;
+        }
+        match ProgressStream::open(target.as_deref()) {
+            Ok(stream) => progress::init(stream),
+            Err(err) => {
+                console::error(format!("Could not open the progress stream '{}': {}", target.as_deref().unwrap_or("stdout"), err));
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    match invocation {
+        Ok(Invocation::Build(mut options)) => {
+            if let Err(err) = load_config(&mut options) {
+                console::error(err);
+                progress::emit(Event::Result { exit_code: 1, output: None });
+                return ExitCode::FAILURE;
+            }
+            let mut output = None;
+            let exit_code = build_audiobook(&options, &mut output);
+            let written = !options.dry_run && !options.estimate
+                && [ExitCode::SUCCESS, ExitCode::from(EXIT_DEGRADED)].contains(&exit_code);
+            progress::emit(Event::Result { exit_code: exit_status(exit_code), output: output.filter(|_| written) });
+            exit_code
+        }
+        Ok(Invocation::Doctor(options)) => {
+            let diagnostics = doctor::diagnose(&SystemRunner, &env::temp_dir());
+            if options.json {
+                console::print(serde_json::to_string_pretty(&diagnostics).unwrap_or_default());
             } else {
-                // Token is not too common, so end removal phase and keep it.
-                in_removal_phase = false;
-                cleaned_tokens.push(token.text);
+                console::print(diagnostics.render().trim_end());
             }
-        } else {
-            // Once the removal phase is over, keep all tokens (especially bracketed ones).
-            if token.is_bracketed {
-                in_removal_phase = false;
+            ExitCode::SUCCESS
+        }
+        Ok(Invocation::Diff(mut options)) => {
+            if let Err(err) = load_config(&mut options.build) {
+                console::error(err);
+                return ExitCode::FAILURE;
+            }
+            let color = console::color_enabled(global.no_color, env::var_os("NO_COLOR"), io::stdout().is_terminal());
+            print_chapter_diff(&options, color)
+        }
+        Ok(Invocation::CleanTitles(options)) => {
+            print_clean_titles(&options);
+            ExitCode::SUCCESS
+        }
+        Ok(Invocation::Retag(options)) => {
+            if let Err(err) = retag::retag(&options) {
+                console::error(err);
+                ExitCode::FAILURE
+            } else {
+                console::print(format!("Success: Tags updated in '{}'", options.input_file));
+                ExitCode::SUCCESS
             }
-            cleaned_tokens.push(token.text);
+        }
+        Err(err) => {
+            console::error(err);
+            console::line(cli::usage(program));
+            ExitCode::FAILURE
         }
     }
-    cleaned_tokens.join("").trim().to_string()
 }
 
-/// Retrieves the duration of an audio file in milliseconds by using `ffprobe`.
-/// This function invokes `ffprobe` as a subprocess and parses the output to obtain the duration.
-///
-/// # Arguments
-///
-/// * `file_path` - The file path to the audio file as a string slice.
-///
-/// # Returns
-///
-/// An `Option<u64>` representing the duration in milliseconds, or `None` if the duration cannot be determined.
-fn get_duration_ms(file_path: &str) -> Option<u64> {
-    let output = Command::new("ffprobe")
-        .args(&[
-            "-v", "error",
-            "-show_entries", "format=duration",
-            "-of", "default=noprint_wrappers=1:nokey=1",
-            file_path,
-        ])
-        .output()
-        .ok()?;
-    if !output.status.success() {
-        eprintln!("ffprobe error for {}: {}", file_path, String::from_utf8_lossy(&output.stderr));
-        return None;
-    }
-    let duration_str = String::from_utf8_lossy(&output.stdout);
-    let duration_sec: f64 = duration_str.trim().parse().ok()?;
-    Some((duration_sec * 1000.0).round() as u64)
+/// Reads the `--config` of a build or of the directory sides of `diff` into `loaded_config`.
+fn load_config(options: &mut BuildOptions) -> Result<(), String> {
+    if let Some(source) = &options.config {
+        options.loaded_config = Some(read_config(source)?);
+    }
+    Ok(())
 }
 
-/// Extracts audio stream information from a file using `ffprobe`.
-/// It retrieves details such as the codec name and bitrate of the first audio stream.
-///
-/// # Arguments
-///
-/// * `file_path` - The file path to the audio file.
+/// The cleaning options with titles that clean to nothing named "Chapter {n}", which the tool
+/// always does rather than write an empty chapter title.
+fn numbered_clean_options(clean: &CleanOptions) -> CleanOptions {
+    let mut clean = clean.clone();
+    clean.number_untitled = true;
+    clean
+}
+
+/// Reads one title per line from stdin and prints each cleaned title, for experimenting
+/// with the cleaning options without touching any audio.
+fn print_clean_titles(options: &CleanTitlesOptions) {
+    let titles: Vec<String> = io::stdin().lines().map_while(Result::ok).collect();
+    for title in clean_titles(&titles, &numbered_clean_options(&options.clean)) {
+        console::print(title);
+    }
+}
+
+/// Reads the chapters of one side of `diff`: the chapters of a `plan.json`, the chapters of an
+/// existing book, or the chapters a build would write for an input directory or zip archive.
+fn load_diff_side(input: &str, build: &BuildOptions) -> Result<Vec<plan::Chapter>, String> {
+    let path = Path::new(input);
+    if path.is_dir() || is_zip_archive(path) {
+        let options = BuildOptions { input_directories: vec![input.to_string()], ..build.clone() };
+        let mut chapters = Vec::new();
+        return match plan_or_build(&options, Some(&mut chapters), &mut None) {
+            code if code == ExitCode::SUCCESS => Ok(chapters),
+            _ => Err(format!("Could not plan the chapters of '{}'", input)),
+        };
+    }
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+        let text = fs::read_to_string(path).map_err(|err| format!("Could not read '{}': {}", input, err))?;
+        let plan: BookPlan = serde_json::from_str(text.trim_start_matches('\u{feff}')).map_err(|err| format!("'{}' is not a build plan: {}", input, err))?;
+        return Ok(plan.chapters);
+    }
+    let info = inspect_source(input, build.tag_encoding).ok_or_else(|| format!("Could not read the chapters of '{}'", input))?;
+    Ok(info.chapters.into_iter()
+        .enumerate()
+        .map(|(index, chapter)| plan::Chapter::new(index + 1, chapter.title, chapter.start_ms, chapter.end_ms))
+        .collect())
+}
+
+/// Prints how the chapters of the two sides of `diff` differ.
 ///
 /// # Returns
 ///
-/// An `Option` containing a tuple:
-/// - A `String` representing the codec name.
-/// - An `Option<u64>` representing the bitrate in bits per second (if available).
-fn get_audio_info(file_path: &str) -> Option<(String, Option<u64>)> {
-    let output = Command::new("ffprobe")
-        .args(&[
-            "-v", "error",
-            "-select_streams", "a:0",
-            "-show_entries", "stream=codec_name,bit_rate",
-            "-of", "default=noprint_wrappers=1:nokey=1",
-            file_path,
-        ])
-        .output()
-        .ok()?;
-    if !output.status.success() {
-        eprintln!("ffprobe error for {}: {}", file_path, String::from_utf8_lossy(&output.stderr));
-        return None;
-    }
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    let mut lines = output_str.lines();
-    let codec = lines.next()?.to_string();
-    let bit_rate = lines.next().and_then(|s| s.trim().parse::<u64>().ok());
-    Some((codec, bit_rate))
+/// Success when the chapters are the same, `EXIT_CHAPTERS_DIFFER` when they differ, and failure
+/// when a side cannot be read.
+fn print_chapter_diff(options: &DiffOptions, color: bool) -> ExitCode {
+    let sides = load_diff_side(&options.left, &options.build)
+        .and_then(|left| Ok((left, load_diff_side(&options.right, &options.build)?)));
+    let (left, right) = match sides {
+        Ok(sides) => sides,
+        Err(err) => {
+            console::error(err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let diffs = diff_chapters(&left, &right);
+    let render = |color| if options.unified { render_unified(&diffs, color) } else { render_side_by_side(&diffs, terminal_width(), color) };
+    console::out(&render(color));
+    console::console().record(&render(false));
+    console::print(summarize(&diffs));
+    if diffs.iter().all(|diff| diff.is_same()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_CHAPTERS_DIFFER)
+    }
 }
 
-/// Re-encodes an audio file to AAC using the `libfdk_aac` codec at a constant bitrate
-/// that matches the source file's bitrate (or defaults to 128k if unavailable).
-/// The output is written to a temporary file.
+/// Builds an audiobook from the audio files in the input directory.
 ///
-/// # Arguments
-///
-/// * `file_path` - The file path of the source audio file.
+/// This function:
+/// 1. Validates the input directories, or extracts a zip archive to a temporary work directory.
+/// 2. Searches for supported audio files (mp3, m4a, flac) within the input directories.
+/// 3. Processes chapter titles to clean them up using dynamic token frequency analysis.
+/// 4. Re-encodes each audio file to ensure consistent audio quality and bitrate.
+/// 5. Constructs a concat list (unless there is only one file) and metadata file (including
+///    chapters and durations).
+/// 6. Optionally incorporates a cover image, either given explicitly or found in the first
+///    input directory that has one.
+/// 7. Invokes ffmpeg to merge all processed audio files into a single audiobook file.
 ///
-/// # Returns
+/// # Behavior
 ///
-/// An `Option<NamedTempFile>` containing the temporary file with the re-encoded audio,
-/// or `None` if the process fails.
-fn reencode_audio(file_path: &str) -> Option<NamedTempFile> {
-    // Create a temporary file for the re-encoded output with a .m4a extension.
-    let tmpfile = Builder::new().suffix(".m4a").tempfile().ok()?;
-    let tmpfile_path = tmpfile.path().to_str().unwrap().to_string();
-
-    // Retrieve the source file's bitrate; default to 128k if not available.
-    let bitrate_str = if let Some((_, Some(bit_rate))) = get_audio_info(file_path) {
-        // Convert bits per second to kilobits per second.
-        format!("{}k", bit_rate / 1000)
+/// On success, the final audiobook is saved to `--output`, or else named after its title in the
+/// input directory or next to a zip archive (see `output::output_path`), and `output` is set to
+/// it. With `--bitrate-ladder` one book is built per bitrate, with the bitrate in its name, and
+/// `output` is left unset.
+/// On failure, relevant error messages are printed to the console on stderr.
+fn build_audiobook(options: &BuildOptions, output: &mut Option<String>) -> ExitCode {
+    plan_or_build(options, None, output)
+}
+
+/// The number behind one of the exit codes this tool returns, for `Event::Result`.
+fn exit_status(exit_code: ExitCode) -> u8 {
+    (0..=u8::MAX).find(|status| ExitCode::from(*status) == exit_code).unwrap_or(1)
+}
+
+/// Builds an audiobook like `build_audiobook`, or, given `planned`, stops where a dry run would
+/// print its preview and fills in the chapters the preview shows instead. `output` is set to the
+/// book's path once it is chosen.
+fn plan_or_build(options: &BuildOptions, planned: Option<&mut Vec<plan::Chapter>>, output: &mut Option<String>) -> ExitCode {
+    if options.encode.aac_vbr.is_some() && options.encode.encoder != AacEncoder::Native {
+        console::warn("--aac-vbr only applies to --codec aac; libfdk_aac encodes at a constant bitrate");
+    }
+
+    // Work files go to --temp-dir when given, e.g. because the system temp directory is too small.
+    let temp_root: PathBuf = options.temp_dir.as_ref().map(PathBuf::from).unwrap_or_else(env::temp_dir);
+    if !temp_root.is_dir() {
+        console::error(format!("Temp directory '{}' does not exist", temp_root.display()));
+        return ExitCode::FAILURE;
+    }
+    let same_directory = |a: &Path, b: &Path| matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b);
+    if options.input_directories.iter().any(|input| same_directory(&temp_root, Path::new(input))) {
+        console::error("The temp directory cannot be an input directory itself; use a subdirectory of it");
+        return ExitCode::FAILURE;
+    }
+    // With --output the book's path is known up front, so a second run on it stops here, before
+    // any work; a book named after its title is locked once the title is known.
+    let output_lock = if planned.is_none() && !options.dry_run && !options.estimate && options.bitrate_ladder.is_empty() && options.output.is_some() {
+        match OutputLock::acquire(Path::new(&output_path(options, None)), options.wait_for_lock) {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                console::error(err);
+                return ExitCode::FAILURE;
+            }
+        }
     } else {
-        "128k".to_string() // fallback if bitrate information isn't available
+        None
     };
+    let input_label = options.input_directories.join("', '");
+    // Each source is probed once for its audio and once for its tags, however many steps ask.
+    let probes = SourceProbes::new(&SystemRunner, options.tag_encoding);
+    let mut timer = PhaseTimer::new();
+    timer.begin(BuildPhase::Scan);
 
-    // Execute ffmpeg to re-encode the audio stream using libfdk_aac at the desired bitrate.
-    let status = Command::new("ffmpeg")
-        .args(&[
-            "-i", file_path,
-            "-vn",
-            "-map", "0:a",
-            "-c:a", "libfdk_aac",
-            "-b:a", &bitrate_str,
-            "-y", &tmpfile_path,
-        ])
-        .status()
-        .ok()?;
-    if status.success() {
-        Some(tmpfile)
+    // A zip archive is extracted to a work directory, removed when the build ends,
+    // and then processed like an input directory.
+    let input_path = Path::new(&options.input_directories[0]);
+    let archive = if is_zip_archive(input_path) {
+        match extract_archive(input_path, &temp_root) {
+            Ok(extracted) => Some(extracted),
+            Err(err) => {
+                console::error(err);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else if let Some(invalid) = options.input_directories.iter().find(|input| !Path::new(input).is_dir()) {
+        console::error(format!("'{}' is not a valid directory or zip archive", invalid));
+        return ExitCode::FAILURE;
     } else {
-        eprintln!("Error reencoding file: {}", file_path);
         None
+    };
+    // With --follow-symlinks, a symlinked input directory is resolved to the folder it points at.
+    let input_directories = match &archive {
+        Some(extracted) => vec![extracted.path().to_string_lossy().to_string()],
+        None if options.follow_symlinks => options.input_directories.iter()
+            .map(|input| fs::canonicalize(input).map_or_else(|_| input.clone(), |path| path.to_string_lossy().to_string()))
+            .collect(),
+        None => options.input_directories.clone(),
+    };
+
+    // With --incremental, encodes and the fingerprint of the last build are kept in the input
+    // directory, so that a re-run only encodes new files and skips the mux if nothing changed.
+    let cache = match (options.incremental, &archive) {
+        (false, _) => None,
+        (true, Some(_)) => {
+            console::error("--incremental needs an input directory, not a zip archive");
+            return ExitCode::FAILURE;
+        }
+        (true, None) => match IncrementalCache::open(Path::new(&input_directories[0])) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                console::error(format!("Could not open the incremental cache in '{}': {}", input_directories[0], err));
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    // Collect supported audio files directory by directory, skipping a temp directory or cache
+    // inside an input, so the directory order comes first unless all files are sorted by name together.
+    let excluded_dirs: Vec<&Path> = [Some(temp_root.as_path()), cache.as_ref().map(IncrementalCache::dir)].into_iter().flatten().collect();
+    let mut scanned_entries = Vec::new();
+    for input_directory in &input_directories {
+        scanned_entries.extend(collect_audio_files(input_directory, &excluded_dirs, options.include_hidden, options.extract_audio, options.follow_symlinks));
+    }
+    if options.interleave_sort {
+        scanned_entries.sort_by_key(|entry| entry.file_name().to_os_string());
+    }
+
+    // A video without sound, e.g. a slide recording next to the lectures, has nothing to extract.
+    if options.extract_audio {
+        let (kept, silent) = drop_silent_videos(scanned_entries, |path| probes.audio_info(path).is_some());
+        for video in silent {
+            console::warn(format!("Skipping '{}': it has no audio stream", video.path().display()));
+        }
+        scanned_entries = kept;
+    }
+
+    // Drop extra links to a file that is already included, also across input directories.
+    let (mut audio_file_entries, duplicates) = dedupe_linked_files(scanned_entries);
+    for (duplicate, kept) in duplicates {
+        console::warn(format!(
+            "Skipping '{}': it is the same file as '{}'",
+            duplicate.path().display(),
+            kept.path().display()
+        ));
+    }
+    // With --dedupe-files, also drop copies of a file that is already included.
+    if options.dedupe_files {
+        let (kept, copies) = dedupe_identical_files(audio_file_entries);
+        for (copy, original) in copies {
+            console::warn(format!(
+                "Skipping '{}': it has the same contents as '{}'",
+                copy.path().display(),
+                original.path().display()
+            ));
+        }
+        audio_file_entries = kept;
+    }
+
+    if audio_file_entries.is_empty() {
+        console::error(format!("No supported audio files found in '{}'", input_label));
+        return ExitCode::from(EXIT_NO_AUDIO);
+    }
+
+    // Names garbled by a legacy encoding, e.g. from a zip made on an old Windows system, usually
+    // come with garbled tags too.
+    if let Some(garbled) = audio_file_entries.iter().find(|entry| looks_like_mojibake(&entry.file_name().to_string_lossy())) {
+        console::warn(format!(
+            "'{}' looks garbled by a legacy text encoding; if its tags or chapter titles are too, try --tag-encoding auto",
+            garbled.file_name().to_string_lossy()
+        ));
+    }
+
+    timer.begin(BuildPhase::Probe);
+
+    // Fill in the tags not given on the command line from --config, then from the metadata
+    // defaults files in the book's folder and the author's folder above it, e.g.
+    // `Frank Herbert/m4btool.toml`. A zip archive has no folder of its own, so only the one it
+    // lies in is read.
+    let mut layered_tags = LayeredTags::new(&options.tags);
+    if !options.no_metadata {
+        if let (Some(source), Some(config)) = (&options.config, &options.loaded_config) {
+            layered_tags.fill(&config.tags, &format!("--config {}", source));
+        }
+        let book_dir = fs::canonicalize(input_path).unwrap_or_else(|_| input_path.to_path_buf());
+        let folders = if archive.is_some() {
+            vec![book_dir.parent()]
+        } else {
+            vec![Some(book_dir.as_path()), book_dir.parent()]
+        };
+        for folder in folders.into_iter().flatten() {
+            match load_defaults(folder) {
+                Ok(Some((path, tags))) => layered_tags.fill(&tags, &format!("'{}'", path.display())),
+                Ok(None) => {}
+                Err(err) => {
+                    console::error(err);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+
+    // Let the user's metadata command fill in the tags still missing.
+    // Any failure only costs the looked-up metadata, never the build.
+    let mut looked_up_cover = None;
+    if let Some(command_line) = options.metadata_command.as_ref().filter(|_| !options.no_metadata && !options.compare) {
+        let inferred_title = options.tags.title.clone().unwrap_or_else(|| {
+            fs::canonicalize(input_path).ok()
+                .and_then(|path| path.file_stem().map(|stem| stem.to_string_lossy().to_string()))
+                .unwrap_or_default()
+        });
+        let inferred_author = layered_tags.tags.author.clone().unwrap_or_default();
+        match run_metadata_command(command_line, &inferred_title, &inferred_author, &options.input_directories[0], METADATA_COMMAND_TIMEOUT) {
+            Ok(lookup) => {
+                let mut looked_up = BookTags::default();
+                lookup.fill_missing(&mut looked_up);
+                layered_tags.fill(&looked_up, "--metadata-command");
+                looked_up_cover = lookup.cover.filter(|cover| {
+                    let exists = Path::new(cover).is_file();
+                    if !exists {
+                        console::warn(format!("Ignoring the looked-up cover '{}': the file does not exist", cover));
+                    }
+                    exists
+                });
+            }
+            Err(err) => console::warn(format!("Metadata command failed: {}; continuing without it", err)),
+        }
+    }
+    let book_tags = layered_tags.tags.clone();
+
+    // A --metadata-file is read and checked before anything is encoded, and muxed as it is.
+    let metadata_file = match &options.metadata_file {
+        Some(path) => match load_metadata_file(path) {
+            Ok((text, metadata)) => Some((path, text, metadata)),
+            Err(err) => {
+                console::error(err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    // Files from an archive are sorted by file name like a directory, unless archive order was requested.
+    if let (Some(extracted), true) = (&archive, options.archive_order) {
+        audio_file_entries.sort_by_key(|entry| extracted.audio_files.iter().position(|path| path == entry.path()));
+    }
+
+    // Order by the disc and track tags instead of the file names when requested.
+    if options.sort_by_tags {
+        let positions: Vec<_> = audio_file_entries.iter()
+            .map(|entry| {
+                probes.book_info(entry.path())
+                    .ok_or_else(|| "its tags could not be read".to_string())
+                    .and_then(|info| track_position(&info.tags))
+            })
+            .collect();
+        for (entry, position) in audio_file_entries.iter().zip(&positions) {
+            if let Err(reason) = position {
+                console::warn(format!("Cannot sort '{}' by tags ({}); placing it after the tagged files", entry.path().display(), reason));
+            }
+        }
+        audio_file_entries = order_by_tags(&positions).into_iter().map(|index| audio_file_entries[index].clone()).collect();
+    }
+
+    // A file order from --config overrides every other order.
+    if let Some(order) = options.loaded_config.as_ref().and_then(|config| config.files.as_ref()) {
+        let file_names: Vec<String> = audio_file_entries.iter().map(|entry| entry.file_name().to_string_lossy().to_string()).collect();
+        match order_files(&file_names, order) {
+            Ok(ordered) => {
+                if order.len() < ordered.len() {
+                    console::warn(format!("{} files missing from the configuration's file list follow the listed ones", ordered.len() - order.len()));
+                }
+                audio_file_entries = ordered.into_iter().map(|index| audio_file_entries[index].clone()).collect();
+            }
+            Err(err) => {
+                console::error(format!("Invalid configuration {}: {}", config_label(options.config.as_deref().unwrap_or_default()), err));
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    // Drop files too short to be meaningful chapters (artifacts, stray silence).
+    if let Some(min_duration_ms) = options.min_file_duration_ms {
+        let durations: Vec<Option<u64>> = audio_file_entries.iter()
+            .map(|entry| probes.duration_ms(entry.path()))
+            .collect();
+        let (kept, skipped) = drop_short_files(audio_file_entries, &durations, min_duration_ms);
+        for (entry, duration_ms) in skipped {
+            console::line(format!("Skipping '{}': {} ms is below --min-file-duration", entry.path().display(), duration_ms));
+        }
+        audio_file_entries = kept;
+        if audio_file_entries.is_empty() {
+            console::error(format!("No audio files in '{}' are at least {} ms long", input_label, min_duration_ms));
+            return ExitCode::from(EXIT_NO_AUDIO);
+        }
+    }
+
+    // Pin front and back matter such as credits to the ends of the book, whatever the sort order.
+    let mut placements = vec![Placement::Main; audio_file_entries.len()];
+    if !options.front_matter.is_empty() || !options.back_matter.is_empty() {
+        let file_names: Vec<String> = audio_file_entries.iter().map(|entry| entry.file_name().to_string_lossy().to_string()).collect();
+        match pin_matter(&file_names, &options.front_matter, &options.back_matter) {
+            Ok(pinned) => {
+                audio_file_entries = pinned.iter().map(|(index, _)| audio_file_entries[*index].clone()).collect();
+                placements = pinned.into_iter().map(|(_, placement)| placement).collect();
+            }
+            Err(err) => {
+                console::error(err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    // Build chapter titles and token frequency map for dynamic title cleaning. Front and back
+    // matter keep their file names as titles and do not count towards the word frequencies.
+    let mut chapter_titles: Vec<String> = audio_file_entries.iter()
+        .filter_map(|entry| entry.path().file_stem().map(|stem| stem.to_string_lossy().to_string()))
+        .collect();
+    let sanitized = strip_invisible_titles(chapter_titles.iter_mut());
+    if sanitized > 0 {
+        console::warn(format!("Removed invisible or bidi control characters from the titles of {} files", sanitized));
+    }
+    let main_titles: Vec<String> = chapter_titles.iter()
+        .zip(&placements)
+        .filter(|(_, placement)| **placement == Placement::Main)
+        .map(|(title, _)| title.clone())
+        .collect();
+    let clean = numbered_clean_options(&options.clean);
+    let main_traces = trace_clean_titles(&main_titles, &clean);
+    let mut cleaned_main_titles = if options.title_include_dirs {
+        let main_dirs: Vec<Vec<String>> = audio_file_entries.iter()
+            .zip(&placements)
+            .filter(|(_, placement)| **placement == Placement::Main)
+            .map(|(entry, _)| subdirectories(entry))
+            .collect();
+        clean_titles_with_dirs(&main_titles, &main_dirs, &clean)
+    } else {
+        main_traces.iter().map(|trace| trace.cleaned.clone()).collect()
+    }.into_iter();
+    let mut cleaned_titles: Vec<String> = chapter_titles.iter()
+        .zip(&placements)
+        .map(|(title, placement)| match placement {
+            Placement::Main => cleaned_main_titles.next().unwrap_or_default(),
+            Placement::Front | Placement::Back => title.clone(),
+        })
+        .collect();
+
+    // --compare shows how each file name was cleaned, for tuning the cleaning options, and stops.
+    if options.compare {
+        let mut main_removals = main_traces.into_iter().map(|trace| trace.removed);
+        let rows: Vec<CompareRow> = chapter_titles.iter()
+            .zip(&cleaned_titles)
+            .zip(&placements)
+            .map(|((stem, title), placement)| {
+                let (removed, note) = match placement {
+                    Placement::Main => (main_removals.next().unwrap_or_default(), None),
+                    Placement::Front => (Vec::new(), Some("front matter, not cleaned")),
+                    Placement::Back => (Vec::new(), Some("back matter, not cleaned")),
+                };
+                CompareRow { stem: stem.clone(), title: title.clone(), removed, note }
+            })
+            .collect();
+        let table = render_compare(&rows, terminal_width());
+        console::out(&table);
+        console::console().record(&table);
+        return ExitCode::SUCCESS;
+    }
+
+    // The chapters of --config, or else a chapters.txt in the input directory, name every file's
+    // chapter in book order, unless --no-chapters-file is given or its lines do not match the files.
+    if let Some(titles) = options.loaded_config.as_ref().and_then(|config| config.chapters.clone()) {
+        if titles.len() != cleaned_titles.len() {
+            console::error(format!(
+                "Invalid configuration {}: chapters: {} titles for {} files",
+                config_label(options.config.as_deref().unwrap_or_default()), titles.len(), cleaned_titles.len()
+            ));
+            return ExitCode::FAILURE;
+        }
+        cleaned_titles = titles;
+    } else if !options.no_chapters_file {
+        match load_chapter_titles(Path::new(&input_directories[0])) {
+            Ok(Some(titles)) => match match_chapter_titles(titles, cleaned_titles.len()) {
+                Ok(titles) => {
+                    console::line(format!("Using the chapter titles from {}", CHAPTERS_FILE));
+                    cleaned_titles = titles;
+                }
+                Err(err) => console::warn(format!("Ignoring {}; cleaning the file names instead", err)),
+            },
+            Ok(None) => {}
+            Err(err) => console::warn(err),
+        }
+    }
+
+    // Probe every file once, for the book's channels and sample rate and the preview rows.
+    let source_infos: Vec<Option<AudioInfo>> = audio_file_entries.iter()
+        .map(|entry| probes.audio_info(entry.path()))
+        .collect();
+
+    // Without --channels, settle mixed layouts on the fewest channels among the files instead of
+    // upmixing mono speech into stereo.
+    let mut encode = options.encode.clone();
+    if encode.channels.is_none() {
+        let source_channels: Vec<Option<u32>> = source_infos.iter().map(|info| info.as_ref()?.channels).collect();
+        encode.channels = common_channels(&source_channels);
+        if let Some(channels) = encode.channels {
+            console::line(format!("Encoding as {}; pass --channels to choose", describe_channels(&source_channels, channels)));
+        }
+    }
+
+    // Files with their own settings in --config are encoded apart from the rest of the book, and
+    // the concat only copies files of one sample rate together, so without --sample-rate every
+    // file is resampled to the book's most common rate. The loudness filter of --normalize
+    // raises every file to 192 kHz unless it is given a rate, so it needs one too.
+    let file_overrides: Vec<Option<FileOverride>> = audio_file_entries.iter()
+        .map(|entry| {
+            let stem = entry.path().file_stem().unwrap_or_default().to_string_lossy().to_string();
+            options.loaded_config.as_ref()?.file_override(&entry.file_name().to_string_lossy(), &stem).copied()
+        })
+        .collect();
+    if let Some(config) = &options.loaded_config {
+        for (name, _) in &config.overrides {
+            if !audio_file_entries.iter().any(|entry| entry.file_name().to_string_lossy() == *name || entry.path().file_stem().is_some_and(|stem| stem.to_string_lossy() == *name)) {
+                console::warn(format!("The settings of '{}' in --config match no input file", name));
+            }
+        }
+    }
+    if encode.sample_rate.is_none() && (encode.normalize || file_overrides.iter().any(Option::is_some)) {
+        let source_rates: Vec<Option<u32>> = source_infos.iter().map(|info| info.as_ref()?.sample_rate).collect();
+        encode.sample_rate = common_sample_rate(&source_rates).or(encode.normalize.then_some(44_100));
+        let reason = if encode.normalize { "--normalize keeps one rate" } else { "the files with their own settings join the rest" };
+        if let Some(rate) = encode.sample_rate {
+            console::line(format!("Resampling every file to {} Hz so that {}; pass --sample-rate to choose", rate, reason));
+        }
+    }
+
+    // Work out each file's trim window up front so impossible trims fail before any encoding.
+    // A file's own trims in --config replace --trim-start and --trim-end.
+    let mut trim_windows: Vec<Option<TrimWindow>> = vec![None; audio_file_entries.len()];
+    for (((entry, window), file_override), info) in audio_file_entries.iter().zip(trim_windows.iter_mut()).zip(&file_overrides).zip(&source_infos) {
+        let trim_start_ms = file_override.and_then(|file| file.trim_start_ms).unwrap_or(options.trim_start_ms);
+        let trim_end_ms = file_override.and_then(|file| file.trim_end_ms).unwrap_or(options.trim_end_ms);
+        if trim_start_ms > 0 || trim_end_ms > 0 {
+            let Some(duration_ms) = info.as_ref().and_then(|info| info.duration_ms) else {
+                console::error(format!("Could not retrieve duration of '{}' needed for trimming", entry.path().display()));
+                return ExitCode::FAILURE;
+            };
+            match plan_trim(duration_ms, trim_start_ms, trim_end_ms) {
+                Ok(planned) => *window = Some(planned),
+                Err(err) => {
+                    console::error(format!("Cannot trim '{}': {}", entry.path().display(), err));
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+
+    // The chapters of a --metadata-file must fit the sources, as trimmed, before anything is
+    // encoded; when a source's length is unknown they are checked against the encoded audio.
+    let planned_ms: Option<u64> = trim_windows.iter()
+        .zip(&source_infos)
+        .map(|(trim, info)| trim.map(|window| window.length_ms).or_else(|| info.as_ref()?.duration_ms))
+        .sum();
+    let metadata_file_checked = match (&metadata_file, planned_ms) {
+        (Some((path, _, metadata)), Some(planned_ms)) if !options.no_metadata => {
+            if !metadata_file_fits(path, metadata, planned_ms, options.strict) {
+                return ExitCode::FAILURE;
+            }
+            true
+        }
+        _ => false,
+    };
+
+    // With --preserve-chapters, read the chapters embedded in each source, such as ID3 chapters
+    // in MP3s, with how much of its start is trimmed.
+    let embedded_chapters: Vec<(Vec<ChapterInfo>, u64)> = if options.preserve_chapters && !options.no_metadata {
+        audio_file_entries.iter()
+            .zip(&trim_windows)
+            .map(|(entry, trim)| {
+                let embedded = probes.book_info(entry.path()).map(|info| info.chapters).unwrap_or_default();
+                (embedded, trim.map_or(0, |window| window.start_ms))
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    // Probe every file and collect its warnings, shown next to its row in a dry run and grouped
+    // by file after a build.
+    let note = |message: &str| vec![Warning::new(WarningKind::Note, message)];
+    // The channel count each file is encoded with.
+    let encoded_channels: Vec<Option<u32>> = file_overrides.iter()
+        .zip(&source_infos)
+        .map(|(file_override, info)| {
+            let channels = file_override.as_ref().and_then(|file| file.channels).or(encode.channels);
+            channels.or_else(|| info.as_ref()?.channels)
+        })
+        .collect();
+    let mut rows: Vec<PreviewRow> = audio_file_entries.iter()
+        .zip(&cleaned_titles)
+        .zip(&placements)
+        .zip(file_overrides.iter().zip(source_infos))
+        .enumerate()
+        .map(|(index, (((entry, title), placement), (file_override, info)))| {
+            let mut warnings = match placement {
+                Placement::Front => note("pinned to the start by --front-matter"),
+                Placement::Main if options.clean.chapter_template.is_some()
+                    && is_unnumbered_title(title, &options.clean.unnumbered_titles) => {
+                    note("front or back matter, not numbered by --chapter-template")
+                }
+                Placement::Main => Vec::new(),
+                Placement::Back => note("pinned to the end by --back-matter"),
+            };
+            if let Some(file_override) = file_override {
+                let settings = file_override.apply(&encode);
+                warnings.extend(note(&file_override.describe(&settings)));
+                // The rest of the book has one count, or the fewest of a mix, as the book would.
+                let rest: Vec<Option<u32>> = encoded_channels.iter().enumerate()
+                    .filter(|(other, _)| *other != index)
+                    .map(|(_, channels)| *channels)
+                    .collect();
+                let book_channels = common_channels(&rest).or_else(|| rest.iter().flatten().next().copied());
+                if book_channels.is_some_and(|book| encoded_channels[index].is_some_and(|channels| channels != book)) {
+                    warnings.extend(note("its channel count differs from the rest of the book, which some players only read once"));
+                }
+            }
+            PreviewRow {
+                file_name: entry.file_name().to_string_lossy().to_string(),
+                title: title.clone(),
+                info,
+                warnings,
+                stream_copy: None,
+            }
+        })
+        .collect();
+    flag_outliers(&mut rows);
+    compare_streams(&mut rows);
+
+    // `diff` only needs the chapters of the plan, laid out as a build would from the sources'
+    // durations instead of the encoded files'.
+    if let Some(planned) = planned {
+        *planned = match &metadata_file {
+            _ if options.no_metadata => Vec::new(),
+            Some((_, _, metadata)) => metadata_file_chapters(&metadata.chapters),
+            None => {
+                let timed_titles: Vec<(String, Option<u64>)> = rows.iter()
+                    .zip(&trim_windows)
+                    .map(|(row, trim)| (row.title.clone(), trim.map(|window| window.length_ms).or_else(|| row.info.as_ref()?.duration_ms)))
+                    .collect();
+                let (mut chapters, _) = plan_chapters(&timed_titles, &embedded_chapters, options);
+                let mut planned_chapters = transliterated_chapters(&mut chapters, options.transliterate);
+                delay_chapters(&mut planned_chapters, options.lead_in_ms.unwrap_or(0));
+                planned_chapters
+            }
+        };
+        return ExitCode::SUCCESS;
     }
+
+    // In a dry run, show the plan instead of building.
+    if options.dry_run {
+        let faststart = if options.faststart { ", faststart" } else { "" };
+        match &options.preset {
+            Some(preset) => console::line(format!("Encode settings from --preset {} and the other flags: {}{}", preset.name, describe_settings(&encode), faststart)),
+            None => console::line(format!("Encode settings: {}{}", describe_settings(&encode), faststart)),
+        }
+        if !options.bitrate_ladder.is_empty() {
+            let labels: Vec<String> = options.bitrate_ladder.iter().map(|&bits_per_second| bitrate_label(bits_per_second)).collect();
+            console::line(format!("Bitrate ladder: one version at each of {}, encoded from the same plan", labels.join(", ")));
+        }
+        let metadata = layered_tags.describe();
+        if !metadata.is_empty() {
+            console::line("Book metadata:");
+            for line in metadata {
+                console::line(format!("  {}", line));
+            }
+        }
+        let preview = render_preview(&rows, options.table_format, terminal_width());
+        console::out(&preview);
+        console::console().record(&preview);
+        return ExitCode::SUCCESS;
+    }
+    let planned_build = PlannedBuild {
+        temp_root,
+        input_path,
+        input_directories,
+        archive: archive.as_ref(),
+        cache: cache.as_ref(),
+        audio_file_entries,
+        cleaned_titles,
+        trim_windows,
+        file_overrides,
+        embedded_chapters,
+        file_warnings: rows.into_iter().map(|row| (row.file_name, row.warnings)).collect(),
+        book_tags,
+        metadata_file,
+        metadata_file_checked,
+        looked_up_cover,
+        encode,
+        probes: &probes,
+        output_locked: output_lock.is_some(),
+    };
+    if options.bitrate_ladder.is_empty() {
+        return encode_and_mux(options, planned_build, &mut timer, output);
+    }
+    // Each version is encoded from the sources at its bitrate rather than transcoded from
+    // another version, and gets the same chapters and cover from the same plan.
+    let mut built = Vec::new();
+    for rung in ladder_builds(options) {
+        let bits_per_second = rung.ladder_rung.unwrap_or_default();
+        console::line(format!("Building the {} version", bitrate_label(bits_per_second)));
+        let mut rung_output = None;
+        let exit_code = encode_and_mux(&rung, planned_build.clone(), &mut timer, &mut rung_output);
+        built.push((bits_per_second, rung_output.unwrap_or_default(), exit_code));
+    }
+    if options.estimate {
+        return ExitCode::SUCCESS;
+    }
+    report_ladder(&built)
 }
 
-/// Main entry point of the audiobook creation tool.
+/// The global tags of the generated FFMETADATA file. Title and author are passed as -metadata
+/// arguments; only the date goes into the file, the same one the -metadata arguments carry,
+/// wherever it came from: a tag option, `--config`, a metadata defaults file, or the sources.
+fn ffmetadata_global_tags(book_tags: &BookTags) -> GlobalTags {
+    let mut global_tags = GlobalTags::default();
+    global_tags.date = book_tags.date.clone().or_else(|| book_tags.year.clone());
+    global_tags
+}
+
+/// Checks the chapters of a `--metadata-file` against the length of the audio, printing how they
+/// do not fit. A re-encode can make the audio slightly shorter or longer than the book the file
+/// was made for, which `check_chapter_file_length` allows for.
 ///
-/// This function:
-/// 1. Validates command-line arguments and input directory.
-/// 2. Searches for supported audio files (mp3, m4a, flac) within the input directory.
-/// 3. Processes chapter titles to clean them up using dynamic token frequency analysis.
-/// 4. Re-encodes each audio file to ensure consistent audio quality and bitrate.
-/// 5. Constructs a concat list and metadata file (including chapters and durations).
-/// 6. Optionally incorporates a cover image if present.
-/// 7. Invokes ffmpeg to merge all processed audio files into a single audiobook file.
+/// # Returns
 ///
-/// # Behavior
+/// Whether the build can go on: a chapter past the end of the audio fails it, and so does one
+/// ending early with `strict`.
+fn metadata_file_fits(path: &str, metadata: &FfMetadata, audio_ms: u64, strict: bool) -> bool {
+    match check_chapter_file_length(&metadata.chapters, audio_ms) {
+        Ok(None) => true,
+        Ok(Some(mismatch)) if strict => {
+            console::error(format!("The metadata file '{}' does not fit the audio: {}", path, mismatch));
+            false
+        }
+        Ok(Some(mismatch)) => {
+            console::warn(format!("The metadata file '{}' does not fit the audio: {}; pass --strict to fail instead", path, mismatch));
+            true
+        }
+        Err(err) => {
+            console::error(format!("The metadata file '{}' does not fit the audio: {}", path, err));
+            false
+        }
+    }
+}
+
+/// Prints the outcome of each version of a `--bitrate-ladder`.
 ///
-/// On success, the final audiobook is saved as `output.m4b` in the input directory.
-/// On failure, relevant error messages are printed to stderr.
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <input_directory>", args[0]);
-        return;
+/// # Returns
+///
+/// The worst outcome of any version: a failure, else a degraded book.
+fn report_ladder(built: &[(u64, String, ExitCode)]) -> ExitCode {
+    let degraded = ExitCode::from(EXIT_DEGRADED);
+    console::print("Bitrate ladder:");
+    for (bits_per_second, output, exit_code) in built {
+        let outcome = match *exit_code {
+            ExitCode::SUCCESS => "",
+            code if code == degraded => " (degraded)",
+            _ => " (failed)",
+        };
+        console::print(format!("  {:<6} {}{}", bitrate_label(*bits_per_second), output, outcome));
     }
-    let input_directory = &args[1];
-    if !Path::new(input_directory).is_dir() {
-        eprintln!("Error: '{}' is not a valid directory", input_directory);
-        return;
+    match built.iter().find(|(.., exit_code)| ![ExitCode::SUCCESS, degraded].contains(exit_code)) {
+        Some((.., failed)) => *failed,
+        None if built.iter().any(|(.., exit_code)| *exit_code == degraded) => degraded,
+        None => ExitCode::SUCCESS,
     }
+}
+
+/// What a build has worked out before encoding: the files in book order with their chapter
+/// titles, trims, and settings, and the book's tags. With `--bitrate-ladder` every version is
+/// encoded and muxed from the same plan.
+#[derive(Clone)]
+struct PlannedBuild<'a> {
+    temp_root: PathBuf,
+    input_path: &'a Path,
+    input_directories: Vec<String>,
+    archive: Option<&'a ExtractedArchive>,
+    cache: Option<&'a IncrementalCache>,
+    audio_file_entries: Vec<walkdir::DirEntry>,
+    cleaned_titles: Vec<String>,
+    trim_windows: Vec<Option<TrimWindow>>,
+    file_overrides: Vec<Option<FileOverride>>,
+    embedded_chapters: Vec<(Vec<ChapterInfo>, u64)>,
+    /// Each source file's name with the warnings the plan found.
+    file_warnings: Vec<(String, Vec<Warning>)>,
+    book_tags: BookTags,
+    metadata_file: Option<(&'a String, String, FfMetadata)>,
+    /// Whether the chapters of the `--metadata-file` were checked against the sources' lengths.
+    metadata_file_checked: bool,
+    looked_up_cover: Option<String>,
+    encode: EncodeSettings,
+    probes: &'a SourceProbes<'a>,
+    /// Whether `plan_or_build` already holds the lock on the output.
+    output_locked: bool,
+}
+
+/// Encodes the files of a plan and muxes them into the book, the part of `plan_or_build` that
+/// each version of a `--bitrate-ladder` repeats. `output` is set to the book's path once it is
+/// chosen.
+fn encode_and_mux(options: &BuildOptions, planned_build: PlannedBuild, timer: &mut PhaseTimer, output: &mut Option<String>) -> ExitCode {
+    let PlannedBuild {
+        temp_root,
+        input_path,
+        input_directories,
+        archive,
+        cache,
+        audio_file_entries,
+        cleaned_titles,
+        trim_windows,
+        file_overrides,
+        mut embedded_chapters,
+        mut file_warnings,
+        mut book_tags,
+        metadata_file,
+        metadata_file_checked,
+        looked_up_cover,
+        encode,
+        probes,
+        output_locked,
+    } = planned_build;
+
+    // Without --output the book is named after its title: from the tag options, the metadata
+    // defaults files, or --metadata-command, else from the --metadata-file, the first file's
+    // album tag, or the input's name.
+    let output_title = if options.output.is_some() {
+        None
+    } else {
+        book_tags.title.clone()
+            .or_else(|| metadata_file.as_ref().and_then(|(_, _, metadata)| metadata.global_tag("title").map(str::to_string)))
+            .or_else(|| {
+                let first = audio_file_entries.first()?;
+                probes.book_info(first.path())?.tags.get("album").cloned()
+            })
+            .filter(|title| !title.trim().is_empty())
+            .or_else(|| input_title(input_path))
+    };
+    let audiobook_output_path = output_path(options, output_title.as_deref());
+    *output = Some(audiobook_output_path.clone());
+    let inputs: Vec<PathBuf> = options.input_directories.iter()
+        .map(|input| fs::canonicalize(input).unwrap_or_else(|_| PathBuf::from(input)))
+        .collect();
+    book_tags.provenance = Some(provenance(&inputs));
+    // Keep a second run on the same output, e.g. from a scheduler that fired twice, from
+    // replacing the book or reusing the cache while this one works. Held until the build ends.
+    let _output_lock = if options.estimate || output_locked {
+        None
+    } else {
+        match OutputLock::acquire(Path::new(&audiobook_output_path), options.wait_for_lock) {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                console::error(err);
+                return ExitCode::FAILURE;
+            }
+        }
+    };
 
-    // Define the output audiobook path.
-    let audiobook_output_path = format!("{}/output.m4b", input_directory);
-    if Path::new(&audiobook_output_path).exists() {
+    // On a terminal, confirm before replacing an existing book or starting a long encode. An
+    // incremental build replaces its own previous book without asking.
+    // Waiting for an answer does not count towards any phase.
+    timer.end();
+    // A book named after its title may share the name of a different book, which is only
+    // replaced with --overwrite.
+    if !options.estimate && options.output.is_none() && !options.overwrite && Path::new(&audiobook_output_path).exists() {
+        let existing = inspect_book(&audiobook_output_path).and_then(|info| info.tags.get(PROVENANCE_KEY).cloned());
+        if let Err(err) = check_replace(&audiobook_output_path, existing.as_deref(), book_tags.provenance.as_deref().unwrap_or_default()) {
+            console::error(err);
+            return ExitCode::FAILURE;
+        }
+    }
+    if !options.estimate && !options.overwrite && cache.is_none() && Path::new(&audiobook_output_path).exists() && !console::confirm(format!("'{}' already exists. Overwrite it?", audiobook_output_path)) {
+        console::line("Cancelled; nothing was written");
+        return ExitCode::FAILURE;
+    }
+    if console::console().prompts() && !options.estimate {
+        let total_ms: u64 = audio_file_entries.iter().filter_map(|entry| probes.duration_ms(entry.path())).sum();
+        let estimate_ms = estimate_encode_ms(total_ms, options.jobs.unwrap_or(1), options.normalize_two_pass);
+        if estimate_ms > LONG_ENCODE_MS
+            && !console::confirm(format!(
+                "Encoding {:.1} hours of audio will take about {} minutes. Continue?",
+                total_ms as f64 / 3_600_000.0,
+                estimate_ms.div_ceil(60_000)
+            ))
+        {
+            console::line("Cancelled; nothing was written");
+            return ExitCode::FAILURE;
+        }
+    }
+    timer.begin(BuildPhase::Probe);
+    if !options.estimate && cache.is_none() && Path::new(&audiobook_output_path).exists() {
         if let Err(err) = fs::remove_file(&audiobook_output_path) {
-            eprintln!("Error removing existing file '{}': {}", audiobook_output_path, err);
-            return;
+            console::error(format!("Could not remove existing file '{}': {}", audiobook_output_path, err));
+            return ExitCode::FAILURE;
         }
     }
 
-    // Collect supported audio files from the input directory and sort them by filename.
-    let mut audio_file_entries: Vec<_> = WalkDir::new(input_directory)
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            entry.file_type().is_file() &&
-            entry.path().extension().map(|ext| {
-                let ext_lc = ext.to_string_lossy().to_lowercase();
-                ext_lc == "mp3" || ext_lc == "m4a" || ext_lc == "flac"
-            }).unwrap_or(false)
+    // Without --year or --date, take the date from the first file's tags when it has a valid one.
+    // A --metadata-file replaces the tags taken from the files.
+    if !options.no_metadata && metadata_file.is_none() && book_tags.year.is_none() && book_tags.date.is_none() {
+        book_tags.date = audio_file_entries.first()
+            .and_then(|entry| probes.book_info(entry.path()))
+            .and_then(|info| ["date", "year"].iter().find_map(|key| parse_date(info.tags.get(*key)?).ok()));
+    }
+
+    // Without --language, use the language most source files are tagged with, if any.
+    if !options.no_metadata && metadata_file.is_none() && book_tags.language.is_none() {
+        let languages: Vec<Option<String>> = audio_file_entries.iter()
+            .map(|entry| probes.book_info(entry.path()).and_then(|info| info.language))
+            .collect();
+        book_tags.language = majority_language(&languages);
+    }
+
+    // Load per-file bitrate overrides and warn about entries that match no input file.
+    let mut bitrate_overrides = match &options.bitrate_overrides {
+        Some(path) => match BitrateOverrides::load(path) {
+            Ok(overrides) => overrides,
+            Err(err) => {
+                console::error(err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => BitrateOverrides::default(),
+    };
+    if let Some(bits_per_second) = options.ladder_rung {
+        bitrate_overrides.set_all(bits_per_second);
+    }
+    let input_names: Vec<(String, String)> = audio_file_entries.iter()
+        .map(|entry| {
+            let stem = entry.path().file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+            (entry.file_name().to_string_lossy().to_string(), stem)
         })
         .collect();
-    audio_file_entries.sort_by_key(|entry| entry.file_name().to_os_string());
+    for (name, suggestion) in bitrate_overrides.unmatched(&input_names) {
+        match suggestion {
+            Some(closest) => console::warn(format!("Bitrate override '{}' matches no input file (did you mean '{}'?)", name, closest)),
+            None => console::warn(format!("Bitrate override '{}' matches no input file", name)),
+        }
+    }
+    // A bitrate among a file's own settings in --config replaces the sidecar's.
+    for (name, file_override) in options.loaded_config.iter().flat_map(|config| &config.overrides) {
+        if let Some(bits_per_second) = file_override.bitrate {
+            bitrate_overrides.insert(name, bits_per_second);
+        }
+    }
 
-    if audio_file_entries.is_empty() {
-        eprintln!("No supported audio files found in '{}'", input_directory);
-        return;
+    // With --estimate, project the size from each file's target bitrate and the time from a short
+    // benchmark encode of the shortest file, then stop without building.
+    if options.estimate {
+        let file_settings: Vec<EncodeSettings> = file_overrides.iter()
+            .map(|file_override| file_override.map_or_else(|| encode.clone(), |file| file.apply(&encode)))
+            .collect();
+        let sources: Vec<Option<SourceEstimate>> = audio_file_entries.iter()
+            .zip(&trim_windows)
+            .zip(&file_settings)
+            .map(|((entry, trim), settings)| {
+                let duration_ms = trim.map(|window| window.length_ms).or_else(|| probes.duration_ms(entry.path()))?;
+                let stem = entry.path().file_stem().unwrap_or_default().to_string_lossy().to_string();
+                let bitrate_override = bitrate_overrides.get(&entry.file_name().to_string_lossy(), &stem);
+                Some(SourceEstimate { duration_ms, bits_per_second: target_bits_per_second(probes.audio_info(entry.path()).as_ref(), settings, bitrate_override).bits_per_second })
+            })
+            .collect();
+        // The benchmark runs the encode that file will get, with its own settings in --config.
+        let shortest = audio_file_entries.iter()
+            .zip(&file_settings)
+            .zip(&sources)
+            .filter_map(|((entry, settings), source)| Some((entry, settings, (*source)?.duration_ms)))
+            .min_by_key(|(_, _, duration_ms)| *duration_ms);
+        let speed = shortest.and_then(|(entry, settings, duration_ms)| {
+            let stem = entry.path().file_stem().unwrap_or_default().to_string_lossy().to_string();
+            let bitrate_override = bitrate_overrides.get(&entry.file_name().to_string_lossy(), &stem);
+            let target = target_bits_per_second(probes.audio_info(entry.path()).as_ref(), settings, bitrate_override);
+            benchmark_speed(&entry.path().to_string_lossy(), duration_ms, settings, &target, &temp_root)
+        });
+        if speed.is_none() {
+            console::warn("The benchmark encode failed; assuming a typical encode speed");
+        }
+        let estimate = Estimate::new(&sources, speed, options.jobs.unwrap_or(1), options.normalize_two_pass).render();
+        console::out(&estimate);
+        console::console().record(&estimate);
+        return ExitCode::SUCCESS;
     }
 
-    // Build chapter titles and token frequency map for dynamic title cleaning.
-    let chapter_titles: Vec<String> = audio_file_entries.iter()
-        .filter_map(|entry| entry.path().file_stem().map(|stem| stem.to_string_lossy().to_string()))
+    // Use the explicit cover if given, otherwise attempt to locate one with a supported extension.
+    // Several explicit covers are combined into one image, or the first is used if that fails.
+    // Without any cover file, keep the picture embedded in the sources, so remuxing an existing
+    // book keeps its cover. A plain concatenation or --no-cover carries no cover.
+    if let Some(missing) = options.covers.iter().find(|cover| !Path::new(cover).is_file()) {
+        console::error(format!("Cover image '{}' does not exist", missing));
+        return ExitCode::FAILURE;
+    }
+    for cover in &options.covers {
+        if let Err(err) = inspect_cover(cover) {
+            console::error(format!("Cover image '{}' is not usable: {}", cover, err));
+            return ExitCode::FAILURE;
+        }
+    }
+    let source_files: Vec<PathBuf> = audio_file_entries.iter().map(|entry| entry.path().to_path_buf()).collect();
+    let mut collage_path = None;
+    let mut extracted_cover_path = None;
+    let cover_image_path = match options.covers.as_slice() {
+        _ if options.no_metadata || options.no_cover => None,
+        [cover] => Some(cover.clone()),
+        [first, ..] => match compose_cover(&options.covers, options.cover_layout, &temp_root) {
+            Ok(collage) => {
+                let path = collage.to_string_lossy().to_string();
+                collage_path = Some(collage);
+                Some(path)
+            }
+            Err(err) => {
+                console::warn(format!("Could not combine the covers ({}); using '{}'", err, first));
+                Some(first.clone())
+            }
+        },
+        [] if looked_up_cover.is_some() => looked_up_cover.clone(),
+        [] if archive.is_some() => archive.as_ref()
+            .and_then(|extracted| extracted.cover.as_ref())
+            .map(|cover| cover.to_string_lossy().to_string()),
+        [] => input_directories.iter()
+            .flat_map(|input_directory| COVER_EXTENSIONS.iter().map(move |ext| format!("{}/cover.{}", input_directory, ext)))
+            .find(|path| Path::new(path).exists())
+            .or_else(|| {
+                let source = first_with_cover(&source_files, |file| probes.book_info(file))?;
+                match extract_cover(source, &temp_root, &SystemRunner) {
+                    Ok(cover) => {
+                        console::line(format!("Using the cover embedded in '{}'", source.display()));
+                        let path = cover.to_string_lossy().to_string();
+                        extracted_cover_path = Some(cover);
+                        Some(path)
+                    }
+                    Err(err) => {
+                        console::warn(format!("Could not extract the cover embedded in '{}': {}", source.display(), err));
+                        None
+                    }
+                }
+            }),
+    };
+
+    // Check the cover now instead of failing the mux after the encode, and convert phone photos
+    // in HEIC or AVIF to JPEG, which players can show.
+    let mut converted_cover_path = None;
+    let cover_image_path = match cover_image_path {
+        Some(cover) => {
+            let checked = inspect_cover(&cover).and_then(|image| {
+                if !image.format.needs_conversion() {
+                    return Ok((cover.clone(), image));
+                }
+                let converted = convert_cover(&cover, &temp_root, &SystemRunner)
+                    .map_err(|err| format!("could not convert it from {}: {}", image.format.name(), err))?;
+                let path = converted.to_string_lossy().to_string();
+                let image = inspect_cover(&path)?;
+                converted_cover_path = Some(converted);
+                Ok((path, image))
+            });
+            match checked {
+                Ok((path, image)) => {
+                    console::line(format!("Cover: '{}' ({})", cover, describe_cover(&image)));
+                    if let Some(warning) = small_cover_warning(&image) {
+                        console::warn(format!("The cover '{}' {}", cover, warning));
+                    }
+                    Some(path)
+                }
+                Err(err) => {
+                    console::error(format!("Cover image '{}' is not usable: {}", cover, err));
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Turn a phone photo upright by its EXIF orientation, which players ignore in an attached
+    // picture. A cover that cannot be rotated is attached as it is.
+    let mut oriented_cover_path = None;
+    let cover_image_path = match cover_image_path {
+        Some(cover) if !options.no_auto_orient_cover => match orient_cover(&cover, &temp_root, &SystemRunner) {
+            Ok(None) => Some(cover),
+            Ok(Some(oriented)) => {
+                console::line(format!("Turned the cover '{}' upright by its EXIF orientation", cover));
+                let path = oriented.to_string_lossy().to_string();
+                oriented_cover_path = Some(oriented);
+                Some(path)
+            }
+            Err(err) => {
+                console::warn(format!("Could not turn the cover '{}' upright ({}); attaching it as it is", cover, err));
+                Some(cover)
+            }
+        },
+        cover => cover,
+    };
+
+    // Shrink the cover to fit --max-cover-bytes; a cover that fits is then attached unchanged.
+    let mut shrunk_cover_path = None;
+    let mut copy_cover = false;
+    let cover_image_path = match (cover_image_path, options.max_cover_bytes) {
+        (Some(cover), Some(max_bytes)) => match shrink_cover(&cover, max_bytes, &temp_root, &SystemRunner) {
+            Ok(None) => {
+                copy_cover = true;
+                Some(cover)
+            }
+            Ok(Some(shrunk)) => {
+                console::line(format!("Shrank the cover '{}' to fit {} bytes", cover, max_bytes));
+                copy_cover = true;
+                let path = shrunk.to_string_lossy().to_string();
+                shrunk_cover_path = Some(shrunk);
+                Some(path)
+            }
+            Err(err) => {
+                console::warn(format!("Could not shrink the cover '{}' ({}); attaching it as it is", cover, err));
+                Some(cover)
+            }
+        },
+        (cover, _) => cover,
+    };
+
+    // With --incremental, key each file's encode by its contents and settings, and stop when
+    // nothing that goes into the book changed since the last build.
+    let mut source_keys: Vec<Option<String>> = vec![None; audio_file_entries.len()];
+    let mut book_fingerprint = None;
+    if let Some(cache) = &cache {
+        for ((entry, (trim, key)), file_override) in audio_file_entries.iter().zip(trim_windows.iter().zip(source_keys.iter_mut())).zip(&file_overrides) {
+            let stem = entry.path().file_stem().unwrap_or_default().to_string_lossy().to_string();
+            let bitrate_override = bitrate_overrides.get(&entry.file_name().to_string_lossy(), &stem);
+            let file_encode = file_override.map_or_else(|| encode.clone(), |file| file.apply(&encode));
+            let settings = format!("{:?} {:?} {:?} {}", file_encode, bitrate_override, trim, options.normalize_two_pass);
+            match source_key(entry.path(), &settings) {
+                Ok(source_key) => *key = Some(source_key),
+                Err(err) => console::warn(format!("Could not read '{}' for the incremental cache: {}", entry.path().display(), err)),
+            }
+        }
+        let mut parts: Vec<String> = source_keys.iter().map(|key| key.clone().unwrap_or_default()).collect();
+        parts.push(format!("{:?}", options));
+        parts.push(format!("{:?}", book_tags));
+        parts.push(format!("{:?}", cleaned_titles));
+        for input in cover_image_path.iter().chain(&options.bitrate_overrides).chain(&options.metadata_file) {
+            parts.push(hash_file(Path::new(input)).unwrap_or_default());
+        }
+        let current = fingerprint(&parts);
+        if source_keys.iter().all(Option::is_some) && cache.is_up_to_date(&current, Path::new(&audiobook_output_path)) {
+            console::print(format!("Up to date: '{}'", audiobook_output_path));
+            return ExitCode::SUCCESS;
+        }
+        book_fingerprint = Some(current);
+    }
+    let cached_encodes: Vec<Option<PathBuf>> = source_keys.iter()
+        .map(|key| cache.as_ref().zip(key.as_deref()).and_then(|(cache, key)| cache.encoded(key)))
         .collect();
-    let token_frequency_map = build_token_frequency(&chapter_titles);
-    let total_chapters = chapter_titles.len();
+    let reused_count = cached_encodes.iter().flatten().count();
+
+    // Sum the source sizes now, for the work directory placement, the space check, and the
+    // compression ratio reported by --stats.
+    let source_bytes: u64 = audio_file_entries.iter()
+        .filter_map(|entry| fs::metadata(entry.path()).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let output_dir = match Path::new(&audiobook_output_path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    // When the system temp directory, often a small tmpfs, cannot hold the encoded files, write
+    // them next to the output instead. An explicit --temp-dir is always used as given.
+    let mut moved_work_dir: Option<TempDir> = None;
+    if options.temp_dir.is_none() {
+        if let WorkDirPlacement::NextToOutput { reason } = place_work_dir(&temp_root, &output_dir, source_bytes, &SystemSpace) {
+            match Builder::new().prefix(WORK_DIR_PREFIX).tempdir_in(&output_dir) {
+                Ok(dir) => {
+                    console::line(format!("Writing the work files to '{}': {}; pass --temp-dir to choose", dir.path().display(), reason));
+                    moved_work_dir = Some(dir);
+                }
+                Err(err) => console::warn(format!("Could not create a work directory next to the output: {}", err)),
+            }
+        }
+    }
+    let work_root: PathBuf = moved_work_dir.as_ref().map_or_else(|| temp_root.clone(), |dir| dir.path().to_path_buf());
+
+    // Two-pass encoding keeps its pass logs in a work directory that is removed when the build ends.
+    let passlog_dir: Option<TempDir> = if options.normalize_two_pass {
+        match tempfile::tempdir_in(&work_root) {
+            Ok(dir) => Some(dir),
+            Err(err) => {
+                console::error(format!("Could not create work directory for pass logs: {}", err));
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+
+    // Every ffmpeg run's full stderr is logged per source file, for the post-mortem of a failed build.
+    let log_dir = match Builder::new().prefix("m4btool-logs-").tempdir_in(&temp_root) {
+        Ok(dir) => dir,
+        Err(err) => {
+            console::error(format!("Could not create work directory for logs: {}", err));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // With --fail-on-warning, an encode or mux that succeeds but warns of damage fails the build.
+    let warning_check = options.fail_on_warning
+        .then(|| WarningCheck::new(&options.ffmpeg_warning_patterns).expect("patterns checked when parsing"));
 
     let mut reencoded_tempfiles: Vec<NamedTempFile> = Vec::new();
     let mut final_files: Vec<(String, String)> = Vec::new();
+    // The position of each final file among the sources, by which its warnings are kept.
+    let mut source_indices: Vec<usize> = Vec::new();
 
     // Re-encode all audio files to ensure a consistent audio format.
-    for entry in audio_file_entries {
-        let file_path = entry.path().to_str().unwrap().to_string();
-        let original_title = entry.path().file_stem().unwrap().to_string_lossy().to_string();
-        let mut final_file_path = file_path.clone();
-
-        if let Some(tmpfile) = reencode_audio(&file_path) {
-            final_file_path = tmpfile.path().to_str().unwrap().to_string();
-            reencoded_tempfiles.push(tmpfile);
-        } else {
-            eprintln!("Re-encoding failed for {}. Using original file.", file_path);
-        }
-        final_files.push((final_file_path, original_title));
-    }
-
-    // Create a temporary file listing all files for ffmpeg concatenation.
-    let mut concat_file = NamedTempFile::new().expect("Could not create temporary file for concat list");
-    for (file_path, _) in &final_files {
-        writeln!(concat_file, "file '{}'", file_path).expect("Error writing to concat list file");
-    }
-    let concat_file_path = concat_file.into_temp_path();
-
-    // Generate metadata file with chapter markers, durations, and cleaned titles.
-    let metadata_temp_file = NamedTempFile::new().expect("Could not create temporary file for metadata");
-    {
-        let mut metadata_writer = BufWriter::new(&metadata_temp_file);
-        writeln!(metadata_writer, ";FFMETADATA1").expect("Error writing metadata header");
-
-        let mut current_chapter_start_ms = 0u64;
-        for (file_path, original_title) in &final_files {
-            let cleaned_title = dynamic_clean_title(&original_title, &token_frequency_map, total_chapters, 0.8);
-            if let Some(duration_ms) = get_duration_ms(file_path) {
-                let chapter_end_ms = current_chapter_start_ms + duration_ms;
-                writeln!(metadata_writer, "[CHAPTER]").expect("Error writing chapter marker");
-                writeln!(metadata_writer, "TIMEBASE=1/1000").expect("Error writing timebase");
-                writeln!(metadata_writer, "START={}", current_chapter_start_ms).expect("Error writing chapter start");
-                writeln!(metadata_writer, "END={}", chapter_end_ms).expect("Error writing chapter end");
-                writeln!(metadata_writer, "title={}", cleaned_title).expect("Error writing chapter title");
-                current_chapter_start_ms = chapter_end_ms;
-            } else {
-                eprintln!("Warning: Could not retrieve duration for file '{}'", file_path);
-            }
-        }
-        metadata_writer.flush().expect("Error flushing metadata writer");
-    }
-    let metadata_file_path = metadata_temp_file.into_temp_path();
-
-    // Attempt to locate a cover image with a supported extension.
-    let cover_image_extensions = ["jpg", "jpeg", "png", "webp"];
-    let cover_image_path = cover_image_extensions.iter()
-        .map(|ext| format!("{}/cover.{}", input_directory, ext))
-        .find(|path| Path::new(path).exists());
-
-    // Build the ffmpeg command with appropriate arguments based on whether a cover image is present.
-    let mut ffmpeg_cmd = Command::new("ffmpeg");
-    ffmpeg_cmd
-        .arg("-f")
-        .arg("concat")
-        .arg("-safe")
-        .arg("0")
-        .arg("-i")
-        .arg(concat_file_path.to_str().unwrap());
-
-    if let Some(ref cover_path) = cover_image_path {
-        ffmpeg_cmd
-            .arg("-i")
-            .arg(cover_path)
-            .arg("-i")
-            .arg(metadata_file_path.to_str().unwrap())
-            .arg("-map")
-            .arg("0:a")
-            .arg("-map")
-            .arg("1")
-            .arg("-map_metadata")
-            .arg("2");
-    } else {
-        ffmpeg_cmd
-            .arg("-i")
-            .arg(metadata_file_path.to_str().unwrap())
-            .arg("-map")
-            .arg("0:a")
-            .arg("-map_metadata")
-            .arg("1");
+    // The encoded files and the book are each at most about the size of the sources. When the
+    // work directory shares a filesystem with the output, both are checked against it together.
+    for shortage in check_space(&[(&work_root, source_bytes), (&output_dir, source_bytes)], filesystem_space) {
+        console::warn(format!("Low disk space: {}", shortage));
     }
+    // Each file's duration is probed as soon as it is encoded, while other files are still encoding.
+    let job_count = audio_file_entries.len();
+    let started_jobs = AtomicUsize::new(0);
+    let mut book_plan = BookPlan::new(source_files, Vec::new());
+    book_plan.encode_settings = encode.clone();
+    book_plan.faststart = options.faststart;
+    book_plan.file_settings = book_plan.files.iter()
+        .zip(&file_overrides)
+        .filter_map(|(file, file_override)| {
+            let file_override = file_override.as_ref()?;
+            let mut settings = FileSettings::new(file.clone(), file_override.apply(&encode));
+            settings.trim_start_ms = file_override.trim_start_ms;
+            settings.trim_end_ms = file_override.trim_end_ms;
+            Some(settings)
+        })
+        .collect();
+    book_plan.output = Some(PathBuf::from(&audiobook_output_path));
+    book_plan.work_dir = Some(work_root.clone());
+    let jobs: Vec<_> = audio_file_entries.into_iter().zip(cleaned_titles).zip(trim_windows.into_iter().zip(file_overrides)).collect();
+    timer.begin(BuildPhase::Encode);
+    let encode_job = |job_index: usize, ((entry, cleaned_title), (trim, file_override)): ((walkdir::DirEntry, String), (Option<TrimWindow>, Option<FileOverride>)), runner: &dyn CommandRunner| {
+        let started = started_jobs.fetch_add(1, Ordering::Relaxed) + 1;
+        console::console().progress(started, job_count, &format!("Encoding {}", entry.file_name().to_string_lossy()));
+        let file_path = entry.path().to_string_lossy().to_string();
+        let file_done = |elapsed: Duration, outcome| progress::emit(Event::FileDone {
+            index: job_index + 1,
+            total: job_count,
+            file: entry.file_name().to_string_lossy().to_string(),
+            elapsed_ms: elapsed.as_millis() as u64,
+            outcome,
+        });
+        if let Some(cached) = &cached_encodes[job_index] {
+            file_done(Duration::ZERO, FileOutcome::Cached);
+            return Ok((cached.to_string_lossy().to_string(), cleaned_title, None, Duration::ZERO));
+        }
+        let original_title = entry.path().file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let bitrate_override = bitrate_overrides.get(&entry.file_name().to_string_lossy(), &original_title);
 
-    ffmpeg_cmd.arg("-c:a").arg("copy");
+        let passlog = passlog_dir.as_ref().map(|dir| passlog_path(dir.path(), job_index));
+        let log = log_dir.path().join(encode_log_name(job_index, entry.path()));
 
-    if cover_image_path.is_some() {
-        ffmpeg_cmd.arg("-c:v")
-                  .arg("mjpeg")
-                  .arg("-disposition:v:0")
-                  .arg("attached_pic");
+        let settings = file_override.map_or_else(|| encode.clone(), |file| file.apply(&encode));
+        let tools = EncodeTools { runner, warnings: warning_check.as_ref(), work_dir: &work_root, log: &log };
+        let target = target_bits_per_second(probes.audio_info(entry.path()).as_ref(), &settings, bitrate_override);
+        let (reencoded, elapsed) = profile::measure(|| reencode_audio(&file_path, &settings, &target, passlog.as_deref(), trim, &tools));
+        match reencoded {
+            Ok(tmpfile) => {
+                file_done(elapsed, FileOutcome::Encoded);
+                Ok((tmpfile.path().to_string_lossy().to_string(), cleaned_title, Some(tmpfile), elapsed))
+            }
+            // The source would hide the damage the check is there to catch.
+            Err(EncodeFailure::Warned(warning)) => Err(warning),
+            Err(EncodeFailure::Failed) => {
+                console::warn(format!("Using the original file for '{}'", file_path));
+                file_done(elapsed, FileOutcome::Original);
+                Ok((file_path, cleaned_title, None, elapsed))
+            }
+        }
+    };
+    let probe_job = |_: usize, encoded: &Result<(String, String, Option<NamedTempFile>, Duration), String>, runner: &dyn CommandRunner| match encoded {
+        Ok((final_file_path, _, _, _)) if !options.no_metadata => probe_duration_ms(final_file_path, runner),
+        _ => None,
+    };
+    let mut durations = Vec::with_capacity(job_count);
+    let encoded = encode_and_probe(jobs, options.jobs.unwrap_or(1), &SystemRunner, encode_job, probe_job);
+    let mut encoded_count = 0;
+    let mut skipped_files = Vec::new();
+    for (index, (source, outcome)) in book_plan.files.iter().zip(encoded).enumerate() {
+        // A file whose processing failed outright is left out of the book, or stops the build
+        // with --strict.
+        let ((final_file_path, cleaned_title, tmpfile, elapsed), duration_ms) = match outcome {
+            Ok((Ok(done), duration_ms)) => (done, duration_ms),
+            Ok((Err(warning), _)) => {
+                console::console().finish_progress();
+                console::error(format!("Encoding '{}' failed: ffmpeg warned '{}'; failing because of --fail-on-warning", source.display(), warning));
+                return ExitCode::FAILURE;
+            }
+            Err(err) if options.strict => {
+                console::console().finish_progress();
+                console::error(format!("Could not process '{}': {}", source.display(), err));
+                return ExitCode::FAILURE;
+            }
+            Err(err) => {
+                console::warn(format!("Skipping '{}': processing it failed ({}); pass --strict to stop instead", source.display(), err));
+                file_warnings[index].1.push(Warning::new(WarningKind::Skipped, "processing failed; left out of the book"));
+                skipped_files.push(index);
+                continue;
+            }
+        };
+        timer.record_file(source.file_name().unwrap_or_default().to_string_lossy(), elapsed);
+        if tmpfile.is_none() && cached_encodes[index].is_none() {
+            file_warnings[index].1.push(Warning::new(WarningKind::ProbeFallback, "could not be encoded; the original file is used"));
+            // The original is used whole, so its embedded chapters start where it does.
+            if let Some((_, start_ms)) = embedded_chapters.get_mut(index) {
+                *start_ms = 0;
+            }
+            for settings in book_plan.file_settings.iter_mut().filter(|settings| settings.file == *source) {
+                settings.trim_start_ms = None;
+                settings.trim_end_ms = None;
+            }
+        }
+        if let (Some(cache), Some(key), Some(tmpfile)) = (&cache, &source_keys[index], &tmpfile) {
+            match cache.store(key, tmpfile.path()) {
+                Ok(_) => encoded_count += 1,
+                Err(err) => console::warn(format!("Could not cache the encode of '{}': {}", source.display(), err)),
+            }
+        }
+        reencoded_tempfiles.extend(tmpfile);
+        final_files.push((final_file_path, cleaned_title));
+        source_indices.push(index);
+        durations.push(duration_ms);
+    }
+    console::console().finish_progress();
+    if final_files.is_empty() {
+        console::error("No file could be processed");
+        return ExitCode::FAILURE;
     }
+    book_plan.files = without_indices(book_plan.files, &skipped_files);
+    book_plan.file_settings.retain(|settings| book_plan.files.contains(&settings.file));
+    embedded_chapters = without_indices(embedded_chapters, &skipped_files);
+    timer.begin(BuildPhase::Metadata);
 
-    ffmpeg_cmd
-        .arg("-metadata")
-        .arg("title=Audiobook")
-        .arg(&audiobook_output_path);
+    // With --lead-in, encode that much silence like the encoded files to go before the first one.
+    let lead_in_path = match options.lead_in_ms {
+        Some(lead_in_ms) => {
+            let format = LeadInFormat::matching(&encode, get_audio_info(&final_files[0].0).as_ref());
+            match make_lead_in(&encode, format, lead_in_ms, &work_root, &SystemRunner) {
+                Ok(lead_in) => Some(lead_in),
+                Err(err) => {
+                    console::error(format!("Could not make the lead-in: {}", err));
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => None,
+    };
 
-    println!("Executing ffmpeg command: {:?}", ffmpeg_cmd);
+    // Create a temporary file listing all files for ffmpeg concatenation. A single file is
+    // remuxed directly instead.
+    let concat_file_path = (final_files.len() > 1 || lead_in_path.is_some()).then(|| {
+        let mut concat_file = NamedTempFile::new_in(&work_root).expect("Could not create temporary file for concat list");
+        let final_paths: Vec<String> = lead_in_path.iter()
+            .map(|lead_in| lead_in.to_string_lossy().to_string())
+            .chain(final_files.iter().map(|(file_path, _)| file_path.clone()))
+            .collect();
+        write_concat_list(&mut concat_file, &final_paths).expect("Error writing to concat list file");
+        concat_file.into_temp_path()
+    });
 
-    // Execute the constructed ffmpeg command and log the result.
-    match ffmpeg_cmd.output() {
-        Ok(output) => {
-            if !output.status.success() {
-                eprintln!("FFmpeg execution failed: {}", String::from_utf8_lossy(&output.stderr));
-            } else {
-                println!("Success: Audiobook created at '{}'", audiobook_output_path);
+    // Generate metadata file with chapter markers, durations, and cleaned titles,
+    // unless a plain concatenation without any metadata was requested.
+    let mut chapters: Vec<(String, u64)> = Vec::new();
+    let mut planned_chapter_count = 0;
+    let metadata_file_path = if options.no_metadata {
+        None
+    } else if let Some((path, text, metadata)) = &metadata_file {
+        // Without the length of every source before encoding, check the chapters against the
+        // encoded audio instead.
+        if metadata_file_checked {
+            // Already checked against the sources.
+        } else if durations.iter().any(Option::is_none) {
+            console::warn(format!("Could not check '{}' against the length of the audio: some durations are unknown", path));
+        } else if !metadata_file_fits(path, metadata, durations.iter().flatten().sum(), options.strict) {
+            return ExitCode::FAILURE;
+        }
+        book_plan.chapters = metadata_file_chapters(&metadata.chapters);
+        chapters = book_plan.chapters.iter().map(|chapter| (chapter.title.clone(), chapter.end_ms - chapter.start_ms)).collect();
+        planned_chapter_count = chapters.len();
+
+        let mut metadata_temp_file = NamedTempFile::new_in(&work_root).expect("Could not create temporary file for metadata");
+        metadata_temp_file.write_all(text.as_bytes()).expect("Error writing metadata file");
+        Some(metadata_temp_file.into_temp_path())
+    } else {
+        for ((file_path, duration_ms), &source_index) in final_files.iter().map(|(file_path, _)| file_path).zip(&durations).zip(&source_indices) {
+            if duration_ms.is_none() {
+                console::warn(format!("Could not retrieve duration for file '{}'", file_path));
+                file_warnings[source_index].1.push(Warning::new(WarningKind::ProbeFallback, "duration unknown; left out of the chapters"));
+            }
+        }
+        let timed_titles: Vec<(String, Option<u64>)> = final_files.iter().map(|(_, title)| title.clone()).zip(durations.iter().copied()).collect();
+        (chapters, planned_chapter_count) = plan_chapters(&timed_titles, &embedded_chapters, options);
+        book_plan.chapters = transliterated_chapters(&mut chapters, options.transliterate);
+        let timed_files: Vec<(PathBuf, u64)> = book_plan.files.iter().cloned().zip(durations.iter().map(|duration_ms| duration_ms.unwrap_or(0))).collect();
+        assign_sources(&mut book_plan.chapters, &timed_files);
+        let kept_warnings = without_indices(file_warnings.clone(), &skipped_files);
+        let warnings_by_path: Vec<(PathBuf, Vec<Warning>)> = book_plan.files.iter().cloned().zip(kept_warnings.into_iter().map(|(_, warnings)| warnings)).collect();
+        attach_warnings(&mut book_plan.chapters, &warnings_by_path);
+        // The chapters start after the lead-in, which belongs to none of them.
+        delay_chapters(&mut book_plan.chapters, options.lead_in_ms.unwrap_or(0));
+
+        if let Err(err) = check_timeline(&chapter_spans(&chapters), MIN_CHAPTER_MS) {
+            report_fatal(&temp_root, &PostMortem {
+                error: &format!("Invalid chapter timeline: {}", err),
+                command_line: None,
+                stderr: &[],
+                concat_list: concat_file_path.as_deref(),
+                metadata: None,
+                log_dir: Some(log_dir.path()),
+                plan: &book_plan,
+            });
+            return ExitCode::FAILURE;
+        }
+
+        let global_tags = ffmetadata_global_tags(&book_tags);
+        let mut metadata_temp_file = NamedTempFile::new_in(&work_root).expect("Could not create temporary file for metadata");
+        metadata_temp_file.write_all(write_ffmetadata_chapters(&book_plan.chapters, &global_tags).as_bytes()).expect("Error writing metadata file");
+        Some(metadata_temp_file.into_temp_path())
+    };
+
+    // Fall back to a generic title when none was supplied, and summarize the final chapter plan
+    // for library software. A --metadata-file keeps its own tags, which only the tag options
+    // override.
+    if metadata_file.is_none() {
+        book_tags.title.get_or_insert_with(|| "Audiobook".to_string());
+        book_tags.track = Some("1/1".to_string());
+        book_tags.total_duration_ms = Some(options.lead_in_ms.unwrap_or(0) + chapters.iter().map(|(_, duration_ms)| duration_ms).sum::<u64>());
+        book_tags.chapter_count = Some(chapters.len());
+    }
+    book_plan.metadata = book_tags.clone();
+    if let Some((_, _, metadata)) = &metadata_file {
+        let file_tag = |key: &str| metadata.global_tag(key).map(str::to_string);
+        book_plan.metadata.title = book_plan.metadata.title.take().or_else(|| file_tag("title"));
+        book_plan.metadata.author = book_plan.metadata.author.take().or_else(|| file_tag("artist"));
+        if book_plan.metadata.year.is_none() {
+            book_plan.metadata.date = book_plan.metadata.date.take().or_else(|| file_tag("date"));
+        }
+    }
+    book_plan.cover = cover_image_path.as_ref().map(PathBuf::from);
+
+    // With --chapter-thumbnails, show a small copy of the cover during every chapter in a timed
+    // image track. A thumbnail that cannot be made leaves the book without the track.
+    let mut chapter_thumbnail_path = None;
+    let mut chapter_images_path = None;
+    if let Some(cover) = cover_image_path.as_deref().filter(|_| options.chapter_thumbnails) {
+        match make_chapter_thumbnail(cover, &work_root, &SystemRunner) {
+            Ok(thumbnail) => {
+                // The first image also covers the lead-in.
+                let lead_in_ms = book_plan.lead_in_ms();
+                let durations: Vec<u64> = (lead_in_ms > 0).then_some(lead_in_ms).into_iter()
+                    .chain(book_plan.chapters.iter().map(|chapter| chapter.end_ms.saturating_sub(chapter.start_ms)))
+                    .collect();
+                let mut images_file = NamedTempFile::new_in(&work_root).expect("Could not create temporary file for chapter images");
+                images_file.write_all(write_image_list(&thumbnail.to_string_lossy(), &durations).as_bytes()).expect("Error writing chapter images list");
+                chapter_images_path = Some(images_file.into_temp_path());
+                chapter_thumbnail_path = Some(thumbnail);
             }
+            Err(err) => console::warn(format!("Could not make the chapter thumbnails from the cover '{}' ({}); writing the book without them", cover, err)),
+        }
+    }
+    let plan = MuxPlan {
+        audio: match &concat_file_path {
+            Some(concat_list) => MuxInput::Concat(concat_list),
+            None => MuxInput::File(Path::new(&final_files[0].0)),
         },
-        Err(err) => {
-            eprintln!("Error executing ffmpeg command: {}", err);
+        cover: cover_image_path.as_deref(),
+        copy_cover,
+        metadata: metadata_file_path.as_deref(),
+        chapter_images: chapter_images_path.as_deref(),
+        tags: (!options.no_metadata).then_some(&book_tags),
+        brand: options.brand.or_else(|| Brand::for_output(&audiobook_output_path)),
+        faststart: book_plan.faststart,
+        extra_args: &options.mux_args,
+        output: &audiobook_output_path,
+    };
+    if let Some(dump_dir) = &options.dump_intermediate {
+        match dump_intermediate(&plan, Path::new(dump_dir)) {
+            Ok(written) => written.iter().for_each(|path| console::line(format!("Wrote '{}'", path.display()))),
+            Err(err) => console::warn(format!("Could not write the intermediate files to '{}': {}", dump_dir, err)),
         }
     }
+    // A broken concat list would otherwise only fail the mux, after all the other work.
+    if let Some(Err(err)) = concat_file_path.as_deref().map(|concat_list| check_concat_list(concat_list, &SystemRunner)) {
+        report_fatal(&temp_root, &PostMortem {
+            error: &err,
+            command_line: None,
+            stderr: &[],
+            concat_list: concat_file_path.as_deref(),
+            metadata: metadata_file_path.as_deref(),
+            log_dir: Some(log_dir.path()),
+            plan: &book_plan,
+        });
+        return ExitCode::FAILURE;
+    }
+    let mut print_mux_command = |ffmpeg_cmd: &Command| {
+        if options.print_command {
+            console::print(shell::command_line(ffmpeg_cmd));
+        } else {
+            console::print(format!("Executing ffmpeg command: {:?}", ffmpeg_cmd));
+        }
+    };
+
+    // An incremental build left the previous book in place until now.
+    if cache.is_some() && Path::new(&audiobook_output_path).exists() {
+        if let Err(err) = fs::remove_file(&audiobook_output_path) {
+            console::error(format!("Could not remove existing file '{}': {}", audiobook_output_path, err));
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // Execute the mux and log the result.
+    timer.begin(BuildPhase::Mux);
+    let cover_optional = !options.require_cover && !options.strict;
+    let exit_code = match run_mux(&plan, &SystemRunner, cover_optional, warning_check.as_ref(), &mut print_mux_command) {
+        Ok(outcome) => {
+            if outcome.cover_dropped {
+                console::warn(format!(
+                    "The audiobook was created WITHOUT its cover '{}'; pass --strict to fail instead",
+                    plan.cover.unwrap_or_default()
+                ));
+            } else if plan.chapter_images.is_some() {
+                if let Err(err) = link_chapter_images(Path::new(&audiobook_output_path)) {
+                    console::warn(format!("{}; players will not show the chapter thumbnails", err));
+                }
+            }
+            console::print(format!("Success: Audiobook created at '{}'", audiobook_output_path));
+            timer.begin(BuildPhase::Verify);
+            if let Some(brand) = plan.brand {
+                check_brand(&audiobook_output_path, brand);
+            }
+            if let Some(tags) = plan.tags {
+                check_written_tags(&audiobook_output_path, tags);
+            }
+            if chapters.len() < planned_chapter_count {
+                console::print(format!("Chapters: {} (coalesced from {})", chapters.len(), planned_chapter_count));
+            }
+            if options.write_vtt {
+                let vtt_path = Path::new(&audiobook_output_path).with_extension("vtt");
+                match fs::write(&vtt_path, book_plan.write_vtt()) {
+                    Ok(()) => console::print(format!("Chapters written to '{}'", vtt_path.display())),
+                    Err(err) => console::warn(format!("Could not write '{}': {}", vtt_path.display(), err)),
+                }
+            }
+            // Library managers look for their sidecars in the book's folder.
+            let sidecars = [
+                (options.write_opf, OPF_FILE, write_opf(&book_tags)),
+                (options.write_audiobookshelf_metadata, AUDIOBOOKSHELF_FILE, write_audiobookshelf_metadata(&book_tags, &book_plan.chapters)),
+            ];
+            for (_, file_name, contents) in sidecars.iter().filter(|(wanted, ..)| *wanted) {
+                let sidecar_path = Path::new(&audiobook_output_path).with_file_name(file_name);
+                match fs::write(&sidecar_path, contents) {
+                    Ok(()) => console::print(format!("Metadata written to '{}'", sidecar_path.display())),
+                    Err(err) => console::warn(format!("Could not write '{}': {}", sidecar_path.display(), err)),
+                }
+            }
+            // With --also-export-tracks the encoded files also become loose tracks, tagged as
+            // an album of the book.
+            let mut tracks_failed = false;
+            if let Some(dir) = &options.export_tracks {
+                let tracks: Vec<ExportTrack> = final_files.iter()
+                    .map(|(audio, title)| ExportTrack { audio, title })
+                    .collect();
+                let cover = plan.cover.filter(|_| !outcome.cover_dropped);
+                match export_tracks(&tracks, Path::new(dir), &book_plan.metadata, cover, copy_cover, &SystemRunner) {
+                    Ok(written) => console::print(format!("Tracks: {} written to '{}'", written.len(), dir)),
+                    Err(err) => {
+                        console::warn(format!("Could not export the tracks: {}", err));
+                        tracks_failed = true;
+                    }
+                }
+            }
+            if let Some(recap) = render_warning_recap(&file_warnings) {
+                console::print(recap.trim_end());
+            }
+            if options.stats {
+                match stats::measure_book(&audiobook_output_path, source_bytes) {
+                    Ok(book_stats) => console::print(book_stats.recap().trim_end()),
+                    Err(err) => console::warn(format!("Could not measure the audiobook: {}", err)),
+                }
+            }
+            if let (Some(cache), Some(book_fingerprint)) = (&cache, &book_fingerprint) {
+                console::print(format!("Reused {} encoded files, encoded {}", reused_count, encoded_count));
+                let keys: Vec<String> = source_keys.iter().flatten().cloned().collect();
+                // A book with skipped files is not up to date, so the next run tries them again.
+                let saved = cache.prune(&keys).and_then(|_| {
+                    if skipped_files.is_empty() { cache.save_state(book_fingerprint, reused_count, encoded_count) } else { Ok(()) }
+                });
+                if let Err(err) = saved {
+                    console::warn(format!("Could not update the incremental cache: {}", err));
+                }
+            }
+            timer.end();
+            if options.profile {
+                console::print(timer.report(durations.iter().flatten().sum()).trim_end());
+            }
+            if outcome.cover_dropped || !skipped_files.is_empty() || tracks_failed { ExitCode::from(EXIT_DEGRADED) } else { ExitCode::SUCCESS }
+        }
+        Err(failure) => {
+            report_fatal(&temp_root, &PostMortem {
+                error: &failure.message,
+                command_line: failure.command_line.as_deref(),
+                stderr: &failure.stderr,
+                concat_list: concat_file_path.as_deref(),
+                metadata: metadata_file_path.as_deref(),
+                log_dir: Some(log_dir.path()),
+                plan: &book_plan,
+            });
+            ExitCode::FAILURE
+        }
+    };
+
+    // Keep the concat list, metadata, and re-encoded files so the printed command can be re-run,
+    // and with --keep-temp also the encode logs.
+    if options.keep_temp {
+        let log_dir = log_dir.keep();
+        console::line(format!("Kept '{}'", log_dir.display()));
+    }
+    if options.print_command || options.keep_temp {
+        let kept = reencoded_tempfiles.into_iter().map(|tmpfile| tmpfile.into_temp_path())
+            .chain(concat_file_path)
+            .chain(metadata_file_path)
+            .chain(collage_path)
+            .chain(extracted_cover_path)
+            .chain(converted_cover_path)
+            .chain(oriented_cover_path)
+            .chain(shrunk_cover_path)
+            .chain(chapter_thumbnail_path)
+            .chain(chapter_images_path)
+            .chain(lead_in_path)
+            .map(|temp_path| temp_path.keep());
+        // The kept files must outlive the work directory next to the output, if there is one.
+        let kept: Vec<_> = kept.collect();
+        if let Some(dir) = moved_work_dir {
+            let _ = dir.keep();
+        }
+        for kept_path in kept {
+            match kept_path {
+                Ok(path) => console::line(format!("Kept '{}'", path.display())),
+                Err(err) => console::warn(format!("Could not keep '{}': {}", err.path.display(), err.error)),
+            }
+        }
+    }
+    exit_code
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::{self, File};
-    use std::io::Write;
-    use tempfile::tempdir;
-    use walkdir::WalkDir;
 
-    /// Tests that `split_title_tokens` correctly identifies both bracketed and non-bracketed tokens.
+    /// Tests that only the titles with invisible characters are changed and counted.
+    #[test]
+    fn test_strip_invisible_titles() {
+        let mut titles = vec!["01\u{200B} Arrakis".to_string(), "02 Desert ".to_string(), "\u{FEFF}03 \u{202E}hctieS".to_string()];
+        assert_eq!(strip_invisible_titles(titles.iter_mut()), 2);
+        assert_eq!(titles, vec!["01 Arrakis", "02 Desert ", "03 hctieS"]);
+        assert_eq!(strip_invisible_titles(titles.iter_mut()), 0);
+    }
+
+    /// Tests that only files below the minimum duration are dropped, keeping the order of the rest.
+    #[test]
+    fn test_drop_short_files() {
+        let files = vec!["01.mp3", "blip.mp3", "02.mp3", "unknown.mp3", "03.mp3"];
+        let durations = [Some(600_000), Some(400), Some(1_000), None, Some(999)];
+        let (kept, skipped) = drop_short_files(files, &durations, 1_000);
+        assert_eq!(kept, vec!["01.mp3", "02.mp3", "unknown.mp3"]);
+        assert_eq!(skipped, vec![("blip.mp3", 400), ("03.mp3", 999)]);
+    }
+
+    /// Tests that the chapters are laid out as a build writes them, with each file's embedded
+    /// chapters, without files of unknown duration, and with a tiny last chapter merged.
+    #[test]
+    fn test_plan_chapters() {
+        let files = vec![
+            ("Prologue".to_string(), Some(60_000)),
+            ("Unknown".to_string(), None),
+            ("Arrakis".to_string(), Some(120_000)),
+            ("Blip".to_string(), Some(400)),
+        ];
+        let embedded = |titles: &[&str]| titles.iter()
+            .enumerate()
+            .map(|(index, title)| ChapterInfo { start_ms: index as u64 * 60_000, end_ms: (index as u64 + 1) * 60_000, title: title.to_string() })
+            .collect::<Vec<_>>();
+        let embedded_chapters = vec![(Vec::new(), 0), (Vec::new(), 0), (embedded(&["The Desert", "The Sietch"]), 0), (Vec::new(), 0)];
+        let (chapters, planned_count) = plan_chapters(&files, &embedded_chapters, &BuildOptions::default());
+        assert_eq!(chapters, vec![("Prologue".to_string(), 60_000), ("The Desert".to_string(), 60_000), ("The Sietch".to_string(), 60_400)]);
+        assert_eq!(planned_count, 3);
+
+        let split = BuildOptions { time_split: Some(chapters::TimeSplit::Count(2)), ..BuildOptions::default() };
+        let (chapters, _) = plan_chapters(&files, &[], &split);
+        assert_eq!(chapters.iter().map(|(_, duration_ms)| duration_ms).sum::<u64>(), 180_400);
+        assert_eq!(chapters.len(), 2);
+    }
+
+    /// Tests that a metadata file that runs past the planned audio stops the build, and one that
+    /// ends early only does so with --strict.
+    #[test]
+    fn test_metadata_file_fits() {
+        let text = ";FFMETADATA1\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=60000\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=60000\nEND=120000\n";
+        let metadata = m4btool::read_ffmetadata(text).unwrap();
+        assert!(metadata_file_fits("book.txt", &metadata, 120_500, true));
+        assert!(!metadata_file_fits("book.txt", &metadata, 90_000, false));
+        assert!(metadata_file_fits("book.txt", &metadata, 180_000, false));
+        assert!(!metadata_file_fits("book.txt", &metadata, 180_000, true));
+    }
+
+    /// Tests a book whose last file is a 0.4 s "The End" through the chapter steps of a build,
+    /// from the encoded files' durations to the metadata file the mux reads: by default the file
+    /// joins the previous chapter, and with `--keep-tiny-chapters` it is padded to a second.
+    /// Either way every chapter written is one ffmpeg keeps, so the book has as many chapters as
+    /// were planned.
+    #[test]
+    fn test_tiny_final_file() {
+        let files = vec![("Part 1".to_string(), Some(61_000)), ("Part 2".to_string(), Some(59_000)), ("The End".to_string(), Some(400))];
+        for keep_tiny_chapters in [false, true] {
+            let options = BuildOptions { keep_tiny_chapters, ..BuildOptions::default() };
+            let (mut chapters, planned_count) = plan_chapters(&files, &[], &options);
+            assert_eq!(planned_count, chapters.len());
+            assert_eq!(check_timeline(&chapter_spans(&chapters), MIN_CHAPTER_MS), Ok(()));
+            let planned_chapters = transliterated_chapters(&mut chapters, options.transliterate);
+
+            let text = write_ffmetadata_chapters(&planned_chapters, &GlobalTags::default());
+            let read_back = m4btool::read_ffmetadata(&text).unwrap().chapters;
+            let spans: Vec<(u64, u64)> = read_back.iter().map(|chapter| (chapter.start_ms, chapter.end_ms)).collect();
+            assert_eq!(spans.last().map(|&(_, end_ms)| end_ms), Some(120_400));
+            assert!(spans.iter().all(|(start_ms, end_ms)| end_ms - start_ms >= MIN_CHAPTER_MS));
+            let titles: Vec<&str> = read_back.iter().filter_map(|chapter| chapter.title.as_deref()).collect();
+            if keep_tiny_chapters {
+                assert_eq!(spans, vec![(0, 61_000), (61_000, 119_400), (119_400, 120_400)]);
+                assert_eq!(titles, vec!["Part 1", "Part 2", "The End"]);
+            } else {
+                assert_eq!(spans, vec![(0, 61_000), (61_000, 120_400)]);
+                assert_eq!(titles, vec!["Part 1", "Part 2"]);
+            }
+        }
+    }
+
+    /// Tests that a scanned file's subdirectories are taken relative to the input directory.
     #[test]
-    fn test_split_title_tokens() {
-        let title = "Chapter 1 [Intro] (Overview)";
-        let tokens = split_title_tokens(title);
-        assert!(!tokens.is_empty());
-        let bracketed: Vec<_> = tokens.iter().filter(|t| t.is_bracketed).collect();
-        assert!(!bracketed.is_empty());
+    fn test_subdirectories() {
+        let book = tempfile::tempdir().unwrap();
+        fs::create_dir_all(book.path().join("Part One/Disc 1")).unwrap();
+        fs::write(book.path().join("Part One/Disc 1/01.mp3"), b"").unwrap();
+        fs::write(book.path().join("00.mp3"), b"").unwrap();
+        let entries = collect_audio_files(&book.path().to_string_lossy(), &[], false, false, false);
+        let dirs: Vec<Vec<String>> = entries.iter().map(subdirectories).collect();
+        assert_eq!(dirs, vec![Vec::<String>::new(), vec!["Part One".to_string(), "Disc 1".to_string()]]);
     }
 
-    /// Tests that `dynamic_clean_title` properly cleans a title by removing common tokens.
+    /// Tests the chapters of a single-file book: its whole title as one chapter without a chapter
+    /// source, and the embedded or time-based chapters with one.
     #[test]
-    fn test_dynamic_clean_title() {
-        let titles = vec![
-            "Chapter 1 [Intro]".to_string(),
-            "Chapter 2 [Intro]".to_string(),
-            "Chapter 3 [Intro]".to_string(),
+    fn test_single_file_chapters() {
+        let titles = clean_titles(&["Dune - Book One".to_string()], &Default::default());
+        assert_eq!(titles, vec!["Dune - Book One"]);
+        assert_eq!(expand_embedded_chapters(&titles[0], 3_600_000, &[], 0), vec![("Dune - Book One".to_string(), 3_600_000)]);
+
+        let embedded = vec![
+            ChapterInfo { start_ms: 0, end_ms: 1_200_000, title: "Prologue".to_string() },
+            ChapterInfo { start_ms: 1_200_000, end_ms: 3_600_000, title: "Arrakis".to_string() },
         ];
-        let freq = build_token_frequency(&titles);
-        let cleaned = dynamic_clean_title("Chapter 1 [Intro]", &freq, titles.len(), 0.8);
-        assert!(!cleaned.is_empty());
+        assert_eq!(
+            expand_embedded_chapters(&titles[0], 3_600_000, &embedded, 0),
+            vec![("Prologue".to_string(), 1_200_000), ("Arrakis".to_string(), 2_400_000)]
+        );
+        assert_eq!(split_by_time(3_600_000, chapters::TimeSplit::Count(3)).len(), 3);
     }
 
-    /// Tests the collection of audio files from a directory, ensuring only supported files are picked up.
+    /// Tests that the FFMETADATA file gets the book's date, or else its year, whatever set it.
     #[test]
-    fn test_collect_audio_files() {
-        let dir = tempdir().unwrap();
-        let file_names = ["test.mp3", "audio.m4a", "sound.flac", "ignore.txt"];
-        for name in &file_names {
-            let file_path = dir.path().join(name);
-            File::create(&file_path).unwrap();
-        }
-        let audio_files: Vec<_> = WalkDir::new(dir.path())
-            .into_iter()
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| {
-                entry.file_type().is_file() &&
-                entry.path().extension().map(|ext| {
-                    let ext_lc = ext.to_string_lossy().to_lowercase();
-                    ext_lc == "mp3" || ext_lc == "m4a" || ext_lc == "flac"
-                }).unwrap_or(false)
-            })
-            .collect();
-        assert_eq!(audio_files.len(), 3);
+    fn test_ffmetadata_global_tags() {
+        let dated = BookTags { year: Some("1965".to_string()), date: Some("1965-08-01".to_string()), ..Default::default() };
+        assert_eq!(ffmetadata_global_tags(&dated).date.as_deref(), Some("1965-08-01"));
+        let from_year = BookTags { year: Some("1965".to_string()), ..Default::default() };
+        assert_eq!(ffmetadata_global_tags(&from_year).date.as_deref(), Some("1965"));
+        assert_eq!(ffmetadata_global_tags(&BookTags::default()).date, None);
+    }
+
+    /// Tests that a folder with only a cover stops before touching the existing book or running
+    /// the metadata command.
+    #[cfg(unix)]
+    #[test]
+    fn test_directory_without_audio() {
+        let input = tempfile::tempdir().unwrap();
+        fs::write(input.path().join("cover.jpg"), b"jpeg").unwrap();
+        fs::write(input.path().join("output.m4b"), b"the previous build").unwrap();
+        let marker = input.path().join("looked-up");
+        let options = BuildOptions {
+            input_directories: vec![input.path().to_string_lossy().to_string()],
+            metadata_command: Some(format!("sh -c 'touch \"$0\"' '{}'", marker.display())),
+            ..Default::default()
+        };
+        assert_eq!(build_audiobook(&options, &mut None), ExitCode::from(EXIT_NO_AUDIO));
+        assert_eq!(fs::read(input.path().join("output.m4b")).unwrap(), b"the previous build");
+        assert!(!marker.exists());
+    }
+
+    /// Tests two builds into an output that a first run holds: the one without --wait-for-lock
+    /// stops right away, and the one with it goes on once the first run is done.
+    #[test]
+    fn test_builds_on_locked_output() {
+        let input = tempfile::tempdir().unwrap();
+        let output = input.path().join("Dune.m4b");
+        fs::write(&output, b"the previous build").unwrap();
+        let options = BuildOptions {
+            input_directories: vec![input.path().to_string_lossy().to_string()],
+            output: Some(output.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let first_run = OutputLock::acquire(&output, false).unwrap();
+
+        let waiting = {
+            let options = BuildOptions { wait_for_lock: true, ..options.clone() };
+            std::thread::spawn(move || build_audiobook(&options, &mut None))
+        };
+        assert_eq!(build_audiobook(&options, &mut None), ExitCode::FAILURE);
+        assert!(!waiting.is_finished());
+        assert!(lock::lock_path(&output).is_file());
+
+        drop(first_run);
+        // With the lock free, the waiting build goes on to find that there is nothing to build.
+        assert_eq!(waiting.join().unwrap(), ExitCode::from(EXIT_NO_AUDIO));
+        assert!(!lock::lock_path(&output).exists());
+        assert_eq!(fs::read(&output).unwrap(), b"the previous build");
     }
 }
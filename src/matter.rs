@@ -0,0 +1,166 @@
+use std::cmp::Ordering;
+
+use regex::Regex;
+
+/// Where a file goes in the book.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement {
+    /// Pinned to the start by `--front-matter`, such as opening credits.
+    Front,
+    /// Ordered by the book's sort order.
+    Main,
+    /// Pinned to the end by `--back-matter`, such as closing credits or an author's note.
+    Back,
+}
+
+/// Returns `true` if a file name matches a glob with `*` (any run of characters) and `?` (one
+/// character). Matching is case-insensitive, so `*credits*` matches "00 Opening Credits.mp3".
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut regex = String::from("(?i)^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).is_ok_and(|regex| regex.is_match(name))
+}
+
+/// Compares names the way people count: runs of digits by their value, so "2" comes before "10",
+/// and everything else case-insensitively.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(digit);
+                    }
+                    digits.trim_start_matches('0').to_string()
+                };
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Decides where a file goes from the `--front-matter` and `--back-matter` globs.
+///
+/// # Returns
+///
+/// The placement, or an error message if the file matches globs of both kinds.
+pub fn placement(file_name: &str, front: &[String], back: &[String]) -> Result<Placement, String> {
+    let front_match = front.iter().find(|pattern| glob_matches(pattern, file_name));
+    let back_match = back.iter().find(|pattern| glob_matches(pattern, file_name));
+    match (front_match, back_match) {
+        (Some(front), Some(back)) => Err(format!(
+            "'{}' matches both --front-matter '{}' and --back-matter '{}'",
+            file_name, front, back
+        )),
+        (Some(_), None) => Ok(Placement::Front),
+        (None, Some(_)) => Ok(Placement::Back),
+        (None, None) => Ok(Placement::Main),
+    }
+}
+
+/// Moves front matter to the start and back matter to the end of the book.
+///
+/// The main files keep their order, while the front and back matter are each ordered among
+/// themselves by `natural_cmp` of their file names.
+///
+/// # Arguments
+///
+/// * `file_names` - The file names in the book's sort order.
+/// * `front`, `back` - The `--front-matter` and `--back-matter` globs.
+///
+/// # Returns
+///
+/// The new order as indices into `file_names`, each with its placement, or the error for the
+/// first file that matches both kinds of glob.
+pub fn pin_matter(file_names: &[String], front: &[String], back: &[String]) -> Result<Vec<(usize, Placement)>, String> {
+    let mut placed = Vec::with_capacity(file_names.len());
+    for (index, file_name) in file_names.iter().enumerate() {
+        placed.push((index, placement(file_name, front, back)?));
+    }
+    let rank = |placement: Placement| match placement {
+        Placement::Front => 0,
+        Placement::Main => 1,
+        Placement::Back => 2,
+    };
+    // The sort is stable, so main files keep their order.
+    placed.sort_by(|&(a, a_placement), &(b, b_placement)| {
+        rank(a_placement).cmp(&rank(b_placement)).then_with(|| match a_placement {
+            Placement::Main => Ordering::Equal,
+            _ => natural_cmp(&file_names[a], &file_names[b]),
+        })
+    });
+    Ok(placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests wildcards, case-insensitivity, and that other characters are taken literally.
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("*credits*", "00 Opening Credits.mp3"));
+        assert!(glob_matches("zzz_*", "zzz_authors_note.mp3"));
+        assert!(glob_matches("0?.mp3", "07.mp3"));
+        assert!(!glob_matches("0?.mp3", "007.mp3"));
+        assert!(!glob_matches("*.mp3", "intro.m4a"));
+        assert!(glob_matches("[intro].mp3", "[Intro].mp3"));
+        assert!(!glob_matches("a.b", "axb"));
+    }
+
+    /// Tests that numbers compare by value.
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec!["Credits 10", "credits 2", "Credits 1", "Credits 02b", "Credits"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["Credits", "Credits 1", "credits 2", "Credits 02b", "Credits 10"]);
+    }
+
+    /// Tests pinning out-of-order credits and an author's note, and files matching both globs.
+    #[test]
+    fn test_pin_matter() {
+        let names: Vec<String> = ["00 Opening Credits.mp3", "01 Storm.mp3", "02 Calm.mp3", "99 Closing Credits.mp3", "zzz_authors_note.mp3"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        // Listed in the wrong order on purpose: pinned files are sorted among themselves.
+        let front = vec!["*opening*".to_string()];
+        let back = vec!["zzz_*".to_string(), "*closing*".to_string()];
+        let pinned = pin_matter(&names, &front, &back).unwrap();
+        assert_eq!(
+            pinned,
+            vec![(0, Placement::Front), (1, Placement::Main), (2, Placement::Main), (3, Placement::Back), (4, Placement::Back)]
+        );
+
+        let reversed: Vec<String> = names.iter().rev().cloned().collect();
+        let order: Vec<usize> = pin_matter(&reversed, &front, &back).unwrap().into_iter().map(|(index, _)| index).collect();
+        assert_eq!(order, vec![4, 2, 3, 1, 0]);
+
+        let conflict = pin_matter(&names, &["*credits*".to_string()], &["99*".to_string()]);
+        assert_eq!(conflict, Err("'99 Closing Credits.mp3' matches both --front-matter '*credits*' and --back-matter '99*'".to_string()));
+    }
+}
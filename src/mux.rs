@@ -0,0 +1,552 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use m4btool::{sanitize_filename, FilenameOptions};
+
+use crate::console;
+use crate::ffmpeg_warnings::WarningCheck;
+use crate::runner::CommandRunner;
+use crate::shell::{self, os_args};
+use crate::tags::{BookTags, METADATA_TAGS_MOVFLAG};
+
+/// The MP4 major brand written to the output, which some players check to treat a file as an
+/// audiobook with chapters and a cover.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Brand {
+    /// `M4B `, the audiobook brand Apple devices look for.
+    M4b,
+    /// `M4A `, plain iTunes audio.
+    M4a,
+    /// `mp42`, generic MP4 version 2.
+    Mp42,
+}
+
+impl Brand {
+    /// The four-character brand code, padded with spaces as in the file.
+    pub fn code(self) -> &'static str {
+        match self {
+            Brand::M4b => "M4B ",
+            Brand::M4a => "M4A ",
+            Brand::Mp42 => "mp42",
+        }
+    }
+
+    /// The brand to use when none was given: `M4B ` for an `.m4b` output, otherwise whatever
+    /// ffmpeg picks for the extension. ffmpeg writes `.m4b` files with its `ipod` muxer, whose
+    /// default brand is `M4A `.
+    pub fn for_output(output: &str) -> Option<Brand> {
+        Path::new(output)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("m4b"))
+            .then_some(Brand::M4b)
+    }
+}
+
+/// Where the final mux reads the book's audio from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MuxInput<'a> {
+    /// A concat demuxer list naming every encoded file in order.
+    Concat(&'a Path),
+    /// The only encoded file of a single-file book, remuxed without a concat list.
+    File(&'a Path),
+}
+
+/// The inputs of the final mux that combines the encoded files into the audiobook.
+#[derive(Debug, Clone)]
+pub struct MuxPlan<'a> {
+    pub audio: MuxInput<'a>,
+    /// Cover image to attach.
+    pub cover: Option<&'a str>,
+    /// The cover is a JPEG already sized for the book and is copied instead of re-encoded.
+    pub copy_cover: bool,
+    /// FFMETADATA file with the chapters.
+    pub metadata: Option<&'a Path>,
+    /// Concat list of the image shown during each chapter, muxed as a timed video track for
+    /// `--chapter-thumbnails`.
+    pub chapter_images: Option<&'a Path>,
+    /// Book tags to write; `None` for a plain concatenation.
+    pub tags: Option<&'a BookTags>,
+    /// MP4 major brand to write; `None` keeps ffmpeg's default.
+    pub brand: Option<Brand>,
+    /// Move the index to the front with `-movflags +faststart`, for `--faststart`.
+    pub faststart: bool,
+    /// Extra ffmpeg output options from `--ffmpeg-mux-args`, placed just before the output path.
+    pub extra_args: &'a [String],
+    pub output: &'a str,
+}
+
+/// How a successful mux went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MuxOutcome {
+    /// The cover could not be attached and the book was written without it.
+    pub cover_dropped: bool,
+}
+
+/// Why the mux failed, with what is needed to reproduce it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MuxFailure {
+    pub message: String,
+    /// The failing ffmpeg command line, if ffmpeg could be started.
+    pub command_line: Option<String>,
+    /// ffmpeg's full stderr.
+    pub stderr: Vec<u8>,
+}
+
+impl fmt::Display for MuxFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Copies the concat list and FFMETADATA file of a plan into `dir`, named after the output, e.g.
+/// `Dune.concat.txt` and `Dune.ffmetadata.txt`, so they can be inspected or fed to ffmpeg by hand.
+///
+/// # Returns
+///
+/// The paths of the copies.
+pub fn dump_intermediate(plan: &MuxPlan, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let stem = Path::new(plan.output).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let mut written = Vec::new();
+    let concat_list = match plan.audio {
+        MuxInput::Concat(list) => Some(list),
+        MuxInput::File(_) => None,
+    };
+    for (source, kind) in [(concat_list, "concat"), (plan.metadata, "ffmetadata")] {
+        if let Some(source) = source {
+            let copy = dir.join(sanitize_filename(&format!("{}.{}.txt", stem, kind), &FilenameOptions::default()));
+            fs::copy(source, &copy)?;
+            written.push(copy);
+        }
+    }
+    Ok(written)
+}
+
+/// Builds the ffmpeg arguments for a mux plan, without the program name.
+///
+/// Inputs are numbered in the order they are added: the concat list or single audio file first,
+/// then the optional cover, metadata file, and chapter images. Extra arguments always come last before the output path.
+pub fn mux_args(plan: &MuxPlan) -> Vec<OsString> {
+    let mut args = match plan.audio {
+        MuxInput::Concat(list) => {
+            let mut args = os_args(&["-f", "concat", "-safe", "0", "-i"]);
+            args.push(list.into());
+            args
+        }
+        MuxInput::File(file) => vec!["-i".into(), file.into()],
+    };
+
+    let mut next_input_index = 1;
+    let cover_input_index = plan.cover.map(|cover_path| {
+        args.extend(os_args(&["-i", cover_path]));
+        next_input_index += 1;
+        next_input_index - 1
+    });
+    let metadata_input_index = plan.metadata.map(|metadata_path| {
+        args.push("-i".into());
+        args.push(metadata_path.into());
+        next_input_index += 1;
+        next_input_index - 1
+    });
+    let images_input_index = plan.chapter_images.map(|list| {
+        args.extend(os_args(&["-f", "concat", "-safe", "0", "-i"]));
+        args.push(list.into());
+        next_input_index += 1;
+        next_input_index - 1
+    });
+
+    args.extend(os_args(&["-map", "0:a"]));
+    if let Some(index) = cover_input_index {
+        args.extend(os_args(&["-map", &index.to_string()]));
+    }
+    if let Some(index) = images_input_index {
+        args.extend(os_args(&["-map", &index.to_string()]));
+    }
+    if let Some(index) = metadata_input_index {
+        args.extend(os_args(&["-map_metadata", &index.to_string()]));
+    }
+
+    args.extend(os_args(&["-c:a", "copy"]));
+
+    if plan.cover.is_some() {
+        let codec = if plan.copy_cover { "copy" } else { "mjpeg" };
+        args.extend(os_args(&["-c:v", codec, "-disposition:v:0", "attached_pic"]));
+    }
+    if plan.chapter_images.is_some() {
+        // The MP4 muxer does not take JPEG frames in a timed track, so the thumbnails are
+        // encoded as MPEG-4 video, one frame per chapter. `chapter_track` links the track to the
+        // chapters after the mux.
+        let stream = if plan.cover.is_some() { "v:1" } else { "v:0" };
+        args.extend(os_args(&[
+            &format!("-c:{}", stream), "mpeg4",
+            &format!("-q:{}", stream), "3",
+            &format!("-fps_mode:{}", stream), "passthrough",
+            &format!("-disposition:{}", stream), "0",
+        ]));
+    }
+
+    if let Some(tags) = plan.tags {
+        args.extend(tags.ffmpeg_args().into_iter().map(OsString::from));
+    }
+    if let Some(brand) = plan.brand {
+        args.extend(os_args(&["-brand", brand.code()]));
+    }
+    // ffmpeg keeps only the last -movflags, so the flags go into one.
+    let mut movflags = String::new();
+    if plan.tags.is_some() {
+        movflags.push_str(METADATA_TAGS_MOVFLAG);
+    }
+    if plan.faststart {
+        movflags.push_str("+faststart");
+    }
+    if !movflags.is_empty() {
+        args.extend(os_args(&["-movflags", &movflags]));
+    }
+    args.extend(plan.extra_args.iter().map(OsString::from));
+    args.push(plan.output.into());
+    args
+}
+
+/// Builds the ffmpeg command for a mux plan.
+pub fn mux_command(plan: &MuxPlan) -> Command {
+    let mut ffmpeg_cmd = Command::new("ffmpeg");
+    ffmpeg_cmd.args(mux_args(plan));
+    ffmpeg_cmd
+}
+
+/// Runs the mux, retrying once without the cover if a mux that included a cover fails.
+///
+/// A broken cover (unsupported image, odd dimensions) fails the whole mux even though the
+/// audio is fine, so when `cover_optional` is set a failed mux with a cover is retried without
+/// it. If the retry fails too, the cover was not the problem and the original error is reported.
+///
+/// # Arguments
+///
+/// * `plan` - The inputs of the mux.
+/// * `runner` - Runs the ffmpeg commands.
+/// * `cover_optional` - Whether to retry without the cover instead of failing.
+/// * `warnings` - The check of `--fail-on-warning`: a mux that succeeds with one of its
+///   warnings fails, without a retry.
+/// * `on_command` - Called with each command just before it runs, e.g. to print it.
+///
+/// # Returns
+///
+/// The `MuxOutcome`, or an error message.
+pub fn run_mux(
+    plan: &MuxPlan,
+    runner: &dyn CommandRunner,
+    cover_optional: bool,
+    warnings: Option<&WarningCheck>,
+    on_command: &mut dyn FnMut(&Command),
+) -> Result<MuxOutcome, MuxFailure> {
+    let warned = |command: &Command, output: Output| {
+        let warning = warnings?.find(&output.stderr)?;
+        Some(MuxFailure {
+            message: format!("ffmpeg warned '{}'; failing because of --fail-on-warning", warning),
+            command_line: Some(shell::command_line(command)),
+            stderr: output.stderr,
+        })
+    };
+    let mut ffmpeg_cmd = mux_command(plan);
+    on_command(&ffmpeg_cmd);
+    let output = runner.run(&mut ffmpeg_cmd).map_err(|err| MuxFailure {
+        message: format!("Could not execute ffmpeg: {}", err),
+        command_line: None,
+        stderr: Vec::new(),
+    })?;
+    if output.status.success() {
+        return match warned(&ffmpeg_cmd, output) {
+            Some(failure) => Err(failure),
+            None => Ok(MuxOutcome { cover_dropped: false }),
+        };
+    }
+    let error = MuxFailure {
+        message: format!("FFmpeg execution failed: {}", String::from_utf8_lossy(&output.stderr)),
+        command_line: Some(shell::command_line(&ffmpeg_cmd)),
+        stderr: output.stderr,
+    };
+    let Some(cover) = plan.cover.filter(|_| cover_optional) else {
+        return Err(error);
+    };
+
+    console::warn(format!("Could not attach cover '{}'; retrying without a cover", cover));
+    // The failed attempt may have left a partial file behind, which ffmpeg would refuse to overwrite.
+    let _ = fs::remove_file(plan.output);
+    // The thumbnails come from the cover, so they go with it.
+    let without_cover = MuxPlan { cover: None, chapter_images: None, ..plan.clone() };
+    let mut retry_cmd = mux_command(&without_cover);
+    on_command(&retry_cmd);
+    match runner.run(&mut retry_cmd) {
+        Ok(retry) if retry.status.success() => match warned(&retry_cmd, retry) {
+            Some(failure) => Err(failure),
+            None => Ok(MuxOutcome { cover_dropped: true }),
+        },
+        _ => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use {std::cell::RefCell, std::io, std::os::unix::process::ExitStatusExt, std::process::ExitStatus};
+
+    /// Records every command and fails any mux that attaches a cover, like an unsupported image would,
+    /// or with `always_fails` every mux. A mux that succeeds prints `success_stderr`.
+    #[cfg(unix)]
+    #[derive(Default)]
+    struct CoverFailingRunner {
+        commands: RefCell<Vec<String>>,
+        always_fails: bool,
+        success_stderr: &'static str,
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for CoverFailingRunner {
+        fn run(&self, command: &mut Command) -> io::Result<Output> {
+            let line = shell::command_line(command);
+            let fails = self.always_fails || line.contains("attached_pic");
+            self.commands.borrow_mut().push(line);
+            Ok(Output {
+                status: ExitStatus::from_raw(if fails { 256 } else { 0 }),
+                stdout: Vec::new(),
+                stderr: if fails { b"Error while opening encoder for output stream #0:1".to_vec() } else { self.success_stderr.as_bytes().to_vec() },
+            })
+        }
+    }
+
+    fn plan_with_cover() -> MuxPlan<'static> {
+        MuxPlan {
+            audio: MuxInput::Concat(Path::new("/tmp/list.txt")),
+            cover: Some("/books/dune/cover.webp"),
+            copy_cover: false,
+            metadata: Some(Path::new("/tmp/chapters.txt")),
+            chapter_images: None,
+            tags: None,
+            brand: Some(Brand::M4b),
+            faststart: false,
+            extra_args: &[],
+            output: "/nonexistent/output.m4b",
+        }
+    }
+
+    fn strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter().map(|arg| arg.to_string_lossy().to_string()).collect()
+    }
+
+    /// Golden test for a mux with cover, chapters, and tags.
+    #[test]
+    fn test_mux_args_with_cover() {
+        let tags = BookTags { title: Some("Dune".to_string()), author: Some("Frank Herbert".to_string()), ..Default::default() };
+        let plan = MuxPlan { tags: Some(&tags), ..plan_with_cover() };
+        assert_eq!(
+            strings(mux_args(&plan)),
+            [
+                "-f", "concat", "-safe", "0", "-i", "/tmp/list.txt",
+                "-i", "/books/dune/cover.webp",
+                "-i", "/tmp/chapters.txt",
+                "-map", "0:a", "-map", "1", "-map_metadata", "2",
+                "-c:a", "copy",
+                "-c:v", "mjpeg", "-disposition:v:0", "attached_pic",
+                "-metadata", "title=Dune", "-metadata", "artist=Frank Herbert",
+                "-brand", "M4B ", "-movflags", "+use_metadata_tags",
+                "/nonexistent/output.m4b",
+            ]
+        );
+        let faststart = strings(mux_args(&MuxPlan { faststart: true, ..plan }));
+        assert_eq!(faststart.iter().filter(|arg| *arg == "-movflags").count(), 1);
+        assert!(faststart.windows(2).any(|window| window == ["-movflags", "+use_metadata_tags+faststart"]));
+    }
+
+    /// Golden test for a mux with chapter thumbnails: the image list is the last input, and its
+    /// track is encoded after the cover's.
+    #[test]
+    fn test_mux_args_with_chapter_images() {
+        let plan = MuxPlan { chapter_images: Some(Path::new("/tmp/images.txt")), brand: None, ..plan_with_cover() };
+        assert_eq!(
+            strings(mux_args(&plan)),
+            [
+                "-f", "concat", "-safe", "0", "-i", "/tmp/list.txt",
+                "-i", "/books/dune/cover.webp",
+                "-i", "/tmp/chapters.txt",
+                "-f", "concat", "-safe", "0", "-i", "/tmp/images.txt",
+                "-map", "0:a", "-map", "1", "-map", "3", "-map_metadata", "2",
+                "-c:a", "copy",
+                "-c:v", "mjpeg", "-disposition:v:0", "attached_pic",
+                "-c:v:1", "mpeg4", "-q:v:1", "3", "-fps_mode:v:1", "passthrough", "-disposition:v:1", "0",
+                "/nonexistent/output.m4b",
+            ]
+        );
+    }
+
+    /// Tests that a cover already shrunk to fit is copied rather than re-encoded.
+    #[test]
+    fn test_mux_args_copies_prepared_cover() {
+        let plan = MuxPlan { cover: Some("/tmp/tmpe5f6.jpg"), copy_cover: true, ..plan_with_cover() };
+        let args = strings(mux_args(&plan));
+        assert!(args.windows(4).any(|window| window == ["-c:v", "copy", "-disposition:v:0", "attached_pic"]));
+    }
+
+    /// Golden test for a mux without a cover, where the metadata becomes input 1, and the tags
+    /// share one -movflags with faststart.
+    #[test]
+    fn test_mux_args_without_cover() {
+        let tags = BookTags { title: Some("Dune".to_string()), ..Default::default() };
+        let plan = MuxPlan { cover: None, tags: Some(&tags), ..plan_with_cover() };
+        assert_eq!(
+            strings(mux_args(&plan)),
+            [
+                "-f", "concat", "-safe", "0", "-i", "/tmp/list.txt",
+                "-i", "/tmp/chapters.txt",
+                "-map", "0:a", "-map_metadata", "1",
+                "-c:a", "copy",
+                "-metadata", "title=Dune",
+                "-brand", "M4B ", "-movflags", "+use_metadata_tags",
+                "/nonexistent/output.m4b",
+            ]
+        );
+    }
+
+    /// Golden test for a plain concatenation with faststart and extra arguments placed before
+    /// the output.
+    #[test]
+    fn test_mux_args_plain_concatenation_with_extra_args() {
+        let extra_args = vec!["-metadata".to_string(), "comment=Read by Jane Doe".to_string()];
+        let plan = MuxPlan { cover: None, metadata: None, brand: None, faststart: true, extra_args: &extra_args, ..plan_with_cover() };
+        assert_eq!(
+            strings(mux_args(&plan)),
+            [
+                "-f", "concat", "-safe", "0", "-i", "/tmp/list.txt",
+                "-map", "0:a", "-c:a", "copy",
+                "-movflags", "+faststart",
+                "-metadata", "comment=Read by Jane Doe",
+                "/nonexistent/output.m4b",
+            ]
+        );
+    }
+
+    /// Tests that a single-file book is remuxed straight from its file, without a concat list.
+    #[test]
+    fn test_mux_args_single_file() {
+        let plan = MuxPlan { audio: MuxInput::File(Path::new("/tmp/tmpa1b2.m4a")), cover: None, brand: None, ..plan_with_cover() };
+        assert_eq!(
+            strings(mux_args(&plan)),
+            [
+                "-i", "/tmp/tmpa1b2.m4a", "-i", "/tmp/chapters.txt",
+                "-map", "0:a", "-map_metadata", "1", "-c:a", "copy",
+                "/nonexistent/output.m4b",
+            ]
+        );
+        let dump = tempfile::tempdir().unwrap();
+        assert!(dump_intermediate(&MuxPlan { metadata: None, ..plan }, dump.path()).unwrap().is_empty());
+    }
+
+    /// Tests that only .m4b outputs get the audiobook brand by default.
+    #[test]
+    fn test_brand_for_output() {
+        assert_eq!(Brand::for_output("/books/Dune.m4b"), Some(Brand::M4b));
+        assert_eq!(Brand::for_output("/books/Dune.M4B"), Some(Brand::M4b));
+        assert_eq!(Brand::for_output("/books/Dune.m4a"), None);
+        assert_eq!(Brand::for_output("/books/Dune"), None);
+    }
+
+    /// Tests that the concat list and metadata are copied under names taken from the output.
+    #[test]
+    fn test_dump_intermediate() {
+        let work = tempfile::tempdir().unwrap();
+        let concat_list = work.path().join("tmpa1b2");
+        let metadata = work.path().join("tmpc3d4");
+        fs::write(&concat_list, "file '/tmp/one.m4a'\nfile '/tmp/two.m4a'\n").unwrap();
+        fs::write(&metadata, ";FFMETADATA1\n[CHAPTER]\n").unwrap();
+        let plan = MuxPlan {
+            audio: MuxInput::Concat(&concat_list),
+            cover: None,
+            copy_cover: false,
+            metadata: Some(&metadata),
+            chapter_images: None,
+            tags: None,
+            brand: None,
+            faststart: false,
+            extra_args: &[],
+            output: "/books/Dune.m4b",
+        };
+
+        let dump = work.path().join("dump");
+        let written = dump_intermediate(&plan, &dump).unwrap();
+        assert_eq!(written, vec![dump.join("Dune.concat.txt"), dump.join("Dune.ffmetadata.txt")]);
+        assert_eq!(fs::read_to_string(&written[0]).unwrap(), "file '/tmp/one.m4a'\nfile '/tmp/two.m4a'\n");
+        assert_eq!(fs::read_to_string(&written[1]).unwrap(), ";FFMETADATA1\n[CHAPTER]\n");
+
+        let without_metadata = MuxPlan { metadata: None, ..plan };
+        assert_eq!(dump_intermediate(&without_metadata, &dump).unwrap().len(), 1);
+        let reserved = MuxPlan { output: "/books/con.m4b", ..plan };
+        let written = dump_intermediate(&reserved, &dump).unwrap();
+        assert!(written.iter().all(|path| m4btool::is_safe_filename(&path.file_name().unwrap().to_string_lossy())));
+        assert_eq!(written[0], dump.join("con_.concat.txt"));
+    }
+
+    /// Tests that a failing cover mux is retried once without the cover.
+    #[cfg(unix)]
+    #[test]
+    fn test_cover_failure_retries_without_cover() {
+        let runner = CoverFailingRunner::default();
+        let outcome = run_mux(&plan_with_cover(), &runner, true, None, &mut |_| {});
+        assert_eq!(outcome, Ok(MuxOutcome { cover_dropped: true }));
+        let commands = runner.commands.borrow();
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].contains("cover.webp"));
+        assert_eq!(
+            commands[1],
+            "ffmpeg -f concat -safe 0 -i /tmp/list.txt -i /tmp/chapters.txt -map 0:a -map_metadata 1 -c:a copy -brand 'M4B ' /nonexistent/output.m4b"
+        );
+    }
+
+    /// Tests that when the retry without the cover fails too, the first failure is reported.
+    #[cfg(unix)]
+    #[test]
+    fn test_cover_retry_failure_reports_first_error() {
+        let runner = CoverFailingRunner { always_fails: true, ..Default::default() };
+        let failure = run_mux(&plan_with_cover(), &runner, true, None, &mut |_| {}).unwrap_err();
+        assert!(failure.command_line.unwrap().contains("cover.webp"));
+        let commands = runner.commands.borrow();
+        assert_eq!(commands.len(), 2);
+        // The retry muxes the same encoded files.
+        assert!(commands.iter().all(|command| command.contains("-i /tmp/list.txt")));
+    }
+
+    /// Tests that without --cover-optional a cover failure fails the build.
+    #[cfg(unix)]
+    #[test]
+    fn test_cover_failure_without_cover_optional() {
+        let runner = CoverFailingRunner::default();
+        let outcome = run_mux(&plan_with_cover(), &runner, false, None, &mut |_| {});
+        let failure = outcome.unwrap_err();
+        assert!(failure.message.contains("output stream #0:1"));
+        assert_eq!(failure.stderr, b"Error while opening encoder for output stream #0:1");
+        assert!(failure.command_line.unwrap().contains("cover.webp"));
+        assert_eq!(runner.commands.borrow().len(), 1);
+    }
+
+    /// Tests that with --fail-on-warning a mux that succeeds with a warning fails without a
+    /// retry, and that without it the same mux succeeds.
+    #[cfg(unix)]
+    #[test]
+    fn test_warned_mux_fails() {
+        const WARNED: &str = "[ipod @ 0x55d0c] Application provided invalid, non monotonically increasing dts to muxer in stream 0: 4410 >= 4410\n";
+
+        let check = WarningCheck::new(&[]).unwrap();
+        let plan = MuxPlan { cover: None, ..plan_with_cover() };
+        let runner = CoverFailingRunner { success_stderr: WARNED, ..Default::default() };
+        let failure = run_mux(&plan, &runner, true, Some(&check), &mut |_| {}).unwrap_err();
+        assert!(failure.message.starts_with("ffmpeg warned '[ipod @ 0x55d0c] Application provided invalid"));
+        assert!(failure.message.ends_with("'; failing because of --fail-on-warning"));
+        assert_eq!(failure.stderr, WARNED.as_bytes());
+        assert_eq!(runner.commands.borrow().len(), 1);
+
+        assert_eq!(run_mux(&plan, &runner, true, None, &mut |_| {}), Ok(MuxOutcome { cover_dropped: false }));
+    }
+}
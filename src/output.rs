@@ -0,0 +1,149 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use m4btool::{sanitize_filename, FilenameOptions};
+
+use crate::archive::is_zip_archive;
+use crate::cli::BuildOptions;
+use crate::incremental::Fnv;
+use crate::ladder::rung_output;
+
+/// The book's file name when no title is known at all.
+pub const FALLBACK_OUTPUT_NAME: &str = "output.m4b";
+
+/// Names the book after its title, e.g. `Dune_ Book 1.m4b` for "Dune: Book 1", or
+/// `FALLBACK_OUTPUT_NAME` without a usable title.
+pub fn output_file_name(title: Option<&str>) -> String {
+    match title.map(str::trim).filter(|title| !title.is_empty()) {
+        Some(title) => sanitize_filename(&format!("{}.m4b", title), &FilenameOptions::default()),
+        None => FALLBACK_OUTPUT_NAME.to_string(),
+    }
+}
+
+/// The name of an input as a last-resort title: a folder's name, or an archive's without its
+/// extension.
+pub fn input_title(input: &Path) -> Option<String> {
+    let resolved = fs::canonicalize(input).unwrap_or_else(|_| input.to_path_buf());
+    let name = if is_zip_archive(input) { resolved.file_stem() } else { resolved.file_name() };
+    name.map(|name| name.to_string_lossy().to_string())
+}
+
+/// Where a build writes the book: `--output`, or a file named after `title` inside the first
+/// input directory or next to a zip archive. One version of a `--bitrate-ladder` has its
+/// bitrate inserted before the extension.
+pub fn output_path(options: &BuildOptions, title: Option<&str>) -> String {
+    let input = Path::new(&options.input_directories[0]);
+    let output = match &options.output {
+        Some(output) => output.clone(),
+        None => {
+            let dir = if is_zip_archive(input) { input.parent().unwrap_or(Path::new("")) } else { input };
+            dir.join(output_file_name(title)).to_string_lossy().to_string()
+        }
+    };
+    match options.ladder_rung {
+        Some(bits_per_second) => rung_output(&output, bits_per_second),
+        None => output,
+    }
+}
+
+/// Identifies the inputs a book was built from, written into the book so that a later build
+/// can tell its own book from another one with the same name. Only the inputs' locations
+/// count, so a rebuild after the files changed still recognizes the book.
+pub fn provenance(inputs: &[PathBuf]) -> String {
+    let mut hash = Fnv::new();
+    for input in inputs {
+        hash.update(input.to_string_lossy().as_bytes());
+        hash.update(&[0]);
+    }
+    format!("m4btool inputs {}", hash.hex())
+}
+
+/// Checks that a book already at the output named after its title may be replaced: only one
+/// built from the same inputs may, unless `--overwrite` is given.
+///
+/// # Arguments
+///
+/// * `output` - The existing book.
+/// * `existing` - The provenance recorded in it, if any.
+/// * `provenance` - The provenance of this build.
+///
+/// # Returns
+///
+/// An error message naming the book when it is someone else's.
+pub fn check_replace(output: &str, existing: Option<&str>, provenance: &str) -> Result<(), String> {
+    if existing == Some(provenance) {
+        return Ok(());
+    }
+    let reason = match existing {
+        Some(_) => "was built from other inputs",
+        None => "was not built by m4btool from these inputs",
+    };
+    Err(format!(
+        "'{}' already exists and {}; pass --overwrite to replace it, or --output to write elsewhere",
+        output, reason
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspect::parse_flat_output;
+    use crate::tags::{BookTags, PROVENANCE_KEY};
+
+    /// Tests naming the book after its title, inside the input or next to an archive, and the
+    /// fallback without a title.
+    #[test]
+    fn test_output_path() {
+        let options = BuildOptions { input_directories: vec!["books/dune".to_string()], ..BuildOptions::default() };
+        assert_eq!(output_path(&options, Some("Dune: Book 1")), "books/dune/Dune_ Book 1.m4b");
+        assert_eq!(output_path(&options, Some("  ")), "books/dune/output.m4b");
+        assert_eq!(output_path(&options, None), "books/dune/output.m4b");
+
+        let downloads = tempfile::tempdir().unwrap();
+        let zip = downloads.path().join("dune.zip");
+        fs::write(&zip, b"PK").unwrap();
+        let archive = BuildOptions { input_directories: vec![zip.to_string_lossy().to_string()], ..BuildOptions::default() };
+        assert_eq!(output_path(&archive, Some("Dune")), downloads.path().join("Dune.m4b").to_string_lossy());
+        assert_eq!(input_title(&zip).as_deref(), Some("dune"));
+        assert_eq!(input_title(Path::new("books/Dune.Messiah")).as_deref(), Some("Dune.Messiah"));
+
+        let given = BuildOptions { output: Some("out/book.m4b".to_string()), ..options.clone() };
+        assert_eq!(output_path(&given, Some("Dune")), "out/book.m4b");
+        let rung = BuildOptions { ladder_rung: Some(64_000), ..options };
+        assert_eq!(output_path(&rung, Some("Dune")), "books/dune/Dune.64k.m4b");
+    }
+
+    /// Tests the collision cases: a book from the same inputs is replaced, one from other inputs
+    /// or without a provenance is not.
+    #[test]
+    fn test_check_replace() {
+        let dune = provenance(&[PathBuf::from("/books/Dune")]);
+        let messiah = provenance(&[PathBuf::from("/books/Dune Messiah")]);
+        assert_ne!(dune, messiah);
+        assert_eq!(dune, provenance(&[PathBuf::from("/books/Dune")]));
+        assert_ne!(provenance(&[PathBuf::from("/a"), PathBuf::from("b")]), provenance(&[PathBuf::from("/ab")]));
+
+        assert_eq!(check_replace("Dune.m4b", Some(&dune), &dune), Ok(()));
+        assert_eq!(
+            check_replace("Dune.m4b", Some(&messiah), &dune).unwrap_err(),
+            "'Dune.m4b' already exists and was built from other inputs; pass --overwrite to replace it, or --output to write elsewhere"
+        );
+        assert!(check_replace("Dune.m4b", None, &dune).unwrap_err().contains("was not built by m4btool"));
+    }
+
+    /// Tests a second build into the same output: the provenance the first build's mux wrote,
+    /// as ffprobe reports the tags of the book back, lets it replace its own book only.
+    #[test]
+    fn test_rebuild_same_output() {
+        let dune = provenance(&[PathBuf::from("/books/Dune")]);
+        let tags = BookTags { title: Some("Dune".to_string()), provenance: Some(dune.clone()), ..Default::default() };
+        let probed: String = tags.metadata_pairs().iter()
+            .map(|(key, value)| format!("format.format_name=\"mov,mp4,m4a,3gp,3g2,mj2\"\nformat.tags.{}=\"{}\"\n", key, value))
+            .collect();
+        let existing = parse_flat_output(&probed).tags.get(PROVENANCE_KEY).cloned();
+        assert_eq!(existing.as_deref(), Some(dune.as_str()));
+        assert_eq!(check_replace("Dune.m4b", existing.as_deref(), &dune), Ok(()));
+        let messiah = provenance(&[PathBuf::from("/books/Dune Messiah")]);
+        assert!(check_replace("Dune.m4b", existing.as_deref(), &messiah).is_err());
+    }
+}
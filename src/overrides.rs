@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The file in the input directory whose lines replace the chapter titles, one per file in order.
+pub const CHAPTERS_FILE: &str = "chapters.txt";
+
+/// Per-file bitrate overrides read from a sidecar file.
+///
+/// The sidecar has one `filename = bitrate` entry per line, e.g. `03 - Interlude.mp3 = 192k`.
+/// Blank lines and lines starting with `#` are ignored. A filename matches either the full
+/// file name or its stem.
+#[derive(Debug, Default)]
+pub struct BitrateOverrides {
+    entries: HashMap<String, u64>,
+    /// The bitrate of every file, over the entries, e.g. for one version of a `--bitrate-ladder`.
+    all: Option<u64>,
+}
+
+impl BitrateOverrides {
+    /// Reads and parses a sidecar file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read bitrate overrides '{}': {}", path, err))?;
+        Self::parse(&content).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    /// Parses the contents of a sidecar file.
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut entries = HashMap::new();
+        for (line_number, line) in content.trim_start_matches('\u{feff}').lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, bitrate) = line
+                .rsplit_once('=')
+                .ok_or_else(|| format!("line {}: expected 'filename = bitrate'", line_number + 1))?;
+            let bits_per_second = parse_bitrate(bitrate).map_err(|err| format!("line {}: {}", line_number + 1, err))?;
+            entries.insert(name.trim().to_string(), bits_per_second);
+        }
+        Ok(BitrateOverrides { entries, all: None })
+    }
+
+    /// Looks up the override for a file, matching its full name first and then its stem.
+    ///
+    /// # Returns
+    ///
+    /// The overriding bitrate in bits per second, or `None` to use the default bitrate.
+    pub fn get(&self, file_name: &str, stem: &str) -> Option<u64> {
+        self.all.or_else(|| self.entries.get(file_name).or_else(|| self.entries.get(stem)).copied())
+    }
+
+    /// Sets the bitrate of the file with this name or stem, replacing its entry if it has one.
+    pub fn insert(&mut self, name: &str, bits_per_second: u64) {
+        self.entries.insert(name.to_string(), bits_per_second);
+    }
+
+    /// Overrides the bitrate of every file, whether it has an entry or not.
+    pub fn set_all(&mut self, bits_per_second: u64) {
+        self.all = Some(bits_per_second);
+    }
+
+    /// Finds override entries that match none of the given files.
+    ///
+    /// # Arguments
+    ///
+    /// * `files` - `(file name, stem)` pairs for every input file.
+    ///
+    /// # Returns
+    ///
+    /// Each unmatched entry together with the closest file name, if one is similar enough to be a likely typo.
+    pub fn unmatched(&self, files: &[(String, String)]) -> Vec<(String, Option<String>)> {
+        let mut unmatched: Vec<_> = self.entries.keys()
+            .filter(|name| !files.iter().any(|(file_name, stem)| file_name == *name || stem == *name))
+            .map(|name| {
+                let suggestion = files.iter()
+                    .map(|(file_name, _)| (edit_distance(name, file_name), file_name))
+                    .filter(|(distance, _)| *distance <= 3)
+                    .min()
+                    .map(|(_, file_name)| file_name.clone());
+                (name.clone(), suggestion)
+            })
+            .collect();
+        unmatched.sort();
+        unmatched
+    }
+}
+
+/// Reads the chapter titles from the `chapters.txt` in a directory.
+///
+/// # Returns
+///
+/// The titles, or `None` if the directory has no `chapters.txt`.
+pub fn load_chapter_titles(dir: &Path) -> Result<Option<Vec<String>>, String> {
+    let path = dir.join(CHAPTERS_FILE);
+    match fs::read_to_string(&path) {
+        Ok(content) => Ok(Some(parse_chapter_titles(&content))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("Could not read '{}': {}", path.display(), err)),
+    }
+}
+
+/// Parses a chapters file: one title per line, in book order. Surrounding whitespace, blank
+/// lines, and a byte order mark left by Windows editors are ignored.
+pub fn parse_chapter_titles(content: &str) -> Vec<String> {
+    content.trim_start_matches('\u{feff}')
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Checks that a chapters file has exactly one title per input file.
+///
+/// # Returns
+///
+/// The titles, or a message explaining the mismatch.
+pub fn match_chapter_titles(titles: Vec<String>, file_count: usize) -> Result<Vec<String>, String> {
+    if titles.len() == file_count {
+        Ok(titles)
+    } else {
+        Err(format!("{} has {} titles for {} files", CHAPTERS_FILE, titles.len(), file_count))
+    }
+}
+
+/// Parses a bitrate such as "192k", "192K", or "192000" into bits per second.
+pub fn parse_bitrate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.strip_suffix(['k', 'K']) {
+        Some(digits) => (digits, 1000),
+        None => (value, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(number * multiplier),
+        _ => Err(format!("invalid bitrate '{}'", value)),
+    }
+}
+
+/// Computes the Levenshtein distance between two strings, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b_chars.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that overrides resolve by file name or stem and fall back to `None` otherwise.
+    #[test]
+    fn test_override_resolution() {
+        let overrides = BitrateOverrides::parse("# noisy files\n02 - Music.mp3 = 192k\n05 - Outro=96000\n").unwrap();
+        assert_eq!(overrides.get("02 - Music.mp3", "02 - Music"), Some(192000));
+        assert_eq!(overrides.get("05 - Outro.flac", "05 - Outro"), Some(96000));
+        assert_eq!(overrides.get("01 - Intro.mp3", "01 - Intro"), None);
+        let with_bom = BitrateOverrides::parse("\u{feff}01 - Intro.mp3 = 64k\n").unwrap();
+        assert_eq!(with_bom.get("01 - Intro.mp3", "01 - Intro"), Some(64_000));
+        assert!(BitrateOverrides::parse("02 - Music.mp3 192k").is_err());
+        assert!(BitrateOverrides::parse("02 - Music.mp3 = fast").is_err());
+
+        let mut overrides = overrides;
+        overrides.set_all(64_000);
+        assert_eq!(overrides.get("02 - Music.mp3", "02 - Music"), Some(64_000));
+        assert_eq!(overrides.get("01 - Intro.mp3", "01 - Intro"), Some(64_000));
+    }
+
+    /// Tests that entries naming no input file are reported with a typo suggestion.
+    #[test]
+    fn test_unmatched_overrides() {
+        let overrides = BitrateOverrides::parse("02 - Musik.mp3 = 192k\nunrelated.wav = 64k\n").unwrap();
+        let files = vec![("02 - Music.mp3".to_string(), "02 - Music".to_string())];
+        assert_eq!(
+            overrides.unmatched(&files),
+            vec![
+                ("02 - Musik.mp3".to_string(), Some("02 - Music.mp3".to_string())),
+                ("unrelated.wav".to_string(), None),
+            ]
+        );
+    }
+
+    /// Tests that a chapters file with one title per file replaces the titles in order.
+    #[test]
+    fn test_chapter_titles_matching() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(load_chapter_titles(dir.path()), Ok(None));
+        fs::write(dir.path().join(CHAPTERS_FILE), "\u{feff}Prologue\r\n\r\n  The Desert Planet \r\nMuad'Dib\n").unwrap();
+        let titles = load_chapter_titles(dir.path()).unwrap().unwrap();
+        assert_eq!(match_chapter_titles(titles, 3).unwrap(), vec!["Prologue", "The Desert Planet", "Muad'Dib"]);
+    }
+
+    /// Tests that a line count that does not match the files is rejected with the counts.
+    #[test]
+    fn test_chapter_titles_mismatch() {
+        let titles = parse_chapter_titles("Prologue\nPart One\n");
+        assert_eq!(match_chapter_titles(titles.clone(), 3), Err("chapters.txt has 2 titles for 3 files".to_string()));
+        assert!(match_chapter_titles(titles, 1).is_err());
+        assert_eq!(match_chapter_titles(Vec::new(), 0), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,184 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::runner::CommandRunner;
+
+/// Encodes jobs on a pool of worker threads and probes each result as soon as its encode ends.
+///
+/// Each worker takes the next job, encodes it, and probes the result right away, so the probes
+/// of finished files overlap with the encodes still running on other workers instead of waiting
+/// for a separate pass after the last encode. The chapter plan can be built as soon as the last
+/// probe lands.
+///
+/// A job whose encode or probe panics, e.g. on a file name it cannot handle, fails on its own:
+/// the worker catches the panic and goes on with the next job.
+///
+/// # Arguments
+///
+/// * `jobs` - The jobs in book order.
+/// * `workers` - How many jobs run at once; at least 1.
+/// * `runner` - Runs the ffmpeg and ffprobe commands of every job.
+/// * `encode` - Runs one job, given its index, and returns what the probe needs.
+/// * `probe` - Measures an encoded result, returning its duration in milliseconds.
+///
+/// # Returns
+///
+/// Each job's encode result and duration, or the message of its panic, in job order whatever
+/// order they finished in.
+pub fn encode_and_probe<J, T, E, P>(jobs: Vec<J>, workers: usize, runner: &(dyn CommandRunner + Sync), encode: E, probe: P) -> Vec<Result<(T, Option<u64>), String>>
+where
+    J: Send,
+    T: Send,
+    E: Fn(usize, J, &dyn CommandRunner) -> T + Sync,
+    P: Fn(usize, &T, &dyn CommandRunner) -> Option<u64> + Sync,
+{
+    let count = jobs.len();
+    let queue = Mutex::new(jobs.into_iter().enumerate());
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<Option<Result<(T, Option<u64>), String>>>>());
+    thread::scope(|scope| {
+        for _ in 0..workers.clamp(1, count.max(1)) {
+            scope.spawn(|| loop {
+                let Some((index, job)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let encoded = encode(index, job, runner);
+                    let duration_ms = probe(index, &encoded, runner);
+                    (encoded, duration_ms)
+                }));
+                // A panicking job must not poison the results for the other workers.
+                let mut results = results.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                results[index] = Some(outcome.map_err(panic_message));
+            });
+        }
+    });
+    results.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())
+        .into_iter()
+        .map(|result| result.expect("every job was run by a worker"))
+        .collect()
+}
+
+/// The message a panic was raised with.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload.downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown error".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use {
+        std::io,
+        std::os::unix::process::ExitStatusExt,
+        std::process::{Command, ExitStatus, Output},
+        std::sync::mpsc::{self, Receiver, Sender},
+        std::time::Duration,
+    };
+
+    /// Holds the encode of `waiting_file` until a probe has run, recording the order in which
+    /// the programs ran.
+    #[cfg(unix)]
+    struct RendezvousRunner {
+        waiting_file: &'static str,
+        probed: Mutex<Sender<()>>,
+        probe_seen: Mutex<Receiver<()>>,
+        runs: Mutex<Vec<String>>,
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for RendezvousRunner {
+        fn run(&self, command: &mut Command) -> io::Result<Output> {
+            let program = command.get_program().to_string_lossy().to_string();
+            if command.get_args().any(|arg| arg == self.waiting_file) {
+                // A probe that only runs after every encode has finished never arrives.
+                let waited = self.probe_seen.lock().unwrap().recv_timeout(Duration::from_secs(10));
+                self.runs.lock().unwrap().push(format!("{} {}", program, self.waiting_file));
+                waited.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no probe ran while the encode was running"))?;
+            } else {
+                self.runs.lock().unwrap().push(program);
+            }
+            Ok(Output { status: ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new() })
+        }
+
+        fn query(&self, command: &mut Command) -> io::Result<Output> {
+            let output = self.run(command)?;
+            self.probed.lock().unwrap().send(()).unwrap();
+            Ok(output)
+        }
+    }
+
+    /// Tests that the probe of a finished file runs while another file is still encoding, and
+    /// that results come back in job order.
+    #[cfg(unix)]
+    #[test]
+    fn test_probes_overlap_encodes() {
+        let (probed, probe_seen) = mpsc::channel();
+        let runner = RendezvousRunner { waiting_file: "02.mp3", probed: Mutex::new(probed), probe_seen: Mutex::new(probe_seen), runs: Mutex::new(Vec::new()) };
+        let results = encode_and_probe(
+            vec!["01.mp3", "02.mp3", "03.mp3"],
+            2,
+            &runner,
+            |index, source, runner| {
+                runner.run(Command::new("ffmpeg").arg(source)).unwrap();
+                format!("encoded-{}.m4a", index)
+            },
+            |index, _, runner| {
+                runner.query(&mut Command::new("ffprobe")).unwrap();
+                Some(index as u64 * 1000)
+            },
+        );
+
+        let results: Vec<(String, Option<u64>)> = results.into_iter().map(Result::unwrap).collect();
+        let names: Vec<&str> = results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["encoded-0.m4a", "encoded-1.m4a", "encoded-2.m4a"]);
+        assert_eq!(results[2].1, Some(2000));
+
+        let runs = runner.runs.lock().unwrap();
+        let first_probe = runs.iter().position(|program| program == "ffprobe").unwrap();
+        let held_encode = runs.iter().position(|program| program == "ffmpeg 02.mp3").unwrap();
+        assert!(first_probe < held_encode, "{:?}", runs);
+    }
+
+    /// Fails the encode of one file name, like ffmpeg failing to start on it.
+    #[cfg(unix)]
+    struct FailingRunner {
+        bad_file: &'static str,
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for FailingRunner {
+        fn run(&self, command: &mut Command) -> io::Result<Output> {
+            if command.get_args().any(|arg| arg == self.bad_file) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "file name contains a NUL byte"));
+            }
+            Ok(Output { status: ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new() })
+        }
+    }
+
+    /// Tests that a job that panics on a failing file fails alone, with its message, while the
+    /// other jobs on the same worker still run.
+    #[cfg(unix)]
+    #[test]
+    fn test_failing_job_is_isolated() {
+        let runner = FailingRunner { bad_file: "02.mp3" };
+        let results = encode_and_probe(
+            vec!["01.mp3", "02.mp3", "03.mp3"],
+            1,
+            &runner,
+            |_, source, runner| {
+                runner.run(Command::new("ffmpeg").arg(source)).expect("ffmpeg could not start");
+                source.replace(".mp3", ".m4a")
+            },
+            |_, _, _| Some(1000),
+        );
+        assert_eq!(results[0], Ok(("01.m4a".to_string(), Some(1000))));
+        assert!(results[1].as_ref().is_err_and(|message| message.contains("file name contains a NUL byte")));
+        assert_eq!(results[2], Ok(("03.m4a".to_string(), Some(1000))));
+        assert_eq!(panic_message(Box::new("static")), "static");
+        assert_eq!(panic_message(Box::new(42)), "unknown error");
+    }
+}
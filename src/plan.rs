@@ -0,0 +1,449 @@
+//! The book plan.
+//!
+//! A `BookPlan` describes a build as a whole: the source files in book order, the chapters laid
+//! out on the book's timeline, the book tags, the encode settings, and the cover. m4btool writes
+//! the FFMETADATA and WebVTT chapters from it and saves it in post-mortem bundles, so other tools
+//! can read and write plans in the same shape.
+//!
+//! With the `serde` feature (enabled by default) the types implement `Serialize` and
+//! `Deserialize`. Times are whole milliseconds and paths are plain strings.
+//!
+//! # Stability
+//!
+//! Within a major version the fields of `BookPlan`, `Chapter`, `BookTags`, and `EncodeSettings`
+//! keep their names and meanings. New fields may be added in minor releases; they are optional,
+//! so plans written by an older version still load. `BookPlan` and `Chapter` are
+//! `#[non_exhaustive]`, so construct them with their `new` functions or `Default`; build
+//! `BookTags` and `EncodeSettings` with struct update syntax from their `Default`.
+
+use std::path::PathBuf;
+
+use crate::book_tags::BookTags;
+use crate::ffmetadata::{write_ffmetadata_chapters, GlobalTags};
+use crate::webvtt::write_vtt_chapters_at;
+
+/// A chapter placed on the book's timeline.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Chapter {
+    /// The chapter's position in the book, counted from 1.
+    pub index: usize,
+    pub title: String,
+    pub start_ms: u64,
+    pub end_ms: u64,
+    /// The source files the chapter's audio comes from: usually one, several for merged
+    /// chapters, or none if unknown.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub source: Vec<PathBuf>,
+    /// A second title kept next to the displayed one, such as the title before transliteration.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub original_title: Option<String>,
+    /// Warnings about the chapter's source files, such as a file that could not be probed.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub warnings: Vec<Warning>,
+}
+
+impl Chapter {
+    /// Creates a chapter without source files or an original title.
+    pub fn new(index: usize, title: impl Into<String>, start_ms: u64, end_ms: u64) -> Self {
+        Chapter { index, title: title.into(), start_ms, end_ms, ..Default::default() }
+    }
+
+    /// The chapter's length in milliseconds.
+    pub fn duration_ms(&self) -> u64 {
+        self.end_ms.saturating_sub(self.start_ms)
+    }
+}
+
+/// Lays chapters out back to back from zero, numbering them from 1.
+///
+/// # Arguments
+///
+/// * `chapters` - The chapter titles with their durations in milliseconds, in order.
+pub fn lay_out_chapters(chapters: &[(String, u64)]) -> Vec<Chapter> {
+    let mut start_ms = 0;
+    chapters.iter()
+        .enumerate()
+        .map(|(index, (title, duration_ms))| {
+            let placed = Chapter::new(index + 1, title.as_str(), start_ms, start_ms + duration_ms);
+            start_ms = placed.end_ms;
+            placed
+        })
+        .collect()
+}
+
+/// Fills in each chapter's source files from where the files lie on the book's timeline.
+///
+/// # Arguments
+///
+/// * `chapters` - The chapters, laid out on the timeline.
+/// * `files` - Each source file in book order with its duration in milliseconds.
+pub fn assign_sources(chapters: &mut [Chapter], files: &[(PathBuf, u64)]) {
+    let mut spans = Vec::with_capacity(files.len());
+    let mut start_ms = 0;
+    for (path, duration_ms) in files {
+        spans.push((path, start_ms, start_ms + duration_ms));
+        start_ms += duration_ms;
+    }
+    for chapter in chapters {
+        chapter.source = spans.iter()
+            .filter(|(_, start_ms, end_ms)| *start_ms < chapter.end_ms && chapter.start_ms < *end_ms)
+            .map(|(path, ..)| path.to_path_buf())
+            .collect();
+    }
+}
+
+/// Moves every chapter `lead_in_ms` later, so the first starts after a lead-in of silence that
+/// belongs to no chapter. Call after `assign_sources`, whose files start at zero.
+pub fn delay_chapters(chapters: &mut [Chapter], lead_in_ms: u64) {
+    for chapter in chapters {
+        chapter.start_ms += lead_in_ms;
+        chapter.end_ms += lead_in_ms;
+    }
+}
+
+/// What a `Warning` is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// The file could not be probed or encoded, and the build fell back to a default or to the
+    /// original file.
+    ProbeFallback,
+    /// The file is far shorter or longer than the book's other files.
+    DurationOutlier,
+    /// The sample rate differs from the book's, or is unusually low or high.
+    SampleRate,
+    /// The channel layout differs from the book's.
+    Channels,
+    /// Processing the file failed, and it was left out of the book.
+    Skipped,
+    /// Anything else worth a look, such as a file pinned to the start of the book.
+    Note,
+}
+
+/// A warning about one source file, kept with the chapters it affects.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Warning {
+    pub kind: WarningKind,
+    /// The warning as shown to the user, e.g. "8000 Hz is telephone quality".
+    pub message: String,
+}
+
+impl Warning {
+    pub fn new(kind: WarningKind, message: impl Into<String>) -> Self {
+        Warning { kind, message: message.into() }
+    }
+}
+
+/// Copies each source file's warnings onto the chapters whose audio comes from it, as filled in
+/// by `assign_sources`. A chapter spanning several files gets the warnings of each, once.
+///
+/// # Arguments
+///
+/// * `chapters` - The chapters, with their source files.
+/// * `warnings` - Each source file with its warnings.
+pub fn attach_warnings(chapters: &mut [Chapter], warnings: &[(PathBuf, Vec<Warning>)]) {
+    for chapter in chapters {
+        for (path, file_warnings) in warnings {
+            if !chapter.source.contains(path) {
+                continue;
+            }
+            for warning in file_warnings {
+                if !chapter.warnings.contains(warning) {
+                    chapter.warnings.push(warning.clone());
+                }
+            }
+        }
+    }
+}
+
+/// The AAC encoder used for the per-file encodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AacEncoder {
+    /// The Fraunhofer FDK encoder, which needs an ffmpeg built with `--enable-libfdk-aac`.
+    #[default]
+    #[cfg_attr(feature = "serde", serde(rename = "libfdk_aac"))]
+    Fdk,
+    /// ffmpeg's built-in `aac` encoder, available in every build.
+    #[cfg_attr(feature = "serde", serde(rename = "aac"))]
+    Native,
+}
+
+impl AacEncoder {
+    /// The ffmpeg codec name.
+    pub fn codec_name(self) -> &'static str {
+        match self {
+            AacEncoder::Fdk => "libfdk_aac",
+            AacEncoder::Native => "aac",
+        }
+    }
+}
+
+/// The AAC profile of the per-file encodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AacProfile {
+    /// Low Complexity, which every player decodes.
+    #[default]
+    #[cfg_attr(feature = "serde", serde(rename = "LC"))]
+    Lc,
+    /// High-Efficiency AAC, which keeps speech clear at low bitrates. Only libfdk_aac writes it.
+    #[cfg_attr(feature = "serde", serde(rename = "HE-AAC"))]
+    He,
+}
+
+impl AacProfile {
+    /// The profile's name in messages and the dry-run plan.
+    pub fn name(self) -> &'static str {
+        match self {
+            AacProfile::Lc => "LC",
+            AacProfile::He => "HE-AAC",
+        }
+    }
+}
+
+/// How the source files are encoded: the settings the command-line tool encodes with, as
+/// recorded in a plan.
+///
+/// Build it with struct update syntax from `EncodeSettings::default()`, which new fields do not
+/// break.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct EncodeSettings {
+    pub encoder: AacEncoder,
+    /// VBR quality (`-q:a`, 0.1 to 2.0) for the native encoder, used instead of a constant bitrate.
+    /// Ignored by libfdk_aac.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub aac_vbr: Option<f64>,
+    /// The constant bitrate in bits per second; `None` matches each source's. A file's own
+    /// settings still win.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub bitrate: Option<u64>,
+    pub aac_profile: AacProfile,
+    /// Resample every file to this rate in Hz; `None` keeps each source's rate.
+    pub sample_rate: Option<u32>,
+    /// Convert every file to this many channels; `None` keeps each source's layout.
+    pub channels: Option<u32>,
+    /// Change the volume by this many decibels; `None` leaves it. Only set for a file with
+    /// settings of its own.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub gain_db: Option<f64>,
+    /// Even out the loudness of every file.
+    pub normalize: bool,
+    /// Encoder options placed right after the codec and bitrate, e.g. `-afterburner 1` for
+    /// libfdk_aac.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub encoder_args: Vec<String>,
+    /// Extra ffmpeg output options for every encode, placed after the tool's own.
+    pub extra_args: Vec<String>,
+    /// Pass bitrates to ffmpeg in bits per second instead of rounding them down to whole kbps.
+    pub exact_bitrate: bool,
+}
+
+/// A source file encoded with settings of its own instead of the book's.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct FileSettings {
+    pub file: PathBuf,
+    /// The settings the file is encoded with, the book's with its own applied.
+    pub settings: EncodeSettings,
+    /// How much of the file's start is left out, in milliseconds.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub trim_start_ms: Option<u64>,
+    /// How much of the file's end is left out, in milliseconds.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub trim_end_ms: Option<u64>,
+}
+
+impl FileSettings {
+    /// Creates the settings of a file without any trim.
+    pub fn new(file: impl Into<PathBuf>, settings: EncodeSettings) -> Self {
+        FileSettings { file: file.into(), settings, ..Default::default() }
+    }
+}
+
+/// Everything needed to build a book.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct BookPlan {
+    /// The source files in book order.
+    pub files: Vec<PathBuf>,
+    pub chapters: Vec<Chapter>,
+    /// The book-level tags.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub metadata: BookTags,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub encode_settings: EncodeSettings,
+    /// The files encoded with settings of their own, which replace `encode_settings` for them.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub file_settings: Vec<FileSettings>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cover: Option<PathBuf>,
+    /// Whether the index is moved to the front of the book.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub faststart: bool,
+    /// Where the book is written.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub output: Option<PathBuf>,
+    /// The directory holding the encoded files and other work files during the build.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub work_dir: Option<PathBuf>,
+}
+
+impl BookPlan {
+    /// Creates a plan with files and chapters and nothing else.
+    pub fn new(files: Vec<PathBuf>, chapters: Vec<Chapter>) -> Self {
+        BookPlan { files, chapters, ..Default::default() }
+    }
+
+    /// Where the first chapter starts: after the lead-in of silence, if the book has one.
+    pub fn lead_in_ms(&self) -> u64 {
+        self.chapters.first().map_or(0, |chapter| chapter.start_ms)
+    }
+
+    /// Renders the plan's chapters and its title, author, and date as an FFMETADATA file.
+    pub fn write_ffmetadata(&self) -> String {
+        write_ffmetadata_chapters(&self.chapters, &GlobalTags::from(&self.metadata))
+    }
+
+    /// Renders the plan's chapters as a WebVTT chapters file.
+    pub fn write_vtt(&self) -> String {
+        let chapters: Vec<(String, u64)> = self.chapters.iter().map(|chapter| (chapter.title.clone(), chapter.duration_ms())).collect();
+        write_vtt_chapters_at(&chapters, self.lead_in_ms())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_plan() -> BookPlan {
+        let mut chapters = lay_out_chapters(&[
+            ("Prologue".to_string(), 61_250),
+            ("Part One".to_string(), 120_000),
+            ("Part Two".to_string(), 30_000),
+        ]);
+        chapters[1].original_title = Some("第一部".to_string());
+        let files = vec![(PathBuf::from("/books/dune/01.mp3"), 100_000), (PathBuf::from("/books/dune/02.mp3"), 111_250)];
+        assign_sources(&mut chapters, &files);
+        let warning = Warning::new(WarningKind::SampleRate, "8000 Hz is telephone quality");
+        attach_warnings(&mut chapters, &[(files[1].0.clone(), vec![warning])]);
+
+        let mut plan = BookPlan::new(files.into_iter().map(|(path, _)| path).collect(), chapters);
+        plan.metadata.title = Some("Dune".to_string());
+        plan.metadata.date = Some("1965".to_string());
+        plan.metadata.narrator = Some("Scott Brick".to_string());
+        plan.encode_settings = EncodeSettings { encoder: AacEncoder::Native, aac_vbr: Some(1.2), normalize: true, ..Default::default() };
+        let mut louder = plan.encode_settings.clone();
+        louder.gain_db = Some(3.0);
+        louder.bitrate = Some(96_000);
+        let mut file_settings = FileSettings::new("/books/dune/02.mp3", louder);
+        file_settings.trim_start_ms = Some(2_500);
+        plan.file_settings = vec![file_settings];
+        plan.cover = Some(PathBuf::from("/books/dune/cover.jpg"));
+        plan.faststart = true;
+        plan.output = Some(PathBuf::from("/books/Dune.m4b"));
+        plan
+    }
+
+    /// Tests the timeline layout and that chapters spanning a file boundary get both files.
+    #[test]
+    fn test_chapters_and_sources() {
+        let plan = sample_plan();
+        let spans: Vec<(usize, u64, u64)> = plan.chapters.iter().map(|chapter| (chapter.index, chapter.start_ms, chapter.end_ms)).collect();
+        assert_eq!(spans, vec![(1, 0, 61_250), (2, 61_250, 181_250), (3, 181_250, 211_250)]);
+        assert_eq!(plan.chapters[0].source, vec![PathBuf::from("/books/dune/01.mp3")]);
+        assert_eq!(plan.chapters[1].source, plan.files);
+        assert_eq!(plan.chapters[2].source, vec![PathBuf::from("/books/dune/02.mp3")]);
+    }
+
+    /// Tests that a file's warnings reach every chapter with its audio, and no other.
+    #[test]
+    fn test_attach_warnings() {
+        let mut plan = sample_plan();
+        assert!(plan.chapters[0].warnings.is_empty());
+        assert_eq!(plan.chapters[1].warnings.len(), 1);
+        assert_eq!(plan.chapters[2].warnings, plan.chapters[1].warnings);
+
+        let repeated = plan.chapters[1].warnings.clone();
+        attach_warnings(&mut plan.chapters, &[(plan.files[1].clone(), repeated)]);
+        assert_eq!(plan.chapters[1].warnings.len(), 1);
+    }
+
+    /// Tests the files written for a plan: every chapter at its place with its original title, and
+    /// the tags FFMETADATA carries, which leave out the narrator.
+    #[test]
+    fn test_ffmetadata_round_trip() {
+        let plan = sample_plan();
+        let text = plan.write_ffmetadata();
+        assert!(text.starts_with(";FFMETADATA1\ntitle=Dune\ndate=1965\n[CHAPTER]\n"));
+        assert!(text.contains("START=61250\nEND=181250\ntitle=Part One\noriginal_title=第一部\n"));
+        let read_back = crate::read_ffmetadata(&text).unwrap().chapters;
+        let written: Vec<(u64, u64, Option<&str>)> = read_back.iter().map(|chapter| (chapter.start_ms, chapter.end_ms, chapter.title.as_deref())).collect();
+        let planned: Vec<(u64, u64, Option<&str>)> = plan.chapters.iter().map(|chapter| (chapter.start_ms, chapter.end_ms, Some(chapter.title.as_str()))).collect();
+        assert_eq!(written, planned);
+        assert!(plan.write_vtt().contains("00:01:01.250 --> 00:03:01.250\nPart One\n"));
+    }
+
+    /// Tests delaying the chapters for a lead-in: every start and end moves, the sources stay, and
+    /// the FFMETADATA and WebVTT files start the first chapter after the lead-in.
+    #[test]
+    fn test_delay_chapters() {
+        let mut plan = sample_plan();
+        delay_chapters(&mut plan.chapters, 3_000);
+        let spans: Vec<(u64, u64)> = plan.chapters.iter().map(|chapter| (chapter.start_ms, chapter.end_ms)).collect();
+        assert_eq!(spans, vec![(3_000, 64_250), (64_250, 184_250), (184_250, 214_250)]);
+        assert_eq!(plan.lead_in_ms(), 3_000);
+        assert_eq!(plan.chapters[1].source, plan.files);
+
+        let read_back = crate::read_ffmetadata(&plan.write_ffmetadata()).unwrap().chapters;
+        let written: Vec<(u64, u64)> = read_back.iter().map(|chapter| (chapter.start_ms, chapter.end_ms)).collect();
+        assert_eq!(written, spans);
+        assert!(plan.write_vtt().contains("\n1\n00:00:03.000 --> 00:01:04.250\nPrologue\n"));
+        assert_eq!(sample_plan().lead_in_ms(), 0);
+    }
+
+    /// Tests a hand-edited export: the FFMETADATA file written for a plan, with one title changed,
+    /// reads back with the plan's times, the original titles left alone, and the new one.
+    #[test]
+    fn test_edited_ffmetadata_round_trip() {
+        let plan = sample_plan();
+        let edited = plan.write_ffmetadata().replace("title=Part One\n", "title=Part One: Arrakis\n");
+        let metadata = crate::read_ffmetadata(&edited).unwrap();
+        assert_eq!(metadata.global_tag("title"), Some("Dune"));
+        let read: Vec<(u64, u64, Option<&str>)> = metadata.chapters.iter()
+            .map(|chapter| (chapter.start_ms, chapter.end_ms, chapter.title.as_deref()))
+            .collect();
+        assert_eq!(read, vec![
+            (0, 61_250, Some("Prologue")),
+            (61_250, 181_250, Some("Part One: Arrakis")),
+            (181_250, 211_250, Some("Part Two")),
+        ]);
+    }
+
+    /// Tests that a plan survives JSON unchanged, and that plans without the optional fields load.
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_round_trip() {
+        let plan = sample_plan();
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(serde_json::from_str::<BookPlan>(&json).unwrap(), plan);
+        assert!(json.contains(r#""warnings":[{"kind":"sample_rate","message":"8000 Hz is telephone quality"}]"#));
+        assert!(json.contains(r#""file_settings":[{"file":"/books/dune/02.mp3","settings":{"encoder":"aac","aac_vbr":1.2,"bitrate":96000,"aac_profile":"LC","sample_rate":null,"channels":null,"gain_db":3.0,"normalize":true,"#));
+        assert!(json.contains(r#""narrator":"Scott Brick""#));
+        assert!(json.contains(r#""faststart":true"#));
+        assert!(json.contains(r#""trim_start_ms":2500}]"#));
+
+        let minimal: BookPlan = serde_json::from_str(r#"{"files": ["a.mp3"], "chapters": [{"index": 1, "title": "A", "start_ms": 0, "end_ms": 5}]}"#).unwrap();
+        assert_eq!(minimal, BookPlan::new(vec![PathBuf::from("a.mp3")], vec![Chapter::new(1, "A", 0, 5)]));
+    }
+}
@@ -0,0 +1,171 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use m4btool::{sanitize_filename, BookPlan, FilenameOptions};
+use tempfile::Builder;
+
+use crate::console;
+use crate::doctor::{diagnose, Diagnostics};
+use crate::runner::SystemRunner;
+use crate::shell;
+
+/// The name of the encode log for the `job_index`-th source file (counted from 0), e.g.
+/// "003-Chapter 1.mp3.log", so the logs sort in book order.
+pub fn encode_log_name(job_index: usize, source: &Path) -> String {
+    let file_name = source.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    sanitize_filename(&format!("{:03}-{}.log", job_index + 1, file_name), &FilenameOptions::default())
+}
+
+/// Appends one command run to a log file: the command line, the command's full stderr, and its
+/// exit status. Both passes of a two-pass encode go into the same log.
+pub fn append_command_log(log: &Path, command: &Command, output: &Output) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(log)?;
+    writeln!(file, "$ {}", shell::command_line(command))?;
+    file.write_all(&output.stderr)?;
+    if !output.stderr.is_empty() && !output.stderr.ends_with(b"\n") {
+        writeln!(file)?;
+    }
+    writeln!(file, "[{}]\n", output.status)
+}
+
+/// What a fatal failure left behind, gathered into a post-mortem bundle.
+#[derive(Debug, Clone)]
+pub struct PostMortem<'a> {
+    /// The error message shown to the user.
+    pub error: &'a str,
+    /// The failing command line, if the failure came from a command.
+    pub command_line: Option<&'a str>,
+    /// The failing command's full stderr.
+    pub stderr: &'a [u8],
+    pub concat_list: Option<&'a Path>,
+    pub metadata: Option<&'a Path>,
+    /// The directory of per-file encode logs, copied into the bundle.
+    pub log_dir: Option<&'a Path>,
+    /// The build plan as far as it got.
+    pub plan: &'a BookPlan,
+}
+
+/// Writes a post-mortem bundle into a new `m4btool-postmortem-*` directory under `parent`.
+///
+/// The bundle holds `error.txt`, and where available `command.txt`, `stderr.log`, `concat.txt`,
+/// `ffmetadata.txt`, `plan.json`, the `doctor` report as `environment.txt` and
+/// `environment.json`, and the encode logs under `logs/`. Files
+/// that cannot be copied are skipped, so a half-written work directory still yields a bundle.
+///
+/// # Returns
+///
+/// The path of the bundle directory.
+pub fn write_post_mortem(parent: &Path, report: &PostMortem, environment: &Diagnostics) -> io::Result<PathBuf> {
+    let dir = Builder::new().prefix("m4btool-postmortem-").tempdir_in(parent)?.keep();
+    fs::write(dir.join("error.txt"), format!("{}\n", report.error))?;
+    if let Some(command_line) = report.command_line {
+        fs::write(dir.join("command.txt"), format!("{}\n", command_line))?;
+    }
+    if !report.stderr.is_empty() {
+        fs::write(dir.join("stderr.log"), report.stderr)?;
+    }
+    for (source, name) in [(report.concat_list, "concat.txt"), (report.metadata, "ffmetadata.txt")] {
+        if let Some(source) = source {
+            let _ = fs::copy(source, dir.join(name));
+        }
+    }
+    let plan = serde_json::to_string_pretty(report.plan).map_err(io::Error::other)?;
+    fs::write(dir.join("plan.json"), plan + "\n")?;
+    fs::write(dir.join("environment.txt"), environment.render())?;
+    let environment = serde_json::to_string_pretty(environment).map_err(io::Error::other)?;
+    fs::write(dir.join("environment.json"), environment + "\n")?;
+    if let Some(log_dir) = report.log_dir {
+        fs::create_dir_all(dir.join("logs"))?;
+        for entry in fs::read_dir(log_dir)?.flatten() {
+            let _ = fs::copy(entry.path(), dir.join("logs").join(entry.file_name()));
+        }
+    }
+    Ok(dir)
+}
+
+/// Reports a fatal failure, pointing to a post-mortem bundle written under `parent`.
+pub fn report_fatal(parent: &Path, report: &PostMortem) {
+    let environment = diagnose(&SystemRunner, parent);
+    match write_post_mortem(parent, report, &environment) {
+        Ok(bundle) => console::error(format!("{}\nDetails were saved to '{}'", report.error, bundle.display())),
+        Err(err) => {
+            console::error(report.error);
+            console::warn(format!("Could not save the failure details: {}", err));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use m4btool::is_safe_filename;
+    use m4btool::plan::Chapter;
+
+    /// Tests the log name, and that each run's command, stderr, and status are appended.
+    #[cfg(unix)]
+    #[test]
+    fn test_append_command_log() {
+        assert_eq!(encode_log_name(2, Path::new("/books/dune/Chapter 1.mp3")), "003-Chapter 1.mp3.log");
+        let long_name = encode_log_name(0, Path::new(&format!("/books/{}.mp3", "ü".repeat(200))));
+        assert!(is_safe_filename(&long_name) && long_name.ends_with(".log"));
+
+        let work = tempfile::tempdir().unwrap();
+        let log = work.path().join("001-one.mp3.log");
+        for script in ["echo analysis >&2", "echo 'Invalid data found' >&2; exit 1"] {
+            let mut command = Command::new("sh");
+            command.args(["-c", script]);
+            let output = command.output().unwrap();
+            append_command_log(&log, &command, &output).unwrap();
+        }
+        let text = fs::read_to_string(&log).unwrap();
+        assert!(text.starts_with("$ sh -c 'echo analysis >&2'\nanalysis\n[exit status: 0]\n\n$ sh -c "));
+        assert!(text.ends_with("Invalid data found\n[exit status: 1]\n\n"));
+    }
+
+    /// Tests that a failure bundle holds the failing command and every work file that exists.
+    #[test]
+    fn test_write_post_mortem() {
+        let work = tempfile::tempdir().unwrap();
+        let concat_list = work.path().join("concat");
+        fs::write(&concat_list, "file '/tmp/one.m4a'\n").unwrap();
+        let log_dir = work.path().join("logs");
+        fs::create_dir(&log_dir).unwrap();
+        fs::write(log_dir.join("001-one.mp3.log"), "$ ffmpeg -i one.mp3\n").unwrap();
+
+        let plan = BookPlan::new(vec![PathBuf::from("/books/one.mp3")], vec![Chapter::new(1, "One", 0, 61_250)]);
+        let report = PostMortem {
+            error: "FFmpeg execution failed: Invalid argument",
+            command_line: Some("ffmpeg -f concat -safe 0 -i concat out.m4b"),
+            stderr: b"[concat] Impossible to open '/tmp/one.m4a'\nInvalid argument\n",
+            concat_list: Some(&concat_list),
+            metadata: Some(&work.path().join("missing-metadata")),
+            log_dir: Some(&log_dir),
+            plan: &plan,
+        };
+        let environment = Diagnostics {
+            version: "0.1.0".to_string(),
+            platform: "linux-x86_64".to_string(),
+            ffmpeg: Some("ffmpeg version 7.1".to_string()),
+            ffprobe: None,
+            encoders: Default::default(),
+            temp_dir: work.path().to_path_buf(),
+            temp_free_bytes: None,
+            cpus: Some(4),
+        };
+        let bundle = write_post_mortem(work.path(), &report, &environment).unwrap();
+
+        assert!(bundle.file_name().unwrap().to_string_lossy().starts_with("m4btool-postmortem-"));
+        let read = |name: &str| fs::read_to_string(bundle.join(name)).unwrap();
+        assert_eq!(read("command.txt"), "ffmpeg -f concat -safe 0 -i concat out.m4b\n");
+        assert!(read("stderr.log").ends_with("Invalid argument\n"));
+        assert_eq!(read("concat.txt"), "file '/tmp/one.m4a'\n");
+        assert!(!bundle.join("ffmetadata.txt").exists());
+        assert_eq!(read("logs/001-one.mp3.log"), "$ ffmpeg -i one.mp3\n");
+        assert!(read("environment.txt").contains("ffmpeg: ffmpeg version 7.1\nffprobe: not found\n"));
+        assert!(read("environment.json").contains("\"cpus\": 4"));
+
+        assert_eq!(serde_json::from_str::<BookPlan>(&read("plan.json")).unwrap(), plan);
+    }
+}
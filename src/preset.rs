@@ -0,0 +1,74 @@
+use crate::encode::{AacEncoder, AacProfile, EncodeSettings};
+
+/// A bundle of settings chosen with `--preset`. The flags given next to it replace its values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    pub name: &'static str,
+    pub settings: EncodeSettings,
+    /// Move the index to the front of the book, so players can start it before it has downloaded.
+    pub faststart: bool,
+}
+
+/// Speech at a small size: mono 64k HE-AAC, normalized.
+pub const VOICE: Preset = Preset { name: "voice", settings: settings(Some(64_000), AacProfile::He, Some(1), Some(44_100), true), faststart: true };
+/// Speech with room to spare: mono 96k LC, normalized.
+pub const VOICE_HQ: Preset = Preset { name: "voice-hq", settings: settings(Some(96_000), AacProfile::Lc, Some(1), Some(44_100), true), faststart: true };
+/// Music, or a full-cast recording: stereo 128k LC, as mastered.
+pub const MUSIC: Preset = Preset { name: "music", settings: settings(Some(128_000), AacProfile::Lc, Some(2), Some(44_100), false), faststart: true };
+/// The smallest book that is still easy to follow: mono 32k HE-AAC at 22.05 kHz, normalized.
+pub const TINY: Preset = Preset { name: "tiny", settings: settings(Some(32_000), AacProfile::He, Some(1), Some(22_050), true), faststart: true };
+/// A copy to keep: stereo LC at each source's bitrate and sample rate, as mastered.
+pub const ARCHIVE: Preset = Preset { name: "archive", settings: settings(None, AacProfile::Lc, Some(2), None, false), faststart: false };
+
+/// Every preset, in the order `--help` lists them.
+pub const PRESETS: [Preset; 5] = [VOICE, VOICE_HQ, MUSIC, TINY, ARCHIVE];
+
+/// The settings of a preset, with libfdk_aac and everything else left at its default.
+const fn settings(bitrate: Option<u64>, aac_profile: AacProfile, channels: Option<u32>, sample_rate: Option<u32>, normalize: bool) -> EncodeSettings {
+    EncodeSettings {
+        encoder: AacEncoder::Fdk,
+        aac_vbr: None,
+        bitrate,
+        aac_profile,
+        sample_rate,
+        channels,
+        gain_db: None,
+        normalize,
+        encoder_args: Vec::new(),
+        extra_args: Vec::new(),
+        exact_bitrate: false,
+    }
+}
+
+/// Parses a `--preset` value.
+pub fn parse_preset(value: &str) -> Result<Preset, String> {
+    PRESETS.into_iter().find(|preset| preset.name == value).ok_or_else(|| {
+        let names: Vec<&str> = PRESETS.iter().map(|preset| preset.name).collect();
+        format!("Invalid preset '{}': expected {}", value, names.join(", "))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encode::describe_settings;
+
+    use super::*;
+
+    /// Tests that every preset resolves to the values the usage text and README document.
+    #[test]
+    fn test_presets() {
+        let documented = [
+            ("voice", "libfdk_aac HE-AAC at 64k, mono, 44100 Hz, loudness normalized", true),
+            ("voice-hq", "libfdk_aac LC at 96k, mono, 44100 Hz, loudness normalized", true),
+            ("music", "libfdk_aac LC at 128k, stereo, 44100 Hz", true),
+            ("tiny", "libfdk_aac HE-AAC at 32k, mono, 22050 Hz, loudness normalized", true),
+            ("archive", "libfdk_aac LC at the source's bitrate, stereo, the source's sample rate", false),
+        ];
+        assert_eq!(PRESETS.len(), documented.len());
+        for (name, description, faststart) in documented {
+            let preset = parse_preset(name).unwrap();
+            assert_eq!((describe_settings(&preset.settings).as_str(), preset.faststart), (description, faststart), "{}", name);
+        }
+        assert_eq!(parse_preset("podcast").unwrap_err(), "Invalid preset 'podcast': expected voice, voice-hq, music, tiny, archive");
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use crate::console;
+use crate::inspect::{flat_pairs, probe_book_info, BookInfo};
+use crate::runner::{CommandRunner, SystemRunner};
+use crate::tag_encoding::TagEncoding;
+
+/// Retrieves the duration of an audio file in milliseconds by using `ffprobe`.
+/// This function invokes `ffprobe` as a subprocess and parses the output to obtain the duration.
+///
+/// # Arguments
+///
+/// * `file_path` - The file path to the audio file as a string slice.
+///
+/// # Returns
+///
+/// An `Option<u64>` representing the duration in milliseconds, or `None` if the duration cannot be determined.
+pub fn get_duration_ms(file_path: &str) -> Option<u64> {
+    probe_duration_ms(file_path, &SystemRunner)
+}
+
+/// Retrieves the duration of an audio file in milliseconds like `get_duration_ms`, running
+/// `ffprobe` with `runner`.
+pub fn probe_duration_ms(file_path: &str, runner: &dyn CommandRunner) -> Option<u64> {
+    let output = runner.query(Command::new("ffprobe").args([
+        "-v", "error",
+        "-show_entries", "format=duration",
+        "-of", "default=noprint_wrappers=1:nokey=1",
+        file_path,
+    ]))
+    .ok()?;
+    if !output.status.success() {
+        console::warn(format!("ffprobe error for {}: {}", file_path, String::from_utf8_lossy(&output.stderr).trim()));
+        return None;
+    }
+    let duration_str = String::from_utf8_lossy(&output.stdout);
+    let duration_sec: f64 = duration_str.trim().parse().ok()?;
+    Some((duration_sec * 1000.0).round() as u64)
+}
+
+/// Audio stream and format details of a file, gathered with a single `ffprobe` call.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AudioInfo {
+    /// The codec name of the first audio stream, e.g. "mp3".
+    pub codec: String,
+    /// The stream bitrate in bits per second (if available).
+    pub bit_rate: Option<u64>,
+    /// The sample rate in Hz.
+    pub sample_rate: Option<u32>,
+    /// The number of audio channels.
+    pub channels: Option<u32>,
+    /// The container duration in milliseconds.
+    pub duration_ms: Option<u64>,
+    /// The codec profile, e.g. "LC" or "HE-AAC" for AAC.
+    pub profile: Option<String>,
+    /// The channel layout, e.g. "stereo" or "5.1(side)".
+    pub channel_layout: Option<String>,
+    /// The MD5 of the codec's extradata; for AAC this is the AudioSpecificConfig.
+    pub extradata_hash: Option<String>,
+}
+
+/// Whether a file's audio stream can be stream-copied next to a reference stream in one
+/// concatenation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Compatibility {
+    /// Every parameter matters for concatenation matches, so the stream can be copied.
+    Identical,
+    /// The stream differs, e.g. in sample rate, and must be encoded to the reference's parameters.
+    NeedsReencode { reason: String },
+    /// The stream cannot be compared because a parameter of either stream is unknown, so
+    /// neither copying nor matching the reference is safe.
+    Incompatible { reason: String },
+}
+
+impl Compatibility {
+    /// The decision as a short name: "copy", "reencode", or "incompatible".
+    pub fn decision(&self) -> &'static str {
+        match self {
+            Compatibility::Identical => "copy",
+            Compatibility::NeedsReencode { .. } => "reencode",
+            Compatibility::Incompatible { .. } => "incompatible",
+        }
+    }
+
+    /// Why the stream cannot be copied, if it cannot.
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            Compatibility::Identical => None,
+            Compatibility::NeedsReencode { reason } | Compatibility::Incompatible { reason } => Some(reason),
+        }
+    }
+}
+
+/// Checks whether the stream `b` can be stream-copied into one concatenation with the reference
+/// stream `a`: the codec, profile, sample rate, channel count, and channel layout must match,
+/// and for AAC also the AudioSpecificConfig, which players read once for the whole file.
+///
+/// A profile, layout, or extradata that only one side reports is treated as unknown rather than
+/// as a difference, since older ffprobe versions leave them out.
+pub fn streams_compatible(a: &AudioInfo, b: &AudioInfo) -> Compatibility {
+    for (side, info) in [("reference", a), ("file", b)] {
+        let unknown = if info.codec.is_empty() {
+            Some("codec")
+        } else if info.sample_rate.is_none() {
+            Some("sample rate")
+        } else if info.channels.is_none() {
+            Some("channel count")
+        } else {
+            None
+        };
+        if let Some(name) = unknown {
+            return Compatibility::Incompatible { reason: format!("the {} of the {} is unknown", name, side) };
+        }
+    }
+    let differs = |name: &str, a: &str, b: &str| Compatibility::NeedsReencode { reason: format!("{} {} differs from the reference's {}", name, b, a) };
+    if a.codec != b.codec {
+        return differs("codec", &a.codec, &b.codec);
+    }
+    if let (Some(profile_a), Some(profile_b)) = (&a.profile, &b.profile) {
+        if profile_a != profile_b {
+            return differs("profile", profile_a, profile_b);
+        }
+    }
+    if a.sample_rate != b.sample_rate {
+        return differs("sample rate", &format!("{} Hz", a.sample_rate.unwrap_or_default()), &format!("{} Hz", b.sample_rate.unwrap_or_default()));
+    }
+    if a.channels != b.channels {
+        return differs("channel count", &a.channels.unwrap_or_default().to_string(), &b.channels.unwrap_or_default().to_string());
+    }
+    if let (Some(layout_a), Some(layout_b)) = (&a.channel_layout, &b.channel_layout) {
+        if layout_a != layout_b {
+            return differs("channel layout", layout_a, layout_b);
+        }
+    }
+    if a.codec == "aac" {
+        if let (Some(config_a), Some(config_b)) = (&a.extradata_hash, &b.extradata_hash) {
+            if config_a != config_b {
+                return Compatibility::NeedsReencode { reason: "the AAC decoder configuration differs from the reference's".to_string() };
+            }
+        }
+    }
+    Compatibility::Identical
+}
+
+/// Extracts audio stream information from a file using `ffprobe`.
+/// It retrieves the codec name, bitrate, sample rate, and channel count of the first
+/// audio stream together with the container duration, all in one probe.
+///
+/// # Arguments
+///
+/// * `file_path` - The file path to the audio file.
+///
+/// # Returns
+///
+/// An `Option<AudioInfo>`, or `None` if the file has no audio stream or cannot be probed.
+pub fn get_audio_info(file_path: &str) -> Option<AudioInfo> {
+    probe_audio_info(file_path, &SystemRunner)
+}
+
+/// Retrieves the audio details of a file like `get_audio_info`, running `ffprobe` with `runner`.
+pub fn probe_audio_info(file_path: &str, runner: &dyn CommandRunner) -> Option<AudioInfo> {
+    let output = runner.query(Command::new("ffprobe").args([
+        "-v", "error",
+        "-select_streams", "a:0",
+        "-show_data_hash", "md5",
+        "-show_entries", "stream=codec_name,profile,bit_rate,sample_rate,channels,channel_layout,extradata_hash:format=duration",
+        "-of", "flat",
+        file_path,
+    ]))
+    .ok()?;
+    if !output.status.success() {
+        console::warn(format!("ffprobe error for {}: {}", file_path, String::from_utf8_lossy(&output.stderr).trim()));
+        return None;
+    }
+    parse_audio_info(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// What `ffprobe` reports about the source files of a build, kept so that each file is probed
+/// once for its audio stream and once for its tags and chapters, however many steps of the
+/// build ask about it.
+pub struct SourceProbes<'a> {
+    runner: &'a (dyn CommandRunner + Sync),
+    /// Re-decodes the tags and chapter titles of `book_info`, from `--tag-encoding`.
+    tag_encoding: Option<TagEncoding>,
+    audio: Mutex<HashMap<PathBuf, Option<AudioInfo>>>,
+    books: Mutex<HashMap<PathBuf, Option<BookInfo>>>,
+}
+
+impl<'a> SourceProbes<'a> {
+    pub fn new(runner: &'a (dyn CommandRunner + Sync), tag_encoding: Option<TagEncoding>) -> Self {
+        SourceProbes { runner, tag_encoding, audio: Mutex::new(HashMap::new()), books: Mutex::new(HashMap::new()) }
+    }
+
+    /// The audio details of a file, as `get_audio_info` reports them.
+    pub fn audio_info(&self, path: &Path) -> Option<AudioInfo> {
+        if let Some(info) = self.audio.lock().unwrap().get(path) {
+            return info.clone();
+        }
+        let info = probe_audio_info(&path.to_string_lossy(), self.runner);
+        self.audio.lock().unwrap().insert(path.to_path_buf(), info.clone());
+        info
+    }
+
+    /// The duration of a file in milliseconds, from its audio details.
+    pub fn duration_ms(&self, path: &Path) -> Option<u64> {
+        self.audio_info(path)?.duration_ms
+    }
+
+    /// The tags, chapters, and cover of a file, as `inspect_book` reports them, re-decoded with
+    /// the tag encoding.
+    pub fn book_info(&self, path: &Path) -> Option<BookInfo> {
+        if let Some(info) = self.books.lock().unwrap().get(path) {
+            return info.clone();
+        }
+        let info = probe_book_info(&path.to_string_lossy(), self.runner).map(|info| match self.tag_encoding {
+            Some(encoding) => info.redecode_tags(encoding),
+            None => info,
+        });
+        self.books.lock().unwrap().insert(path.to_path_buf(), info.clone());
+        info
+    }
+}
+
+/// Parses the flat `ffprobe` output requested by `get_audio_info`.
+fn parse_audio_info(output: &str) -> Option<AudioInfo> {
+    let known = |value: String| Some(value).filter(|value| !value.is_empty() && value != "N/A" && value != "unknown");
+    let mut info = AudioInfo::default();
+    for (key, value) in flat_pairs(output) {
+        match key.as_str() {
+            "streams.stream.0.codec_name" => info.codec = value,
+            "streams.stream.0.bit_rate" => info.bit_rate = value.parse().ok(),
+            "streams.stream.0.sample_rate" => info.sample_rate = value.parse().ok(),
+            "streams.stream.0.channels" => info.channels = value.parse().ok(),
+            "streams.stream.0.profile" => info.profile = known(value),
+            "streams.stream.0.channel_layout" => info.channel_layout = known(value),
+            "streams.stream.0.extradata_hash" => info.extradata_hash = known(value),
+            "format.duration" => {
+                info.duration_ms = value.parse::<f64>().ok().map(|sec| (sec * 1000.0).round() as u64)
+            }
+            _ => {}
+        }
+    }
+    if info.codec.is_empty() {
+        return None;
+    }
+    Some(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that stream and format fields are read from one probe, with "N/A" treated as missing.
+    #[test]
+    fn test_parse_audio_info() {
+        let output = "streams.stream.0.codec_name=\"flac\"\nstreams.stream.0.sample_rate=\"96000\"\n\
+                      streams.stream.0.channels=2\nstreams.stream.0.bit_rate=\"N/A\"\nformat.duration=\"61.5005\"\n";
+        let info = parse_audio_info(output).unwrap();
+        assert_eq!(info.codec, "flac");
+        assert_eq!(info.bit_rate, None);
+        assert_eq!(info.sample_rate, Some(96000));
+        assert_eq!(info.channels, Some(2));
+        assert_eq!(info.duration_ms, Some(61501));
+        assert_eq!(parse_audio_info("format.duration=\"1.0\"\n"), None);
+    }
+
+    /// Tests that profile, layout, and extradata are read, with "unknown" treated as missing.
+    #[test]
+    fn test_parse_stream_parameters() {
+        let output = "streams.stream.0.codec_name=\"aac\"\nstreams.stream.0.profile=\"LC\"\n\
+                      streams.stream.0.channel_layout=\"unknown\"\nstreams.stream.0.extradata_hash=\"MD5:5b0a\"\n";
+        let info = parse_audio_info(output).unwrap();
+        assert_eq!(info.profile.as_deref(), Some("LC"));
+        assert_eq!(info.channel_layout, None);
+        assert_eq!(info.extradata_hash.as_deref(), Some("MD5:5b0a"));
+    }
+
+    fn aac() -> AudioInfo {
+        AudioInfo {
+            codec: "aac".to_string(),
+            sample_rate: Some(44100),
+            channels: Some(2),
+            profile: Some("LC".to_string()),
+            channel_layout: Some("stereo".to_string()),
+            extradata_hash: Some("MD5:1210".to_string()),
+            ..AudioInfo::default()
+        }
+    }
+
+    /// Tests the matrix of stream parameters that allow or prevent a stream copy.
+    #[test]
+    fn test_streams_compatible() {
+        let reference = aac();
+        let with = |change: fn(&mut AudioInfo)| {
+            let mut info = aac();
+            change(&mut info);
+            streams_compatible(&reference, &info)
+        };
+        assert_eq!(streams_compatible(&reference, &aac()), Compatibility::Identical);
+        // A different bitrate or duration does not matter for concatenation.
+        assert_eq!(with(|info| { info.bit_rate = Some(64_000); info.duration_ms = Some(1) }), Compatibility::Identical);
+        // Parameters only one side reports are not a difference.
+        assert_eq!(with(|info| { info.profile = None; info.channel_layout = None; info.extradata_hash = None }), Compatibility::Identical);
+
+        let reencode = |compatibility: Compatibility| match compatibility {
+            Compatibility::NeedsReencode { reason } => reason,
+            other => panic!("expected NeedsReencode, got {:?}", other),
+        };
+        assert_eq!(reencode(with(|info| info.codec = "mp3".to_string())), "codec mp3 differs from the reference's aac");
+        assert_eq!(reencode(with(|info| info.profile = Some("HE-AAC".to_string()))), "profile HE-AAC differs from the reference's LC");
+        assert_eq!(reencode(with(|info| info.sample_rate = Some(22050))), "sample rate 22050 Hz differs from the reference's 44100 Hz");
+        assert_eq!(reencode(with(|info| { info.channels = Some(1); info.channel_layout = Some("mono".to_string()) })), "channel count 1 differs from the reference's 2");
+        assert_eq!(reencode(with(|info| info.channel_layout = Some("downmix".to_string()))), "channel layout downmix differs from the reference's stereo");
+        assert!(reencode(with(|info| info.extradata_hash = Some("MD5:1190".to_string()))).contains("AAC decoder configuration"));
+
+        // Extradata is only compared for AAC.
+        let mp3 = |hash: &str| AudioInfo { codec: "mp3".to_string(), extradata_hash: Some(hash.to_string()), ..aac() };
+        assert_eq!(streams_compatible(&mp3("MD5:1"), &mp3("MD5:2")), Compatibility::Identical);
+
+        let incompatible = with(|info| info.sample_rate = None);
+        assert_eq!(incompatible, Compatibility::Incompatible { reason: "the sample rate of the file is unknown".to_string() });
+        assert_eq!(incompatible.decision(), "incompatible");
+        let unknown_reference = AudioInfo { channels: None, ..aac() };
+        assert_eq!(streams_compatible(&unknown_reference, &aac()).reason(), Some("the channel count of the reference is unknown"));
+        assert_eq!(Compatibility::Identical.reason(), None);
+    }
+
+    /// Answers every ffprobe query with the output of one file, counting the queries by kind.
+    #[cfg(unix)]
+    struct CountingRunner {
+        queries: Mutex<Vec<String>>,
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for CountingRunner {
+        fn run(&self, _: &mut Command) -> std::io::Result<std::process::Output> {
+            unreachable!("probes only query")
+        }
+
+        fn query(&self, command: &mut Command) -> std::io::Result<std::process::Output> {
+            use std::os::unix::process::ExitStatusExt;
+            let audio = command.get_args().any(|arg| arg == "-select_streams");
+            self.queries.lock().unwrap().push(if audio { "audio" } else { "book" }.to_string());
+            let stdout = if audio {
+                "streams.stream.0.codec_name=\"mp3\"\nstreams.stream.0.channels=1\nformat.duration=\"61.5\"\n"
+            } else {
+                "format.format_name=\"mp3\"\nformat.tags.album=\"Dune\"\n"
+            };
+            Ok(std::process::Output { status: std::process::ExitStatus::from_raw(0), stdout: stdout.as_bytes().to_vec(), stderr: Vec::new() })
+        }
+    }
+
+    /// Tests that every step asking about a file shares one probe of each kind per file.
+    #[cfg(unix)]
+    #[test]
+    fn test_source_probes() {
+        let runner = CountingRunner { queries: Mutex::new(Vec::new()) };
+        let probes = SourceProbes::new(&runner, None);
+        let one = Path::new("01.mp3");
+        assert_eq!(probes.duration_ms(one), Some(61_500));
+        assert_eq!(probes.audio_info(one).and_then(|info| info.channels), Some(1));
+        assert_eq!(probes.book_info(one).and_then(|info| info.tags.get("album").cloned()).as_deref(), Some("Dune"));
+        assert!(probes.book_info(one).is_some());
+        assert_eq!(probes.duration_ms(Path::new("02.mp3")), Some(61_500));
+        assert_eq!(*runner.queries.lock().unwrap(), ["audio", "book", "audio"]);
+    }
+}
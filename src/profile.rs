@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use m4btool::{BuildPhase, Event};
+
+use crate::progress;
+
+/// Records the wall-clock time of each build phase and of each file's encode, shown with
+/// `--profile`.
+///
+/// Phases run one after another: beginning a phase ends the previous one, and a phase begun
+/// again adds to its earlier time. Encodes run in parallel, so their per-file times are recorded
+/// separately and may add up to more than the encode phase.
+#[derive(Debug, Default)]
+pub struct PhaseTimer {
+    phases: Vec<(BuildPhase, Duration)>,
+    current: Option<(BuildPhase, Instant)>,
+    files: Vec<(String, Duration)>,
+}
+
+impl PhaseTimer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ends the running phase, if any, and starts `phase`, reporting it to `--progress-json`.
+    pub fn begin(&mut self, phase: BuildPhase) {
+        progress::emit(Event::Phase { phase });
+        self.begin_at(phase, Instant::now());
+    }
+
+    /// Ends the running phase, if any.
+    pub fn end(&mut self) {
+        self.end_at(Instant::now());
+    }
+
+    fn begin_at(&mut self, phase: BuildPhase, now: Instant) {
+        self.end_at(now);
+        self.current = Some((phase, now));
+    }
+
+    fn end_at(&mut self, now: Instant) {
+        let Some((phase, started)) = self.current.take() else { return };
+        let elapsed = now.saturating_duration_since(started);
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    /// Records how long one file took to encode.
+    pub fn record_file(&mut self, name: impl Into<String>, elapsed: Duration) {
+        self.files.push((name.into(), elapsed));
+    }
+
+    /// The time of a finished phase, or zero if it never ran.
+    pub fn phase(&self, phase: BuildPhase) -> Duration {
+        self.phases.iter().find(|(name, _)| *name == phase).map_or(Duration::ZERO, |(_, elapsed)| *elapsed)
+    }
+
+    /// The summed time of all finished phases.
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, elapsed)| *elapsed).sum()
+    }
+
+    /// Hours of audio encoded per hour of wall-clock time in the encode phase, or `None` when
+    /// nothing was encoded.
+    pub fn realtime_factor(&self, audio_ms: u64) -> Option<f64> {
+        let encode = self.phase(BuildPhase::Encode);
+        (audio_ms > 0 && !encode.is_zero()).then(|| audio_ms as f64 / 1000.0 / encode.as_secs_f64())
+    }
+
+    /// Renders the phases, the total, and the per-file encode times as the table printed after a
+    /// build.
+    pub fn report(&self, audio_ms: u64) -> String {
+        let seconds = |elapsed: &Duration| format!("{:>9.2} s", elapsed.as_secs_f64());
+        let mut report = String::from("Profile:\n");
+        for (phase, elapsed) in &self.phases {
+            report.push_str(&format!("  {:<10} {}", phase.name(), seconds(elapsed)));
+            if *phase == BuildPhase::Encode {
+                if let Some(factor) = self.realtime_factor(audio_ms) {
+                    report.push_str(&format!("  ({:.1}x realtime)", factor));
+                }
+            }
+            report.push('\n');
+        }
+        report.push_str(&format!("  {:<10} {}\n", "total", seconds(&self.total())));
+        if !self.files.is_empty() {
+            let width = self.files.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0);
+            report.push_str("Encode per file:\n");
+            for (name, elapsed) in &self.files {
+                report.push_str(&format!("  {:<width$} {}\n", name, seconds(elapsed), width = width));
+            }
+        }
+        report
+    }
+}
+
+/// Runs `work` and returns its result with how long it took.
+pub fn measure<T>(work: impl FnOnce() -> T) -> (T, Duration) {
+    let started = Instant::now();
+    let result = work();
+    (result, started.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that beginning a phase ends the previous one and that a repeated phase accumulates.
+    #[test]
+    fn test_phase_timer() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut timer = PhaseTimer::new();
+        timer.begin_at(BuildPhase::Scan, at(0));
+        timer.begin_at(BuildPhase::Probe, at(200));
+        timer.begin_at(BuildPhase::Encode, at(1_200));
+        timer.begin_at(BuildPhase::Probe, at(61_200));
+        timer.end_at(at(61_700));
+        timer.end_at(at(90_000));
+
+        assert_eq!(timer.phase(BuildPhase::Scan), Duration::from_millis(200));
+        assert_eq!(timer.phase(BuildPhase::Probe), Duration::from_millis(1_500));
+        assert_eq!(timer.phase(BuildPhase::Encode), Duration::from_secs(60));
+        assert_eq!(timer.phase(BuildPhase::Mux), Duration::ZERO);
+        assert_eq!(timer.total(), Duration::from_millis(61_700));
+    }
+
+    /// Tests the realtime factor: two hours of audio encoded in a minute is 120x.
+    #[test]
+    fn test_realtime_factor() {
+        let start = Instant::now();
+        let mut timer = PhaseTimer::new();
+        assert_eq!(timer.realtime_factor(7_200_000), None);
+        timer.begin_at(BuildPhase::Encode, start);
+        timer.end_at(start + Duration::from_secs(60));
+        assert_eq!(timer.realtime_factor(7_200_000), Some(120.0));
+        assert_eq!(timer.realtime_factor(0), None);
+    }
+
+    /// Tests the printed table.
+    #[test]
+    fn test_report() {
+        let start = Instant::now();
+        let mut timer = PhaseTimer::new();
+        timer.begin_at(BuildPhase::Scan, start);
+        timer.begin_at(BuildPhase::Encode, start + Duration::from_millis(250));
+        timer.end_at(start + Duration::from_millis(30_250));
+        timer.record_file("01 - Intro.mp3", Duration::from_millis(12_500));
+        timer.record_file("02.mp3", Duration::from_millis(17_000));
+        assert_eq!(
+            timer.report(3_600_000),
+            "Profile:\n\
+             \x20 scan            0.25 s\n\
+             \x20 encode         30.00 s  (120.0x realtime)\n\
+             \x20 total          30.25 s\n\
+             Encode per file:\n\
+             \x20 01 - Intro.mp3     12.50 s\n\
+             \x20 02.mp3             17.00 s\n"
+        );
+    }
+}
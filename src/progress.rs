@@ -0,0 +1,267 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use m4btool::{event_line, BuildPhase, Event};
+
+/// The mux reports its progress at most this often.
+const MUX_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The `--progress-json` stream: one JSON event per line, in the shape of `m4btool::Event`.
+pub struct ProgressStream {
+    state: Mutex<StreamState>,
+    /// Whether the events go to stdout, which human-readable results then leave to stderr.
+    on_stdout: bool,
+}
+
+struct StreamState {
+    out: Box<dyn Write + Send>,
+    /// The phase of the last `Event::Phase`, which decides whether ffmpeg's times are mux progress.
+    phase: Option<BuildPhase>,
+    last_mux_progress: Option<Instant>,
+}
+
+impl ProgressStream {
+    pub fn new(out: Box<dyn Write + Send>, on_stdout: bool) -> Self {
+        ProgressStream { state: Mutex::new(StreamState { out, phase: None, last_mux_progress: None }), on_stdout }
+    }
+
+    /// Opens the stream on stdout, or on a file or named pipe at `target`.
+    pub fn open(target: Option<&str>) -> io::Result<Self> {
+        match target {
+            None => Ok(ProgressStream::new(Box::new(io::stdout()), true)),
+            // A named pipe cannot be truncated, so opening it this way works for both.
+            Some(path) => {
+                let file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+                Ok(ProgressStream::new(Box::new(file), false))
+            }
+        }
+    }
+
+    /// Writes an event as its own line and flushes it, so a reader sees it right away.
+    pub fn emit(&self, event: &Event) {
+        let mut state = self.lock();
+        if let Event::Phase { phase } = event {
+            state.phase = Some(*phase);
+            state.last_mux_progress = None;
+        }
+        let _ = writeln!(state.out, "{}", event_line(event));
+        let _ = state.out.flush();
+    }
+
+    /// Reports how much of the book ffmpeg has written, if the mux is running and the last
+    /// report is at least `MUX_PROGRESS_INTERVAL` old.
+    pub fn ffmpeg_time(&self, out_time_ms: u64, now: Instant) {
+        let mut state = self.lock();
+        if state.phase != Some(BuildPhase::Mux) {
+            return;
+        }
+        if state.last_mux_progress.is_some_and(|last| now.saturating_duration_since(last) < MUX_PROGRESS_INTERVAL) {
+            return;
+        }
+        state.last_mux_progress = Some(now);
+        let _ = writeln!(state.out, "{}", event_line(&Event::MuxProgress { out_time_ms }));
+        let _ = state.out.flush();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, StreamState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Reads the output time from one of ffmpeg's status lines, e.g. 83450 from
+/// `size=  1024kB time=00:01:23.45 bitrate= 100.5kbits/s`.
+pub fn parse_status_time(line: &str) -> Option<u64> {
+    let (_, rest) = line.split_once("time=")?;
+    let value = rest.split_whitespace().next()?;
+    let mut parts = value.rsplitn(3, ':');
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    let minutes: u64 = parts.next()?.parse().ok()?;
+    let hours: u64 = parts.next()?.parse().ok()?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+    Some((hours * 3_600 + minutes * 60) * 1000 + (seconds * 1000.0).round() as u64)
+}
+
+/// Splits ffmpeg's stderr into lines as it is read, at line breaks and at the carriage returns
+/// that ffmpeg redraws its status line with.
+#[derive(Debug, Default)]
+pub struct StderrLines {
+    pending: Vec<u8>,
+}
+
+impl StderrLines {
+    /// Adds a chunk of stderr and returns the lines it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n' || b == b'\r') {
+            let text: Vec<u8> = self.pending.drain(..=end).collect();
+            lines.push(String::from_utf8_lossy(&text[..end]).to_string());
+        }
+        lines
+    }
+
+    /// The text after the last line break, once stderr has ended.
+    pub fn rest(&self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| String::from_utf8_lossy(&self.pending).to_string())
+    }
+}
+
+static STREAM: OnceLock<ProgressStream> = OnceLock::new();
+
+/// Installs the process-wide stream. Only the first call has an effect.
+pub fn init(stream: ProgressStream) {
+    let _ = STREAM.set(stream);
+}
+
+/// Writes an event to the process-wide stream, if `--progress-json` set one up.
+pub fn emit(event: Event) {
+    if let Some(stream) = STREAM.get() {
+        stream.emit(&event);
+    }
+}
+
+/// Passes a time read from ffmpeg's status output to the process-wide stream, if any.
+pub fn ffmpeg_time(out_time_ms: u64) {
+    if let Some(stream) = STREAM.get() {
+        stream.ffmpeg_time(out_time_ms, Instant::now());
+    }
+}
+
+/// Whether `--progress-json` is on.
+pub fn enabled() -> bool {
+    STREAM.get().is_some()
+}
+
+/// Whether the events go to stdout, so results meant for stdout must go to stderr instead.
+pub fn owns_stdout() -> bool {
+    STREAM.get().is_some_and(|stream| stream.on_stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use m4btool::{parse_event_line, FileOutcome};
+
+    /// A writer that can be read back after the stream has written to it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Tests reading times from ffmpeg's status lines.
+    #[test]
+    fn test_parse_status_time() {
+        assert_eq!(parse_status_time("size=  1024kB time=00:01:23.45 bitrate= 100.5kbits/s speed=41x"), Some(83_450));
+        assert_eq!(parse_status_time("size=N/A time=12:00:00.00 bitrate=N/A"), Some(43_200_000));
+        assert_eq!(parse_status_time("size=N/A time=N/A bitrate=N/A"), None);
+        assert_eq!(parse_status_time("time=-00:00:00.02"), None);
+        assert_eq!(parse_status_time("Stream mapping:"), None);
+    }
+
+    /// The stderr of a mux by ffmpeg 6.1 as recorded, status lines and all: copy muxes start
+    /// with a negative time, and each status line but the last ends in a carriage return.
+    const RECORDED_MUX: &str = "ffmpeg version 6.1.1 Copyright (c) 2000-2023 the FFmpeg developers\n\
+        \x20 built with gcc 13 (GCC)\n\
+        Input #0, concat, from '/tmp/m4btool-work-2f9c/concat.txt':\n\
+        \x20 Duration: N/A, start: 0.000000, bitrate: 64 kb/s\n\
+        \x20 Stream #0:0: Audio: aac (LC) (mp4a / 0x6134706D), 44100 Hz, mono, fltp, 64 kb/s\n\
+        Input #1, ffmetadata, from '/tmp/m4btool-work-2f9c/metadata.txt':\n\
+        \x20 Duration: 02:01:40.00, start: 0.000000, bitrate: 0 kb/s\n\
+        Stream mapping:\n\
+        \x20 Stream #0:0 -> #0:0 (copy)\n\
+        Output #0, ipod, to 'Dune.m4b':\n\
+        \x20 Metadata:\n\
+        \x20   title           : Dune\n\
+        \x20   encoder         : Lavf60.16.100\n\
+        \x20 Stream #0:0: Audio: aac (LC) (mp4a / 0x6134706D), 44100 Hz, mono, fltp, 64 kb/s\n\
+        Press [q] to stop, [?] for help\n\
+        size=       0kB time=-577014:32:22.77 bitrate=  -0.0kbits/s speed=N/A    \r\
+        size=   12288kB time=00:26:12.37 bitrate=  64.0kbits/s speed=3.14e+03x    \r\
+        size=   24576kB time=00:52:24.71 bitrate=  64.0kbits/s speed=3.14e+03x    \r\
+        size=   36864kB time=01:18:37.08 bitrate=  64.0kbits/s speed=3.14e+03x    \r\
+        size=   49152kB time=01:44:49.44 bitrate=  64.0kbits/s speed=3.14e+03x    \r\
+        size=   57088kB time=02:01:39.98 bitrate=  64.1kbits/s speed=3.1e+03x    \n\
+        video:0kB audio:56902kB subtitle:0kB other streams:0kB global headers:0kB muxing overhead: 0.325862%\n";
+
+    /// Tests the mux progress of a recorded mux, read in chunks that split its lines the way a
+    /// pipe does and parsed like `console::run_captured` parses it, with a status line every
+    /// half second: the negative time is skipped and the rest are reported at most once a second.
+    #[test]
+    fn test_recorded_mux_output() {
+        let buffer = SharedBuffer::default();
+        let stream = ProgressStream::new(Box::new(buffer.clone()), false);
+        let start = Instant::now();
+        stream.emit(&Event::Phase { phase: BuildPhase::Mux });
+        let mut lines = StderrLines::default();
+        let mut status_lines = 0;
+        for chunk in RECORDED_MUX.as_bytes().chunks(37) {
+            for line in lines.push(chunk) {
+                if line.starts_with("size=") {
+                    status_lines += 1;
+                }
+                if let Some(out_time_ms) = parse_status_time(&line) {
+                    stream.ffmpeg_time(out_time_ms, start + Duration::from_millis(500 * status_lines));
+                }
+            }
+        }
+        assert_eq!(lines.rest(), None);
+        assert_eq!(status_lines, 6);
+
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<Event> = text.lines().map(|line| parse_event_line(line).unwrap()).collect();
+        assert_eq!(events, vec![
+            Event::Phase { phase: BuildPhase::Mux },
+            Event::MuxProgress { out_time_ms: 1_572_370 },
+            Event::MuxProgress { out_time_ms: 4_717_080 },
+            Event::MuxProgress { out_time_ms: 7_299_980 },
+        ]);
+    }
+
+    /// Tests the stream of a whole build, fed the way the build feeds it, read back line by line
+    /// as a wrapper would: the phases in order, the mux progress only while muxing and at most
+    /// once a second, and the result last.
+    #[test]
+    fn test_build_stream() {
+        let buffer = SharedBuffer::default();
+        let stream = ProgressStream::new(Box::new(buffer.clone()), false);
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        stream.emit(&Event::Phase { phase: BuildPhase::Scan });
+        stream.emit(&Event::Phase { phase: BuildPhase::Encode });
+        // Encodes also print times, which are not the mux's.
+        stream.ffmpeg_time(1_000, at(0));
+        stream.emit(&Event::FileDone { index: 2, total: 2, file: "02 - Storm.mp3".to_string(), elapsed_ms: 900, outcome: FileOutcome::Encoded });
+        stream.emit(&Event::FileDone { index: 1, total: 2, file: "01 - Intro.mp3".to_string(), elapsed_ms: 0, outcome: FileOutcome::Cached });
+        stream.emit(&Event::Warning { message: "Cover is small".to_string() });
+        stream.emit(&Event::Phase { phase: BuildPhase::Mux });
+        for (now_ms, out_time_ms) in [(0, 10_000), (400, 20_000), (999, 30_000), (1_000, 40_000), (2_500, 50_000)] {
+            stream.ffmpeg_time(out_time_ms, at(now_ms));
+        }
+        stream.emit(&Event::Result { exit_code: 0, output: Some("books/dune/output.m4b".to_string()) });
+
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<Event> = text.lines().map(|line| parse_event_line(line).unwrap()).collect();
+        let mux_times: Vec<u64> = events.iter()
+            .filter_map(|event| match event { Event::MuxProgress { out_time_ms } => Some(*out_time_ms), _ => None })
+            .collect();
+        assert_eq!(mux_times, vec![10_000, 40_000, 50_000]);
+        assert_eq!(events.len(), 10);
+        assert_eq!(events[0], Event::Phase { phase: BuildPhase::Scan });
+        assert!(matches!(&events[2], Event::FileDone { index: 2, outcome: FileOutcome::Encoded, .. }));
+        assert_eq!(events.last(), Some(&Event::Result { exit_code: 0, output: Some("books/dune/output.m4b".to_string()) }));
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::Builder;
+
+use crate::cli::RetagOptions;
+use crate::inspect::{inspect_book, BookInfo};
+use crate::tags::{BookTags, METADATA_TAGS_MOVFLAG};
+
+/// Container-level tags that ffmpeg rewrites on every remux and that are therefore
+/// excluded when checking that untouched fields survived.
+const REMUX_MANAGED_TAGS: [&str; 4] = ["encoder", "major_brand", "minor_version", "compatible_brands"];
+
+/// Rewrites the tags and/or cover of an existing MP4 audiobook without re-encoding.
+///
+/// The audio is stream-copied into a temporary file next to the original, the result is
+/// inspected to confirm that the audio packets, chapters, and untouched tags are unchanged, and
+/// only then is the original atomically replaced.
+///
+/// # Arguments
+///
+/// * `options` - The file to retag together with the tags and cover to apply.
+///
+/// # Returns
+///
+/// `Ok(())` when the file was rewritten, or an error message; on error the original file is left untouched.
+pub fn retag(options: &RetagOptions) -> Result<(), String> {
+    let input_path = Path::new(&options.input_file);
+    if !input_path.is_file() {
+        return Err(format!("'{}' is not a file", options.input_file));
+    }
+    if options.tags.is_empty() && options.cover.is_none() {
+        return Err("Nothing to change: pass at least one of --title, --author, --year, --date, or --cover".to_string());
+    }
+    if let Some(cover) = &options.cover {
+        if !Path::new(cover).is_file() {
+            return Err(format!("Cover image '{}' does not exist", cover));
+        }
+    }
+
+    let before = inspect_book(&options.input_file)
+        .ok_or_else(|| format!("Could not inspect '{}'", options.input_file))?;
+    if !before.is_mp4() {
+        return Err(format!(
+            "Refusing to retag '{}': not an MP4 container (detected '{}')",
+            options.input_file, before.format_name
+        ));
+    }
+
+    // Write next to the original so the final rename stays on one filesystem and is atomic.
+    let parent_dir = input_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let extension = input_path.extension().map(|ext| ext.to_string_lossy().to_string()).unwrap_or_else(|| "m4b".to_string());
+    let tmpfile = Builder::new()
+        .prefix(".m4btool-retag-")
+        .suffix(&format!(".{}", extension))
+        .tempfile_in(parent_dir)
+        .map_err(|err| format!("Could not create temporary file in '{}': {}", parent_dir.display(), err))?;
+    let tmpfile_path = tmpfile.path().to_string_lossy().to_string();
+
+    let audio_before = audio_checksum(&options.input_file);
+    let output = Command::new("ffmpeg")
+        .args(retag_args(&options.input_file, options.cover.as_deref(), &options.tags, &tmpfile_path))
+        .output()
+        .map_err(|err| format!("Error executing ffmpeg command: {}", err))?;
+    if !output.status.success() {
+        return Err(format!("FFmpeg execution failed: {}", String::from_utf8_lossy(&output.stderr)));
+    }
+
+    let after = inspect_book(&tmpfile_path)
+        .ok_or_else(|| format!("Could not inspect rewritten file '{}'", tmpfile_path))?;
+    verify_retag(&before, &after, &options.tags, options.cover.is_some())?;
+    verify_audio(audio_before.as_deref(), audio_checksum(&tmpfile_path).as_deref())?;
+
+    if let Ok(metadata) = fs::metadata(input_path) {
+        // Keep the original file mode rather than the restrictive temp file default.
+        let _ = fs::set_permissions(tmpfile.path(), metadata.permissions());
+    }
+    tmpfile
+        .persist(input_path)
+        .map_err(|err| format!("Could not replace '{}': {}", options.input_file, err.error))?;
+    Ok(())
+}
+
+/// Builds the ffmpeg arguments for a stream-copy remux that keeps chapters and existing tags
+/// while applying the requested tags and, optionally, a replacement cover.
+fn retag_args(input: &str, cover: Option<&str>, tags: &BookTags, output: &str) -> Vec<String> {
+    let mut args: Vec<String> = vec!["-v".into(), "error".into(), "-i".into(), input.into()];
+    if let Some(cover_path) = cover {
+        args.extend(["-i".into(), cover_path.into()]);
+        args.extend(["-map".into(), "0:a".into(), "-map".into(), "1:v".into()]);
+    } else {
+        // Keep the existing attached picture, if any.
+        args.extend(["-map".into(), "0:a".into(), "-map".into(), "0:v?".into()]);
+    }
+    args.extend(["-map_metadata".into(), "0".into(), "-map_chapters".into(), "0".into(), "-c".into(), "copy".into()]);
+    if cover.is_some() {
+        args.extend(["-c:v".into(), "mjpeg".into(), "-disposition:v:0".into(), "attached_pic".into()]);
+    }
+    args.extend(tags.ffmpeg_args());
+    args.extend(["-movflags".into(), METADATA_TAGS_MOVFLAG.into()]);
+    args.extend(["-y".into(), output.into()]);
+    args
+}
+
+/// Checks that a retagged file has the requested tags, while chapters, the cover, and
+/// every other tag are identical to the original.
+fn verify_retag(before: &BookInfo, after: &BookInfo, requested: &BookTags, cover_replaced: bool) -> Result<(), String> {
+    let requested_pairs = requested.metadata_pairs();
+    for (key, value) in &requested_pairs {
+        if after.tags.get(*key) != Some(value) {
+            return Err(format!("Verification failed: tag '{}' was not written", key));
+        }
+    }
+    if requested.language.is_some() && after.language != requested.language {
+        return Err("Verification failed: the audio language was not written".to_string());
+    }
+    for (key, value) in &before.tags {
+        if REMUX_MANAGED_TAGS.contains(&key.as_str()) || requested_pairs.iter().any(|(k, _)| k == key) {
+            continue;
+        }
+        if after.tags.get(key) != Some(value) {
+            return Err(format!("Verification failed: untouched tag '{}' changed", key));
+        }
+    }
+
+    if before.chapters.len() != after.chapters.len() {
+        return Err(format!(
+            "Verification failed: chapter count changed from {} to {}",
+            before.chapters.len(),
+            after.chapters.len()
+        ));
+    }
+    for (index, (old, new)) in before.chapters.iter().zip(&after.chapters).enumerate() {
+        if old.title != new.title || old.start_ms.abs_diff(new.start_ms) > 1 || old.end_ms.abs_diff(new.end_ms) > 1 {
+            return Err(format!("Verification failed: chapter {} changed", index + 1));
+        }
+    }
+
+    if !cover_replaced && before.has_cover != after.has_cover {
+        return Err("Verification failed: cover image was not preserved".to_string());
+    }
+    if cover_replaced && !after.has_cover {
+        return Err("Verification failed: cover image was not attached".to_string());
+    }
+    Ok(())
+}
+
+/// Hashes the audio packets of a file as they are stored, without decoding them, using ffmpeg's
+/// `md5` muxer.
+///
+/// # Returns
+///
+/// The hex digest, or `None` if ffmpeg could not read the file.
+fn audio_checksum(path: &str) -> Option<String> {
+    let output = Command::new("ffmpeg")
+        .args(["-v", "error", "-i", path, "-map", "0:a", "-c", "copy", "-f", "md5", "-"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_md5(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Reads the digest from the `md5` muxer's "MD5=<hex>" output.
+fn parse_md5(output: &str) -> Option<String> {
+    output.lines().find_map(|line| line.trim().strip_prefix("MD5=")).map(str::to_string)
+}
+
+/// Checks that the stream copy kept the audio byte for byte. A checksum that could not be
+/// taken skips the check rather than failing the retag.
+fn verify_audio(before: Option<&str>, after: Option<&str>) -> Result<(), String> {
+    match (before, after) {
+        (Some(before), Some(after)) if before != after => Err("Verification failed: the audio changed".to_string()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspect::ChapterInfo;
+
+    fn sample_book() -> BookInfo {
+        let mut info = BookInfo { format_name: "mov,mp4,m4a".to_string(), has_cover: true, ..Default::default() };
+        info.tags.insert("title".to_string(), "Old Title".to_string());
+        info.tags.insert("artist".to_string(), "Jane Doe".to_string());
+        info.tags.insert("encoder".to_string(), "Lavf58".to_string());
+        info.chapters.push(ChapterInfo { start_ms: 0, end_ms: 1000, title: "One".to_string() });
+        info
+    }
+
+    /// Tests that a retag changing only the title passes verification while other changes are caught.
+    #[test]
+    fn test_verify_retag() {
+        let before = sample_book();
+        let requested = BookTags { title: Some("New Title".to_string()), ..Default::default() };
+
+        let mut after = sample_book();
+        after.tags.insert("title".to_string(), "New Title".to_string());
+        after.tags.insert("encoder".to_string(), "Lavf61".to_string());
+        assert!(verify_retag(&before, &after, &requested, false).is_ok());
+
+        let mut changed_author = after.clone();
+        changed_author.tags.insert("artist".to_string(), "Someone Else".to_string());
+        assert!(verify_retag(&before, &changed_author, &requested, false).is_err());
+
+        let mut lost_chapter = after.clone();
+        lost_chapter.chapters.clear();
+        assert!(verify_retag(&before, &lost_chapter, &requested, false).is_err());
+    }
+
+    /// Tests that a retag is only accepted when the audio packets hash the same while tags change.
+    #[test]
+    fn test_verify_audio_preserved() {
+        let before = sample_book();
+        let requested = BookTags { title: Some("New Title".to_string()), ..Default::default() };
+        let mut after = sample_book();
+        after.tags.insert("title".to_string(), "New Title".to_string());
+        assert!(verify_retag(&before, &after, &requested, false).is_ok());
+
+        let checksum = parse_md5("MD5=9e107d9d372bb6826bd81d3542a419d6\n");
+        assert_eq!(checksum.as_deref(), Some("9e107d9d372bb6826bd81d3542a419d6"));
+        assert!(verify_audio(checksum.as_deref(), Some("9e107d9d372bb6826bd81d3542a419d6")).is_ok());
+        assert!(verify_audio(checksum.as_deref(), Some("e4d909c290d0fb1ca068ffaddf22cbd0")).is_err());
+        assert!(verify_audio(checksum.as_deref(), None).is_ok());
+        assert_eq!(parse_md5(""), None);
+    }
+
+    /// Tests that the remux copies streams, chapters, and metadata from the original, keeping
+    /// custom tags.
+    #[test]
+    fn test_retag_args() {
+        let tags = BookTags { author: Some("Jane Doe".to_string()), ..Default::default() };
+        let args = retag_args("in.m4b", None, &tags, "out.m4b");
+        assert_eq!(
+            args,
+            vec![
+                "-v", "error", "-i", "in.m4b", "-map", "0:a", "-map", "0:v?", "-map_metadata", "0",
+                "-map_chapters", "0", "-c", "copy", "-metadata", "artist=Jane Doe", "-movflags", "+use_metadata_tags",
+                "-y", "out.m4b",
+            ]
+        );
+    }
+}
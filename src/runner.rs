@@ -0,0 +1,25 @@
+use std::io;
+use std::process::{Command, Output, Stdio};
+
+use crate::console;
+
+/// Runs external commands, so code that drives ffmpeg can be exercised with a fake in tests.
+pub trait CommandRunner {
+    /// Runs the command to completion and returns its exit status and captured stderr.
+    fn run(&self, command: &mut Command) -> io::Result<Output>;
+
+    /// Runs a command whose answer is its output, such as `ffmpeg -version`, and returns its
+    /// stdout and stderr without showing either.
+    fn query(&self, command: &mut Command) -> io::Result<Output> {
+        command.stdin(Stdio::null()).output()
+    }
+}
+
+/// Runs commands for real, with stderr routed through the console.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, command: &mut Command) -> io::Result<Output> {
+        console::run_captured(command)
+    }
+}
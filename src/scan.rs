@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::console;
+use crate::incremental::hash_file;
+
+/// Image extensions recognized for a `cover.*` file next to the audio files. HEIC and AVIF
+/// covers are converted to JPEG.
+pub const COVER_EXTENSIONS: [&str; 7] = ["jpg", "jpeg", "png", "webp", "heic", "heif", "avif"];
+
+/// Video containers whose audio is used with `--extract-audio`, e.g. recorded lectures.
+pub const VIDEO_EXTENSIONS: [&str; 3] = ["mp4", "mkv", "webm"];
+
+/// Directories that NAS systems fill with thumbnails and metadata: Synology `@eaDir` and QNAP `.@__thumb`.
+const JUNK_DIRECTORIES: [&str; 2] = ["@eaDir", ".@__thumb"];
+
+/// Prefix of the work directory a build creates next to its output when the temp directory is
+/// too small. One left behind by an interrupted build is never scanned, even with hidden files.
+pub const WORK_DIR_PREFIX: &str = ".m4btool-work-";
+
+/// Collects supported audio files, and optionally video files, from the input directory and sorts
+/// them by filename.
+///
+/// # Arguments
+///
+/// * `input_directory` - The directory to scan recursively.
+/// * `excluded_dirs` - Directories inside the input that are skipped, such as a work directory
+///   left there by an earlier run. They are compared by canonical path.
+/// * `include_hidden` - Also scan hidden files and folders (leading dot) and NAS junk folders,
+///   which are skipped by default because macOS `._` resource forks and NAS thumbnails can
+///   carry audio extensions.
+/// * `include_video` - Also collect video files (`VIDEO_EXTENSIONS`), whose audio is extracted.
+/// * `follow_symlinks` - Follow symlinked files and folders. A link back to a folder being
+///   scanned is reported and skipped instead of being followed forever. Without it, symlinks
+///   below the input directory are skipped; a symlinked input directory itself is always followed.
+///
+/// # Returns
+///
+/// The matching directory entries in filename order.
+pub fn collect_audio_files(input_directory: &str, excluded_dirs: &[&Path], include_hidden: bool, include_video: bool, follow_symlinks: bool) -> Vec<DirEntry> {
+    let excluded: Vec<PathBuf> = excluded_dirs.iter().filter_map(|dir| fs::canonicalize(dir).ok()).collect();
+    let is_excluded = |entry: &DirEntry| {
+        entry.depth() > 0
+            && entry.file_type().is_dir()
+            && !excluded.is_empty()
+            && fs::canonicalize(entry.path()).is_ok_and(|path| excluded.contains(&path))
+    };
+    let mut audio_file_entries: Vec<_> = WalkDir::new(input_directory)
+        .follow_links(follow_symlinks)
+        .into_iter()
+        .filter_entry(|entry| !is_excluded(entry) && !is_work_dir(entry) && (include_hidden || !is_hidden_or_junk(entry)))
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                if let (Some(path), Some(ancestor)) = (err.path(), err.loop_ancestor()) {
+                    console::warn(format!("Skipping '{}': it links back to '{}'", path.display(), ancestor.display()));
+                }
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_file() && (is_audio_file(entry.path()) || (include_video && is_video_file(entry.path()))))
+        .collect();
+    audio_file_entries.sort_by_key(|entry| entry.file_name().to_os_string());
+    audio_file_entries
+}
+
+/// Returns `true` for a hidden file or folder, or a NAS junk folder, below the input directory.
+fn is_hidden_or_junk(entry: &DirEntry) -> bool {
+    let name = entry.file_name().to_string_lossy();
+    entry.depth() > 0 && (name.starts_with('.') || (entry.file_type().is_dir() && JUNK_DIRECTORIES.contains(&name.as_ref())))
+}
+
+/// Returns `true` for a build's work directory below the input directory.
+fn is_work_dir(entry: &DirEntry) -> bool {
+    entry.depth() > 0 && entry.file_type().is_dir() && entry.file_name().to_string_lossy().starts_with(WORK_DIR_PREFIX)
+}
+
+/// Returns `true` when the path has one of the supported audio extensions (mp3, m4a, flac).
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension().map(|ext| {
+        let ext_lc = ext.to_string_lossy().to_lowercase();
+        ext_lc == "mp3" || ext_lc == "m4a" || ext_lc == "flac"
+    }).unwrap_or(false)
+}
+
+/// Returns `true` when the path has one of the video extensions in `VIDEO_EXTENSIONS`.
+pub fn is_video_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| VIDEO_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// Drops video files without an audio stream, such as a screen recording without sound. Audio
+/// files are kept without being probed.
+///
+/// # Arguments
+///
+/// * `entries` - The scanned files.
+/// * `has_audio` - Whether a file has an audio stream, e.g. by probing it with `get_audio_info`.
+///
+/// # Returns
+///
+/// The kept entries in their original order, and the dropped videos.
+pub fn drop_silent_videos(entries: Vec<DirEntry>, has_audio: impl Fn(&Path) -> bool) -> (Vec<DirEntry>, Vec<DirEntry>) {
+    entries.into_iter().partition(|entry| !is_video_file(entry.path()) || has_audio(entry.path()))
+}
+
+/// Identifies the underlying file regardless of the path used to reach it.
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = std::path::PathBuf;
+
+/// Returns the (device, inode) pair of a file, so hardlinks and symlinks to it compare equal.
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Returns the canonical path of a file, so symlinks to it compare equal.
+/// Windows file indices are not available on stable Rust, so hardlinks are not detected there.
+#[cfg(not(unix))]
+fn file_id(path: &Path) -> Option<FileId> {
+    std::fs::canonicalize(path).ok()
+}
+
+/// Removes entries that refer to the same underlying file as an earlier or preferred entry.
+///
+/// When several paths point at one file, a path that is not a symlink is preferred; otherwise
+/// the first path in scan order is kept. Entries whose identity cannot be read are always kept.
+///
+/// # Returns
+///
+/// The remaining entries in their original order, and each dropped entry paired with the kept one.
+pub fn dedupe_linked_files(entries: Vec<DirEntry>) -> (Vec<DirEntry>, Vec<(DirEntry, DirEntry)>) {
+    let ids: Vec<Option<FileId>> = entries.iter().map(|entry| file_id(entry.path())).collect();
+
+    // Pick the preferred entry for every file identity: the first real file, else the first symlink.
+    let mut winners: HashMap<&FileId, usize> = HashMap::new();
+    for (index, id) in ids.iter().enumerate() {
+        let Some(id) = id else { continue };
+        let replaces_symlink = winners.get(id)
+            .is_some_and(|&current| entries[current].path_is_symlink() && !entries[index].path_is_symlink());
+        if !winners.contains_key(id) || replaces_symlink {
+            winners.insert(id, index);
+        }
+    }
+    let winner_of: Vec<Option<usize>> = ids.iter().map(|id| id.as_ref().map(|id| winners[id])).collect();
+
+    let mut kept = Vec::new();
+    let mut duplicates = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        match winner_of[index] {
+            Some(winner) if winner != index => duplicates.push((entry.clone(), entries[winner].clone())),
+            _ => kept.push(entry.clone()),
+        }
+    }
+    (kept, duplicates)
+}
+
+/// Removes entries whose contents are identical to an earlier entry's, for `--dedupe-files`, e.g.
+/// the same chapter saved twice under different names.
+///
+/// Only files that share their size with another file are hashed, so a book without duplicates
+/// costs one metadata read per file, and files with the same hash are compared byte for byte.
+/// Files whose size or contents cannot be read are kept.
+///
+/// # Returns
+///
+/// The remaining entries in their original order, and each dropped entry paired with the kept one.
+pub fn dedupe_identical_files(entries: Vec<DirEntry>) -> (Vec<DirEntry>, Vec<(DirEntry, DirEntry)>) {
+    dedupe_by_hash(entries, hash_file)
+}
+
+/// `dedupe_identical_files` with the hash the files are grouped by.
+fn dedupe_by_hash(entries: Vec<DirEntry>, hash: impl Fn(&Path) -> io::Result<String>) -> (Vec<DirEntry>, Vec<(DirEntry, DirEntry)>) {
+    let sizes: Vec<Option<u64>> = entries.iter().map(|entry| fs::metadata(entry.path()).ok().map(|metadata| metadata.len())).collect();
+    let mut size_counts: HashMap<u64, usize> = HashMap::new();
+    for size in sizes.iter().flatten() {
+        *size_counts.entry(*size).or_insert(0) += 1;
+    }
+
+    // The kept files of each size and hash; more than one only when different contents collide.
+    let mut kept_with: HashMap<(u64, String), Vec<usize>> = HashMap::new();
+    let mut kept = Vec::new();
+    let mut duplicates = Vec::new();
+    for (index, (entry, size)) in entries.iter().zip(&sizes).enumerate() {
+        let key = size
+            .filter(|size| size_counts[size] > 1)
+            .and_then(|size| hash(entry.path()).ok().map(|hash| (size, hash)));
+        let Some(key) = key else {
+            kept.push(entry.clone());
+            continue;
+        };
+        let candidates = kept_with.entry(key).or_default();
+        match candidates.iter().find(|&&first| same_contents(entries[first].path(), entry.path()).unwrap_or(false)) {
+            Some(&first) => duplicates.push((entry.clone(), entries[first].clone())),
+            None => {
+                candidates.push(index);
+                kept.push(entry.clone());
+            }
+        }
+    }
+    (kept, duplicates)
+}
+
+/// Compares two files of the same size byte for byte.
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (BufReader::new(File::open(a)?), BufReader::new(File::open(b)?));
+    loop {
+        let chunk = a.fill_buf()?;
+        if chunk.is_empty() {
+            return Ok(b.fill_buf()?.is_empty());
+        }
+        let length = chunk.len();
+        let mut other = vec![0; length];
+        if b.read_exact(&mut other).is_err() || other != chunk {
+            return Ok(false);
+        }
+        a.consume(length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use tempfile::tempdir;
+
+    /// Tests the collection of audio files from a directory, ensuring only supported files are picked up.
+    #[test]
+    fn test_collect_audio_files() {
+        let dir = tempdir().unwrap();
+        let file_names = ["test.mp3", "audio.m4a", "sound.flac", "ignore.txt"];
+        for name in &file_names {
+            let file_path = dir.path().join(name);
+            File::create(&file_path).unwrap();
+        }
+        let audio_files = collect_audio_files(dir.path().to_str().unwrap(), &[], false, false, false);
+        assert_eq!(audio_files.len(), 3);
+    }
+
+    /// Tests that video files are only collected with `--extract-audio`, and that videos without an
+    /// audio stream are dropped without probing the audio files.
+    #[test]
+    fn test_collect_video_files() {
+        let dir = tempdir().unwrap();
+        for name in ["01 - Lecture.mp4", "02 - Lecture.MKV", "03 - Slides.webm", "04 - Notes.mp3", "poster.jpg"] {
+            File::create(dir.path().join(name)).unwrap();
+        }
+        let names = |files: Vec<DirEntry>| files.iter().map(|e| e.file_name().to_string_lossy().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(collect_audio_files(dir.path().to_str().unwrap(), &[], false, false, false)), vec!["04 - Notes.mp3"]);
+
+        let entries = collect_audio_files(dir.path().to_str().unwrap(), &[], false, true, false);
+        assert_eq!(entries.len(), 4);
+        let probed = std::cell::RefCell::new(Vec::new());
+        let (kept, dropped) = drop_silent_videos(entries, |path| {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            probed.borrow_mut().push(name.clone());
+            !name.contains("Slides")
+        });
+        assert_eq!(names(kept), vec!["01 - Lecture.mp4", "02 - Lecture.MKV", "04 - Notes.mp3"]);
+        assert_eq!(names(dropped), vec!["03 - Slides.webm"]);
+        assert_eq!(probed.borrow().len(), 3);
+    }
+
+    /// Tests that resource forks, hidden folders, and NAS thumbnail folders are skipped unless requested.
+    #[test]
+    fn test_collect_audio_files_skips_hidden_and_junk() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("@eaDir/01 - Intro.mp3")).unwrap();
+        fs::create_dir_all(dir.path().join(".hidden")).unwrap();
+        for name in ["01 - Intro.mp3", "._01 - Intro.mp3", "@eaDir/01 - Intro.mp3/SYNOAUDIO_THUMB.mp3", ".hidden/02.mp3"] {
+            fs::write(dir.path().join(name), b"audio").unwrap();
+        }
+
+        let names = |files: Vec<DirEntry>| files.iter().map(|e| e.file_name().to_string_lossy().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(collect_audio_files(dir.path().to_str().unwrap(), &[], false, false, false)), vec!["01 - Intro.mp3"]);
+        assert_eq!(collect_audio_files(dir.path().to_str().unwrap(), &[], true, false, false).len(), 4);
+    }
+
+    /// Tests that a work directory inside the input, reached through another path, is not scanned,
+    /// and that neither is one left next to the output by an interrupted build.
+    #[test]
+    fn test_collect_audio_files_skips_work_directory() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("01 - Intro.mp3"), b"intro").unwrap();
+        fs::create_dir_all(dir.path().join("work/m4btool-passlogs")).unwrap();
+        fs::write(dir.path().join("work/.tmpA1b2C3.m4a"), b"leftover encode").unwrap();
+
+        let work_dir = dir.path().join("work/m4btool-passlogs/..");
+        let audio_files = collect_audio_files(dir.path().to_str().unwrap(), &[&work_dir], false, false, false);
+        let names: Vec<_> = audio_files.iter().map(|e| e.file_name().to_string_lossy().to_string()).collect();
+        assert_eq!(names, vec!["01 - Intro.mp3"]);
+
+        fs::create_dir_all(dir.path().join(".m4btool-work-x1Y2z3")).unwrap();
+        fs::write(dir.path().join(".m4btool-work-x1Y2z3/.tmpD4e5F6.m4a"), b"leftover encode").unwrap();
+        let audio_files = collect_audio_files(dir.path().to_str().unwrap(), &[], true, false, false);
+        let names: Vec<_> = audio_files.iter().map(|e| e.file_name().to_string_lossy().to_string()).collect();
+        assert_eq!(names, vec![".tmpA1b2C3.m4a", "01 - Intro.mp3"]);
+    }
+
+    /// Tests that symlinked files and folders are only used with `follow_symlinks`, that a link
+    /// loop is skipped, and that a symlinked input directory is scanned either way.
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_audio_files_follows_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let library = tempdir().unwrap();
+        let shared = library.path().join("shared");
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(shared.join("03 - Epilogue.mp3"), b"epilogue").unwrap();
+        let book = library.path().join("book");
+        fs::create_dir_all(&book).unwrap();
+        fs::write(book.join("01 - Intro.mp3"), b"intro").unwrap();
+        symlink(shared.join("03 - Epilogue.mp3"), book.join("02 - Linked.mp3")).unwrap();
+        symlink(&shared, book.join("extras")).unwrap();
+        symlink(&book, book.join("loop")).unwrap();
+
+        let names = |follow: bool| -> Vec<String> {
+            let entries = collect_audio_files(book.to_str().unwrap(), &[], false, false, follow);
+            entries.iter().map(|e| e.path().strip_prefix(&book).unwrap().to_string_lossy().to_string()).collect()
+        };
+        assert_eq!(names(false), vec!["01 - Intro.mp3"]);
+        assert_eq!(names(true), vec!["01 - Intro.mp3", "02 - Linked.mp3", "extras/03 - Epilogue.mp3"]);
+
+        let linked_book = library.path().join("linked-book");
+        symlink(&book, &linked_book).unwrap();
+        assert_eq!(collect_audio_files(linked_book.to_str().unwrap(), &[], false, false, false).len(), 1);
+    }
+
+    /// Tests that a hardlinked pair collapses into a single input file.
+    #[test]
+    fn test_dedupe_hardlinked_files() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("01 - Intro.mp3"), b"intro").unwrap();
+        fs::write(dir.path().join("02 - Storm.mp3"), b"storm").unwrap();
+        fs::hard_link(dir.path().join("01 - Intro.mp3"), dir.path().join("latest.mp3")).unwrap();
+
+        let (kept, duplicates) = dedupe_linked_files(collect_audio_files(dir.path().to_str().unwrap(), &[], false, false, false));
+        let kept_names: Vec<_> = kept.iter().map(|e| e.file_name().to_string_lossy().to_string()).collect();
+        if cfg!(unix) {
+            assert_eq!(kept_names, vec!["01 - Intro.mp3", "02 - Storm.mp3"]);
+            assert_eq!(duplicates.len(), 1);
+            assert_eq!(duplicates[0].0.file_name(), "latest.mp3");
+        }
+    }
+
+    /// Tests that a copy of a file under another name is dropped in favor of the first one, while
+    /// a file of the same size with other contents is kept.
+    #[test]
+    fn test_dedupe_identical_files() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("01 - Intro.mp3"), b"intro").unwrap();
+        fs::write(dir.path().join("02 - Storm.mp3"), b"storm").unwrap();
+        fs::write(dir.path().join("03 - Storm (copy).mp3"), b"storm").unwrap();
+        fs::write(dir.path().join("04 - Calm.mp3"), b"a calm sea").unwrap();
+
+        let (kept, duplicates) = dedupe_identical_files(collect_audio_files(dir.path().to_str().unwrap(), &[], false, false, false));
+        let kept_names: Vec<_> = kept.iter().map(|e| e.file_name().to_string_lossy().to_string()).collect();
+        assert_eq!(kept_names, vec!["01 - Intro.mp3", "02 - Storm.mp3", "04 - Calm.mp3"]);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!((duplicates[0].0.file_name(), duplicates[0].1.file_name()), ("03 - Storm (copy).mp3".as_ref(), "02 - Storm.mp3".as_ref()));
+    }
+
+    /// Tests that files whose hashes collide are only dropped when their bytes match too.
+    #[test]
+    fn test_dedupe_hash_collision() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("01 - Storm.mp3"), b"storm").unwrap();
+        fs::write(dir.path().join("02 - Calm.mp3"), b"calm!").unwrap();
+        fs::write(dir.path().join("03 - Calm (copy).mp3"), b"calm!").unwrap();
+
+        let colliding = |_: &Path| Ok("0".to_string());
+        let (kept, duplicates) = dedupe_by_hash(collect_audio_files(dir.path().to_str().unwrap(), &[], false, false, false), colliding);
+        let kept_names: Vec<_> = kept.iter().map(|e| e.file_name().to_string_lossy().to_string()).collect();
+        assert_eq!(kept_names, vec!["01 - Storm.mp3", "02 - Calm.mp3"]);
+        assert_eq!((duplicates[0].0.file_name(), duplicates[0].1.file_name()), ("03 - Calm (copy).mp3".as_ref(), "02 - Calm.mp3".as_ref()));
+    }
+}
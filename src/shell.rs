@@ -0,0 +1,145 @@
+use std::ffi::{OsStr, OsString};
+use std::process::Command;
+
+/// Quotes one argument for a POSIX shell.
+///
+/// Arguments made only of characters that no shell treats specially are returned as-is;
+/// everything else is wrapped in single quotes, with embedded single quotes written as `'\''`.
+pub fn quote(arg: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "_-+=%@:,./".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Renders a command as a copy-pasteable shell command line.
+///
+/// The program and arguments are taken from the `Command` itself, so the output is exactly
+/// what would be executed.
+pub fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg: &OsStr| quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Converts string arguments to the `OsString`s that `Command` takes.
+pub fn os_args(items: &[&str]) -> Vec<OsString> {
+    items.iter().map(OsString::from).collect()
+}
+
+/// Splits a string into arguments the way a POSIX shell would, without any expansion.
+///
+/// Whitespace separates arguments, single quotes keep their contents literally, double quotes
+/// allow `\"` and `\\` escapes, and a backslash outside quotes escapes the next character.
+///
+/// # Returns
+///
+/// The arguments, or an error message for an unterminated quote or trailing backslash.
+pub fn split(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    // Distinguishes an empty quoted argument ('') from no argument at all.
+    let mut in_arg = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            '\'' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err(format!("Unterminated single quote in '{}'", line)),
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => current.push(c),
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => return Err(format!("Unterminated double quote in '{}'", line)),
+                        },
+                        Some(c) => current.push(c),
+                        None => return Err(format!("Unterminated double quote in '{}'", line)),
+                    }
+                }
+            }
+            '\\' => {
+                in_arg = true;
+                match chars.next() {
+                    Some(c) => current.push(c),
+                    None => return Err(format!("Trailing backslash in '{}'", line)),
+                }
+            }
+            c => {
+                in_arg = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests quoting of spaces, quotes, and shell metacharacters.
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("-c:a"), "-c:a");
+        assert_eq!(quote("/tmp/book/output.m4b"), "/tmp/book/output.m4b");
+        assert_eq!(quote("title=The Long Way"), "'title=The Long Way'");
+        assert_eq!(quote("artist=O'Brien"), r"'artist=O'\''Brien'");
+        assert_eq!(quote("say \"hi\" $HOME"), "'say \"hi\" $HOME'");
+        assert_eq!(quote(""), "''");
+    }
+
+    /// Tests splitting with quoted arguments containing spaces and escapes.
+    #[test]
+    fn test_split() {
+        assert_eq!(split("-af 'volume=2, highpass=f=80'").unwrap(), vec!["-af", "volume=2, highpass=f=80"]);
+        assert_eq!(split(r#"-metadata "comment=Read by \"Jane\" Doe""#).unwrap(), vec!["-metadata", r#"comment=Read by "Jane" Doe"#]);
+        assert_eq!(split(r"-metadata genre=Science\ Fiction").unwrap(), vec!["-metadata", "genre=Science Fiction"]);
+        assert_eq!(split("  -vn   ''  ").unwrap(), vec!["-vn", ""]);
+        assert_eq!(split("").unwrap(), Vec::<String>::new());
+        assert!(split("-af 'volume=2").is_err());
+        assert!(split(r#"-af "volume=2"#).is_err());
+    }
+
+    /// Tests that split undoes quote for awkward arguments.
+    #[test]
+    fn test_split_round_trips_quote() {
+        let args = ["title=It's \"Here\"", "", "a b", "$HOME"];
+        let line = args.iter().map(|arg| quote(arg)).collect::<Vec<_>>().join(" ");
+        assert_eq!(split(&line).unwrap(), args);
+    }
+
+    /// Tests that the rendered command line uses the command's own argument vector.
+    #[test]
+    fn test_command_line() {
+        let mut command = Command::new("ffmpeg");
+        command.args(["-i", "my book/list.txt", "-metadata", "title=It's Here"]);
+        assert_eq!(command_line(&command), r"ffmpeg -i 'my book/list.txt' -metadata 'title=It'\''s Here'");
+    }
+}
@@ -0,0 +1,222 @@
+use std::path::Path;
+
+/// Identifies the filesystem a path lives on.
+pub type FilesystemId = u64;
+
+/// Returns the filesystem of a path and the bytes available on it, or `None` when unknown.
+#[cfg(unix)]
+pub fn filesystem_space(path: &Path) -> Option<(FilesystemId, u64)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let device = std::fs::metadata(path).ok()?.dev();
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stats` is a writable statvfs.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some((device as FilesystemId, stats.f_bavail as u64 * stats.f_frsize as u64))
+}
+
+/// Free space cannot be queried without platform APIs here, so the pre-check is skipped.
+#[cfg(not(unix))]
+pub fn filesystem_space(_path: &Path) -> Option<(FilesystemId, u64)> {
+    None
+}
+
+/// Returns `true` when a path lives on a filesystem held in memory, such as a tmpfs `/tmp`.
+#[cfg(target_os = "linux")]
+pub fn is_in_memory(path: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else { return false };
+    let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stats` is a writable statfs.
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return false;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    let is_tmpfs = stats.f_type as i64 == libc::TMPFS_MAGIC as i64;
+    is_tmpfs
+}
+
+/// Only Linux reports tmpfs through `statfs` in a portable way, so elsewhere nothing counts as in memory.
+#[cfg(not(target_os = "linux"))]
+pub fn is_in_memory(_path: &Path) -> bool {
+    false
+}
+
+/// What the work directory placement needs to know about a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilesystemSpace {
+    pub id: FilesystemId,
+    pub free_bytes: u64,
+    /// Whether the filesystem is held in memory, such as a tmpfs.
+    pub in_memory: bool,
+}
+
+/// Looks up the filesystem of a directory, so tests can stand in for the real disks.
+pub trait SpaceProbe {
+    /// Returns the filesystem of a directory, or `None` when it cannot be queried.
+    fn space(&self, path: &Path) -> Option<FilesystemSpace>;
+}
+
+/// Queries the real filesystems.
+pub struct SystemSpace;
+
+impl SpaceProbe for SystemSpace {
+    fn space(&self, path: &Path) -> Option<FilesystemSpace> {
+        let (id, free_bytes) = filesystem_space(path)?;
+        Some(FilesystemSpace { id, free_bytes, in_memory: is_in_memory(path) })
+    }
+}
+
+/// Where the build writes its encoded files and other large work files.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WorkDirPlacement {
+    /// In the temp directory, as usual.
+    Temp,
+    /// Next to the output, because the temp directory is too small; `reason` says why.
+    NextToOutput { reason: String },
+}
+
+/// Decides whether the work files fit in the temp directory, or should go next to the output.
+///
+/// The work files are moved when they do not fit in the temp directory but the output's
+/// filesystem has room for them and the book together. Only half of an in-memory temp
+/// directory's free space is counted, since filling a tmpfs takes memory from everything else.
+/// When either filesystem cannot be queried, the temp directory is kept.
+///
+/// # Arguments
+///
+/// * `temp_dir` - The system temp directory.
+/// * `output_dir` - The directory the book is written to.
+/// * `needed_bytes` - About how much the work files take, e.g. the summed source sizes.
+/// * `probe` - Looks up the filesystems, e.g. `SystemSpace`.
+pub fn place_work_dir(temp_dir: &Path, output_dir: &Path, needed_bytes: u64, probe: &impl SpaceProbe) -> WorkDirPlacement {
+    let (Some(temp), Some(output)) = (probe.space(temp_dir), probe.space(output_dir)) else {
+        return WorkDirPlacement::Temp;
+    };
+    let usable = if temp.in_memory { temp.free_bytes / 2 } else { temp.free_bytes };
+    if needed_bytes <= usable || temp.id == output.id || output.free_bytes < needed_bytes.saturating_mul(2) {
+        return WorkDirPlacement::Temp;
+    }
+    let kind = if temp.in_memory { "in-memory " } else { "" };
+    WorkDirPlacement::NextToOutput {
+        reason: format!(
+            "the {}temp directory '{}' has {} MB free but the work files need about {} MB",
+            kind,
+            temp_dir.display(),
+            temp.free_bytes / 1_000_000,
+            needed_bytes / 1_000_000
+        ),
+    }
+}
+
+/// Checks that every filesystem has room for the files the build will write to it.
+///
+/// Needs on the same filesystem are added up and compared once against its free space, so a
+/// work directory next to the output is counted together with it rather than twice against
+/// the same free space. Paths whose filesystem cannot be queried are not checked.
+///
+/// # Arguments
+///
+/// * `needs` - Each directory the build writes to, with the bytes it will write there.
+/// * `space` - Looks up a directory's filesystem and free bytes, e.g. `filesystem_space`.
+///
+/// # Returns
+///
+/// A message for every filesystem that is too small; empty when everything fits.
+pub fn check_space(needs: &[(&Path, u64)], space: impl Fn(&Path) -> Option<(FilesystemId, u64)>) -> Vec<String> {
+    // (filesystem, free bytes, needed bytes, directories) in first-seen order.
+    let mut filesystems: Vec<(FilesystemId, u64, u64, Vec<&Path>)> = Vec::new();
+    for &(path, bytes) in needs {
+        let Some((id, available)) = space(path) else { continue };
+        match filesystems.iter_mut().find(|(seen, ..)| *seen == id) {
+            Some((_, _, needed, paths)) => {
+                *needed += bytes;
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+            None => filesystems.push((id, available, bytes, vec![path])),
+        }
+    }
+    filesystems.into_iter()
+        .filter(|(_, available, needed, _)| needed > available)
+        .map(|(_, available, needed, paths)| {
+            let names: Vec<String> = paths.iter().map(|path| format!("'{}'", path.display())).collect();
+            format!(
+                "{} may need up to {} MB but only {} MB are free",
+                names.join(" and "),
+                needed / 1_000_000,
+                available / 1_000_000
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a work directory on the output's filesystem is checked together with it, once.
+    #[test]
+    fn test_check_space_shared_filesystem() {
+        let space = |path: &Path| match path.to_str() {
+            Some("/books/dune") | Some("/books/dune/.work") => Some((1, 250_000_000)),
+            Some("/tmp") => Some((2, 50_000_000)),
+            _ => None,
+        };
+        let needs = [(Path::new("/books/dune/.work"), 100_000_000), (Path::new("/books/dune"), 100_000_000)];
+        assert!(check_space(&needs, space).is_empty());
+
+        let needs = [(Path::new("/books/dune/.work"), 150_000_000), (Path::new("/books/dune"), 150_000_000)];
+        assert_eq!(
+            check_space(&needs, space),
+            vec!["'/books/dune/.work' and '/books/dune' may need up to 300 MB but only 250 MB are free"]
+        );
+
+        let needs = [(Path::new("/tmp"), 100_000_000), (Path::new("/books/dune"), 100_000_000), (Path::new("/unknown"), 1)];
+        assert_eq!(check_space(&needs, space), vec!["'/tmp' may need up to 100 MB but only 50 MB are free"]);
+    }
+
+    /// Stands in for a machine with a 2 GB tmpfs `/tmp`, a large disk for books, and a small one.
+    struct FakeSpace;
+
+    impl SpaceProbe for FakeSpace {
+        fn space(&self, path: &Path) -> Option<FilesystemSpace> {
+            match path.to_str()? {
+                "/tmp" => Some(FilesystemSpace { id: 1, free_bytes: 2_000_000_000, in_memory: true }),
+                "/var/tmp" => Some(FilesystemSpace { id: 2, free_bytes: 2_000_000_000, in_memory: false }),
+                "/books" => Some(FilesystemSpace { id: 3, free_bytes: 100_000_000_000, in_memory: false }),
+                "/small" => Some(FilesystemSpace { id: 4, free_bytes: 3_000_000_000, in_memory: false }),
+                _ => None,
+            }
+        }
+    }
+
+    /// Tests when the work files move next to the output, and that a tmpfs only counts half.
+    #[test]
+    fn test_place_work_dir() {
+        let place = |temp: &str, output: &str, needed: u64| place_work_dir(Path::new(temp), Path::new(output), needed, &FakeSpace);
+        assert_eq!(place("/tmp", "/books", 900_000_000), WorkDirPlacement::Temp);
+        assert_eq!(
+            place("/tmp", "/books", 1_500_000_000),
+            WorkDirPlacement::NextToOutput {
+                reason: "the in-memory temp directory '/tmp' has 2000 MB free but the work files need about 1500 MB".to_string(),
+            }
+        );
+        assert_eq!(place("/var/tmp", "/books", 1_500_000_000), WorkDirPlacement::Temp);
+        assert!(matches!(place("/var/tmp", "/books", 2_500_000_000), WorkDirPlacement::NextToOutput { .. }));
+        // The output's disk must hold the work files and the book.
+        assert_eq!(place("/tmp", "/small", 1_600_000_000), WorkDirPlacement::Temp);
+        // Moving within one filesystem or to an unknown one does not help.
+        assert_eq!(place("/tmp", "/tmp", 1_500_000_000), WorkDirPlacement::Temp);
+        assert_eq!(place("/tmp", "/elsewhere", 1_500_000_000), WorkDirPlacement::Temp);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs;
+use std::process::Command;
+
+use regex::Regex;
+
+use crate::console;
+use crate::probe::get_duration_ms;
+
+/// Quality metrics of a finished audiobook, shown with `--stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookStats {
+    /// Size of the output file in bytes.
+    pub size_bytes: u64,
+    /// Duration of the output file in milliseconds.
+    pub duration_ms: u64,
+    /// Summed size of the source files in bytes.
+    pub source_bytes: u64,
+    /// Integrated loudness in LUFS, if the loudness analysis succeeded.
+    pub integrated_lufs: Option<f64>,
+    /// True peak level in dBTP, if the loudness analysis succeeded.
+    pub true_peak_dbtp: Option<f64>,
+}
+
+impl BookStats {
+    /// The average bitrate actually achieved, in bits per second.
+    pub fn bit_rate(&self) -> Option<u64> {
+        (self.duration_ms > 0).then(|| self.size_bytes * 8 * 1000 / self.duration_ms)
+    }
+
+    /// The output size per hour of audio, in bytes.
+    pub fn bytes_per_hour(&self) -> Option<u64> {
+        (self.duration_ms > 0).then(|| self.size_bytes * 3_600_000 / self.duration_ms)
+    }
+
+    /// How many times smaller the output is than the summed sources.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.size_bytes > 0 && self.source_bytes > 0).then(|| self.source_bytes as f64 / self.size_bytes as f64)
+    }
+
+    /// Renders the metrics as the indented recap printed after a build.
+    pub fn recap(&self) -> String {
+        let unknown = || "unknown".to_string();
+        let rows = [
+            ("Bitrate", self.bit_rate().map(|rate| format!("{} kb/s", rate / 1000)).unwrap_or_else(unknown)),
+            ("Integrated loudness", self.integrated_lufs.map(|lufs| format!("{:.1} LUFS", lufs)).unwrap_or_else(unknown)),
+            ("True peak", self.true_peak_dbtp.map(|peak| format!("{:.1} dBTP", peak)).unwrap_or_else(unknown)),
+            ("Size per hour", self.bytes_per_hour().map(|bytes| format!("{:.1} MB", bytes as f64 / 1_000_000.0)).unwrap_or_else(unknown)),
+            ("Compression ratio", self.compression_ratio().map(|ratio| format!("{:.2}x", ratio)).unwrap_or_else(unknown)),
+        ];
+        let mut recap = String::from("Stats:\n");
+        for (label, value) in rows {
+            recap.push_str(&format!("  {:<20} {}\n", format!("{}:", label), value));
+        }
+        recap
+    }
+}
+
+/// Measures the finished audiobook. The loudness analysis decodes the whole file once.
+///
+/// # Arguments
+///
+/// * `output_path` - The finished audiobook.
+/// * `source_bytes` - The summed size of the source files, for the compression ratio.
+///
+/// # Returns
+///
+/// The measured `BookStats`, or an error message if the output could not be read at all.
+/// A failed loudness analysis only leaves the loudness fields empty.
+pub fn measure_book(output_path: &str, source_bytes: u64) -> Result<BookStats, String> {
+    let size_bytes = fs::metadata(output_path)
+        .map_err(|err| format!("Could not read '{}': {}", output_path, err))?
+        .len();
+    let duration_ms = get_duration_ms(output_path).unwrap_or(0);
+
+    let output = console::run_captured(Command::new("ffmpeg").args([
+        "-hide_banner", "-nostats",
+        "-i", output_path,
+        "-vn",
+        "-af", "loudnorm=print_format=json",
+        "-f", "null", "-",
+    ]))
+    .map_err(|err| format!("Could not execute ffmpeg: {}", err))?;
+    let (integrated_lufs, true_peak_dbtp) = if output.status.success() {
+        parse_loudnorm(&String::from_utf8_lossy(&output.stderr))
+    } else {
+        console::warn(format!("Loudness analysis failed: {}", console::last_stderr_line(&output)));
+        (None, None)
+    };
+
+    Ok(BookStats { size_bytes, duration_ms, source_bytes, integrated_lufs, true_peak_dbtp })
+}
+
+/// Extracts the integrated loudness and true peak from the `loudnorm` filter's JSON report.
+///
+/// Only the `"key" : "value"` pairs are matched, so the log prefix, whitespace, and extra
+/// fields that differ between ffmpeg versions do not matter. When the report appears more
+/// than once, the last one wins.
+fn parse_loudnorm(stderr: &str) -> (Option<f64>, Option<f64>) {
+    (loudnorm_field(stderr, "input_i"), loudnorm_field(stderr, "input_tp"))
+}
+
+/// Reads one value of the `loudnorm` filter's JSON report, e.g. `input_i`, from the last report
+/// in `stderr`.
+pub fn loudnorm_field(stderr: &str, key: &str) -> Option<f64> {
+    let pattern = Regex::new(&format!(r#""{}"\s*:\s*"?\s*([-+]?(?:inf|[0-9]+(?:\.[0-9]+)?))"#, key)).unwrap();
+    pattern.captures_iter(stderr).last().and_then(|captures| captures[1].parse::<f64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests parsing of the loudnorm report, including the log prefix and silent input.
+    #[test]
+    fn test_parse_loudnorm() {
+        let report = r#"[Parsed_loudnorm_0 @ 0x55d5c8a0c1c0]
+{
+	"input_i" : "-19.42",
+	"input_tp" : "-1.07",
+	"input_lra" : "6.30",
+	"input_thresh" : "-29.64",
+	"output_i" : "-24.08",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.08"
+}
+"#;
+        assert_eq!(parse_loudnorm(report), (Some(-19.42), Some(-1.07)));
+
+        let silent = "[Parsed_loudnorm_0 @ 0x1] \n{\n\"input_i\" : \"-inf\",\n\"input_tp\" : \"-inf\"\n}";
+        assert_eq!(parse_loudnorm(silent), (Some(f64::NEG_INFINITY), Some(f64::NEG_INFINITY)));
+        assert_eq!(parse_loudnorm("Output #0, null"), (None, None));
+    }
+
+    /// Tests the derived metrics and the recap text.
+    #[test]
+    fn test_recap() {
+        let stats = BookStats {
+            size_bytes: 28_800_000,
+            duration_ms: 3_600_000,
+            source_bytes: 115_200_000,
+            integrated_lufs: Some(-19.42),
+            true_peak_dbtp: None,
+        };
+        assert_eq!(stats.bit_rate(), Some(64_000));
+        assert_eq!(stats.bytes_per_hour(), Some(28_800_000));
+        assert_eq!(
+            stats.recap(),
+            "Stats:\n\
+             \x20 Bitrate:             64 kb/s\n\
+             \x20 Integrated loudness: -19.4 LUFS\n\
+             \x20 True peak:           unknown\n\
+             \x20 Size per hour:       28.8 MB\n\
+             \x20 Compression ratio:   4.00x\n"
+        );
+    }
+}
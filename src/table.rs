@@ -0,0 +1,526 @@
+use std::collections::HashMap;
+use std::env;
+
+use m4btool::plan::{Warning, WarningKind};
+use m4btool::{Removal, RemovedToken};
+
+use crate::encode::layout_name;
+use crate::probe::{streams_compatible, AudioInfo, Compatibility};
+
+/// Output format of the dry-run preview table.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TableFormat {
+    /// Aligned columns sized to the terminal, with footnotes for warnings.
+    #[default]
+    Plain,
+    /// Tab-separated values with a header row.
+    Tsv,
+    /// A JSON array with one object per file.
+    Json,
+}
+
+/// Parses a `--table-format` value.
+pub fn parse_table_format(value: &str) -> Result<TableFormat, String> {
+    match value {
+        "plain" => Ok(TableFormat::Plain),
+        "tsv" => Ok(TableFormat::Tsv),
+        "json" => Ok(TableFormat::Json),
+        _ => Err(format!("Invalid table format '{}': expected plain, tsv, or json", value)),
+    }
+}
+
+/// One input file as shown in the dry-run preview.
+#[derive(Debug, Clone, Default)]
+pub struct PreviewRow {
+    pub file_name: String,
+    pub title: String,
+    /// Probe results, or `None` if the file could not be probed.
+    pub info: Option<AudioInfo>,
+    /// Warnings about this file, such as sample-rate or channel outliers.
+    pub warnings: Vec<Warning>,
+    /// Whether the file's stream could be copied next to the first file's, set by `compare_streams`.
+    pub stream_copy: Option<Compatibility>,
+}
+
+/// Sample rates below this are flagged as telephone quality.
+const LOW_SAMPLE_RATE_HZ: u32 = 16000;
+/// Lossy sources above this sample rate were almost certainly upsampled before encoding.
+const UPSAMPLED_RATE_HZ: u32 = 48000;
+const LOSSY_CODECS: [&str; 4] = ["mp3", "aac", "vorbis", "opus"];
+/// Files shorter than the book's median duration divided by this are flagged, e.g. a stray clip.
+const SHORT_DURATION_RATIO: u64 = 20;
+/// Files longer than the book's median duration times this are flagged, e.g. a whole other book.
+const LONG_DURATION_RATIO: u64 = 5;
+/// Durations are only compared in books with at least this many probed files.
+const MIN_FILES_FOR_DURATION: usize = 3;
+
+/// Attaches warnings to files whose sample rate, channel count, or duration stands out from the
+/// rest of the book.
+///
+/// The book's expected values are the most common ones among the probed files, so a lone 96 kHz
+/// file or a stereo file in an otherwise mono book is flagged together with the flag that fixes it
+/// or what the build will do about it. Durations are compared with the median, so only files far
+/// shorter or longer than a typical chapter are flagged.
+pub fn flag_outliers(rows: &mut [PreviewRow]) {
+    let common_rate = most_common(rows.iter().filter_map(|row| row.info.as_ref()?.sample_rate));
+    let common_channels = most_common(rows.iter().filter_map(|row| row.info.as_ref()?.channels));
+    let mut durations: Vec<u64> = rows.iter().filter_map(|row| row.info.as_ref()?.duration_ms).collect();
+    durations.sort_unstable();
+    let median_duration = (durations.len() >= MIN_FILES_FOR_DURATION).then(|| durations[durations.len() / 2]);
+
+    for row in rows.iter_mut() {
+        let Some(info) = &row.info else {
+            row.warnings.push(Warning::new(WarningKind::ProbeFallback, "could not be probed"));
+            continue;
+        };
+        if let (Some(rate), Some(common)) = (info.sample_rate, common_rate) {
+            if rate < LOW_SAMPLE_RATE_HZ {
+                row.warnings.push(Warning::new(WarningKind::SampleRate, format!("{} Hz is telephone quality", rate)));
+            }
+            if rate != common {
+                row.warnings.push(Warning::new(WarningKind::SampleRate, format!(
+                    "{} Hz differs from the book's {} Hz; use --sample-rate {} to resample",
+                    rate, common, common
+                )));
+            }
+            if rate > UPSAMPLED_RATE_HZ && LOSSY_CODECS.contains(&info.codec.as_str()) {
+                row.warnings.push(Warning::new(WarningKind::SampleRate, format!("{} at {} Hz was likely upsampled", info.codec, rate)));
+            }
+        }
+        if let (Some(channels), Some(common)) = (info.channels, common_channels) {
+            let message = if channels > common && common == 1 {
+                Some(format!("{} channels in a mono book; downmixed unless --channels is given", channels))
+            } else if channels < common {
+                Some(format!(
+                    "a {} file downmixes the whole book; use --channels {} to keep it {}",
+                    layout_name(channels), common, layout_name(common)
+                ))
+            } else if channels != common {
+                Some(format!("{} channels differs from the book's {}", channels, common))
+            } else {
+                None
+            };
+            row.warnings.extend(message.map(|message| Warning::new(WarningKind::Channels, message)));
+        }
+        if let (Some(duration_ms), Some(median_ms)) = (info.duration_ms, median_duration) {
+            let comparison = if duration_ms < median_ms / SHORT_DURATION_RATIO {
+                Some("much shorter")
+            } else if duration_ms > median_ms * LONG_DURATION_RATIO {
+                Some("much longer")
+            } else {
+                None
+            };
+            row.warnings.extend(comparison.map(|comparison| Warning::new(WarningKind::DurationOutlier, format!(
+                "{} is {} than the typical {}",
+                format_duration(duration_ms), comparison, format_duration(median_ms)
+            ))));
+        }
+    }
+}
+
+/// Decides for each file whether its stream could be stream-copied into one concatenation with
+/// the first probed file, or would have to be encoded to that file's parameters. Files that
+/// could not be probed get no decision.
+pub fn compare_streams(rows: &mut [PreviewRow]) {
+    let Some(reference) = rows.iter().find_map(|row| row.info.clone()) else { return };
+    for row in rows.iter_mut() {
+        row.stream_copy = row.info.as_ref().map(|info| streams_compatible(&reference, info));
+    }
+}
+
+/// Returns the most frequent value, preferring the larger value on ties.
+fn most_common(values: impl Iterator<Item = u32>) -> Option<u32> {
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for value in values {
+        *counts.entry(value).or_insert(0) += 1;
+    }
+    counts.into_iter().max_by_key(|&(value, count)| (count, value)).map(|(value, _)| value)
+}
+
+/// Returns the terminal width from `COLUMNS`, or 100 when it is unset or invalid.
+pub fn terminal_width() -> usize {
+    env::var("COLUMNS").ok().and_then(|cols| cols.parse().ok()).filter(|&cols| cols > 0).unwrap_or(100)
+}
+
+/// Renders the preview rows in the requested format.
+///
+/// # Arguments
+///
+/// * `rows` - The files to show, in chapter order.
+/// * `format` - The output format.
+/// * `width` - The terminal width used to size the plain table's text columns.
+pub fn render_preview(rows: &[PreviewRow], format: TableFormat, width: usize) -> String {
+    match format {
+        TableFormat::Plain => render_plain(rows, width),
+        TableFormat::Tsv => render_tsv(rows),
+        TableFormat::Json => render_json(rows),
+    }
+}
+
+/// Column headers shared by the plain and TSV formats.
+const HEADERS: [&str; 9] = ["#", "File", "Title", "Codec", "Bitrate", "Rate", "Ch", "Duration", "Notes"];
+/// Minimum width of the file and title columns on narrow terminals.
+const MIN_TEXT_COLUMN: usize = 10;
+
+/// Formats the probe-derived cells of a row: codec, bitrate, sample rate, channels, and duration.
+fn probe_cells(info: Option<&AudioInfo>) -> [String; 5] {
+    let dash = || "-".to_string();
+    let Some(info) = info else { return [dash(), dash(), dash(), dash(), dash()] };
+    [
+        info.codec.clone(),
+        info.bit_rate.map(|b| format!("{}k", b / 1000)).unwrap_or_else(dash),
+        info.sample_rate.map(|r| r.to_string()).unwrap_or_else(dash),
+        info.channels.map(|c| c.to_string()).unwrap_or_else(dash),
+        info.duration_ms.map(format_duration).unwrap_or_else(dash),
+    ]
+}
+
+/// Formats milliseconds as `H:MM:SS`.
+pub fn format_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Shortens `text` to at most `width` characters, marking the cut with an ellipsis.
+pub fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut shortened: String = text.chars().take(width.saturating_sub(1)).collect();
+    shortened.push('…');
+    shortened
+}
+
+fn render_plain(rows: &[PreviewRow], width: usize) -> String {
+    // Number each warning so the table only needs short footnote references.
+    let mut footnotes = Vec::new();
+    let mut cells: Vec<Vec<String>> = Vec::new();
+    for (index, row) in rows.iter().enumerate() {
+        let refs: Vec<String> = row.warnings.iter()
+            .map(|warning| {
+                footnotes.push(format!("{}: {}", row.file_name, warning.message));
+                format!("[{}]", footnotes.len())
+            })
+            .collect();
+        let mut line = vec![(index + 1).to_string(), row.file_name.clone(), row.title.clone()];
+        line.extend(probe_cells(row.info.as_ref()));
+        line.push(refs.join(""));
+        cells.push(line);
+    }
+
+    let mut widths: Vec<usize> = HEADERS.iter().map(|h| h.chars().count()).collect();
+    for line in &cells {
+        for (column, cell) in line.iter().enumerate() {
+            widths[column] = widths[column].max(cell.chars().count());
+        }
+    }
+    // File and title share whatever the fixed columns leave, but never shrink below a usable minimum.
+    let fixed: usize = widths.iter().enumerate().filter(|(c, _)| *c != 1 && *c != 2).map(|(_, w)| w + 2).sum();
+    let available = width.saturating_sub(fixed + 4) / 2;
+    for column in [1, 2] {
+        widths[column] = widths[column].min(available.max(MIN_TEXT_COLUMN));
+    }
+
+    let mut output = String::new();
+    let header: Vec<String> = HEADERS.iter().map(|h| h.to_string()).collect();
+    for line in std::iter::once(&header).chain(cells.iter()) {
+        let padded: Vec<String> = line.iter().enumerate()
+            .map(|(column, cell)| {
+                let cell = truncate(cell, widths[column]);
+                let pad = widths[column] - cell.chars().count();
+                if column == 0 { format!("{}{}", " ".repeat(pad), cell) } else { format!("{}{}", cell, " ".repeat(pad)) }
+            })
+            .collect();
+        output.push_str(padded.join("  ").trim_end());
+        output.push('\n');
+    }
+    if !footnotes.is_empty() {
+        output.push('\n');
+        for (index, note) in footnotes.iter().enumerate() {
+            output.push_str(&format!("[{}] {}\n", index + 1, note));
+        }
+    }
+    output
+}
+
+fn render_tsv(rows: &[PreviewRow]) -> String {
+    let mut output = HEADERS.join("\t");
+    output.push('\n');
+    for (index, row) in rows.iter().enumerate() {
+        let mut line = vec![(index + 1).to_string(), row.file_name.clone(), row.title.clone()];
+        line.extend(probe_cells(row.info.as_ref()));
+        line.push(row.warnings.iter().map(|warning| warning.message.as_str()).collect::<Vec<_>>().join("; "));
+        // Tabs and newlines inside a cell would break the row structure.
+        let line: Vec<String> = line.iter().map(|cell| cell.replace(['\t', '\n'], " ")).collect();
+        output.push_str(&line.join("\t"));
+        output.push('\n');
+    }
+    output
+}
+
+fn render_json(rows: &[PreviewRow]) -> String {
+    let number = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_else(|| "null".to_string());
+    let objects: Vec<String> = rows.iter().enumerate()
+        .map(|(index, row)| {
+            let info = row.info.as_ref();
+            let warnings: Vec<String> = row.warnings.iter().map(|w| json_string(&w.message)).collect();
+            format!(
+                "  {{\"index\": {}, \"file\": {}, \"title\": {}, \"codec\": {}, \"bit_rate\": {}, \"sample_rate\": {}, \"channels\": {}, \"duration_ms\": {}, \"stream_copy\": {}, \"stream_copy_reason\": {}, \"warnings\": [{}]}}",
+                index + 1,
+                json_string(&row.file_name),
+                json_string(&row.title),
+                info.map(|i| json_string(&i.codec)).unwrap_or_else(|| "null".to_string()),
+                number(info.and_then(|i| i.bit_rate)),
+                number(info.and_then(|i| i.sample_rate).map(u64::from)),
+                number(info.and_then(|i| i.channels).map(u64::from)),
+                number(info.and_then(|i| i.duration_ms)),
+                row.stream_copy.as_ref().map(|c| json_string(c.decision())).unwrap_or_else(|| "null".to_string()),
+                row.stream_copy.as_ref().and_then(Compatibility::reason).map(json_string).unwrap_or_else(|| "null".to_string()),
+                warnings.join(", ")
+            )
+        })
+        .collect();
+    if objects.is_empty() {
+        return "[]\n".to_string();
+    }
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+/// Renders the warnings of a finished build grouped by source file, in book order, as the recap
+/// printed after the build. Files without warnings are left out.
+///
+/// # Returns
+///
+/// The recap, or `None` when no file has warnings.
+pub fn render_warning_recap(files: &[(String, Vec<Warning>)]) -> Option<String> {
+    let flagged: Vec<&(String, Vec<Warning>)> = files.iter().filter(|(_, warnings)| !warnings.is_empty()).collect();
+    if flagged.is_empty() {
+        return None;
+    }
+    let count: usize = flagged.iter().map(|(_, warnings)| warnings.len()).sum();
+    let mut recap = format!("{} {} in {} of {} files:\n", count, if count == 1 { "warning" } else { "warnings" }, flagged.len(), files.len());
+    for (file_name, warnings) in flagged {
+        recap.push_str(&format!("  {}\n", file_name));
+        for warning in warnings {
+            recap.push_str(&format!("    - {}\n", warning.message));
+        }
+    }
+    Some(recap)
+}
+
+/// One file of the `--compare` table.
+#[derive(Debug, Clone, Default)]
+pub struct CompareRow {
+    /// The file name without its extension, as the cleaner sees it.
+    pub stem: String,
+    pub title: String,
+    /// The parts the cleaner removed from the stem.
+    pub removed: Vec<RemovedToken>,
+    /// Why the stem was not cleaned at all, e.g. for front matter.
+    pub note: Option<&'static str>,
+}
+
+/// Renders each file's name next to its cleaned title, with a line under it listing what was
+/// removed and why, e.g. `removed: "Dune" (in 12 of 12 titles), "01" (number)`.
+pub fn render_compare(rows: &[CompareRow], width: usize) -> String {
+    let number_width = rows.len().to_string().len();
+    let longest = rows.iter().map(|row| row.stem.chars().count()).max().unwrap_or(0);
+    let column = longest.max("File".len()).min((width.saturating_sub(number_width + 4) / 2).max(MIN_TEXT_COLUMN));
+    let indent = " ".repeat(number_width + 2);
+    let mut output = format!("{:>number_width$}  {:<column$}  Title\n", "#", "File");
+    for (index, row) in rows.iter().enumerate() {
+        output.push_str(&format!("{:>number_width$}  {:<column$}  {}\n", index + 1, truncate(&row.stem, column), row.title));
+        if let Some(note) = row.note {
+            output.push_str(&format!("{}{}\n", indent, note));
+        } else if !row.removed.is_empty() {
+            let removed: Vec<String> = row.removed.iter().map(|token| format!("\"{}\" ({})", token.text, removal_reason(token))).collect();
+            output.push_str(&format!("{}removed: {}\n", indent, removed.join(", ")));
+        }
+    }
+    output
+}
+
+/// Explains why a part of a title was removed, for `render_compare`.
+fn removal_reason(token: &RemovedToken) -> String {
+    let reason = match token.reason {
+        Removal::Frequent { count, total } => format!("in {} of {} titles", count, total),
+        Removal::Stripped => "--strip".to_string(),
+        Removal::Stopword => "stopword".to_string(),
+        Removal::Common => "shared by every title".to_string(),
+        Removal::Number => "number".to_string(),
+        Removal::Extension => "file extension".to_string(),
+        _ => "cleaned".to_string(),
+    };
+    match token.bracket {
+        Some(_) => format!("bracketed, {}", reason),
+        None => reason,
+    }
+}
+
+/// Encodes a string as a JSON string literal.
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use m4btool::{trace_clean_titles, BracketKind, CleanOptions};
+
+    fn row(name: &str, sample_rate: u32, channels: u32) -> PreviewRow {
+        PreviewRow {
+            file_name: name.to_string(),
+            title: name.trim_end_matches(".mp3").to_string(),
+            info: Some(AudioInfo {
+                codec: "mp3".to_string(),
+                bit_rate: Some(64000),
+                sample_rate: Some(sample_rate),
+                channels: Some(channels),
+                duration_ms: Some(3_723_000),
+                ..AudioInfo::default()
+            }),
+            warnings: Vec::new(),
+            stream_copy: None,
+        }
+    }
+
+    /// Tests that a lone 96 kHz file, an 8 kHz file, and a stereo file in a mono book are flagged.
+    #[test]
+    fn test_flag_outliers() {
+        let mut rows = vec![
+            row("01.mp3", 44100, 1),
+            row("02.mp3", 44100, 1),
+            row("03.mp3", 96000, 1),
+            row("04.mp3", 8000, 1),
+            row("05.mp3", 44100, 2),
+            row("06.mp3", 44100, 1),
+        ];
+        flag_outliers(&mut rows);
+        let has = |row: &PreviewRow, kind: WarningKind, text: &str| {
+            row.warnings.iter().any(|w| w.kind == kind && w.message.contains(text))
+        };
+        assert!(rows[0].warnings.is_empty());
+        assert!(has(&rows[2], WarningKind::SampleRate, "--sample-rate 44100"));
+        assert!(has(&rows[2], WarningKind::SampleRate, "upsampled"));
+        assert!(has(&rows[3], WarningKind::SampleRate, "telephone"));
+        assert!(has(&rows[4], WarningKind::Channels, "downmixed"));
+    }
+
+    /// Tests that a stray clip and an overlong file are flagged against the median duration.
+    #[test]
+    fn test_flag_duration_outliers() {
+        let mut rows: Vec<PreviewRow> = ["01.mp3", "02.mp3", "03.mp3", "04.mp3", "05.mp3"].iter()
+            .map(|name| row(name, 44100, 1))
+            .collect();
+        rows[1].info.as_mut().unwrap().duration_ms = Some(5_000);
+        rows[3].info.as_mut().unwrap().duration_ms = Some(20_000_000);
+        rows[4].info = None;
+        flag_outliers(&mut rows);
+        assert!(rows[0].warnings.is_empty());
+        assert_eq!(rows[1].warnings, vec![Warning::new(WarningKind::DurationOutlier, "0:00:05 is much shorter than the typical 1:02:03")]);
+        assert_eq!(rows[3].warnings[0].kind, WarningKind::DurationOutlier);
+        assert_eq!(rows[4].warnings, vec![Warning::new(WarningKind::ProbeFallback, "could not be probed")]);
+    }
+
+    /// Tests that each file is compared with the first probed one, and the decision in the JSON.
+    #[test]
+    fn test_compare_streams() {
+        let mut rows = vec![row("01.mp3", 44100, 1), row("02.mp3", 44100, 1), row("03.mp3", 22050, 1)];
+        rows[0].info = None;
+        compare_streams(&mut rows);
+        assert_eq!(rows[0].stream_copy, None);
+        assert_eq!(rows[1].stream_copy, Some(Compatibility::Identical));
+        assert_eq!(rows[2].stream_copy.as_ref().map(Compatibility::decision), Some("reencode"));
+        let json = render_preview(&rows, TableFormat::Json, 80);
+        assert!(json.contains("\"stream_copy\": \"copy\", \"stream_copy_reason\": null"));
+        assert!(json.contains("\"stream_copy\": \"reencode\", \"stream_copy_reason\": \"sample rate 22050 Hz differs from the reference's 44100 Hz\""));
+    }
+
+    /// Tests the per-file recap printed after a build.
+    #[test]
+    fn test_render_warning_recap() {
+        let note = |message: &str| Warning::new(WarningKind::Note, message);
+        assert_eq!(render_warning_recap(&[("01.mp3".to_string(), Vec::new())]), None);
+        let files = vec![
+            ("01.mp3".to_string(), Vec::new()),
+            ("02.mp3".to_string(), vec![note("could not be probed"), note("kept unencoded")]),
+            ("03.mp3".to_string(), vec![note("8000 Hz is telephone quality")]),
+        ];
+        assert_eq!(
+            render_warning_recap(&files).unwrap(),
+            "3 warnings in 2 of 3 files:\n\
+             \x20 02.mp3\n\
+             \x20   - could not be probed\n\
+             \x20   - kept unencoded\n\
+             \x20 03.mp3\n\
+             \x20   - 8000 Hz is telephone quality\n"
+        );
+    }
+
+    /// Tests the `--compare` table of traced titles, with a front matter file that is not cleaned
+    /// and a long name truncated on a narrow terminal.
+    #[test]
+    fn test_render_compare() {
+        let titles: Vec<String> = ["Dune (2024) - Chapter 01 [Arrakis]", "Dune (2024) - Chapter 02 Desert"].iter().map(|title| title.to_string()).collect();
+        let mut options = CleanOptions::default();
+        options.protected_brackets = vec![BracketKind::Square];
+        let mut rows: Vec<CompareRow> = trace_clean_titles(&titles, &options)
+            .into_iter()
+            .map(|trace| CompareRow { stem: trace.raw, title: trace.cleaned, removed: trace.removed, note: None })
+            .collect();
+        rows.insert(0, CompareRow { stem: "00 Opening Credits".to_string(), title: "00 Opening Credits".to_string(), removed: Vec::new(), note: Some("front matter, not cleaned") });
+        assert_eq!(
+            render_compare(&rows, 100),
+            "#  File                                Title\n\
+             1  00 Opening Credits                  00 Opening Credits\n\
+             \x20  front matter, not cleaned\n\
+             2  Dune (2024) - Chapter 01 [Arrakis]  [Arrakis]\n\
+             \x20  removed: \"Dune\" (in 2 of 2 titles), \"[2024]\" (bracketed, in 2 of 2 titles), \"Chapter\" (in 2 of 2 titles), \"01\" (number)\n\
+             3  Dune (2024) - Chapter 02 Desert     Desert\n\
+             \x20  removed: \"Dune\" (in 2 of 2 titles), \"[2024]\" (bracketed, in 2 of 2 titles), \"Chapter\" (in 2 of 2 titles), \"02\" (number)\n"
+        );
+        let narrow = render_compare(&rows[1..2], 30);
+        assert_eq!(narrow.lines().nth(1), Some("1  Dune (2024)…  [Arrakis]"));
+    }
+
+    /// Tests that the plain table fits narrow terminals by truncating the text columns.
+    #[test]
+    fn test_render_plain_narrow() {
+        let mut long = row("01 - A Very Long File Name That Would Overflow.mp3", 44100, 1);
+        long.warnings.push(Warning::new(WarningKind::Note, "example warning"));
+        let output = render_preview(&[long], TableFormat::Plain, 60);
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].starts_with("#  File"));
+        assert!(lines[1].contains("01 - A Ve…  "));
+        assert!(lines[1].contains("1:02:03"));
+        assert!(lines[1].ends_with("[1]"));
+        assert_eq!(lines[3], "[1] 01 - A Very Long File Name That Would Overflow.mp3: example warning");
+    }
+
+    /// Tests the TSV and JSON formats used for scripting.
+    #[test]
+    fn test_render_tsv_and_json() {
+        let rows = vec![row("01.mp3", 44100, 1)];
+        let tsv = render_preview(&rows, TableFormat::Tsv, 80);
+        assert_eq!(tsv.lines().nth(1), Some("1\t01.mp3\t01\tmp3\t64k\t44100\t1\t1:02:03\t"));
+        let json = render_preview(&rows, TableFormat::Json, 80);
+        assert!(json.contains("\"file\": \"01.mp3\", \"title\": \"01\", \"codec\": \"mp3\", \"bit_rate\": 64000"));
+        assert!(json.contains("\"stream_copy\": null, \"stream_copy_reason\": null"));
+        assert_eq!(json_string("a\"b\\"), "\"a\\\"b\\\\\"");
+    }
+}
@@ -0,0 +1,129 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+
+/// How tag text read back from ffprobe is re-decoded.
+///
+/// Old Windows rippers wrote ID3 tags in the system code page while declaring them Latin-1, and
+/// some wrote UTF-8 into Latin-1 frames. ffmpeg decodes such tags as declared, which turns
+/// "第一章" into "µÚÒ»ÕÂ" and "Châpter" into "ChÃ¢pter". Both keep the original bytes one per
+/// character, so they can be recovered and decoded again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagEncoding {
+    /// Guess the encoding of each tag from its bytes.
+    Auto,
+    /// Decode every tag with this encoding.
+    Fixed(&'static Encoding),
+}
+
+/// Parses a `--tag-encoding` value: `auto` or a WHATWG encoding label such as `windows-1252`,
+/// `gbk`, `shift_jis`, or `utf-8`.
+pub fn parse_tag_encoding(value: &str) -> Result<TagEncoding, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("auto") {
+        return Ok(TagEncoding::Auto);
+    }
+    Encoding::for_label(value.as_bytes())
+        .map(TagEncoding::Fixed)
+        .ok_or_else(|| format!("Invalid tag encoding '{}': expected auto or an encoding such as windows-1252, gbk, or shift_jis", value))
+}
+
+/// Re-decodes tag text that ffmpeg decoded with the wrong encoding.
+///
+/// Text that is plain ASCII, that cannot be mapped back to single bytes, or whose bytes are not
+/// valid in the chosen encoding is returned unchanged, so correct tags survive any setting.
+pub fn redecode(text: &str, encoding: TagEncoding) -> String {
+    let Some(bytes) = original_bytes(text) else { return text.to_string() };
+    if bytes.is_ascii() {
+        return text.to_string();
+    }
+    let encoding = match encoding {
+        TagEncoding::Fixed(encoding) => encoding,
+        // UTF-8 read as Latin-1 is by far the most common case and is never valid by accident.
+        TagEncoding::Auto if std::str::from_utf8(&bytes).is_ok() => UTF_8,
+        TagEncoding::Auto => {
+            let mut detector = EncodingDetector::new();
+            detector.feed(&bytes, true);
+            detector.guess(None, true)
+        }
+    };
+    match encoding.decode_without_bom_handling_and_without_replacement(&bytes) {
+        Some(decoded) => decoded.into_owned(),
+        None => text.to_string(),
+    }
+}
+
+/// Recovers the bytes behind text that was decoded one byte per character: as Latin-1 (the
+/// declared encoding of ID3 text frames), or as Windows-1252.
+fn original_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.chars().all(|c| (c as u32) <= 0xFF) {
+        return Some(text.chars().map(|c| c as u8).collect());
+    }
+    let (bytes, _, unmappable) = WINDOWS_1252.encode(text);
+    (!unmappable).then(|| bytes.into_owned())
+}
+
+/// Returns `true` for text that looks like it was decoded with the wrong encoding: a UTF-8 lead
+/// byte read as "Ã" or "Â" followed by a continuation byte, a C1 control character, or a run of
+/// three or more accented Latin-1 letters and symbols, as a legacy Chinese or Japanese name
+/// becomes.
+pub fn looks_like_mojibake(text: &str) -> bool {
+    let chars: Vec<char> = text.chars().collect();
+    let is_high_latin1 = |c: char| ('\u{80}'..='\u{FF}').contains(&c) && c != '\u{D7}' && c != '\u{F7}';
+    let is_continuation = |c: char| ('\u{80}'..='\u{BF}').contains(&c) || "€‚ƒ„…†‡ˆ‰Š‹ŒŽ‘’“”•–—˜™š›œžŸ".contains(c);
+    chars.windows(2).any(|pair| matches!(pair[0], 'Ã' | 'Â') && is_continuation(pair[1]))
+        || chars.iter().any(|c| ('\u{80}'..='\u{9F}').contains(c))
+        || chars.windows(3).any(|run| run.iter().all(|c| is_high_latin1(*c)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that Windows-1252 and GBK tags, mis-decoded as Latin-1, round-trip to the original
+    /// text with a fixed encoding and with `auto`.
+    #[test]
+    fn test_redecode_round_trips() {
+        let gbk = Encoding::for_label(b"gbk").unwrap();
+        let as_latin1 = |bytes: &[u8]| bytes.iter().map(|b| *b as char).collect::<String>();
+
+        let (chinese, _, _) = gbk.encode("第一章 风暴");
+        let garbled = as_latin1(&chinese);
+        assert_eq!(garbled, "µÚÒ»ÕÂ ·ç±©");
+        assert_eq!(redecode(&garbled, TagEncoding::Fixed(gbk)), "第一章 风暴");
+        assert_eq!(redecode(&garbled, TagEncoding::Auto), "第一章 风暴");
+
+        let (western, _, _) = WINDOWS_1252.encode("Café – Chapter “One”");
+        let garbled = as_latin1(&western);
+        assert_eq!(redecode(&garbled, TagEncoding::Fixed(WINDOWS_1252)), "Café – Chapter “One”");
+
+        assert_eq!(redecode("ChÃ¢pter 1", TagEncoding::Auto), "Châpter 1");
+        assert_eq!(redecode("ChÃ¢pter 1", parse_tag_encoding("utf-8").unwrap()), "Châpter 1");
+    }
+
+    /// Tests that correct tags are left alone.
+    #[test]
+    fn test_redecode_keeps_correct_text() {
+        assert_eq!(redecode("Chapter 1", TagEncoding::Auto), "Chapter 1");
+        assert_eq!(redecode("第一章", TagEncoding::Auto), "第一章");
+        assert_eq!(redecode("Café", TagEncoding::Auto), "Café");
+        assert_eq!(redecode("Café", TagEncoding::Fixed(UTF_8)), "Café");
+    }
+
+    /// Tests the encoding names accepted by `--tag-encoding`.
+    #[test]
+    fn test_parse_tag_encoding() {
+        assert_eq!(parse_tag_encoding("AUTO"), Ok(TagEncoding::Auto));
+        assert_eq!(parse_tag_encoding("cp1252"), Ok(TagEncoding::Fixed(WINDOWS_1252)));
+        assert!(parse_tag_encoding("klingon").is_err());
+    }
+
+    /// Tests the mojibake patterns that trigger the file name warning.
+    #[test]
+    fn test_looks_like_mojibake() {
+        assert!(looks_like_mojibake("ChÃ¢pter 01"));
+        assert!(looks_like_mojibake("µÚÒ»ÕÂ"));
+        assert!(!looks_like_mojibake("Chapter 01 - Café"));
+        assert!(!looks_like_mojibake("第一章"));
+        assert!(!looks_like_mojibake("Müller – Über"));
+    }
+}
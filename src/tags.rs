@@ -0,0 +1,125 @@
+pub use m4btool::book_tags::{BookTags, ALBUM_ARTIST_KEY, ALBUM_KEY, AUTHOR_KEY, GENRE_KEY, METADATA_TAGS_MOVFLAG, NARRATOR_KEY, PROVENANCE_KEY, TITLE_KEY, TRACK_KEY, YEAR_KEY};
+
+/// Validates a `--year` value, which must be a four-digit year.
+///
+/// # Returns
+///
+/// The trimmed year, or an error message describing why it was rejected.
+pub fn parse_year(value: &str) -> Result<String, String> {
+    let year = value.trim();
+    if year.len() == 4 && year.chars().all(|c| c.is_ascii_digit()) {
+        Ok(year.to_string())
+    } else {
+        Err(format!("Invalid year '{}': expected four digits (e.g. 2024)", value))
+    }
+}
+
+/// Validates a `--date` value and normalizes it to the `©day` format.
+///
+/// Accepts a bare year (`2024`) or a full date with `-`, `/`, or `.` separators and optional
+/// zero padding (`2024-3-5`, `2024/03/05`), and rejects dates that do not exist.
+///
+/// # Returns
+///
+/// The date as `YYYY` or `YYYY-MM-DD`, or an error message describing why it was rejected.
+pub fn parse_date(value: &str) -> Result<String, String> {
+    let invalid = || format!("Invalid date '{}': expected YYYY or YYYY-MM-DD", value);
+    let parts: Vec<&str> = value.trim().split(['-', '/', '.']).collect();
+    let all_digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    match parts.as_slice() {
+        [year] => parse_year(year).map_err(|_| invalid()),
+        [year, month, day] if all_digits(year) && year.len() == 4 && all_digits(month) && all_digits(day) => {
+            let year: u32 = year.parse().map_err(|_| invalid())?;
+            let month: u32 = month.parse().map_err(|_| invalid())?;
+            let day: u32 = day.parse().map_err(|_| invalid())?;
+            if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+                return Err(invalid());
+            }
+            Ok(format!("{:04}-{:02}-{:02}", year, month, day))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Returns the number of days in a month of the Gregorian calendar.
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use m4btool::book_tags::{CHAPTER_COUNT_KEY, TOTAL_DURATION_KEY};
+
+    use super::*;
+
+    /// Snapshot of the tags written for a finished book, in order.
+    #[test]
+    fn test_book_summary_tags() {
+        let tags = BookTags {
+            title: Some("Dune".to_string()),
+            author: Some("Frank Herbert".to_string()),
+            year: Some("1965".to_string()),
+            narrator: Some("Scott Brick".to_string()),
+            track: Some("1/1".to_string()),
+            total_duration_ms: Some(75_600_250),
+            chapter_count: Some(48),
+            language: Some("eng".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            tags.ffmpeg_args(),
+            vec![
+                "-metadata", "title=Dune",
+                "-metadata", "artist=Frank Herbert",
+                "-metadata", "date=1965",
+                "-metadata", "composer=Scott Brick",
+                "-metadata", "track=1/1",
+                "-metadata", "TOTALDURATION=75600250",
+                "-metadata", "CHAPTERCOUNT=48",
+                "-metadata", "language=eng",
+                "-metadata:s:a:0", "language=eng",
+            ]
+        );
+
+        // As ffprobe reports them back from a book muxed with use_metadata_tags, and from one
+        // that lost its custom tags.
+        let probed = "format.tags.title=\"Dune\"\nformat.tags.artist=\"Frank Herbert\"\nformat.tags.date=\"1965\"\n\
+            format.tags.composer=\"Scott Brick\"\nformat.tags.track=\"1/1\"\nformat.tags.TOTALDURATION=\"75600250\"\n\
+            format.tags.CHAPTERCOUNT=\"48\"\n";
+        let written = crate::inspect::parse_flat_output(probed).tags;
+        assert_eq!(written.get("totalduration").map(String::as_str), Some("75600250"));
+        assert!(tags.missing_from(&written).is_empty());
+        let without_custom: HashMap<String, String> = written.into_iter().filter(|(key, _)| !key.ends_with("count") && key != "totalduration").collect();
+        assert_eq!(tags.missing_from(&without_custom), [TOTAL_DURATION_KEY, CHAPTER_COUNT_KEY]);
+    }
+
+    /// Tests date validation and normalization.
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("2024"), Ok("2024".to_string()));
+        assert_eq!(parse_date("2024-03-05"), Ok("2024-03-05".to_string()));
+        assert_eq!(parse_date("2024/3/5"), Ok("2024-03-05".to_string()));
+        assert_eq!(parse_date("2024-02-29"), Ok("2024-02-29".to_string()));
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("2024-04-31").is_err());
+        assert!(parse_date("24-03-05").is_err());
+        assert!(parse_date("2024-03").is_err());
+        assert!(parse_date("March 2024").is_err());
+    }
+
+    /// Tests year validation.
+    #[test]
+    fn test_parse_year() {
+        assert_eq!(parse_year(" 2024 "), Ok("2024".to_string()));
+        assert!(parse_year("24").is_err());
+        assert!(parse_year("20x4").is_err());
+    }
+}
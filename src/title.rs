@@ -0,0 +1,1193 @@
+//! Chapter title cleaning.
+//!
+//! Audiobook files are usually named like `Book Name - Chapter 01 [Intro].mp3`, where most of
+//! each name repeats across the whole set. The cleaner splits every title into tokens, counts
+//! how often each token occurs across all titles, and removes leading tokens that occur in at
+//! least a threshold fraction of them. Bracketed tokens (`[..]`, `(..)`, `【..】`, `（..）`) are
+//! protected from frequency removal by default, and digits are dropped unless the numbering
+//! policy keeps them.
+//!
+//! For sets too small for frequencies to be meaningful, the `CommonPrefix` strategy instead
+//! strips the words that every title starts and ends with.
+
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use crate::title_case::{apply_title_case, TitleCase};
+
+/// How numbers in the original titles are treated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Numbering {
+    /// Drop all digits from titles, so "03 - Storm" becomes "Storm".
+    #[default]
+    Strip,
+    /// Keep the leading number of the original title in front of the cleaned title,
+    /// so "03 - Storm" becomes "03 Storm".
+    KeepLeading,
+}
+
+/// How `clean_titles` decides which parts of the titles are redundant.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CleanStrategy {
+    /// Remove leading words that occur in most titles (see the module documentation).
+    #[default]
+    Frequency,
+    /// Remove the words every title starts and ends with, so `MyBook_Part01_of_12` and
+    /// `MyBook_Part02_of_12` become `Part01` and `Part02`. Numbers inside words are kept as they
+    /// are, and numbers on their own follow `numbering`. Kept words and protected brackets are
+    /// never removed, and stripped words always are, as with `Frequency`.
+    CommonPrefix,
+    /// Use `CommonPrefix` for sets too small for word frequencies to mean anything, and
+    /// `Frequency` otherwise.
+    Auto,
+}
+
+/// Below this many titles, `CleanStrategy::Auto` uses the common prefix and suffix, since with
+/// only a handful of titles nearly every word reaches the frequency threshold or none does.
+pub const AUTO_MIN_FREQUENCY_TITLES: usize = 5;
+
+/// Titles of front and back matter, which a chapter template leaves unnumbered.
+pub const DEFAULT_UNNUMBERED_TITLES: [&str; 6] = ["Prologue", "Introduction", "Preface", "Epilogue", "Afterword", "Credits"];
+
+/// The bracket style a bracketed token was written with, before brackets are standardized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BracketKind {
+    /// Square brackets, `[..]`, and lenticular brackets, `【..】`.
+    Square,
+    /// Parentheses, `(..)`, including full-width ones, `（..）`.
+    Round,
+}
+
+/// Options controlling `clean_titles`.
+///
+/// Construct with `CleanOptions::default()` and adjust the fields you need; new fields may be
+/// added in minor releases, always with a default that preserves the existing behaviour.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct CleanOptions {
+    /// Fraction of titles (0.0–1.0) a token must appear in to be removed from the start of a title.
+    /// Defaults to 0.8.
+    pub threshold: f64,
+    /// Tokens that are never removed by frequency, even when they are common.
+    pub keep: Vec<String>,
+    /// Tokens that are always removed, wherever they appear in a title.
+    pub strip: Vec<String>,
+    /// Words that are always removed, wherever they appear in a title and whichever strategy
+    /// runs. Unlike `strip`, they are compared like the frequency counts, so with `fold_tokens`
+    /// "audiobook" also removes "AudioBook" and "Audiobóok".
+    pub stopwords: Vec<String>,
+    /// Literal `(from, to)` replacements applied in order to each cleaned title.
+    pub rewrites: Vec<(String, String)>,
+    /// How numbers from the original titles are treated.
+    pub numbering: Numbering,
+    /// Bracket styles whose tokens are never removed by frequency. Tokens in other bracket
+    /// styles are cleaned like plain words. Defaults to both styles.
+    pub protected_brackets: Vec<BracketKind>,
+    /// How redundant parts of the titles are found. Defaults to `CleanStrategy::Frequency`.
+    pub strategy: CleanStrategy,
+    /// Treat `_` and `.` as word separators and count words case-insensitively, so
+    /// "Chapter_01" and "chapter 01" share the word "chapter". Cleaned titles keep the original
+    /// casing and the separators between the remaining words. Defaults to `false`.
+    pub normalize_separators: bool,
+    /// Count words regardless of case and of accents on Latin letters, so "Chapter", "chapter",
+    /// and "Chápter" are one word for frequency cleaning. Cleaned titles keep the original
+    /// spelling. Defaults to `true`; turn it off for libraries where case matters.
+    pub fold_tokens: bool,
+    /// The letter case applied to each title after cleaning. Defaults to `TitleCase::Keep`.
+    pub title_case: TitleCase,
+    /// Strip audio file extensions left at the end of a title, such as the ".mp3" of
+    /// "Chapter 3.mp3.flac", with a numeric copy marker after them (".mp3.1"). Other numbers are
+    /// never touched, so "Part 3.1" is kept. Defaults to `false`.
+    pub strip_extension_artifacts: bool,
+    /// Name a title that cleans to nothing "Chapter {n}" after its position, counted from
+    /// `first_chapter_number`. Defaults to `false`, which leaves such a title empty.
+    pub number_untitled: bool,
+    /// The number of the first chapter, for `number_untitled` and the chapter template.
+    /// Defaults to 1.
+    pub first_chapter_number: usize,
+    /// A template such as "Chapter {n}: {title}" applied to each cleaned title, where `{n}`
+    /// counts only the titles that are not front or back matter. Defaults to `None`.
+    pub chapter_template: Option<String>,
+    /// Words that mark a title as front or back matter, left out of the template's numbering
+    /// (see `is_unnumbered_title`). Defaults to `DEFAULT_UNNUMBERED_TITLES`.
+    pub unnumbered_titles: Vec<String>,
+}
+
+impl Default for CleanOptions {
+    fn default() -> Self {
+        CleanOptions {
+            threshold: 0.8,
+            keep: Vec::new(),
+            strip: Vec::new(),
+            stopwords: Vec::new(),
+            rewrites: Vec::new(),
+            numbering: Numbering::default(),
+            protected_brackets: vec![BracketKind::Square, BracketKind::Round],
+            strategy: CleanStrategy::default(),
+            normalize_separators: false,
+            fold_tokens: true,
+            title_case: TitleCase::default(),
+            strip_extension_artifacts: false,
+            number_untitled: false,
+            first_chapter_number: 1,
+            chapter_template: None,
+            unnumbered_titles: DEFAULT_UNNUMBERED_TITLES.iter().map(|title| title.to_string()).collect(),
+        }
+    }
+}
+
+/// Why cleaning removed a part of a title, as reported by `trace_clean_titles`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Removal {
+    /// A leading token found in `count` of the `total` titles, at least the threshold fraction.
+    Frequent { count: usize, total: usize },
+    /// A token listed in `CleanOptions::strip`.
+    Stripped,
+    /// A word listed in `CleanOptions::stopwords`.
+    Stopword,
+    /// A word every title starts or ends with, removed by `CleanStrategy::CommonPrefix`.
+    Common,
+    /// A number, dropped by `Numbering::Strip`.
+    Number,
+    /// An audio file extension left at the end, removed by `CleanOptions::strip_extension_artifacts`.
+    Extension,
+}
+
+/// A part of a title that cleaning removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemovedToken {
+    /// The removed text, with standardized brackets.
+    pub text: String,
+    /// The bracket style the token was written with, if it was bracketed.
+    pub bracket: Option<BracketKind>,
+    pub reason: Removal,
+}
+
+/// How one title was cleaned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitleTrace {
+    pub raw: String,
+    pub cleaned: String,
+    /// The removed parts, in the order they occur in the title.
+    pub removed: Vec<RemovedToken>,
+}
+
+/// Cleans a batch of chapter titles.
+///
+/// The titles are analysed together: a token is only considered redundant relative to the
+/// other titles in the same batch, so pass every title of a book in one call.
+///
+/// # Arguments
+///
+/// * `titles` - The raw titles, typically file stems, in chapter order.
+/// * `options` - The cleaning options.
+///
+/// # Returns
+///
+/// The cleaned titles, one per input title and in the same order. With a chapter template, front
+/// and back matter keep their cleaned title and every other title is rendered with the template.
+/// Otherwise, with `options.number_untitled`, titles with nothing left after cleaning are named
+/// "Chapter {n}", numbered by position from `options.first_chapter_number`.
+///
+/// # Example
+///
+/// ```
+/// use m4btool::{clean_titles, CleanOptions};
+///
+/// let titles = vec!["Dune - Chapter 01 [Arrakis]".to_string(), "Dune - Chapter 02 [Desert]".to_string()];
+/// assert_eq!(clean_titles(&titles, &CleanOptions::default()), vec!["[Arrakis]", "[Desert]"]);
+/// ```
+pub fn clean_titles(titles: &[String], options: &CleanOptions) -> Vec<String> {
+    trace_clean_titles(titles, options).into_iter().map(|trace| trace.cleaned).collect()
+}
+
+/// Cleans a batch of chapter titles like `clean_titles`, and reports for each title which parts
+/// were removed and why, e.g. to tune the cleaning options.
+///
+/// # Example
+///
+/// ```
+/// use m4btool::{trace_clean_titles, CleanOptions, Removal};
+///
+/// let titles = vec!["Dune - Chapter 01 [Arrakis]".to_string(), "Dune - Chapter 02 [Desert]".to_string()];
+/// let traces = trace_clean_titles(&titles, &CleanOptions::default());
+/// assert_eq!(traces[0].cleaned, "[Arrakis]");
+/// let removed: Vec<(&str, Removal)> = traces[0].removed.iter().map(|token| (token.text.as_str(), token.reason)).collect();
+/// assert_eq!(removed, vec![
+///     ("Dune", Removal::Frequent { count: 2, total: 2 }),
+///     ("Chapter", Removal::Frequent { count: 2, total: 2 }),
+///     ("01", Removal::Number),
+/// ]);
+/// ```
+pub fn trace_clean_titles(titles: &[String], options: &CleanOptions) -> Vec<TitleTrace> {
+    let mut extensions = vec![Vec::new(); titles.len()];
+    let stripped: Vec<String>;
+    let sources = if options.strip_extension_artifacts {
+        stripped = titles.iter().map(|title| strip_extension_artifacts(title)).collect();
+        for ((title, stripped), removed) in titles.iter().zip(&stripped).zip(&mut extensions) {
+            if let Some(extension) = title.strip_prefix(stripped.as_str()).filter(|extension| !extension.is_empty()) {
+                removed.push(RemovedToken { text: extension.to_string(), bracket: None, reason: Removal::Extension });
+            }
+        }
+        &stripped
+    } else {
+        titles
+    };
+    let (cleaned, removed): (Vec<String>, Vec<Vec<RemovedToken>>) = remove_redundant_parts(sources, options).into_iter().unzip();
+    titles.iter()
+        .zip(finish_titles(cleaned, options))
+        .zip(removed.into_iter().zip(extensions))
+        .map(|((raw, cleaned), (mut removed, extension))| {
+            removed.extend(extension);
+            TitleTrace { raw: raw.clone(), cleaned, removed }
+        })
+        .collect()
+}
+
+/// Cleans the titles of files kept in subdirectories and prefixes each with its directories, so
+/// `Part One/01.mp3` and `Part Two/01.mp3` become "Part One – 01" and "Part Two – 01".
+///
+/// The file names are cleaned together as by `clean_titles`, except that a name with nothing
+/// left after cleaning, such as a bare track number, is kept as it is. Directory names get
+/// standardized brackets but no frequency cleaning: a book has only a few folders, so every word
+/// they share would count as redundant. Leading directories that all files share, such as the
+/// book's own folder, are left out.
+///
+/// # Arguments
+///
+/// * `titles` - The raw titles, typically file stems, in chapter order.
+/// * `dirs` - Each file's directories below the input directory, outermost first.
+/// * `options` - The cleaning options.
+///
+/// # Example
+///
+/// ```
+/// use m4btool::{clean_titles_with_dirs, CleanOptions};
+///
+/// let titles = vec!["01".to_string(), "02".to_string(), "01".to_string()];
+/// let dirs = vec![vec!["Part One".to_string()], vec!["Part One".to_string()], vec!["Part Two".to_string()]];
+/// assert_eq!(clean_titles_with_dirs(&titles, &dirs, &CleanOptions::default()), vec!["Part One – 01", "Part One – 02", "Part Two – 01"]);
+/// ```
+pub fn clean_titles_with_dirs(titles: &[String], dirs: &[Vec<String>], options: &CleanOptions) -> Vec<String> {
+    let titles: Vec<String> = if options.strip_extension_artifacts {
+        titles.iter().map(|title| strip_extension_artifacts(title)).collect()
+    } else {
+        titles.to_vec()
+    };
+    let shallowest = dirs.iter().map(Vec::len).min().unwrap_or(0);
+    let shared = (0..shallowest).take_while(|&depth| dirs.iter().all(|dir| dir[depth] == dirs[0][depth])).count();
+    let combined = titles.iter()
+        .zip(remove_redundant_parts(&titles, options).into_iter().map(|(cleaned, _)| cleaned))
+        .zip(dirs)
+        .map(|((title, cleaned), dir)| {
+            let name = if cleaned.trim().is_empty() { title.trim().to_string() } else { cleaned };
+            let mut parts: Vec<String> = dir[shared..].iter()
+                .map(|component| standardize_brackets(component).replace('_', " ").trim().to_string())
+                .filter(|component| !component.is_empty())
+                .collect();
+            parts.push(name);
+            parts.join(" – ")
+        })
+        .collect();
+    finish_titles(combined, options)
+}
+
+/// Recases the cleaned titles and applies the chapter template or the "Chapter {n}" fallback.
+fn finish_titles(cleaned: Vec<String>, options: &CleanOptions) -> Vec<String> {
+    let mut next_number = options.first_chapter_number;
+    cleaned
+        .into_iter()
+        .enumerate()
+        .map(|(index, cleaned)| {
+            let cased = apply_title_case(&cleaned, options.title_case);
+            match &options.chapter_template {
+                Some(_) if is_unnumbered_title(&cased, &options.unnumbered_titles) => cased,
+                Some(template) => {
+                    next_number += 1;
+                    render_chapter_template(template, next_number - 1, &cased)
+                }
+                None if options.number_untitled && cased.trim().is_empty() => format!("Chapter {}", options.first_chapter_number + index),
+                None => cased,
+            }
+        })
+        .collect()
+}
+
+/// Returns `true` for a front or back matter title: one that starts or ends with one of `words`
+/// as a whole word, ignoring case, like "Prologue: The Storm" or "Opening Credits".
+pub fn is_unnumbered_title(title: &str, words: &[String]) -> bool {
+    words.iter().filter(|word| !word.trim().is_empty()).any(|word| {
+        let word = regex::escape(word.trim());
+        Regex::new(&format!(r"(?i)^\W*{0}\b|\b{0}\W*$", word)).is_ok_and(|regex| regex.is_match(title.trim()))
+    })
+}
+
+/// Fills `{n}` and `{title}` into a chapter template. Separators left dangling at the end by an
+/// empty title are removed, so "Chapter {n}: {title}" gives "Chapter 3".
+fn render_chapter_template(template: &str, number: usize, title: &str) -> String {
+    template.replace("{n}", &number.to_string())
+        .replace("{title}", title)
+        .trim_end_matches(|c: char| c.is_whitespace() || matches!(c, ':' | '-' | '–' | '—' | ','))
+        .to_string()
+}
+
+/// Removes the characters that are invisible or reorder the text around them: zero-width spaces,
+/// byte order marks, soft hyphens, and other default-ignorable code points, and the bidi
+/// controls such as the right-to-left override U+202E, which can make a title display as
+/// something else. Hebrew, Arabic, and other right-to-left text is kept as it is, and so are the
+/// zero-width joiners that Persian, Indic scripts, and emoji sequences depend on.
+///
+/// # Example
+///
+/// ```
+/// use m4btool::strip_invisible_characters;
+///
+/// assert_eq!(strip_invisible_characters("Chapter\u{200B} 1\u{FEFF}"), "Chapter 1");
+/// ```
+pub fn strip_invisible_characters(title: &str) -> String {
+    title.chars().filter(|&c| !is_invisible_character(c)).collect()
+}
+
+/// Returns `true` for the characters `strip_invisible_characters` removes.
+fn is_invisible_character(c: char) -> bool {
+    matches!(
+        c,
+        '\u{AD}' | '\u{34F}' | '\u{61C}' | '\u{115F}' | '\u{1160}' | '\u{17B4}' | '\u{17B5}' | '\u{180E}'
+            | '\u{200B}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{206F}'
+            | '\u{3164}' | '\u{FEFF}' | '\u{FFA0}' | '\u{FFF0}'..='\u{FFF8}' | '\u{1D173}'..='\u{1D17A}'
+            | '\u{E0000}'..='\u{E007F}'
+    )
+}
+
+/// Removes audio file extensions from the end of a title, each optionally followed by a numeric
+/// copy marker: "track.mp3" and "track.MP3.1" both become "track".
+fn strip_extension_artifacts(title: &str) -> String {
+    let artifacts = Regex::new(r"(?i)(\.(mp3|mp4|m4a|m4b|aac|flac|ogg|oga|opus|wav|wma|aiff?|ape|wv)(\.\d{1,3})?)+$").unwrap();
+    artifacts.replace(title, "").to_string()
+}
+
+/// Runs the selected `CleanStrategy`, rewrites, and numbering policy, before any recasing.
+/// Returns each cleaned title with the parts removed from it.
+fn remove_redundant_parts(titles: &[String], options: &CleanOptions) -> Vec<(String, Vec<RemovedToken>)> {
+    // Every word of a lone title occurs in all titles, so frequencies would remove all of it.
+    let use_common_prefix = titles.len() == 1 || match options.strategy {
+        CleanStrategy::Frequency => false,
+        CleanStrategy::CommonPrefix => true,
+        CleanStrategy::Auto => titles.len() < AUTO_MIN_FREQUENCY_TITLES,
+    };
+    if use_common_prefix {
+        let (listed, mut removed): (Vec<String>, Vec<Vec<RemovedToken>>) = titles.iter().map(|title| remove_listed_words(title, options)).unzip();
+        return strip_common_affixes(&listed, options)
+            .into_iter()
+            .zip(titles)
+            .zip(removed.iter_mut())
+            .map(|(((cleaned, common), title), removed)| {
+                removed.extend(common);
+                let mut cleaned = match options.numbering {
+                    Numbering::Strip => remove_numbers(&cleaned, removed),
+                    Numbering::KeepLeading => cleaned,
+                };
+                for (from, to) in &options.rewrites {
+                    cleaned = cleaned.replace(from.as_str(), to);
+                }
+                if options.numbering == Numbering::KeepLeading {
+                    cleaned = keep_leading_number(title, cleaned, removed);
+                }
+                (cleaned, std::mem::take(removed))
+            })
+            .collect();
+    }
+
+    let token_frequency = build_token_frequency(titles, options);
+    titles.iter()
+        .map(|title| {
+            let (mut cleaned, mut removed) = dynamic_clean_title(title, &token_frequency, titles.len(), options);
+            for (from, to) in &options.rewrites {
+                cleaned = cleaned.replace(from.as_str(), to);
+            }
+            if options.numbering == Numbering::KeepLeading {
+                cleaned = keep_leading_number(title, cleaned, &mut removed);
+            }
+            (cleaned, removed)
+        })
+        .collect()
+}
+
+/// Puts the leading number of the original title back in front of the cleaned title when
+/// cleaning removed it, for `Numbering::KeepLeading`.
+fn keep_leading_number(title: &str, cleaned: String, removed: &mut Vec<RemovedToken>) -> String {
+    let leading_number = Regex::new(r"^\s*(\d+)").unwrap();
+    let Some(number) = leading_number.captures(title).and_then(|c| c.get(1)) else { return cleaned };
+    let Some(kept) = removed.iter().position(|token| matches!(token.reason, Removal::Number | Removal::Common) && token.text == number.as_str()) else {
+        return cleaned;
+    };
+    removed.remove(kept);
+    format!("{} {}", number.as_str(), cleaned).trim().to_string()
+}
+
+/// Removes the stripped words and stopwords from a title for the common-prefix strategy, outside
+/// of protected brackets, joining the remaining words with spaces. A title without any is
+/// returned unchanged.
+fn remove_listed_words(title: &str, options: &CleanOptions) -> (String, Vec<RemovedToken>) {
+    let stopwords = stopword_keys(options);
+    let protected = protected_spans(title, options);
+    let mut kept = Vec::new();
+    let mut removed = Vec::new();
+    for (start, end) in word_spans(title) {
+        let word = &title[start..end];
+        let reason = if is_protected_word(title, (start, end), &protected) {
+            None
+        } else if options.strip.iter().any(|stripped| stripped == word) {
+            Some(Removal::Stripped)
+        } else if stopwords.contains(&token_key(word, options)) {
+            Some(Removal::Stopword)
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => removed.push(RemovedToken { text: word.to_string(), bracket: None, reason }),
+            None => kept.push(word),
+        }
+    }
+    if removed.is_empty() {
+        return (title.to_string(), Vec::new());
+    }
+    (kept.join(" "), removed)
+}
+
+/// Removes the words that are only a number from a title for the common-prefix strategy with
+/// `Numbering::Strip`, joining the remaining words with spaces. A title without such words, or
+/// with nothing else, is returned unchanged.
+fn remove_numbers(title: &str, removed: &mut Vec<RemovedToken>) -> String {
+    let words: Vec<&str> = word_spans(title).into_iter().map(|(start, end)| &title[start..end]).collect();
+    let (numbers, kept): (Vec<&str>, Vec<&str>) = words.into_iter().partition(|word| word.chars().all(|c| c.is_ascii_digit()));
+    if numbers.is_empty() || kept.is_empty() {
+        return title.to_string();
+    }
+    removed.extend(numbers.into_iter().map(|number| RemovedToken { text: number.to_string(), bracket: None, reason: Removal::Number }));
+    kept.join(" ")
+}
+
+/// The character ranges of the bracketed tokens of a title that `options` protects.
+fn protected_spans(title: &str, options: &CleanOptions) -> Vec<Range<usize>> {
+    split_title_tokens(title, options).into_iter()
+        .filter(|token| token.is_protected(options))
+        .map(|token| token.span)
+        .collect()
+}
+
+/// Returns `true` when a word, given by its byte range, lies in one of the `protected` character
+/// ranges of the title.
+fn is_protected_word(title: &str, (start, end): (usize, usize), protected: &[Range<usize>]) -> bool {
+    let first = title[..start].chars().count();
+    let last = first + title[start..end].chars().count();
+    protected.iter().any(|span| span.start < last && first < span.end)
+}
+
+/// The stopwords as they are compared with tokens.
+fn stopword_keys(options: &CleanOptions) -> HashSet<String> {
+    options.stopwords.iter().map(|word| token_key(word.trim(), options)).collect()
+}
+
+/// Returns `true` for the characters that separate words in file names.
+fn is_word_separator(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '_' | '-' | '.' | ',' | ':' | '：')
+}
+
+/// Splits a title into words, returning each word's byte range in the title.
+fn word_spans(title: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (index, c) in title.char_indices() {
+        match (is_word_separator(c), start) {
+            (true, Some(word_start)) => {
+                spans.push((word_start, index));
+                start = None;
+            }
+            (false, None) => start = Some(index),
+            _ => {}
+        }
+    }
+    if let Some(word_start) = start {
+        spans.push((word_start, title.len()));
+    }
+    spans
+}
+
+/// Removes the words that all titles start with and end with.
+///
+/// Comparing whole words rather than characters keeps numbers intact: `Part01` and `Part02`
+/// share the characters `Part0`, but not a word. Every title keeps at least one word, so the
+/// shorter of "Book Intro" and "Book Intro Part" keeps "Intro", and the words removed stop at a
+/// word listed in `CleanOptions::keep` or one inside protected brackets. Underscores in the
+/// remaining text become spaces. Each title comes with the words removed from it.
+fn strip_common_affixes(titles: &[String], options: &CleanOptions) -> Vec<(String, Vec<RemovedToken>)> {
+    let words: Vec<Vec<(&str, bool)>> = titles.iter()
+        .map(|title| {
+            let protected = protected_spans(title, options);
+            word_spans(title).into_iter()
+                .map(|span| {
+                    let word = &title[span.0..span.1];
+                    (word, options.keep.iter().any(|kept| kept == word) || is_protected_word(title, span, &protected))
+                })
+                .collect()
+        })
+        .collect();
+    let shortest = words.iter().map(Vec::len).min().unwrap_or(0);
+    // A single title has nothing to compare against.
+    let comparable = titles.len() > 1;
+    // Whether the word at a position, given from the number of words of each title, is the same
+    // removable word in every title.
+    let common = |position: &dyn Fn(usize) -> usize| {
+        words.iter().all(|w| {
+            let (text, fixed) = w[position(w.len())];
+            !fixed && text == words[0][position(words[0].len())].0
+        })
+    };
+    let mut prefix_len = 0;
+    while comparable && prefix_len + 1 < shortest && common(&|_| prefix_len) {
+        prefix_len += 1;
+    }
+    let mut suffix_len = 0;
+    while comparable && prefix_len + suffix_len + 1 < shortest && common(&|len| len - 1 - suffix_len) {
+        suffix_len += 1;
+    }
+
+    titles.iter()
+        .map(|title| {
+            let spans = word_spans(title);
+            if prefix_len + suffix_len >= spans.len() {
+                return (title.clone(), Vec::new());
+            }
+            let start = spans[prefix_len].0;
+            let end = spans[spans.len() - 1 - suffix_len].1;
+            let removed = spans[..prefix_len].iter()
+                .chain(&spans[spans.len() - suffix_len..])
+                .map(|&(start, end)| RemovedToken { text: title[start..end].to_string(), bracket: None, reason: Removal::Common })
+                .collect();
+            (title[start..end].replace('_', " ").trim().to_string(), removed)
+        })
+        .collect()
+}
+
+/// Represents a token parsed from a chapter title.
+/// A token may either be bracketed (e.g. "[Intro]") or not.
+/// The original bracket style is kept in `bracket`, since the text itself is standardized to square brackets.
+#[derive(Debug)]
+struct TitleToken {
+    bracket: Option<BracketKind>,
+    text: String,
+    /// The character range of the token in the original title.
+    span: Range<usize>,
+}
+
+impl TitleToken {
+    /// Returns the key the token is counted under in the frequency map.
+    fn frequency_key(&self, options: &CleanOptions) -> String {
+        token_key(&self.text, options)
+    }
+
+    /// Returns `true` when the token cannot be removed by frequency under the given options.
+    fn is_protected(&self, options: &CleanOptions) -> bool {
+        self.bracket.is_some_and(|kind| options.protected_brackets.contains(&kind))
+    }
+}
+
+/// Returns the key a token is counted and compared under: folded with `fold_tokens`, lowercased
+/// with `normalize_separators`, and as written otherwise.
+fn token_key(text: &str, options: &CleanOptions) -> String {
+    if options.fold_tokens {
+        fold_token(text)
+    } else if options.normalize_separators {
+        text.to_lowercase()
+    } else {
+        text.to_string()
+    }
+}
+
+/// Folds a token for counting: lowercased, with the accents of Latin letters removed and
+/// combining marks dropped. Other scripts are only lowercased.
+fn fold_token(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        match c {
+            '\u{300}'..='\u{36F}' => {}
+            '\u{C0}'..='\u{24F}' | '\u{1E00}'..='\u{1EFF}' if c != '×' && c != '÷' => {
+                folded.push_str(deunicode::deunicode_char(c).unwrap_or_default());
+            }
+            _ => folded.push(c),
+        }
+    }
+    folded
+}
+
+/// Returns the bracket style of an opening bracket character in the original title.
+fn opening_bracket_kind(c: char) -> Option<BracketKind> {
+    match c {
+        '[' | '【' => Some(BracketKind::Square),
+        '(' | '（' => Some(BracketKind::Round),
+        _ => None,
+    }
+}
+
+/// Standardizes different types of bracket characters in the input string
+/// by replacing them with the common bracket characters "[" and "]".
+///
+/// # Arguments
+///
+/// * `input` - A string slice that potentially contains various bracket styles.
+///
+/// # Returns
+///
+/// A `String` with all bracket types standardized to square brackets.
+fn standardize_brackets(input: &str) -> String {
+    input.replace("（", "[")
+         .replace("）", "]")
+         .replace("(", "[")
+         .replace(")", "]")
+         .replace("【", "[")
+         .replace("】", "]")
+}
+
+/// Splits a chapter title into tokens using regular expressions.
+/// Tokens can either be bracketed segments (like "[Intro]" or "(Overview)")
+/// or non-bracketed text segments. This function leverages `standardize_brackets`
+/// to ensure consistent processing.
+///
+/// # Arguments
+///
+/// * `title` - The chapter title as a string slice.
+///
+/// # Returns
+///
+/// A vector of `TitleToken` instances representing the parsed tokens.
+fn split_title_tokens(title: &str, options: &CleanOptions) -> Vec<TitleToken> {
+    let mut standardized_title = standardize_brackets(title);
+    if options.normalize_separators {
+        standardized_title = standardized_title.replace(['_', '.'], " ");
+    }
+    // Standardizing never reorders characters, so the n-th character of both strings corresponds.
+    let original_chars: Vec<char> = title.chars().collect();
+    // Regex pattern captures either bracketed expressions or continuous non-numeric and non-bracketed text.
+    let token_pattern = Regex::new(r"(\(.*?\)|\[.*?\])|([^0-9\s\-:：\(\)\[\]]+)").unwrap();
+    let mut tokens = Vec::new();
+
+    for capture in token_pattern.captures_iter(&standardized_title) {
+        let Some(token) = capture.get(1).or_else(|| capture.get(2)) else { continue };
+        let start = standardized_title[..token.start()].chars().count();
+        let span = start..start + token.as_str().chars().count();
+        let text = token.as_str().to_string();
+        if capture.get(1).is_some() {
+            let bracket = original_chars.get(start).and_then(|&c| opening_bracket_kind(c)).unwrap_or(BracketKind::Square);
+            tokens.push(TitleToken { bracket: Some(bracket), text, span });
+        } else {
+            tokens.push(TitleToken { bracket: None, text, span });
+        }
+    }
+    tokens
+}
+
+/// Builds a frequency map of unprotected tokens across multiple chapter titles.
+/// This is used later to decide if a token should be removed based on its occurrence frequency.
+///
+/// # Arguments
+///
+/// * `titles` - A slice of chapter title strings.
+/// * `options` - The options deciding which bracketed tokens are protected.
+///
+/// # Returns
+///
+/// A `HashMap` where each key is an unprotected token and the value is the occurrence count.
+fn build_token_frequency(titles: &[String], options: &CleanOptions) -> HashMap<String, usize> {
+    let mut token_frequency = HashMap::new();
+    for title in titles {
+        for token in split_title_tokens(title, options) {
+            // Only count unprotected tokens to avoid removing significant descriptive parts.
+            if !token.is_protected(options) {
+                *token_frequency.entry(token.frequency_key(options)).or_insert(0) += 1;
+            }
+        }
+    }
+    token_frequency
+}
+
+/// Cleans up a chapter title dynamically by removing common tokens that exceed a given frequency threshold.
+/// This helps in removing redundant words from the beginning of titles (e.g., repeated "Chapter" labels).
+///
+/// # Arguments
+///
+/// * `title` - The original chapter title as a string slice.
+/// * `token_frequency` - A frequency map of tokens obtained from `build_token_frequency`.
+/// * `total_titles` - Total number of chapter titles processed.
+/// * `options` - The threshold and keep/strip lists controlling which tokens are removed.
+///
+/// # Returns
+///
+/// A cleaned-up title string with the common tokens removed, and the removed tokens and numbers.
+fn dynamic_clean_title(title: &str, token_frequency: &HashMap<String, usize>, total_titles: usize, options: &CleanOptions) -> (String, Vec<RemovedToken>) {
+    let tokens = split_title_tokens(title, options);
+    let stopwords = stopword_keys(options);
+    // Each removed part with its position, so numbers can be put in between the tokens.
+    let mut removed = dropped_numbers(title, &tokens);
+    let mut cleaned_tokens = Vec::new();
+    let mut in_removal_phase = true;
+
+    for (index, token) in tokens.into_iter().enumerate() {
+        let is_protected = token.is_protected(options);
+        let removal = |reason| (token.span.start, RemovedToken { text: token.text.clone(), bracket: token.bracket, reason });
+        // Stripped tokens and stopwords are removed wherever they appear.
+        if !is_protected && options.strip.contains(&token.text) {
+            removed.push(removal(Removal::Stripped));
+            continue;
+        }
+        if !is_protected && stopwords.contains(&token.frequency_key(options)) {
+            removed.push(removal(Removal::Stopword));
+            continue;
+        }
+        // In the removal phase, skip tokens that are overly common unless they are explicitly kept.
+        if in_removal_phase && !is_protected {
+            let frequency = token_frequency.get(&token.frequency_key(options)).copied().unwrap_or(0);
+            if (frequency as f64) / (total_titles as f64) >= options.threshold && !options.keep.contains(&token.text) {
+                removed.push(removal(Removal::Frequent { count: frequency, total: total_titles }));
+                continue;
+            } else {
+                // Token is not too common, so end removal phase and keep it.
+                in_removal_phase = false;
+                cleaned_tokens.push((index, token));
+            }
+        } else {
+            // Once the removal phase is over, keep all tokens (especially protected ones).
+            if is_protected {
+                in_removal_phase = false;
+            }
+            cleaned_tokens.push((index, token));
+        }
+    }
+    removed.sort_by_key(|(position, _)| *position);
+    let removed = removed.into_iter().map(|(_, token)| token).collect();
+    if !options.normalize_separators {
+        let words: Vec<String> = cleaned_tokens.into_iter().map(|(_, token)| token.text).collect();
+        return (words.join(" ").trim().to_string(), removed);
+    }
+
+    // Rejoin the kept words with the separators that stood between them in the original title.
+    let title_chars: Vec<char> = title.chars().collect();
+    let mut cleaned = String::new();
+    let mut previous: Option<(usize, usize)> = None;
+    for (index, token) in cleaned_tokens {
+        match previous {
+            Some((previous_index, previous_end)) if previous_index + 1 == index => {
+                cleaned.push_str(&separator_between(&title_chars[previous_end..token.span.start]));
+            }
+            Some(_) => cleaned.push(' '),
+            None => {}
+        }
+        previous = Some((index, token.span.end));
+        cleaned.push_str(&token.text);
+    }
+    (cleaned.trim().to_string(), removed)
+}
+
+/// Finds the numbers outside of the tokens, which frequency cleaning always drops, each with its
+/// character position in the title.
+fn dropped_numbers(title: &str, tokens: &[TitleToken]) -> Vec<(usize, RemovedToken)> {
+    let mut numbers: Vec<(usize, RemovedToken)> = Vec::new();
+    let mut previous = None;
+    for (position, c) in title.chars().enumerate() {
+        if !c.is_ascii_digit() || tokens.iter().any(|token| token.span.contains(&position)) {
+            continue;
+        }
+        match numbers.last_mut() {
+            Some((_, number)) if previous == Some(position - 1) => number.text.push(c),
+            _ => numbers.push((position, RemovedToken { text: c.to_string(), bracket: None, reason: Removal::Number })),
+        }
+        previous = Some(position);
+    }
+    numbers
+}
+
+/// Turns the text between two kept words into their separator: digits are dropped, as in the
+/// default cleaning, and repeated separator characters are collapsed, so "_01_" becomes "_".
+fn separator_between(gap: &[char]) -> String {
+    let mut separator = String::new();
+    for &c in gap.iter().filter(|c| !c.is_ascii_digit()) {
+        if !separator.ends_with(c) {
+            separator.push(c);
+        }
+    }
+    separator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(titles: &[&str]) -> Vec<String> {
+        titles.iter().map(|t| t.to_string()).collect()
+    }
+
+    /// Tests that `split_title_tokens` correctly identifies both bracketed and non-bracketed tokens.
+    #[test]
+    fn test_split_title_tokens() {
+        let title = "Chapter 1 [Intro] (Overview)";
+        let tokens = split_title_tokens(title, &CleanOptions::default());
+        assert!(!tokens.is_empty());
+        let bracketed: Vec<_> = tokens.iter().filter_map(|t| t.bracket).collect();
+        assert_eq!(bracketed, vec![BracketKind::Square, BracketKind::Round]);
+    }
+
+    /// Tests that `dynamic_clean_title` properly cleans a title by removing common tokens.
+    #[test]
+    fn test_dynamic_clean_title() {
+        let titles = vec![
+            "Chapter 1 [Intro]".to_string(),
+            "Chapter 2 [Intro]".to_string(),
+            "Chapter 3 [Intro]".to_string(),
+        ];
+        let freq = build_token_frequency(&titles, &CleanOptions::default());
+        let (cleaned, _) = dynamic_clean_title("Chapter 1 [Intro]", &freq, titles.len(), &CleanOptions::default());
+        assert!(!cleaned.is_empty());
+    }
+
+
+    /// Tests that spellings differing in case and accents share one frequency bucket, decomposed
+    /// accents included, and that folding can be turned off.
+    #[test]
+    fn test_fold_tokens() {
+        let titles = strings(&["Chapter 1 Storm", "chapter 2 Calm", "Chápter 3 Rain", "CHAPTER 4 Wind", "Cha\u{301}pter 5 Snow"]);
+        let frequency = build_token_frequency(&titles, &CleanOptions::default());
+        assert_eq!(frequency.get("chapter"), Some(&5));
+        assert_eq!(clean_titles(&titles, &CleanOptions::default()), vec!["Storm", "Calm", "Rain", "Wind", "Snow"]);
+        assert_eq!(fold_token("Ærø_Straße"), "aero_strasse");
+        assert_eq!(fold_token("第一章"), "第一章");
+
+        let case_sensitive = CleanOptions { fold_tokens: false, ..CleanOptions::default() };
+        let frequency = build_token_frequency(&titles, &case_sensitive);
+        assert_eq!(frequency.get("Chapter"), Some(&1));
+        assert!(clean_titles(&titles, &case_sensitive)[0].starts_with("Chapter"));
+    }
+
+    /// Tests that leading tokens shared by most titles are removed while bracketed tokens stay.
+    #[test]
+    fn test_clean_titles_removes_common_prefix() {
+        let titles = strings(&["MyBook Chapter 1 [Intro]", "MyBook Chapter 2 Storm", "MyBook Chapter 3 Calm"]);
+        assert_eq!(clean_titles(&titles, &CleanOptions::default()), vec!["[Intro]", "Storm", "Calm"]);
+    }
+
+    /// Tests that tokens below the threshold are kept and that removal stops at the first kept token.
+    #[test]
+    fn test_clean_titles_threshold() {
+        let titles = strings(&["Part A Storm", "Part B Storm", "Other C Storm"]);
+        // "Part" appears in 2 of 3 titles, "Storm" in all of them but only after a kept token.
+        assert_eq!(clean_titles(&titles, &CleanOptions::default()), vec!["Part A Storm", "Part B Storm", "Other C Storm"]);
+        let options = CleanOptions { threshold: 0.6, ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &options), vec!["A Storm", "B Storm", "Other C Storm"]);
+    }
+
+    /// Tests the keep and strip lists.
+    #[test]
+    fn test_clean_titles_keep_and_strip() {
+        let titles = strings(&["Prologue Book Intro", "Prologue Book Storm", "Prologue Book Calm"]);
+        let options = CleanOptions { keep: strings(&["Prologue"]), strip: strings(&["Book"]), ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &options), vec!["Prologue Intro", "Prologue Storm", "Prologue Calm"]);
+    }
+
+    /// Tests stopwords removed anywhere next to the words frequency cleaning removes, compared
+    /// like the frequency counts, and with the common-prefix strategy.
+    #[test]
+    fn test_clean_titles_stopwords() {
+        let titles = strings(&["Dune Chapter 1 Arrakis", "Dune Chapter 2 AUDIOBOOK Desert", "Dune Audiobook Chapter 3 Sietch", "Dune Chapter 4 Water audiobóok"]);
+        let options = CleanOptions { stopwords: strings(&["Audiobook"]), ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &options), vec!["Arrakis", "Desert", "Sietch", "Water"]);
+        // Without the stopword, its varying position ends the frequency removal early.
+        assert_eq!(clean_titles(&titles, &CleanOptions::default())[2], "Audiobook Chapter Sietch");
+
+        let exact = CleanOptions { fold_tokens: false, ..options.clone() };
+        assert_eq!(clean_titles(&titles, &exact)[1], "AUDIOBOOK Desert");
+        let bracketed = strings(&["Book [Audiobook] Intro", "Book Audiobook Storm", "Book Calm"]);
+        assert_eq!(clean_titles(&bracketed, &options), vec!["[Audiobook] Intro", "Storm", "Calm"]);
+
+        let prefix = CleanOptions { strategy: CleanStrategy::CommonPrefix, ..options };
+        assert_eq!(clean_titles(&strings(&["Dune_01_Arrakis", "Dune_audiobook_02_Desert"]), &prefix), vec!["Arrakis", "Desert"]);
+    }
+
+    /// Tests that rewrites apply to the cleaned title and that leading numbers can be kept.
+    #[test]
+    fn test_clean_titles_rewrites_and_numbering() {
+        let titles = strings(&["01 Book Intro", "02 Book Storm"]);
+        let options = CleanOptions {
+            rewrites: vec![("Storm".to_string(), "The Storm".to_string())],
+            numbering: Numbering::KeepLeading,
+            ..CleanOptions::default()
+        };
+        assert_eq!(clean_titles(&titles, &options), vec!["01 Intro", "02 The Storm"]);
+    }
+
+    /// Tests that full-width and lenticular brackets are treated like square brackets.
+    #[test]
+    fn test_clean_titles_cjk_brackets() {
+        let titles = strings(&["三体 第1章【科学边界】", "三体 第2章（台球）"]);
+        assert_eq!(clean_titles(&titles, &CleanOptions::default()), vec!["[科学边界]", "[台球]"]);
+    }
+
+    /// Tests that only the protected bracket style is exempt from frequency cleaning.
+    #[test]
+    fn test_clean_titles_protected_brackets() {
+        let titles = strings(&["Dune (2024) [Arrakis] Sand", "Dune (2024) [Arrakis] Worm", "Dune (2024) [Arrakis] Spice"]);
+        assert_eq!(clean_titles(&titles, &CleanOptions::default()), vec!["[2024] [Arrakis] Sand", "[2024] [Arrakis] Worm", "[2024] [Arrakis] Spice"]);
+        let square_only = CleanOptions { protected_brackets: vec![BracketKind::Square], ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &square_only), vec!["[Arrakis] Sand", "[Arrakis] Worm", "[Arrakis] Spice"]);
+        let no_protection = CleanOptions { protected_brackets: Vec::new(), ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &no_protection), vec!["Sand", "Worm", "Spice"]);
+    }
+
+    /// Tests that full-width parentheses count as round brackets and lenticular ones as square.
+    #[test]
+    fn test_bracket_kind_of_cjk_brackets() {
+        let kinds: Vec<_> = split_title_tokens("第1章【科学边界】（台球）", &CleanOptions::default()).iter().filter_map(|t| t.bracket).collect();
+        assert_eq!(kinds, vec![BracketKind::Square, BracketKind::Round]);
+    }
+
+    /// Tests that the common-prefix strategy keeps numbered words that frequency cleaning loses.
+    #[test]
+    fn test_clean_strategies_on_small_set() {
+        let titles = strings(&["MyBook_Part01_of_03", "MyBook_Part02_of_03", "MyBook_Part03_of_03"]);
+        // Every word is in every title, so frequency cleaning removes everything.
+        assert_eq!(clean_titles(&titles, &CleanOptions::default()), vec!["", "", ""]);
+        let common_prefix = CleanOptions { strategy: CleanStrategy::CommonPrefix, ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &common_prefix), vec!["Part01", "Part02", "Part03"]);
+        let auto = CleanOptions { strategy: CleanStrategy::Auto, ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &auto), vec!["Part01", "Part02", "Part03"]);
+    }
+
+    /// Tests that a template numbers only the real chapters between a prologue and an epilogue.
+    #[test]
+    fn test_chapter_template_skips_front_and_back_matter() {
+        let titles = strings(&["Dune - Prologue", "Dune - Arrakis", "Dune - Desert", "Dune - Spice", "Dune - EPILOGUE"]);
+        let options = CleanOptions { chapter_template: Some("Chapter {n}: {title}".to_string()), ..CleanOptions::default() };
+        assert_eq!(
+            clean_titles(&titles, &options),
+            vec!["Prologue", "Chapter 1: Arrakis", "Chapter 2: Desert", "Chapter 3: Spice", "EPILOGUE"]
+        );
+
+        // A custom list replaces the defaults, and the counter starts at the first chapter number.
+        let options = CleanOptions {
+            chapter_template: Some("{n}. {title}".to_string()),
+            unnumbered_titles: strings(&["Spice"]),
+            first_chapter_number: 10,
+            ..CleanOptions::default()
+        };
+        assert_eq!(clean_titles(&titles, &options), vec!["10. Prologue", "11. Arrakis", "12. Desert", "Spice", "13. EPILOGUE"]);
+    }
+
+    /// Tests matching front and back matter words at either end of a title only.
+    #[test]
+    fn test_is_unnumbered_title() {
+        let words: Vec<String> = DEFAULT_UNNUMBERED_TITLES.iter().map(|word| word.to_string()).collect();
+        assert!(is_unnumbered_title("Prologue", &words));
+        assert!(is_unnumbered_title("prologue: The Storm", &words));
+        assert!(is_unnumbered_title("Opening Credits", &words));
+        assert!(is_unnumbered_title("(Introduction)", &words));
+        assert!(!is_unnumbered_title("Prologues and Other Tales", &words));
+        assert!(!is_unnumbered_title("After the Epilogue Ends", &words));
+        assert!(!is_unnumbered_title("", &words));
+    }
+
+    /// Tests that only audio extensions and copy markers after them are stripped.
+    #[test]
+    fn test_strip_extension_artifacts() {
+        assert_eq!(strip_extension_artifacts("track.mp3"), "track");
+        assert_eq!(strip_extension_artifacts("Chapter 3.mp3"), "Chapter 3");
+        assert_eq!(strip_extension_artifacts("Chapter 3.MP3.m4a.2"), "Chapter 3");
+        assert_eq!(strip_extension_artifacts("Part 3"), "Part 3");
+        assert_eq!(strip_extension_artifacts("Part 3.1"), "Part 3.1");
+        assert_eq!(strip_extension_artifacts("Notes on mp3"), "Notes on mp3");
+        assert_eq!(strip_extension_artifacts("Vol.2"), "Vol.2");
+
+        let titles = strings(&["Book - Part 1.mp3", "Book - Part 2.mp3.1", "Book - Part 3"]);
+        let options = CleanOptions { strip_extension_artifacts: true, strategy: CleanStrategy::CommonPrefix, ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &options), vec!["1", "2", "3"]);
+    }
+
+    /// Tests removing zero-width spaces, byte order marks, and bidi overrides, while right-to-left
+    /// text and the joiners it needs are kept.
+    #[test]
+    fn test_strip_invisible_characters() {
+        assert_eq!(strip_invisible_characters("Chapter\u{200B}One"), "ChapterOne");
+        assert_eq!(strip_invisible_characters("\u{FEFF}Prologue"), "Prologue");
+        assert_eq!(strip_invisible_characters("Part 1\u{FEFF} - Arrakis"), "Part 1 - Arrakis");
+        // With the override, "Chapter 1 gpj.exe" shows as "Chapter 1 exe.jpg".
+        assert_eq!(strip_invisible_characters("Chapter 1 \u{202E}gpj.exe"), "Chapter 1 gpj.exe");
+        assert_eq!(strip_invisible_characters("\u{2067}Intro\u{2069}\u{200F}"), "Intro");
+        assert_eq!(strip_invisible_characters("\u{200B} Epilogue \u{200B}"), " Epilogue ");
+
+        for title in ["פרק ראשון", "الفصل الأول", "می\u{200C}خواهم", "👩\u{200D}🚀 Launch", "Plain title"] {
+            assert_eq!(strip_invisible_characters(title), title);
+        }
+    }
+
+    /// Tests that fallback titles are numbered from the first chapter number, e.g. for part 2 of a series.
+    #[test]
+    fn test_first_chapter_number() {
+        let titles = strings(&["Book 2 - 01", "Book 2 - 02 Epilogue", "Book 2 - 03"]);
+        let options = CleanOptions { number_untitled: true, first_chapter_number: 15, ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &options), vec!["Chapter 15", "Epilogue", "Chapter 17"]);
+        let numbered = CleanOptions { number_untitled: true, ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &numbered), vec!["Chapter 1", "Epilogue", "Chapter 3"]);
+        assert_eq!(clean_titles(&titles, &CleanOptions::default()), vec!["", "Epilogue", ""]);
+    }
+
+    /// Tests both strategies on a set large enough for Auto to pick frequency cleaning.
+    #[test]
+    fn test_clean_strategies_on_large_set() {
+        let titles = strings(&[
+            "Dune - Chapter 01 - Arrakis",
+            "Dune - Chapter 02 - The Desert",
+            "Dune - Chapter 03 - Sietch Tabr",
+            "Dune - Chapter 04 - Spice",
+            "Dune - Chapter 05 - Worms",
+        ]);
+        let frequency = clean_titles(&titles, &CleanOptions::default());
+        assert_eq!(frequency, vec!["Arrakis", "The Desert", "Sietch Tabr", "Spice", "Worms"]);
+        let auto = CleanOptions { strategy: CleanStrategy::Auto, ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &auto), frequency);
+        // The numbers are on their own, so the default numbering drops them with either strategy.
+        let common_prefix = CleanOptions { strategy: CleanStrategy::CommonPrefix, ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &common_prefix), frequency);
+    }
+
+    /// Tests that every title keeps a word, and that a lone title is left unchanged.
+    #[test]
+    fn test_common_prefix_keeps_whole_titles() {
+        let strip = |titles: &[&str]| strip_common_affixes(&strings(titles), &CleanOptions::default()).into_iter().map(|(title, _)| title).collect::<Vec<_>>();
+        assert_eq!(strip(&["Intro", "Intro"]), vec!["Intro", "Intro"]);
+        assert_eq!(strip(&["Book_One"]), vec!["Book One"]);
+        assert_eq!(strip(&["Book Intro", "Book Intro Part"]), vec!["Intro", "Intro Part"]);
+    }
+
+    /// Tests that the common-prefix strategy keeps kept words and protected brackets, removes
+    /// stripped words, and treats numbers on their own by the numbering policy.
+    #[test]
+    fn test_common_prefix_protections() {
+        let prefix = CleanOptions { strategy: CleanStrategy::CommonPrefix, ..CleanOptions::default() };
+        let titles = strings(&["Dune Prologue Arrakis", "Dune Prologue Desert"]);
+        let keep = CleanOptions { keep: strings(&["Prologue"]), ..prefix.clone() };
+        assert_eq!(clean_titles(&titles, &prefix), vec!["Arrakis", "Desert"]);
+        assert_eq!(clean_titles(&titles, &keep), vec!["Prologue Arrakis", "Prologue Desert"]);
+
+        let strip = CleanOptions { strip: strings(&["Unabridged"]), ..prefix.clone() };
+        assert_eq!(clean_titles(&strings(&["Dune Unabridged Arrakis", "Dune Desert"]), &strip), vec!["Arrakis", "Desert"]);
+
+        let bracketed = strings(&["[Dune] Arrakis", "[Dune] Desert"]);
+        assert_eq!(clean_titles(&bracketed, &prefix), vec!["[Dune] Arrakis", "[Dune] Desert"]);
+        let unprotected = CleanOptions { protected_brackets: Vec::new(), ..prefix.clone() };
+        assert_eq!(clean_titles(&bracketed, &unprotected), vec!["Arrakis", "Desert"]);
+        let bracketed_strip = CleanOptions { strip: strings(&["[Dune]"]), ..prefix.clone() };
+        assert_eq!(clean_titles(&bracketed, &bracketed_strip), vec!["[Dune] Arrakis", "[Dune] Desert"]);
+
+        let numbered = strings(&["Dune - 01 - Arrakis", "Dune - 02 - Desert"]);
+        assert_eq!(clean_titles(&numbered, &prefix), vec!["Arrakis", "Desert"]);
+        let keep_leading = CleanOptions { numbering: Numbering::KeepLeading, ..prefix };
+        assert_eq!(clean_titles(&numbered, &keep_leading), vec!["01 - Arrakis", "02 - Desert"]);
+        assert_eq!(clean_titles(&strings(&["01 Book Arrakis", "01 Book Desert"]), &keep_leading), vec!["01 Arrakis", "01 Desert"]);
+    }
+
+    /// Tests that a single file keeps its whole title under every strategy.
+    #[test]
+    fn test_single_title_is_kept() {
+        let titles = strings(&["Dune - Frank Herbert (Unabridged)"]);
+        for strategy in [CleanStrategy::Frequency, CleanStrategy::CommonPrefix, CleanStrategy::Auto] {
+            let options = CleanOptions { strategy, ..CleanOptions::default() };
+            assert_eq!(clean_titles(&titles, &options), vec!["Dune - Frank Herbert (Unabridged)"]);
+        }
+    }
+
+    /// Tests that files in per-part folders get distinct titles led by their folders, without the
+    /// folder every file shares.
+    #[test]
+    fn test_clean_titles_with_dirs() {
+        let titles = strings(&["01", "02", "01", "02"]);
+        let dirs: Vec<Vec<String>> = vec![
+            strings(&["Dune", "Part One (Arrakis)"]),
+            strings(&["Dune", "Part One (Arrakis)"]),
+            strings(&["Dune", "Part_Two", "Disc 1"]),
+            strings(&["Dune", "Part_Two", "Disc 1"]),
+        ];
+        assert_eq!(
+            clean_titles_with_dirs(&titles, &dirs, &CleanOptions::default()),
+            vec!["Part One [Arrakis] – 01", "Part One [Arrakis] – 02", "Part Two – Disc 1 – 01", "Part Two – Disc 1 – 02"]
+        );
+
+        let titles = strings(&["Dune - Ch 1 - Landing", "Dune - Ch 2 - Storm", "Dune - Ch 3 - Sietch"]);
+        let dirs = vec![strings(&["Book 1"]), strings(&["Book 1"]), strings(&["Book 2"])];
+        assert_eq!(
+            clean_titles_with_dirs(&titles, &dirs, &CleanOptions::default()),
+            vec!["Book 1 – Landing", "Book 1 – Storm", "Book 2 – Sietch"]
+        );
+        assert_eq!(clean_titles_with_dirs(&strings(&["Intro"]), &[Vec::new()], &CleanOptions::default()), vec!["Intro"]);
+    }
+
+    /// Tests the removals reported for each title: frequent, stripped, and stopword tokens with
+    /// bracketed ones marked, dropped numbers, extensions, and the common-prefix strategy's words.
+    #[test]
+    fn test_trace_clean_titles() {
+        let removed = |trace: &TitleTrace| trace.removed.iter().map(|token| (token.text.clone(), token.bracket, token.reason)).collect::<Vec<_>>();
+        let frequent = |count| Removal::Frequent { count, total: 3 };
+
+        let titles = strings(&["Dune (2024) 01 Retail Sand.mp3", "Dune (2024) 02 Worm", "Dune (2024) 03 Spice AUDIOBOOK"]);
+        let options = CleanOptions {
+            protected_brackets: Vec::new(),
+            strip: strings(&["Retail"]),
+            stopwords: strings(&["Audiobook"]),
+            strip_extension_artifacts: true,
+            ..CleanOptions::default()
+        };
+        let traces = trace_clean_titles(&titles, &options);
+        assert_eq!(traces.iter().map(|trace| trace.cleaned.as_str()).collect::<Vec<_>>(), vec!["Sand", "Worm", "Spice"]);
+        assert_eq!(traces.iter().map(|trace| trace.raw.as_str()).collect::<Vec<_>>(), titles);
+        assert_eq!(removed(&traces[0]), vec![
+            ("Dune".to_string(), None, frequent(3)),
+            ("[2024]".to_string(), Some(BracketKind::Round), frequent(3)),
+            ("01".to_string(), None, Removal::Number),
+            ("Retail".to_string(), None, Removal::Stripped),
+            (".mp3".to_string(), None, Removal::Extension),
+        ]);
+        assert_eq!(removed(&traces[2]).last(), Some(&("AUDIOBOOK".to_string(), None, Removal::Stopword)));
+
+        let keep_leading = CleanOptions { numbering: Numbering::KeepLeading, ..CleanOptions::default() };
+        let trace = &trace_clean_titles(&strings(&["01 Book Intro 2", "02 Book Storm"]), &keep_leading)[0];
+        assert_eq!(trace.cleaned, "01 Intro");
+        assert_eq!(removed(trace), vec![
+            ("Book".to_string(), None, Removal::Frequent { count: 2, total: 2 }),
+            ("2".to_string(), None, Removal::Number),
+        ]);
+
+        let common_prefix = CleanOptions { strategy: CleanStrategy::CommonPrefix, stopwords: strings(&["Unabridged"]), ..CleanOptions::default() };
+        let traces = trace_clean_titles(&strings(&["MyBook_Part01_of_03", "MyBook_Part02_of_03_Unabridged"]), &common_prefix);
+        assert_eq!(traces[1].cleaned, "Part02");
+        assert_eq!(removed(&traces[1]), vec![
+            ("Unabridged".to_string(), None, Removal::Stopword),
+            ("MyBook".to_string(), None, Removal::Common),
+            ("of".to_string(), None, Removal::Common),
+            ("03".to_string(), None, Removal::Common),
+        ]);
+        assert!(trace_clean_titles(&strings(&["Intro"]), &CleanOptions::default())[0].removed.is_empty());
+    }
+
+    /// Tests that normalizing separators lets mixed "Chapter_01" and "Chapter 01" names share a prefix.
+    #[test]
+    fn test_normalize_separators_improves_prefix_removal() {
+        let titles = strings(&["Chapter_01_The_Storm", "chapter 02 Calm Seas", "Chapter.03.Landfall", "Chapter 04 - Home"]);
+        assert_eq!(
+            clean_titles(&titles, &CleanOptions::default()),
+            vec!["Chapter_ _The_Storm", "chapter Calm Seas", "Chapter. .Landfall", "Chapter Home"]
+        );
+        let normalized = CleanOptions { normalize_separators: true, ..CleanOptions::default() };
+        assert_eq!(clean_titles(&titles, &normalized), vec!["The_Storm", "Calm Seas", "Landfall", "Home"]);
+    }
+}
@@ -0,0 +1,145 @@
+//! Letter case normalization for cleaned chapter titles.
+//!
+//! Case mapping uses the Unicode default case conversions of the standard library, so accented
+//! Latin, Greek, and Cyrillic letters are handled, and scripts without letter case (CJK, Thai)
+//! pass through unchanged. Language-specific rules such as the Turkish dotted `i` are not applied.
+
+/// Words kept in lowercase inside a title-cased title, unless they start or end it.
+const SMALL_WORDS: [&str; 16] = [
+    "a", "an", "and", "as", "at", "but", "by", "for", "in", "nor", "of", "on", "or", "the", "to", "with",
+];
+
+/// The letter case applied to each title after cleaning.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TitleCase {
+    /// Leave the case as it is.
+    #[default]
+    Keep,
+    /// Capitalize every word except small words such as "of" and "the": "The Call of the Wild".
+    Title,
+    /// Capitalize only the first word: "The call of the wild".
+    Sentence,
+    Lower,
+    Upper,
+}
+
+/// Applies a `TitleCase` to one title.
+///
+/// In `Title` and `Sentence` case, acronyms such as "NASA" stay in capitals, and in `Title`
+/// case so do words with inner capitals such as "iPhone". When the whole title is in capitals
+/// acronyms cannot be told apart from shouted words, so every word is recased.
+///
+/// # Arguments
+///
+/// * `title` - The cleaned title.
+/// * `case` - The case to apply.
+///
+/// # Returns
+///
+/// The recased title, with the spacing and punctuation of the original.
+pub fn apply_title_case(title: &str, case: TitleCase) -> String {
+    match case {
+        TitleCase::Keep => title.to_string(),
+        TitleCase::Lower => title.to_lowercase(),
+        TitleCase::Upper => title.to_uppercase(),
+        TitleCase::Title | TitleCase::Sentence => {
+            let shouting = !title.chars().any(char::is_lowercase);
+            let words: Vec<&str> = title.split(' ').collect();
+            let cased_word_indices: Vec<usize> = (0..words.len()).filter(|&i| words[i].chars().any(is_cased)).collect();
+            let first = cased_word_indices.first().copied();
+            let last = cased_word_indices.last().copied();
+            words.iter()
+                .enumerate()
+                .map(|(index, word)| {
+                    let lower = word.to_lowercase();
+                    let is_small_word = SMALL_WORDS.contains(&trim_punctuation(&lower));
+                    if !shouting && !is_small_word && is_acronym(word) {
+                        return word.to_string();
+                    }
+                    let is_edge = Some(index) == first || Some(index) == last;
+                    match case {
+                        TitleCase::Title if !shouting && has_inner_capital(word) => word.to_string(),
+                        TitleCase::Title if !is_edge && is_small_word => lower,
+                        TitleCase::Title => capitalize_parts(&lower),
+                        _ if Some(index) == first => capitalize_first(&lower),
+                        _ => lower,
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
+}
+
+/// Returns `true` for letters that have an upper and lower case form.
+fn is_cased(c: char) -> bool {
+    c.is_uppercase() || c.is_lowercase()
+}
+
+/// Returns `true` for a word of two or more letters, all of them capitals, like "NASA" or "BBC's".
+fn is_acronym(word: &str) -> bool {
+    let letters: Vec<char> = trim_punctuation(word).trim_end_matches("'s").chars().filter(|c| c.is_alphabetic()).collect();
+    letters.len() >= 2 && letters.iter().all(|c| c.is_uppercase())
+}
+
+/// Returns `true` for a mixed-case word with a capital after its first letter, like "iPhone" or "McCoy".
+fn has_inner_capital(word: &str) -> bool {
+    let word = trim_punctuation(word);
+    word.chars().skip(1).any(char::is_uppercase) && word.chars().any(char::is_lowercase)
+}
+
+/// Strips leading and trailing punctuation, so "(of" and "the," are recognized as small words.
+fn trim_punctuation(word: &str) -> &str {
+    word.trim_matches(|c: char| !c.is_alphanumeric())
+}
+
+/// Capitalizes each hyphen-separated part of a word: "self-help" becomes "Self-Help".
+fn capitalize_parts(word: &str) -> String {
+    word.split('-').map(capitalize_first).collect::<Vec<_>>().join("-")
+}
+
+/// Uppercases the first cased letter, leaving leading punctuation or digits in place: "(part" becomes "(Part".
+fn capitalize_first(word: &str) -> String {
+    let Some(position) = word.char_indices().find(|(_, c)| is_cased(*c)).map(|(i, _)| i) else {
+        return word.to_string();
+    };
+    let mut chars = word[position..].chars();
+    let first = chars.next().unwrap_or_default();
+    format!("{}{}{}", &word[..position], first.to_uppercase(), chars.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests small words, which are only capitalized at the start or end of a title.
+    #[test]
+    fn test_title_case_small_words() {
+        assert_eq!(apply_title_case("THE CALL OF THE WILD", TitleCase::Title), "The Call of the Wild");
+        assert_eq!(apply_title_case("a tale of two cities", TitleCase::Title), "A Tale of Two Cities");
+        assert_eq!(apply_title_case("what it's made of", TitleCase::Title), "What It's Made Of");
+        assert_eq!(apply_title_case("03 - the end of the road (part one)", TitleCase::Title), "03 - The End of the Road (Part One)");
+        assert_eq!(apply_title_case("a self-help guide", TitleCase::Title), "A Self-Help Guide");
+    }
+
+    /// Tests that acronyms and words with inner capitals are kept.
+    #[test]
+    fn test_title_case_acronyms() {
+        assert_eq!(apply_title_case("how NASA built the iPhone", TitleCase::Title), "How NASA Built the iPhone");
+        assert_eq!(apply_title_case("the BBC's story OF radio", TitleCase::Sentence), "The BBC's story of radio");
+        assert_eq!(apply_title_case("NASA", TitleCase::Sentence), "Nasa");
+        assert_eq!(apply_title_case("I am a robot", TitleCase::Title), "I Am a Robot");
+    }
+
+    /// Tests sentence, lower, and upper case with non-ASCII letters and scripts without case.
+    #[test]
+    fn test_title_case_unicode() {
+        assert_eq!(apply_title_case("ÉTÉ À PARIS", TitleCase::Sentence), "Été à paris");
+        assert_eq!(apply_title_case("ΟΔΥΣΣΕΙΑ", TitleCase::Lower), "οδυσσεια");
+        assert_eq!(apply_title_case("straße", TitleCase::Upper), "STRASSE");
+        assert_eq!(apply_title_case("第一章 科学边界", TitleCase::Title), "第一章 科学边界");
+        assert_eq!(apply_title_case("第一章 the beginning", TitleCase::Title), "第一章 The Beginning");
+        assert_eq!(apply_title_case("Mixed Case", TitleCase::Keep), "Mixed Case");
+    }
+}
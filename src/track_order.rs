@@ -0,0 +1,138 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Tag keys that hold the track number, as ffprobe names them for ID3, MP4, and Vorbis comments.
+const TRACK_KEYS: [&str; 2] = ["track", "tracknumber"];
+/// Tag keys that hold the disc number.
+const DISC_KEYS: [&str; 2] = ["disc", "discnumber"];
+/// Tag keys that hold a title sort name: MP4 `sonm`, ID3 `TSOT`, and Vorbis `TITLESORT`.
+const SORT_NAME_KEYS: [&str; 4] = ["sort_name", "title-sort", "tsot", "titlesort"];
+
+/// Where a file belongs in the book according to its tags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackPosition {
+    /// The disc number; files without one are treated as disc 1.
+    pub disc: u32,
+    pub track: u32,
+    /// The title sort name, used to order files that share a disc and track number.
+    pub sort_name: Option<String>,
+}
+
+impl Ord for TrackPosition {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.disc, self.track, &self.sort_name).cmp(&(other.disc, other.track, &other.sort_name))
+    }
+}
+
+impl PartialOrd for TrackPosition {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Parses a track or disc value into its number.
+///
+/// Taggers disagree on the format: iTunes writes `2/23`, EAC zero-pads to `02`, and
+/// foobar2000 writes a plain `2` or `02/23`. The `/total` suffix is dropped and the rest must
+/// be a whole number.
+///
+/// # Returns
+///
+/// The number, or `None` if the value is not a number.
+pub fn parse_position_number(value: &str) -> Option<u32> {
+    let number = value.split('/').next()?.trim();
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// Reads a file's position from its format tags (keys in lowercase, as `inspect_book` returns them).
+///
+/// # Returns
+///
+/// The `TrackPosition`, or an error message naming the missing or unreadable tag.
+pub fn track_position(tags: &HashMap<String, String>) -> Result<TrackPosition, String> {
+    let first_tag = |keys: &[&str]| keys.iter().find_map(|key| tags.get(*key));
+    let track = match first_tag(&TRACK_KEYS) {
+        Some(value) => parse_position_number(value).ok_or_else(|| format!("unreadable track number '{}'", value))?,
+        None => return Err("no track number tag".to_string()),
+    };
+    let disc = match first_tag(&DISC_KEYS) {
+        Some(value) => parse_position_number(value).ok_or_else(|| format!("unreadable disc number '{}'", value))?,
+        None => 1,
+    };
+    let sort_name = first_tag(&SORT_NAME_KEYS).map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+    Ok(TrackPosition { disc, track, sort_name })
+}
+
+/// Orders files by their tag positions.
+///
+/// Files with a readable position come first, by disc, track, and sort name. Files without one
+/// follow in their original order, so they are not scattered among the tagged files.
+///
+/// # Arguments
+///
+/// * `positions` - The position of each file, or why it could not be read, in the current order.
+///
+/// # Returns
+///
+/// The indices of the files in their new order.
+pub fn order_by_tags(positions: &[Result<TrackPosition, String>]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..positions.len()).collect();
+    // `Err` sorts after `Ok`; the stable sort keeps the original order for ties and untagged files.
+    order.sort_by(|&a, &b| match (&positions[a], &positions[b]) {
+        (Ok(a), Ok(b)) => a.cmp(b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => Ordering::Equal,
+    });
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    /// Tests the track formats written by iTunes, EAC, and foobar2000.
+    #[test]
+    fn test_parse_position_number() {
+        assert_eq!(parse_position_number("2/23"), Some(2));
+        assert_eq!(parse_position_number("02"), Some(2));
+        assert_eq!(parse_position_number("2"), Some(2));
+        assert_eq!(parse_position_number("02/23"), Some(2));
+        assert_eq!(parse_position_number(" 10 / 12 "), Some(10));
+        assert_eq!(parse_position_number("A1"), None);
+        assert_eq!(parse_position_number("/12"), None);
+        assert_eq!(parse_position_number(""), None);
+    }
+
+    /// Tests reading the position from ID3, MP4, and Vorbis style tags.
+    #[test]
+    fn test_track_position() {
+        let position = track_position(&tags(&[("track", "3/12"), ("disc", "2/2"), ("title-sort", "Storm, The")])).unwrap();
+        assert_eq!(position, TrackPosition { disc: 2, track: 3, sort_name: Some("Storm, The".to_string()) });
+        let position = track_position(&tags(&[("tracknumber", "07"), ("sort_name", "Intro")])).unwrap();
+        assert_eq!(position, TrackPosition { disc: 1, track: 7, sort_name: Some("Intro".to_string()) });
+        assert_eq!(track_position(&tags(&[("title", "Intro")])), Err("no track number tag".to_string()));
+        assert_eq!(track_position(&tags(&[("track", "1"), ("disc", "B")])), Err("unreadable disc number 'B'".to_string()));
+    }
+
+    /// Tests numeric ordering across discs with sort names as a tiebreaker and untagged files last.
+    #[test]
+    fn test_order_by_tags() {
+        let positions = vec![
+            track_position(&tags(&[("track", "10/12")])),
+            track_position(&tags(&[("track", "A1")])),
+            track_position(&tags(&[("track", "2"), ("disc", "2")])),
+            track_position(&tags(&[("track", "02"), ("tsot", "Beta")])),
+            track_position(&tags(&[("track", "2/12"), ("tsot", "Alpha")])),
+            track_position(&tags(&[])),
+        ];
+        assert_eq!(order_by_tags(&positions), vec![4, 3, 0, 2, 1, 5]);
+    }
+}
@@ -0,0 +1,212 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use m4btool::{sanitize_filename, FilenameOptions};
+
+use crate::console;
+use crate::runner::CommandRunner;
+use crate::shell::os_args;
+use crate::tags::{BookTags, ALBUM_ARTIST_KEY, ALBUM_KEY, AUTHOR_KEY, GENRE_KEY, NARRATOR_KEY, TITLE_KEY, TRACK_KEY, YEAR_KEY};
+
+/// One encoded file of the book, exported as a track by `--also-export-tracks`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportTrack<'a> {
+    /// The encoded file, or the source file when its encode failed.
+    pub audio: &'a str,
+    /// The cleaned title of its chapter.
+    pub title: &'a str,
+}
+
+/// Names the tracks after their titles, numbered so that they sort in book order, e.g.
+/// `01 - Prologue.m4a`. The number leads the name, so titles that are the same, or only differ
+/// in case or in characters a file name cannot hold, still give every track its own file.
+pub fn track_file_names(titles: &[&str]) -> Vec<String> {
+    let width = titles.len().to_string().len().max(2);
+    titles.iter()
+        .enumerate()
+        .map(|(index, title)| sanitize_filename(&format!("{:0width$} - {}.m4a", index + 1, title, width = width), &FilenameOptions::default()))
+        .collect()
+}
+
+/// The tags of one track: its own title and number, and the book's title as the album with
+/// the book's author, narrator, date, and genre.
+pub fn track_tags(book: &BookTags, title: &str, number: usize, total: usize) -> Vec<(&'static str, String)> {
+    let mut pairs = vec![(TITLE_KEY, title.to_string())];
+    let book_pairs = [
+        (ALBUM_KEY, book.title.as_ref()),
+        (AUTHOR_KEY, book.author.as_ref()),
+        (ALBUM_ARTIST_KEY, book.author.as_ref()),
+        (NARRATOR_KEY, book.narrator.as_ref()),
+        (YEAR_KEY, book.date.as_ref().or(book.year.as_ref())),
+        (GENRE_KEY, book.genre.as_ref()),
+    ];
+    pairs.extend(book_pairs.into_iter().filter_map(|(key, value)| value.map(|value| (key, value.clone()))));
+    pairs.push((TRACK_KEY, format!("{}/{}", number, total)));
+    pairs
+}
+
+/// Builds the ffmpeg arguments that remux one encoded file into a tagged track, without the
+/// program name. The audio is copied, and the source's own tags and chapters are dropped so
+/// every track carries the same set of tags.
+pub fn track_args(audio: &str, cover: Option<&str>, copy_cover: bool, tags: &[(&'static str, String)], output: &Path) -> Vec<OsString> {
+    let mut args = os_args(&["-i", audio]);
+    if let Some(cover) = cover {
+        args.extend(os_args(&["-i", cover]));
+    }
+    args.extend(os_args(&["-map", "0:a", "-map_metadata", "-1", "-map_chapters", "-1"]));
+    if cover.is_some() {
+        args.extend(os_args(&["-map", "1"]));
+    }
+    args.extend(os_args(&["-c:a", "copy"]));
+    if cover.is_some() {
+        let codec = if copy_cover { "copy" } else { "mjpeg" };
+        args.extend(os_args(&["-c:v", codec, "-disposition:v:0", "attached_pic"]));
+    }
+    for (key, value) in tags {
+        args.extend(os_args(&["-metadata", &format!("{}={}", key, value)]));
+    }
+    args.extend(os_args(&["-f", "ipod", "-y"]));
+    args.push(output.into());
+    args
+}
+
+/// Writes every encoded file of the book as a tagged `.m4a` track into `dir`, for
+/// `--also-export-tracks`. Nothing is encoded again: each track is a remux of its file.
+///
+/// # Arguments
+///
+/// * `tracks` - The book's encoded files with their chapter titles, in book order.
+/// * `dir` - The directory for the tracks, created if needed.
+/// * `book` - The book's tags, which every track shares.
+/// * `cover` - The book's cover, attached to every track.
+/// * `copy_cover` - Whether the cover is a JPEG or PNG that is attached as it is.
+/// * `runner` - Runs the ffmpeg commands.
+///
+/// # Returns
+///
+/// The tracks written, or an error message naming the first track that failed.
+pub fn export_tracks(
+    tracks: &[ExportTrack],
+    dir: &Path,
+    book: &BookTags,
+    cover: Option<&str>,
+    copy_cover: bool,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<PathBuf>, String> {
+    fs::create_dir_all(dir).map_err(|err| format!("Could not create '{}': {}", dir.display(), err))?;
+    let titles: Vec<&str> = tracks.iter().map(|track| track.title).collect();
+    let mut written = Vec::new();
+    for (index, (track, name)) in tracks.iter().zip(track_file_names(&titles)).enumerate() {
+        let path = dir.join(name);
+        let tags = track_tags(book, track.title, index + 1, tracks.len());
+        let output = runner.run(Command::new("ffmpeg").args(track_args(track.audio, cover, copy_cover, &tags, &path)))
+            .map_err(|err| format!("Could not execute ffmpeg: {}", err))?;
+        if !output.status.success() {
+            return Err(format!("Could not write '{}': {}", path.display(), console::last_stderr_line(&output)));
+        }
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use {std::cell::RefCell, std::io, std::os::unix::process::ExitStatusExt, std::process::{ExitStatus, Output}};
+
+    /// Tests numbering and sanitizing the track names, including titles that collide once
+    /// sanitized.
+    #[test]
+    fn test_track_file_names() {
+        assert_eq!(
+            track_file_names(&["Prologue", "Part 1: Dune", "Part 1? Dune", "CON"]),
+            vec!["01 - Prologue.m4a", "02 - Part 1_ Dune.m4a", "03 - Part 1_ Dune.m4a", "04 - CON.m4a"]
+        );
+        let titles: Vec<String> = (1..=120).map(|n| format!("Chapter {}", n)).collect();
+        let names = track_file_names(&titles.iter().map(String::as_str).collect::<Vec<_>>());
+        assert_eq!((names[0].as_str(), names[119].as_str()), ("001 - Chapter 1.m4a", "120 - Chapter 120.m4a"));
+    }
+
+    /// Golden test for the remux of one track with a cover.
+    #[test]
+    fn test_track_args() {
+        let book = BookTags { title: Some("Dune".to_string()), author: Some("Frank Herbert".to_string()), year: Some("1965".to_string()), ..BookTags::default() };
+        let args: Vec<String> = track_args("/tmp/enc1.m4a", Some("/books/dune/cover.jpg"), true, &track_tags(&book, "Prologue", 1, 12), Path::new("/tracks/01 - Prologue.m4a"))
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(
+            args,
+            vec![
+                "-i", "/tmp/enc1.m4a", "-i", "/books/dune/cover.jpg",
+                "-map", "0:a", "-map_metadata", "-1", "-map_chapters", "-1", "-map", "1",
+                "-c:a", "copy", "-c:v", "copy", "-disposition:v:0", "attached_pic",
+                "-metadata", "title=Prologue", "-metadata", "album=Dune", "-metadata", "artist=Frank Herbert",
+                "-metadata", "album_artist=Frank Herbert", "-metadata", "date=1965", "-metadata", "track=1/12",
+                "-f", "ipod", "-y", "/tracks/01 - Prologue.m4a",
+            ]
+        );
+    }
+
+    /// Writes each track it is asked for as an empty file, and can fail one of them.
+    #[cfg(unix)]
+    struct TrackRunner {
+        commands: RefCell<Vec<Vec<String>>>,
+        fail_at: Option<usize>,
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for TrackRunner {
+        fn run(&self, command: &mut Command) -> io::Result<Output> {
+            let args: Vec<String> = command.get_args().map(|arg| arg.to_string_lossy().to_string()).collect();
+            let fails = self.fail_at == Some(self.commands.borrow().len());
+            if !fails {
+                fs::write(args.last().unwrap(), b"")?;
+            }
+            self.commands.borrow_mut().push(args);
+            Ok(Output {
+                status: ExitStatus::from_raw(if fails { 256 } else { 0 }),
+                stdout: Vec::new(),
+                stderr: if fails { b"Invalid data found when processing input\n".to_vec() } else { Vec::new() },
+            })
+        }
+    }
+
+    /// Tests exporting a book's tracks: one tagged track per encoded file in a new directory,
+    /// and a failed remux reported with the track's name.
+    #[cfg(unix)]
+    #[test]
+    fn test_export_tracks() {
+        let out = tempfile::tempdir().unwrap();
+        let dir = out.path().join("Dune tracks");
+        let book = BookTags { title: Some("Dune".to_string()), author: Some("Frank Herbert".to_string()), ..BookTags::default() };
+        let tracks = [
+            ExportTrack { audio: "/tmp/enc1.m4a", title: "Prologue" },
+            ExportTrack { audio: "/tmp/enc2.m4a", title: "Arrakis" },
+            ExportTrack { audio: "/books/dune/03.mp3", title: "Arrakis" },
+        ];
+        let runner = TrackRunner { commands: RefCell::new(Vec::new()), fail_at: None };
+        let written = export_tracks(&tracks, &dir, &book, None, false, &runner).unwrap();
+        assert_eq!(written, vec![dir.join("01 - Prologue.m4a"), dir.join("02 - Arrakis.m4a"), dir.join("03 - Arrakis.m4a")]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        let commands = runner.commands.borrow();
+        for (index, (command, track)) in commands.iter().zip(&tracks).enumerate() {
+            assert_eq!(command[..2], [String::from("-i"), track.audio.to_string()]);
+            let tags: Vec<&str> = command.windows(2).filter(|pair| pair[0] == "-metadata").map(|pair| pair[1].as_str()).collect();
+            let track_number = format!("track={}/3", index + 1);
+            let title = format!("title={}", track.title);
+            assert_eq!(tags, vec![title.as_str(), "album=Dune", "artist=Frank Herbert", "album_artist=Frank Herbert", track_number.as_str()]);
+            assert!(!command.contains(&"attached_pic".to_string()));
+        }
+
+        let failing = TrackRunner { commands: RefCell::new(Vec::new()), fail_at: Some(1) };
+        assert_eq!(
+            export_tracks(&tracks, &dir, &book, None, false, &failing).unwrap_err(),
+            format!("Could not write '{}': Invalid data found when processing input", dir.join("02 - Arrakis.m4a").display())
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! ASCII transliteration of chapter titles.
+//!
+//! Some players cannot render CJK or other non-Latin scripts. Transliteration replaces every
+//! character with its closest ASCII spelling, using the `deunicode` tables (pinyin for Chinese,
+//! romanization for Cyrillic and Greek).
+
+use regex::Regex;
+
+/// Transliterates a title to ASCII.
+///
+/// Full-width and lenticular brackets become their ASCII counterparts, syllables are separated
+/// by single spaces, and no space is left just inside a bracket. ASCII titles are returned
+/// unchanged.
+///
+/// # Example
+///
+/// ```
+/// use m4btool::transliterate_title;
+///
+/// assert_eq!(transliterate_title("第一章 科学边界"), "Di Yi Zhang Ke Xue Bian Jie");
+/// ```
+pub fn transliterate_title(title: &str) -> String {
+    if title.is_ascii() {
+        return title.to_string();
+    }
+    // Full-width brackets carry their own spacing, so the ASCII ones get a space outside them.
+    let bracketed: String = title.chars()
+        .map(|c| match c {
+            '【' => " [".to_string(),
+            '】' => "] ".to_string(),
+            '（' => " (".to_string(),
+            '）' => ") ".to_string(),
+            other => other.to_string(),
+        })
+        .collect();
+    let ascii = deunicode::deunicode(&bracketed);
+
+    // A number directly followed by a transliterated syllable ("第1章" gives "Di 1Zhang") gets a space.
+    let ascii = Regex::new(r"(\d)([A-Z])").unwrap().replace_all(&ascii, "$1 $2");
+    let ascii = Regex::new(r"\s+").unwrap().replace_all(&ascii, " ");
+    let ascii = Regex::new(r"([\[(]) ").unwrap().replace_all(&ascii, "$1");
+    let ascii = Regex::new(r" ([\])])").unwrap().replace_all(&ascii, "$1");
+    ascii.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests Chinese titles with numbers and brackets, and other scripts.
+    #[test]
+    fn test_transliterate_title() {
+        assert_eq!(transliterate_title("第1章【科学边界】（台球）"), "Di 1 Zhang [Ke Xue Bian Jie] (Tai Qiu)");
+        assert_eq!(transliterate_title("第3章 三体问题"), "Di 3 Zhang San Ti Wen Ti");
+        assert_eq!(transliterate_title("序章"), "Xu Zhang");
+        assert_eq!(transliterate_title("Пролог"), "Prolog");
+        assert_eq!(transliterate_title("Café au lait"), "Cafe au lait");
+        assert_eq!(transliterate_title("Part 1B (Intro)"), "Part 1B (Intro)");
+    }
+}
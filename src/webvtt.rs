@@ -0,0 +1,108 @@
+//! WebVTT chapter generation.
+//!
+//! HTML5 players read chapters from a WebVTT file attached with `<track kind="chapters">`.
+//! This module renders such a file from the same chapter titles and durations as
+//! `write_ffmetadata`.
+
+/// Formats milliseconds as a WebVTT timestamp, `HH:MM:SS.mmm`.
+///
+/// Hours are not wrapped, so books longer than 99 hours get a wider hour field.
+pub fn format_timestamp(ms: u64) -> String {
+    let hours = ms / 3_600_000;
+    let minutes = ms / 60_000 % 60;
+    let seconds = ms / 1_000 % 60;
+    format!("{:02}:{:02}:{:02}.{:03}", hours, minutes, seconds, ms % 1_000)
+}
+
+/// Renders a WebVTT chapters file with one numbered cue per `(title, duration)` entry.
+///
+/// Cues are laid out back to back starting at zero, like the chapters in the FFMETADATA file.
+/// `&` and `<` in titles are escaped, and line breaks are replaced by spaces because a blank
+/// line would end the cue.
+///
+/// # Arguments
+///
+/// * `chapters` - The chapter titles with their durations in milliseconds, in order.
+///
+/// # Returns
+///
+/// The complete file contents.
+///
+/// # Example
+///
+/// ```
+/// use m4btool::write_vtt_chapters;
+///
+/// let chapters = vec![("Intro".to_string(), 1500)];
+/// assert_eq!(write_vtt_chapters(&chapters), "WEBVTT\n\n1\n00:00:00.000 --> 00:00:01.500\nIntro\n");
+/// ```
+pub fn write_vtt_chapters(chapters: &[(String, u64)]) -> String {
+    write_vtt_chapters_at(chapters, 0)
+}
+
+/// Renders a WebVTT chapters file like `write_vtt_chapters`, with the cues laid out back to back
+/// from `start_ms` instead of zero, e.g. after a lead-in of silence.
+pub fn write_vtt_chapters_at(chapters: &[(String, u64)], start_ms: u64) -> String {
+    let mut text = String::from("WEBVTT\n");
+    let mut chapter_start_ms = start_ms;
+    for (index, (title, duration_ms)) in chapters.iter().enumerate() {
+        let chapter_end_ms = chapter_start_ms + duration_ms;
+        text.push_str(&format!(
+            "\n{}\n{} --> {}\n{}\n",
+            index + 1,
+            format_timestamp(chapter_start_ms),
+            format_timestamp(chapter_end_ms),
+            escape_cue_text(title)
+        ));
+        chapter_start_ms = chapter_end_ms;
+    }
+    text
+}
+
+/// Escapes cue text so it is shown literally and stays on one line.
+fn escape_cue_text(title: &str) -> String {
+    title.replace('&', "&amp;").replace('<', "&lt;").replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests timestamp formatting around unit boundaries.
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "00:00:00.000");
+        assert_eq!(format_timestamp(61_250), "00:01:01.250");
+        assert_eq!(format_timestamp(3_599_999), "00:59:59.999");
+        assert_eq!(format_timestamp(3_600_000 * 12 + 7), "12:00:00.007");
+        assert_eq!(format_timestamp(3_600_000 * 100), "100:00:00.000");
+    }
+
+    /// Tests consecutive cues and escaping of cue text.
+    #[test]
+    fn test_write_vtt_chapters() {
+        let chapters = vec![("Arrakis".to_string(), 61_250), ("Q&A <live>\nPart 2".to_string(), 120_000)];
+        assert_eq!(
+            write_vtt_chapters(&chapters),
+            "WEBVTT\n\
+             \n\
+             1\n\
+             00:00:00.000 --> 00:01:01.250\n\
+             Arrakis\n\
+             \n\
+             2\n\
+             00:01:01.250 --> 00:03:01.250\n\
+             Q&amp;A &lt;live> Part 2\n"
+        );
+    }
+
+    /// Tests cues shifted by a lead-in.
+    #[test]
+    fn test_write_vtt_chapters_at() {
+        let chapters = vec![("Arrakis".to_string(), 61_250), ("The Desert".to_string(), 1_000)];
+        assert_eq!(
+            write_vtt_chapters_at(&chapters, 1_500),
+            "WEBVTT\n\n1\n00:00:01.500 --> 00:01:02.750\nArrakis\n\n2\n00:01:02.750 --> 00:01:03.750\nThe Desert\n"
+        );
+    }
+}